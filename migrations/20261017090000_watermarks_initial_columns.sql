@@ -0,0 +1,6 @@
+CREATE TABLE watermarks (
+job TEXT NOT NULL UNIQUE,
+PRIMARY KEY (job),
+watermark TIMESTAMPTZ NOT NULL,
+updated TIMESTAMPTZ NOT NULL
+);
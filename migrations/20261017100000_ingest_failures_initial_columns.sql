@@ -0,0 +1,11 @@
+CREATE TABLE ingest_failures (
+id uuid NOT NULL UNIQUE,
+PRIMARY KEY (id),
+job TEXT NOT NULL,
+raw_row JSONB NOT NULL,
+error TEXT NOT NULL,
+first_seen TIMESTAMPTZ NOT NULL,
+last_seen TIMESTAMPTZ NOT NULL
+);
+-- The same bad row is fetched again on later runs, so it is recorded once per job
+CREATE UNIQUE INDEX ingest_failures_job_raw_row ON ingest_failures (job, md5(raw_row::text));
@@ -0,0 +1,2 @@
+ALTER TABLE subscriptions
+ADD COLUMN unattributed_reason TEXT;
@@ -0,0 +1,8 @@
+CREATE TABLE idempotency_keys (
+key TEXT NOT NULL UNIQUE,
+PRIMARY KEY (key),
+request_hash TEXT NOT NULL,
+response JSONB NOT NULL,
+created TIMESTAMPTZ NOT NULL,
+expires TIMESTAMPTZ NOT NULL
+);
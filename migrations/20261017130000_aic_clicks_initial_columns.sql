@@ -0,0 +1,15 @@
+CREATE TABLE aic_clicks (
+id uuid NOT NULL UNIQUE,
+PRIMARY KEY (id),
+aic_id uuid NOT NULL,
+cj_event_value TEXT NOT NULL,
+clicked TIMESTAMPTZ NOT NULL,
+expires TIMESTAMPTZ NOT NULL
+);
+-- Clicks outlive their AIC being archived, so aic_id isn't a foreign key
+CREATE INDEX aic_clicks_aic_id ON aic_clicks (aic_id);
+-- Only the last click of existing AICs is known
+INSERT INTO aic_clicks (id, aic_id, cj_event_value, clicked, expires)
+SELECT md5(random()::text || id::text)::uuid, id, cj_event_value, created, expires FROM aic;
+INSERT INTO aic_clicks (id, aic_id, cj_event_value, clicked, expires)
+SELECT md5(random()::text || id::text)::uuid, id, cj_event_value, created, expires FROM aic_archive;
@@ -0,0 +1,9 @@
+-- What CJ told us about a click, e.g. publisher_id and utm_source, as JSON
+ALTER TABLE aic
+ADD COLUMN click_metadata JSONB;
+ALTER TABLE aic_archive
+ADD COLUMN click_metadata JSONB;
+ALTER TABLE aic_clicks
+ADD COLUMN click_metadata JSONB;
+ALTER TABLE subscriptions
+ADD COLUMN click_metadata JSONB;
@@ -0,0 +1,15 @@
+CREATE TABLE erasures (
+id uuid NOT NULL UNIQUE,
+PRIMARY KEY (id),
+identifier_type TEXT NOT NULL,
+-- A sha256 of the identifier, so an erasure can be confirmed without keeping what was erased
+identifier_hash TEXT NOT NULL,
+requested_by TEXT NOT NULL,
+erased TIMESTAMPTZ NOT NULL,
+aic_deleted BIGINT NOT NULL,
+aic_archive_deleted BIGINT NOT NULL,
+aic_clicks_deleted BIGINT NOT NULL,
+ingest_failures_deleted BIGINT NOT NULL,
+subscriptions_pseudonymized BIGINT NOT NULL,
+refunds_pseudonymized BIGINT NOT NULL
+);
@@ -0,0 +1 @@
+CREATE INDEX aic_expires_id_idx ON aic (expires, id);
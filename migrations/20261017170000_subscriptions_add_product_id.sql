@@ -0,0 +1,2 @@
+ALTER TABLE subscriptions
+ADD COLUMN product_id TEXT;
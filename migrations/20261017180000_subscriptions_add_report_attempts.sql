@@ -0,0 +1,5 @@
+-- How reporting a subscription to CJ has gone, for retrying with backoff
+ALTER TABLE subscriptions
+ADD COLUMN report_attempts INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN report_last_error TEXT,
+ADD COLUMN report_next_attempt TIMESTAMPTZ;
@@ -0,0 +1,16 @@
+-- Every S2S request sent to CJ and what CJ answered, for when a conversion is disputed
+CREATE TABLE cj_s2s_log (
+id uuid NOT NULL UNIQUE,
+PRIMARY KEY (id),
+-- The subscription's id, sent to CJ as OID
+sub_id uuid NOT NULL,
+sent TIMESTAMPTZ NOT NULL,
+-- The request URL with its SIGNATURE redacted
+url TEXT NOT NULL,
+-- NULL when no response was received, see error
+status INTEGER,
+response_body TEXT,
+error TEXT,
+latency_ms BIGINT NOT NULL
+);
+CREATE INDEX cj_s2s_log_sub_id ON cj_s2s_log (sub_id);
@@ -0,0 +1,4 @@
+-- How retrying a stored row has gone, kept apart from when BigQuery last returned it
+ALTER TABLE ingest_failures
+ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN last_attempt_at TIMESTAMPTZ;
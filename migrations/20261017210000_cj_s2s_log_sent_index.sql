@@ -0,0 +1 @@
+CREATE INDEX cj_s2s_log_sent_idx ON cj_s2s_log (sent);
@@ -0,0 +1,11 @@
+-- A sha256 of each identifier an erasure removed, so that the BigQuery rows it came from
+-- aren't fetched into the database again
+CREATE TABLE erased_identifiers (
+identifier_hash TEXT NOT NULL UNIQUE,
+PRIMARY KEY (identifier_hash),
+erasure_id uuid NOT NULL
+);
+-- What erasures before this table recorded
+INSERT INTO erased_identifiers (identifier_hash, erasure_id)
+SELECT identifier_hash, id FROM erasures
+ON CONFLICT DO NOTHING;
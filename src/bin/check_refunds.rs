@@ -1,8 +1,22 @@
-use lib::{appconfig::CJ, jobs::check_refunds::fetch_and_process_refunds, telemetry::LogKey};
+use clap::Parser;
+use lib::{
+    appconfig::CJ,
+    jobs::{check_refunds::fetch_and_process_refunds, watermark::WatermarkArgs},
+    telemetry::LogKey,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = WatermarkArgs::parse();
     let cj = CJ::new(LogKey::CheckRefunds).await;
-    fetch_and_process_refunds(&cj.bq_client, &cj.db_pool, &cj.statsd).await;
-    cj.shutdown().await
+    args.apply(&LogKey::CheckRefunds, &cj.db_pool, &cj.statsd)
+        .await;
+    let result = fetch_and_process_refunds(
+        &cj.bq_client,
+        &cj.settings.bq_refunds_table,
+        &cj.db_pool,
+        &cj.statsd,
+    )
+    .await;
+    cj.shutdown_after(result).await
 }
@@ -1,11 +1,36 @@
+use clap::Parser;
 use lib::{
-    appconfig::CJ, jobs::check_subscriptions::fetch_and_process_new_subscriptions,
+    appconfig::CJ,
+    jobs::{
+        check_subscriptions::{fetch_and_process_new_subscriptions, resolve_pending_attributions},
+        watermark::WatermarkArgs,
+    },
+    models::aic_clicks::AttributionModel,
     telemetry::LogKey,
 };
+use time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = WatermarkArgs::parse();
     let cj = CJ::new(LogKey::CheckSubscriptions).await;
-    fetch_and_process_new_subscriptions(&cj.bq_client, &cj.db_pool, &cj.statsd).await;
-    cj.shutdown().await
+    let attribution = AttributionModel::from_settings(&cj.settings);
+    args.apply(&LogKey::CheckSubscriptions, &cj.db_pool, &cj.statsd)
+        .await;
+    let result = fetch_and_process_new_subscriptions(
+        &cj.bq_client,
+        &cj.settings.bq_subscriptions_table,
+        &attribution,
+        &cj.db_pool,
+        &cj.statsd,
+    )
+    .await;
+    resolve_pending_attributions(
+        Duration::hours(cj.settings.pending_attribution_window_hours),
+        &attribution,
+        &cj.db_pool,
+        &cj.statsd,
+    )
+    .await;
+    cj.shutdown_after(result).await
 }
@@ -1,8 +1,13 @@
-use lib::{appconfig::CJ, jobs::cleanup::archive_expired_aics, telemetry::LogKey};
+use lib::{
+    appconfig::CJ,
+    jobs::cleanup::{archive_expired_aics, delete_expired_idempotency_keys},
+    telemetry::LogKey,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let cj = CJ::new(LogKey::Cleanup).await;
-    archive_expired_aics(&cj.db_pool, &cj.statsd).await;
+    archive_expired_aics(&cj.settings, &cj.db_pool, &cj.statsd).await;
+    delete_expired_idempotency_keys(&cj.db_pool, &cj.statsd).await;
     cj.shutdown().await
 }
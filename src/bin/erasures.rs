@@ -0,0 +1,12 @@
+use clap::Parser;
+use lib::{appconfig::CJ, jobs::erasures::ErasureArgs, telemetry::LogKey};
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = ErasureArgs::parse();
+    let cj = CJ::new(LogKey::Erasures).await;
+    let result = args
+        .run(&cj.db_pool, &cj.statsd, &mut std::io::stdout())
+        .await;
+    cj.shutdown_after(result).await
+}
@@ -0,0 +1,21 @@
+use clap::Parser;
+use lib::{
+    appconfig::CJ, jobs::ingest_failures::IngestFailureArgs, models::aic_clicks::AttributionModel,
+    telemetry::LogKey,
+};
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = IngestFailureArgs::parse();
+    let cj = CJ::new(LogKey::IngestFailures).await;
+    let attribution = AttributionModel::from_settings(&cj.settings);
+    let result = args
+        .run(
+            &attribution,
+            &cj.db_pool,
+            &cj.statsd,
+            &mut std::io::stdout(),
+        )
+        .await;
+    cj.shutdown_after(result).await
+}
@@ -0,0 +1,16 @@
+use lib::{
+    appconfig::CJ,
+    jobs::purge::{purge_aic_archive, purge_cj_s2s_log, purge_refunds, purge_subscriptions},
+    telemetry::LogKey,
+};
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let cj = CJ::new(LogKey::Purge).await;
+    purge_aic_archive(&cj.settings, &cj.db_pool, &cj.statsd).await;
+    purge_cj_s2s_log(&cj.settings, &cj.db_pool, &cj.statsd).await;
+    // Subscriptions before refunds, which are re-linked to the pseudonymized subscriptions
+    purge_subscriptions(&cj.settings, &cj.db_pool, &cj.statsd).await;
+    purge_refunds(&cj.settings, &cj.db_pool, &cj.statsd).await;
+    cj.shutdown().await
+}
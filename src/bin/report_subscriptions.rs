@@ -1,10 +1,24 @@
+use clap::Parser;
 use lib::{
-    appconfig::CJ, jobs::report_subscriptions::report_subscriptions_to_cj, telemetry::LogKey,
+    appconfig::CJ,
+    jobs::report_subscriptions::{ReportRetryPolicy, ReportSubscriptionsArgs},
+    telemetry::LogKey,
 };
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = ReportSubscriptionsArgs::parse();
     let cj = CJ::new(LogKey::ReportSubscriptions).await;
-    report_subscriptions_to_cj(&cj.db_pool, &cj.cj_client, &cj.statsd).await;
-    cj.shutdown().await
+    let retry = ReportRetryPolicy::from_settings(&cj.settings);
+    let result = args
+        .run(
+            &cj.db_pool,
+            &cj.cj_client,
+            &retry,
+            cj.settings.report_concurrency,
+            &cj.statsd,
+            &mut std::io::stdout(),
+        )
+        .await;
+    cj.shutdown_after(result).await
 }
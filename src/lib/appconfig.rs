@@ -3,7 +3,7 @@ use actix_web::{
     dev::{Server, ServiceRequest},
     error::ErrorUnauthorized,
     http,
-    web::{get, post, put, resource, Data},
+    web::{delete, get, post, put, resource, Data},
     App, Error, HttpServer,
 };
 use actix_web_httpauth::extractors::basic::BasicAuth;
@@ -17,8 +17,9 @@ use tracing_actix_web_mozlog::MozLog;
 
 use crate::{
     bigquery::client::{get_bqclient, BQClient},
-    cj::client::CJClient,
-    controllers, info_and_incr,
+    cj::{client::CJClient, programs::CJPrograms},
+    controllers::{self, aic_token::AICSigner},
+    error_and_incr, info_and_incr,
     settings::{get_settings, Settings},
     telemetry::{init_sentry, init_tracing, LogKey, StatsD},
 };
@@ -61,6 +62,24 @@ impl CJ {
         }
     }
 
+    /// Shuts down after a job, logging the job's error if it failed. A failed job is
+    /// returned as an error so that the binary exits with a non-zero status.
+    pub async fn shutdown_after<E>(&self, result: Result<(), E>) -> std::io::Result<()>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        if let Err(e) = &result {
+            error_and_incr!(
+                &self.statsd,
+                &self.name.add_suffix("failed"),
+                error = e,
+                "Job failed"
+            );
+        }
+        self.shutdown().await?;
+        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
     pub async fn shutdown(&self) -> std::io::Result<()> {
         info_and_incr!(
             &self.statsd,
@@ -100,7 +119,11 @@ pub fn run_server(
     db_pool: PgPool,
     statsd: StatsD,
 ) -> Result<Server, std::io::Error> {
+    let aic_signer = AICSigner::from_settings(&settings);
+    let cj_programs = CJPrograms::from_settings(&settings);
     let server = HttpServer::new(move || {
+        let aic_signer_d = Data::new(aic_signer.clone());
+        let cj_programs_d = Data::new(cj_programs.clone());
         let db_pool_d = Data::new(db_pool.clone());
         let settings_d = Data::new(settings.clone());
         let statsd_d = Data::new(statsd.clone());
@@ -125,6 +148,7 @@ pub fn run_server(
             // AIC
             .service(resource("/aic").route(post().to(controllers::aic::create)))
             .service(resource("/aic/{aic_id}").route(put().to(controllers::aic::update)))
+            .service(resource("/click").route(get().to(controllers::click::landing)))
             // Corrections
             .service(
                 resource("/corrections/today.csv").route(get().to(controllers::corrections::today)),
@@ -134,10 +158,29 @@ pub fn run_server(
                     .route(get().to(controllers::corrections::by_day))
                     .wrap(auth),
             )
+            .service(
+                resource("/corrections/{program}/today.csv")
+                    .route(get().to(controllers::corrections::today)),
+            )
+            .service(
+                resource("/corrections/{program}/{day}.csv")
+                    .route(get().to(controllers::corrections::by_day))
+                    .wrap(HttpAuthentication::basic(basic_auth_middleware)),
+            )
+            // Privacy
+            .service(
+                resource("/personal-data")
+                    .route(delete().to(controllers::erasures::delete))
+                    .wrap(HttpAuthentication::basic(basic_auth_middleware)),
+            )
             // Make data objects available to all routes
+            .app_data(aic_signer_d)
+            .app_data(cj_programs_d)
             .app_data(db_pool_d)
             .app_data(settings_d)
             .app_data(statsd_d)
+            .app_data(controllers::errors::json_config())
+            .app_data(controllers::errors::query_config())
     })
     .listen(listener)?
     .run();
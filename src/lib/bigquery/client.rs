@@ -1,22 +1,52 @@
 use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::StatusCode;
 use secrecy::{ExposeSecret, Secret};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
 
-use crate::settings::Settings;
+use crate::{
+    error,
+    settings::Settings,
+    telemetry::{LogKey, StatsD},
+};
 
-pub use super::model::{BQError, ResultSet};
-use super::model::{GetQueryResultsResponse, QueryResponse};
+pub use super::model::{BQError, FieldType, FromBQValue, QueryParameter, ResultSet};
+use super::model::{GetQueryResultsResponse, JobReference, QueryResponse};
+
+// The longest we ask BigQuery to hold a single request open while waiting for a job.
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(10);
+// How long before an access token expires that we fetch a new one.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
 
 pub async fn get_bqclient(settings: &Settings) -> BQClient {
     // Note we don't have tests that check:
     // - the correct setting of token when using metadata
     // - the correct setting of project when using metadata
     // Take appropriate caution when updating this function.
-    match use_env(settings) {
-        true => BQClient::new(&settings.gcp_project, AccessTokenFromEnv {}, None).await,
-        false => BQClient::new(&settings.gcp_project, AccessTokenFromMetadata {}, None).await,
-    }
+    let mut bq = match &settings.bq_service_account_key_file {
+        Some(key_file) => {
+            let token = AccessTokenFromServiceAccount::from_file(
+                key_file,
+                settings.bq_token_endpoint.as_deref(),
+            );
+            BQClient::new(&settings.gcp_project, token, None).await
+        }
+        None => match use_env(settings) {
+            true => BQClient::new(&settings.gcp_project, AccessTokenFromEnv {}, None).await,
+            false => BQClient::new(&settings.gcp_project, AccessTokenFromMetadata {}, None).await,
+        },
+    };
+    bq.page_size = settings.bq_page_size;
+    bq.query_timeout = Duration::from_secs(settings.bq_query_timeout_seconds);
+    bq.max_retries = settings.bq_max_retries;
+    bq.retry_backoff = Duration::from_millis(settings.bq_retry_backoff_ms);
+    bq.watermark_overlap = time::Duration::minutes(settings.bq_watermark_overlap_minutes);
+    bq
 }
 
 fn use_env(settings: &Settings) -> bool {
@@ -29,18 +59,51 @@ fn use_env(settings: &Settings) -> bool {
 pub struct BQClient {
     domain: String,
     pub project: String,
-    access_token: Secret<String>,
+    token_source: Box<dyn GetAccessToken + Send + Sync>,
+    access_token: Mutex<Option<CachedAccessToken>>,
     client: reqwest::Client,
+    /// Maximum number of rows requested per page of results.
+    pub page_size: u32,
+    /// How long to wait, across all polls, for a query job to complete.
+    pub query_timeout: Duration,
+    /// How many times to retry a request that failed with a 5xx or 429 response.
+    pub max_retries: u32,
+    /// The wait before the first retry. Doubles with each retry after that.
+    pub retry_backoff: Duration,
+    /// How far before a job's watermark to start fetching, to catch late-arriving rows.
+    pub watermark_overlap: time::Duration,
 }
 
 impl BQClient {
-    pub async fn new(project: &str, token: impl GetAccessToken, domain: Option<&str>) -> BQClient {
+    pub async fn new(
+        project: &str,
+        token: impl GetAccessToken + Send + Sync + 'static,
+        domain: Option<&str>,
+    ) -> BQClient {
         let domain = domain.unwrap_or("https://www.googleapis.com");
+        let access_token = match token.get().await {
+            Ok(access_token) => Some(CachedAccessToken::new(access_token)),
+            // Fetched again when first needed, where the error can be returned
+            Err(e) => {
+                error!(
+                    LogKey::BigQuery,
+                    error = e,
+                    "Could not get access token. Continuing..."
+                );
+                None
+            }
+        };
         BQClient {
             domain: domain.to_string(),
             project: project.to_string(),
-            access_token: token.get().await,
+            token_source: Box::new(token),
+            access_token: Mutex::new(access_token),
             client: reqwest::Client::new(),
+            page_size: 10000,
+            query_timeout: Duration::from_secs(300),
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            watermark_overlap: time::Duration::days(1),
         }
     }
     pub fn query_api_url(&self) -> String {
@@ -49,103 +112,482 @@ impl BQClient {
             self.domain, self.project
         )
     }
-    pub async fn get_bq_results(&self, query: &str) -> ResultSet {
-        let resp = self
-            .client
-            .post(self.query_api_url().as_str())
+    fn get_query_results_api_url(&self, job_reference: &JobReference) -> String {
+        format!(
+            "{}/bigquery/v2/projects/{}/queries/{}",
+            self.domain,
+            job_reference.project_id.as_ref().unwrap_or(&self.project),
+            job_reference.job_id.as_ref().unwrap_or(&String::new())
+        )
+    }
+
+    /// Runs the query and returns an iterator over every row of the results.
+    ///
+    /// If the job has not completed by the time the query call returns, getQueryResults
+    /// is polled until it does or until `query_timeout` has passed. Subsequent pages of
+    /// results are fetched by the iterator as it reaches the end of the current page.
+    pub async fn get_bq_results(&self, query: &str) -> Result<ResultIterator<'_>, BQError> {
+        self.get_bq_results_with_params(query, &[]).await
+    }
+
+    /// As `get_bq_results`, for queries that reference named parameters e.g. `@since`.
+    pub async fn get_bq_results_with_params(
+        &self,
+        query: &str,
+        params: &[QueryParameter],
+    ) -> Result<ResultIterator<'_>, BQError> {
+        let deadline = Instant::now() + self.query_timeout;
+        let mut body = json!({
+            "kind": "bigquery#queryResponse",
+            "query": query,
+            "useLegacySql": false,
+            "maxResults": self.page_size,
+            "timeoutMs": poll_timeout_ms(deadline),
+        });
+        if !params.is_empty() {
+            body["parameterMode"] = json!("NAMED");
+            body["queryParameters"] = json!(params);
+        }
+        let url = self.query_api_url();
+        let mut query_response = self
+            .send(|| self.client.post(url.as_str()).json(&body))
+            .await?;
+        while !query_response.job_complete.unwrap_or(false) {
+            if Instant::now() >= deadline {
+                return Err(BQError::Timeout {
+                    job_reference: query_response.job_reference,
+                });
+            }
+            let job_reference = query_response.job_reference.ok_or_else(|| {
+                BQError::InvalidResponse("Incomplete job did not return a job reference".into())
+            })?;
+            query_response = self
+                .get_query_results(&job_reference, None, poll_timeout_ms(deadline))
+                .await?;
+        }
+        ResultIterator::new(self, query_response)
+    }
+
+    async fn get_query_results(
+        &self,
+        job_reference: &JobReference,
+        page_token: Option<&str>,
+        timeout_ms: u128,
+    ) -> Result<QueryResponse, BQError> {
+        let mut params = vec![
+            ("maxResults", self.page_size.to_string()),
+            ("timeoutMs", timeout_ms.to_string()),
+        ];
+        if let Some(location) = &job_reference.location {
+            params.push(("location", location.clone()));
+        }
+        if let Some(page_token) = page_token {
+            params.push(("pageToken", page_token.to_string()));
+        }
+        let url = self.get_query_results_api_url(job_reference);
+        self.send(|| self.client.get(url.as_str()).query(&params))
+            .await
+    }
+
+    /// The current access token, fetching a new one first if there is none or it is about
+    /// to expire.
+    async fn access_token(&self) -> Result<Secret<String>, BQError> {
+        let mut access_token = self.access_token.lock().await;
+        let cached = match access_token.take() {
+            Some(cached) if !cached.needs_refresh() => cached,
+            _ => CachedAccessToken::new(self.token_source.get().await?),
+        };
+        let token = cached.token.clone();
+        *access_token = Some(cached);
+        Ok(token)
+    }
+
+    async fn refresh_access_token(&self) -> Result<(), BQError> {
+        let token = self.token_source.get().await?;
+        *self.access_token.lock().await = Some(CachedAccessToken::new(token));
+        Ok(())
+    }
+
+    async fn send_with_token(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, BQError> {
+        let resp = request
             .header(
                 "Authorization",
-                format!("Bearer {}", self.access_token.expose_secret()),
+                format!("Bearer {}", self.access_token().await?.expose_secret()),
             )
-            .json(&json!({
-                "kind": "bigquery#queryResponse",
-                "query": query,
-                "useLegacySql": false,
-            }))
             .send()
-            .await
-            .expect("Did not successfully query bigquery");
-        if resp.status() != 200 {
-            panic!("Did not successfully query bigquery. {:?}", resp)
+            .await?;
+        Ok(resp)
+    }
+
+    async fn send_authorized(
+        &self,
+        request: &impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, BQError> {
+        let resp = self.send_with_token(request()).await?;
+        // The token may have been revoked or expired early. Get a new one and try once more.
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            self.refresh_access_token().await?;
+            return self.send_with_token(request()).await;
+        }
+        Ok(resp)
+    }
+
+    /// Sends the request built by `request`, retrying with exponential backoff while
+    /// bigquery responds with a 5xx or 429.
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<QueryResponse, BQError> {
+        let mut retries = 0;
+        loop {
+            let resp = self.send_authorized(&request).await?;
+            let status = resp.status();
+            if status.is_success() {
+                let query_results: GetQueryResultsResponse = resp.json().await?;
+                return Ok(QueryResponse::from(query_results));
+            }
+            let transient = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+            if transient && retries < self.max_retries {
+                let backoff = self
+                    .retry_backoff
+                    .saturating_mul(2u32.saturating_pow(retries));
+                error!(
+                    LogKey::BigQuery,
+                    status = status.as_u16(),
+                    retry = retries + 1,
+                    "Transient error from bigquery. Retrying after {:?}",
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+                continue;
+            }
+            let body = resp.text().await.unwrap_or_default();
+            return Err(BQError::from_response(status.as_u16(), body));
+        }
+    }
+}
+
+fn poll_timeout_ms(deadline: Instant) -> u128 {
+    deadline
+        .saturating_duration_since(Instant::now())
+        .min(MAX_POLL_TIMEOUT)
+        .as_millis()
+}
+
+/// Walks every row of a completed query job, fetching further pages as needed.
+///
+/// Dereferences to the `ResultSet` for the current page, so row accessors can be
+/// used directly on the iterator.
+pub struct ResultIterator<'a> {
+    bq: &'a BQClient,
+    current: ResultSet,
+    job_reference: Option<JobReference>,
+    page_token: Option<String>,
+    rows_fetched: usize,
+    total_rows: Option<String>,
+    total_bytes_processed: Option<String>,
+}
+
+impl<'a> ResultIterator<'a> {
+    fn new(bq: &'a BQClient, query_response: QueryResponse) -> Result<Self, BQError> {
+        Ok(ResultIterator {
+            bq,
+            job_reference: query_response.job_reference.clone(),
+            page_token: query_response.page_token.clone(),
+            total_rows: query_response.total_rows.clone(),
+            total_bytes_processed: query_response.total_bytes_processed.clone(),
+            rows_fetched: 0,
+            current: ResultSet::new(query_response)?,
+        })
+    }
+
+    /// Moves to the next row, fetching the next page of results if the current one is exhausted.
+    pub async fn next_row(&mut self) -> Result<bool, BQError> {
+        loop {
+            if self.current.next_row() {
+                self.rows_fetched += 1;
+                return Ok(true);
+            }
+            let page_token = match self.page_token.take() {
+                Some(page_token) => page_token,
+                None => return Ok(false),
+            };
+            let job_reference = self.job_reference.as_ref().ok_or_else(|| {
+                BQError::InvalidResponse("Paged results did not include a job reference".into())
+            })?;
+            let mut query_response = self
+                .bq
+                .get_query_results(job_reference, Some(&page_token), 0)
+                .await?;
+            if query_response.schema.is_none() {
+                query_response.schema = self.current.schema().cloned();
+            }
+            self.page_token = query_response.page_token.clone();
+            self.current = ResultSet::new(query_response)?;
+        }
+    }
+
+    /// Number of rows iterated over so far, across all pages.
+    pub fn rows_fetched(&self) -> usize {
+        self.rows_fetched
+    }
+
+    pub fn report_stats(&self, statsd: &StatsD, key: &LogKey) {
+        statsd.gauge(&key.add_suffix("n-from-bq"), self.rows_fetched);
+        let total_rows = self.total_rows.clone().unwrap_or_else(|| "-1".to_string());
+        match total_rows.parse::<usize>() {
+            Ok(n) => {
+                statsd.gauge(&key.add_suffix("total-n-from-bq"), n);
+            }
+            Err(e) => error!(LogKey::BigQuery, error = e, "Could not get total rows",),
+        };
+        let bytes_processed = self
+            .total_bytes_processed
+            .clone()
+            .unwrap_or_else(|| "-1".to_string());
+        match bytes_processed.parse::<usize>() {
+            Ok(n) => {
+                statsd.gauge(&key.add_suffix("bytes-from-bq"), n);
+            }
+            Err(e) => error!(
+                LogKey::BigQuery,
+                error = e,
+                "Could not get total bytes processed",
+            ),
+        };
+    }
+}
+
+impl Deref for ResultIterator<'_> {
+    type Target = ResultSet;
+
+    fn deref(&self) -> &ResultSet {
+        &self.current
+    }
+}
+
+pub struct AccessToken {
+    pub token: Secret<String>,
+    /// How long the token is valid for, if known. Tokens without an expiry are only
+    /// replaced when BigQuery rejects them.
+    pub expires_in: Option<Duration>,
+}
+
+impl From<Secret<String>> for AccessToken {
+    fn from(token: Secret<String>) -> Self {
+        AccessToken {
+            token,
+            expires_in: None,
+        }
+    }
+}
+
+struct CachedAccessToken {
+    token: Secret<String>,
+    refresh_at: Option<Instant>,
+}
+
+impl CachedAccessToken {
+    fn new(access_token: AccessToken) -> Self {
+        CachedAccessToken {
+            token: access_token.token,
+            refresh_at: access_token
+                .expires_in
+                .map(|expires_in| Instant::now() + expires_in.saturating_sub(TOKEN_REFRESH_MARGIN)),
+        }
+    }
+
+    fn needs_refresh(&self) -> bool {
+        match self.refresh_at {
+            Some(refresh_at) => Instant::now() >= refresh_at,
+            None => false,
         }
-        let query_results: GetQueryResultsResponse =
-            resp.json().await.expect("Couldn't extract body.");
-        ResultSet::new(QueryResponse::from(query_results))
     }
 }
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait GetAccessToken {
-    async fn get(&self) -> Secret<String>;
+    async fn get(&self) -> Result<AccessToken, BQError>;
 }
 
 #[derive(Deserialize, Debug)]
-#[allow(dead_code)]
 struct WorkloadIdentityAccessToken {
     pub access_token: Secret<String>,
+    expires_in: u64,
     #[allow(dead_code)]
-    // The following are used for serialization in production only
-    expires_in: i32,
+    // Used for serialization in production only
     token_type: String,
 }
 
+impl From<WorkloadIdentityAccessToken> for AccessToken {
+    fn from(token: WorkloadIdentityAccessToken) -> Self {
+        AccessToken {
+            token: token.access_token,
+            expires_in: Some(Duration::from_secs(token.expires_in)),
+        }
+    }
+}
+
+/// Reads the access token from the response of the metadata server or a token endpoint.
+async fn access_token_from_response(resp: reqwest::Response) -> Result<AccessToken, BQError> {
+    let status = resp.status();
+    if status != StatusCode::OK {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(BQError::HttpStatus {
+            status: status.as_u16(),
+            body,
+        });
+    }
+    let content: WorkloadIdentityAccessToken = resp.json().await.map_err(|e| {
+        BQError::InvalidResponse(format!("Could not deserialize access token. {}", e))
+    })?;
+    Ok(content.into())
+}
+
 pub struct AccessTokenFromMetadata {}
 #[async_trait]
 impl GetAccessToken for AccessTokenFromMetadata {
     // GCP docs on how this works https://cloud.google.com/run/docs/securing/service-identity#fetching_identity_and_access_tokens_using_the_metadata_server
-    async fn get(&self) -> Secret<String> {
+    async fn get(&self) -> Result<AccessToken, BQError> {
         let client = reqwest::Client::new();
         let resp = client
             .get("http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token")
             .header("Metadata-Flavor", "Google")
             .send()
-            .await;
-        match resp {
-            Ok(r) => {
-                if r.status() == 200 {
-                    let content: WorkloadIdentityAccessToken = r
-                        .json()
-                        .await
-                        .expect("Couldn't deserialize metadata for pod");
-                    content.access_token
-                } else {
-                    let body = r
-                        .text()
-                        .await
-                        .unwrap_or_else(|_| String::from("Failed to get body text."));
-                    panic!("Couldn't get metadata for pod. {:?}", body);
-                }
-            }
-            Err(e) => {
-                panic!("Couldn't get metadata for pod. {:?}", e);
-            }
-        }
+            .await?;
+        access_token_from_response(resp).await
     }
 }
 
 pub struct AccessTokenFromEnv {}
 #[async_trait]
 impl GetAccessToken for AccessTokenFromEnv {
-    async fn get(&self) -> Secret<String> {
-        Secret::new(std::env::var("BQ_ACCESS_TOKEN").expect("BQ_ACCESS_TOKEN not found in env."))
+    async fn get(&self) -> Result<AccessToken, BQError> {
+        // Intentional panic. Only used locally, where the token must be set before running.
+        let token = std::env::var("BQ_ACCESS_TOKEN").expect("BQ_ACCESS_TOKEN not found in env.");
+        Ok(Secret::new(token).into())
+    }
+}
+
+/// The parts of a service account JSON key file that we need.
+#[derive(Deserialize, Debug)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: Secret<String>,
+    private_key_id: Option<String>,
+    token_uri: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ServiceAccountClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+const BIGQUERY_SCOPE: &str = "https://www.googleapis.com/auth/bigquery";
+const DEFAULT_TOKEN_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
+
+/// Signs a JWT assertion with a service account key and exchanges it for an access token.
+pub struct AccessTokenFromServiceAccount {
+    client_email: String,
+    encoding_key: EncodingKey,
+    private_key_id: Option<String>,
+    token_endpoint: String,
+}
+
+impl AccessTokenFromServiceAccount {
+    /// Reads a service account JSON key file. The token endpoint defaults to the
+    /// `token_uri` in the key file.
+    pub fn from_file(path: &str, token_endpoint: Option<&str>) -> Self {
+        // Intentional panics. We can't talk to BigQuery without a usable key.
+        let key_file = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Couldn't read service account key file {}. {:?}", path, e));
+        let key: ServiceAccountKey = serde_json::from_str(&key_file)
+            .expect("Couldn't deserialize service account key file.");
+        let encoding_key = EncodingKey::from_rsa_pem(key.private_key.expose_secret().as_bytes())
+            .expect("Service account private key is not a valid RSA key.");
+        let token_endpoint = token_endpoint
+            .map(String::from)
+            .or(key.token_uri)
+            .unwrap_or_else(|| DEFAULT_TOKEN_ENDPOINT.to_string());
+        AccessTokenFromServiceAccount {
+            client_email: key.client_email,
+            encoding_key,
+            private_key_id: key.private_key_id,
+            token_endpoint,
+        }
+    }
+
+    fn assertion(&self) -> String {
+        let iat = OffsetDateTime::now_utc().unix_timestamp();
+        let claims = ServiceAccountClaims {
+            iss: &self.client_email,
+            scope: BIGQUERY_SCOPE,
+            aud: &self.token_endpoint,
+            iat,
+            exp: iat + 3600,
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = self.private_key_id.clone();
+        // Intentional expect. The key was checked to be a usable RSA key when it was read.
+        jsonwebtoken::encode(&header, &claims, &self.encoding_key)
+            .expect("Couldn't sign service account assertion.")
+    }
+}
+
+#[async_trait]
+impl GetAccessToken for AccessTokenFromServiceAccount {
+    // Google docs on how this works https://developers.google.com/identity/protocols/oauth2/service-account#httprest
+    async fn get(&self) -> Result<AccessToken, BQError> {
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(&self.token_endpoint)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &self.assertion()),
+            ])
+            .send()
+            .await?;
+        access_token_from_response(resp).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, fs::File, io::Read};
+    use std::{
+        env,
+        fs::File,
+        io::Read,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
     use super::*;
     use crate::{
         settings::test_settings::get_test_settings,
         test_utils::{empty_settings, random_simple_ascii_string},
     };
+    use bigdecimal::BigDecimal;
+    use jsonwebtoken::{DecodingKey, Validation};
     use serde_json::Value;
     use serial_test::serial;
-    use time::OffsetDateTime;
+    use std::str::FromStr;
+    use time::{date, time, OffsetDateTime};
     use wiremock::{
-        matchers::{any, body_json, header, method, path},
+        matchers::{
+            any, body_json, body_partial_json, body_string_contains, header, method, path,
+            query_param,
+        },
         Mock, MockServer, ResponseTemplate,
     };
 
@@ -193,12 +635,181 @@ mod tests {
         env::remove_var("BQ_ACCESS_TOKEN");
     }
 
+    const SERVICE_ACCOUNT_KEY_FILE: &str = "tests/fixtures/service_account_key.json";
+
+    async fn mock_token_endpoint() -> MockServer {
+        let mock_oauth = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "a service account token",
+                "expires_in": 3599,
+                "token_type": "Bearer"
+            })))
+            .expect(1)
+            .mount(&mock_oauth)
+            .await;
+        mock_oauth
+    }
+
+    #[test]
+    fn service_account_token_endpoint_defaults_to_key_file_token_uri() {
+        let token = AccessTokenFromServiceAccount::from_file(SERVICE_ACCOUNT_KEY_FILE, None);
+        assert_eq!(token.token_endpoint, "https://oauth2.googleapis.com/token");
+        let token = AccessTokenFromServiceAccount::from_file(
+            SERVICE_ACCOUNT_KEY_FILE,
+            Some("http://localhost:9999/token"),
+        );
+        assert_eq!(token.token_endpoint, "http://localhost:9999/token");
+    }
+
+    #[test]
+    #[should_panic(expected = "Couldn't read service account key file")]
+    fn service_account_missing_key_file_panics() {
+        AccessTokenFromServiceAccount::from_file("tests/fixtures/not_a_file.json", None);
+    }
+
+    #[tokio::test]
+    async fn service_account_exchanges_a_signed_assertion_for_a_token() {
+        let mock_oauth = mock_token_endpoint().await;
+        let token_endpoint = format!("{}/token", mock_oauth.uri());
+        let token = AccessTokenFromServiceAccount::from_file(
+            SERVICE_ACCOUNT_KEY_FILE,
+            Some(&token_endpoint),
+        );
+
+        let access_token = token.get().await.expect("Could not get token");
+        assert_eq!(
+            access_token.token.expose_secret(),
+            "a service account token"
+        );
+        assert_eq!(access_token.expires_in, Some(Duration::from_secs(3599)));
+
+        // The assertion is signed by the key file's private key and addressed to the endpoint
+        let requests = mock_oauth.received_requests().await.unwrap();
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        // JWTs are url-safe so the assertion needs no decoding
+        let assertion = body
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("assertion="))
+            .expect("No assertion in token request");
+        let public_key = std::fs::read("tests/fixtures/service_account_key.pub.pem").unwrap();
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&token_endpoint]);
+        let decoded = jsonwebtoken::decode::<Value>(
+            assertion,
+            &DecodingKey::from_rsa_pem(&public_key).unwrap(),
+            &validation,
+        )
+        .expect("Assertion was not signed with the service account key");
+        assert_eq!(
+            decoded.header.kid.as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+        assert_eq!(
+            decoded.claims["iss"],
+            "cjms-test@cjms-test.iam.gserviceaccount.com"
+        );
+        assert_eq!(
+            decoded.claims["scope"],
+            "https://www.googleapis.com/auth/bigquery"
+        );
+        assert_eq!(
+            decoded.claims["exp"].as_i64().unwrap() - decoded.claims["iat"].as_i64().unwrap(),
+            3600
+        );
+    }
+
+    #[tokio::test]
+    async fn service_account_rejected_assertion_is_an_error() {
+        let mock_oauth = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(
+                ResponseTemplate::new(400).set_body_json(json!({"error": "invalid_grant"})),
+            )
+            .mount(&mock_oauth)
+            .await;
+        let token_endpoint = format!("{}/token", mock_oauth.uri());
+        let result = AccessTokenFromServiceAccount::from_file(
+            SERVICE_ACCOUNT_KEY_FILE,
+            Some(&token_endpoint),
+        )
+        .get()
+        .await;
+        assert!(matches!(
+            result,
+            Err(BQError::HttpStatus { status: 400, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn bq_client_returns_an_error_when_the_token_cannot_be_refreshed() {
+        let mock_oauth = MockServer::start().await;
+        // Expires within the refresh margin, so a new token is fetched for every request
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "a service account token",
+                "expires_in": 30,
+                "token_type": "Bearer"
+            })))
+            .up_to_n_times(2)
+            .mount(&mock_oauth)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_oauth)
+            .await;
+        let mock_google = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        let token_endpoint = format!("{}/token", mock_oauth.uri());
+        let token = AccessTokenFromServiceAccount::from_file(
+            SERVICE_ACCOUNT_KEY_FILE,
+            Some(&token_endpoint),
+        );
+        let bq = BQClient::new(
+            &random_simple_ascii_string(),
+            token,
+            Some(&mock_google.uri()),
+        )
+        .await;
+
+        bq.get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        // The token endpoint is down by the next request
+        assert!(matches!(
+            bq.get_bq_results("SELECT * FROM `dataset.table`;").await,
+            Err(BQError::HttpStatus { status: 500, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_bq_client_uses_service_account_key_file_from_settings() {
+        let mock_oauth = mock_token_endpoint().await;
+        let mut settings = get_test_settings("test_gcp_project");
+        settings.bq_service_account_key_file = Some(SERVICE_ACCOUNT_KEY_FILE.to_string());
+        settings.bq_token_endpoint = Some(format!("{}/token", mock_oauth.uri()));
+        let bq = get_bqclient(&settings).await;
+        assert_eq!(bq.project, "test_gcp_project");
+        assert_eq!(
+            bq.access_token().await.unwrap().expose_secret(),
+            "a service account token"
+        );
+    }
+
     #[tokio::test]
     async fn new_should_set_default_big_query_endpoint() {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(random_simple_ascii_string()));
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
         let project = random_simple_ascii_string();
         let bq = BQClient::new(&project, mock_token, None).await;
         assert_eq!(
@@ -215,7 +826,7 @@ mod tests {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(random_simple_ascii_string()));
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
         let bq = BQClient::new("its_a_project", mock_token, Some("http://localhost")).await;
         assert_eq!(
             bq.query_api_url(),
@@ -229,9 +840,12 @@ mod tests {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(access_token.to_string()));
+            .returning(|| Ok(Secret::new(access_token.to_string()).into()));
         let bq = BQClient::new(&random_simple_ascii_string(), mock_token, None).await;
-        assert_eq!(bq.access_token.expose_secret(), access_token);
+        assert_eq!(
+            bq.access_token().await.unwrap().expose_secret(),
+            access_token
+        );
     }
 
     #[tokio::test]
@@ -244,11 +858,19 @@ mod tests {
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Couldn't get metadata for pod.")]
-    async fn pod_metadata_panics() {
-        // As we can't simulate a pod, we test the panic.
+    async fn pod_metadata_is_an_error_outside_a_pod() {
+        // As we can't simulate a pod, we test the error.
         let token_from_metadata = AccessTokenFromMetadata {};
-        BQClient::new(&random_simple_ascii_string(), token_from_metadata, None).await;
+        assert!(matches!(
+            token_from_metadata.get().await,
+            Err(BQError::Transport(_))
+        ));
+        // The client is still made, and returns the error when it's used
+        let bq = BQClient::new(&random_simple_ascii_string(), token_from_metadata, None).await;
+        assert!(matches!(
+            bq.access_token().await,
+            Err(BQError::Transport(_))
+        ));
     }
 
     #[tokio::test]
@@ -258,7 +880,10 @@ mod tests {
         std::env::set_var("BQ_ACCESS_TOKEN", access_token);
         let token_from_env = AccessTokenFromEnv {};
         let bq = BQClient::new(&random_simple_ascii_string(), token_from_env, None).await;
-        assert_eq!(bq.access_token.expose_secret(), access_token);
+        assert_eq!(
+            bq.access_token().await.unwrap().expose_secret(),
+            access_token
+        );
         std::env::remove_var("BQ_ACCESS_TOKEN");
     }
 
@@ -268,7 +893,7 @@ mod tests {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(access_token.to_string()));
+            .returning(|| Ok(Secret::new(access_token.to_string()).into()));
         let mock_google = MockServer::start().await;
         let bq = BQClient::new(
             &random_simple_ascii_string(),
@@ -290,61 +915,275 @@ mod tests {
                 "kind": "bigquery#queryResponse",
                 "query": query,
                 "useLegacySql": false,
+                "maxResults": 10000,
+                "timeoutMs": 10000,
             })))
             .respond_with(response)
             .expect(1)
             .mount(&mock_google)
             .await;
 
-        bq.get_bq_results(query).await;
+        bq.get_bq_results(query).await.unwrap();
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Did not successfully query bigquery.")]
-    async fn bq_client_query_panics_on_500() {
-        // This tests the manual panic, not the expect.
-        // Not sure how to generate a redirect loop to test the first.
-        // This is fine.
+    async fn bq_client_query_sends_named_query_parameters() {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(random_simple_ascii_string()));
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
         let mock_google = MockServer::start().await;
-        let bq = BQClient::new(
-            &random_simple_ascii_string(),
-            mock_token,
-            Some(&mock_google.uri()),
-        )
-        .await;
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(500))
+        let bq = BQClient::new("a_project", mock_token, Some(&mock_google.uri())).await;
+        let query = "SELECT * FROM `dataset.table` WHERE start_date >= @since;";
+        let since = OffsetDateTime::parse("2022-03-10 23:18:49 +0000", "%F %T %z").unwrap();
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "query": query,
+                "parameterMode": "NAMED",
+                "queryParameters": [{
+                    "name": "since",
+                    "parameterType": {"type": "TIMESTAMP"},
+                    "parameterValue": {"value": "2022-03-10 23:18:49 UTC"},
+                }],
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
             .mount(&mock_google)
             .await;
-        bq.get_bq_results("").await;
+
+        bq.get_bq_results_with_params(query, &[QueryParameter::timestamp("since", since)])
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Couldn't extract body.")]
-    async fn bq_client_query_panics_on_bad_body() {
-        // This tests the manual panic, not the expect.
-        // Not sure how to generate a redirect loop to test the first.
-        // This is fine.
+    async fn bq_client_follows_page_tokens_until_all_rows_are_read() {
+        let access_token = "bearer_token_for_request";
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(random_simple_ascii_string()));
+            .returning(|| Ok(Secret::new(access_token.to_string()).into()));
         let mock_google = MockServer::start().await;
-        let bq = BQClient::new(
+        let mut bq = BQClient::new("a_project", mock_token, Some(&mock_google.uri())).await;
+        bq.page_size = 3;
+
+        let mut first_page = fixture_bigquery_response();
+        first_page["pageToken"] = json!("token_for_page_2");
+        first_page["totalRows"] = json!("5");
+        let mut second_page = fixture_bigquery_response();
+        second_page["rows"] = json!([
+            {"f": [{"v": "1.646954329E9"}, {"v": "price_page_2"}, {"v": "6988"}, {"v": []}]},
+            {"f": [{"v": "1.646954329E9"}, {"v": "price_page_2"}, {"v": "7988"}, {"v": []}]},
+        ]);
+        second_page["totalRows"] = json!("5");
+        Mock::given(method("POST"))
+            .and(path("/bigquery/v2/projects/a_project/queries"))
+            .and(body_partial_json(json!({ "maxResults": 3 })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(first_page))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/bigquery/v2/projects/moz-fx/queries/job_FBJlFsQ9M9G_Jfe8M3sd",
+            ))
+            .and(header(
+                "Authorization",
+                format!("Bearer {}", access_token).as_str(),
+            ))
+            .and(query_param("pageToken", "token_for_page_2"))
+            .and(query_param("maxResults", "3"))
+            .and(query_param("location", "us2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(second_page))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+
+        let mut rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        let mut amounts = vec![];
+        while rs.next_row().await.unwrap() {
+            amounts.push(rs.get_i64_by_name("plan_amount").unwrap().unwrap());
+        }
+        assert_eq!(amounts, vec![3988, 4988, 5988, 6988, 7988]);
+        assert_eq!(rs.rows_fetched(), 5);
+    }
+
+    #[tokio::test]
+    async fn bq_client_polls_get_query_results_until_job_completes() {
+        let mut mock_token = MockGetAccessToken::new();
+        mock_token
+            .expect_get()
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
+        let mock_google = MockServer::start().await;
+        let bq = BQClient::new("a_project", mock_token, Some(&mock_google.uri())).await;
+        let incomplete = json!({
+            "kind": "bigquery#queryResponse",
+            "jobReference": {"projectId": "moz-fx", "jobId": "job_FBJlFsQ9M9G_Jfe8M3sd", "location": "us2"},
+            "jobComplete": false,
+        });
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&incomplete))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/bigquery/v2/projects/moz-fx/queries/job_FBJlFsQ9M9G_Jfe8M3sd",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&incomplete))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_google)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/bigquery/v2/projects/moz-fx/queries/job_FBJlFsQ9M9G_Jfe8M3sd",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+
+        let mut rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        let mut n = 0;
+        while rs.next_row().await.unwrap() {
+            n += 1;
+        }
+        assert_eq!(n, 3);
+    }
+
+    #[tokio::test]
+    async fn bq_client_query_errors_when_job_does_not_complete_before_timeout() {
+        let mut mock_token = MockGetAccessToken::new();
+        mock_token
+            .expect_get()
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
+        let mock_google = MockServer::start().await;
+        let mut bq = BQClient::new("a_project", mock_token, Some(&mock_google.uri())).await;
+        bq.query_timeout = Duration::from_millis(0);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jobReference": {"projectId": "a_project", "jobId": "a_job"},
+                "jobComplete": false,
+            })))
+            .mount(&mock_google)
+            .await;
+        match bq.get_bq_results("").await {
+            Err(BQError::Timeout { job_reference }) => {
+                assert_eq!(job_reference.unwrap().job_id.as_deref(), Some("a_job"));
+            }
+            other => panic!("Expected a timeout, got {:?}", other.err()),
+        }
+    }
+
+    async fn bq_with_fast_retries(mock_google: &MockServer) -> BQClient {
+        let mut mock_token = MockGetAccessToken::new();
+        mock_token
+            .expect_get()
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
+        let mut bq = BQClient::new(
             &random_simple_ascii_string(),
             mock_token,
             Some(&mock_google.uri()),
         )
         .await;
+        bq.retry_backoff = Duration::from_millis(1);
+        bq
+    }
+
+    #[tokio::test]
+    async fn bq_client_query_errors_on_500_once_retries_are_exhausted() {
+        let mock_google = MockServer::start().await;
+        let bq = bq_with_fast_retries(&mock_google).await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500).set_body_string("oops"))
+            .expect(u64::from(bq.max_retries) + 1)
+            .mount(&mock_google)
+            .await;
+        match bq.get_bq_results("").await {
+            Err(BQError::HttpStatus { status, body }) => {
+                assert_eq!(status, 500);
+                assert_eq!(body, "oops");
+            }
+            other => panic!("Expected an HTTP error, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn bq_client_query_retries_transient_errors() {
+        let mock_google = MockServer::start().await;
+        let bq = bq_with_fast_retries(&mock_google).await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        let rs = bq.get_bq_results("").await.unwrap();
+        assert_eq!(rs.row_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn bq_client_query_does_not_retry_job_errors() {
+        let mock_google = MockServer::start().await;
+        let bq = bq_with_fast_retries(&mock_google).await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": {
+                    "code": 400,
+                    "message": "Unrecognized name: nope",
+                    "errors": [{
+                        "reason": "invalidQuery",
+                        "location": "q",
+                        "message": "Unrecognized name: nope",
+                    }],
+                }
+            })))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        match bq.get_bq_results("SELECT nope").await {
+            Err(BQError::JobErrors { errors }) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].reason.as_deref(), Some("invalidQuery"));
+                assert_eq!(
+                    errors[0].message.as_deref(),
+                    Some("Unrecognized name: nope")
+                );
+            }
+            other => panic!("Expected job errors, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn bq_client_query_errors_on_bad_body() {
+        let mock_google = MockServer::start().await;
+        let bq = bq_with_fast_retries(&mock_google).await;
         Mock::given(any())
             .respond_with(ResponseTemplate::new(200))
             .mount(&mock_google)
             .await;
-        bq.get_bq_results("").await;
+        assert!(matches!(
+            bq.get_bq_results("").await,
+            Err(BQError::Transport(_))
+        ));
     }
 
     #[tokio::test]
@@ -352,7 +1191,7 @@ mod tests {
         let mut mock_token = MockGetAccessToken::new();
         mock_token
             .expect_get()
-            .returning(|| Secret::new(random_simple_ascii_string()));
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
         let mock_google = MockServer::start().await;
         let bq = BQClient::new(
             &random_simple_ascii_string(),
@@ -367,7 +1206,10 @@ mod tests {
             .mount(&mock_google)
             .await;
 
-        let mut rs = bq.get_bq_results("SELECT * FROM `dataset.table`;").await;
+        let mut rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
         assert_eq!(rs.row_count(), 3);
         struct TestItem {
             start_date: OffsetDateTime,
@@ -375,7 +1217,7 @@ mod tests {
             plan_amount: i64,
         }
         let mut rows: Vec<TestItem> = Vec::new();
-        while rs.next_row() {
+        while rs.next_row().await.unwrap() {
             let start_date = rs
                 .require_offsetdatetime_by_name("start_date")
                 .expect("Should get start_date");
@@ -397,4 +1239,285 @@ mod tests {
         assert_eq!(rows[1].plan_amount, 4988);
         assert_eq!(rows[2].plan_amount, 5988);
     }
+
+    #[tokio::test]
+    async fn bq_client_returns_a_result_set_that_we_can_read_every_type_from() {
+        let mut mock_token = MockGetAccessToken::new();
+        mock_token
+            .expect_get()
+            .returning(|| Ok(Secret::new(random_simple_ascii_string()).into()));
+        let mock_google = MockServer::start().await;
+        let bq = BQClient::new(
+            &random_simple_ascii_string(),
+            mock_token,
+            Some(&mock_google.uri()),
+        )
+        .await;
+        let mut file = File::open("tests/fixtures/bigquery_all_types_response.json").unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        let fixture: Value = serde_json::from_str(&data).expect("Invalid JSON.");
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture))
+            .mount(&mock_google)
+            .await;
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Plan {
+            plan_id: String,
+            interval_count: i64,
+        }
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct LineItem {
+            sku: String,
+            amount: BigDecimal,
+        }
+
+        let mut rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        assert!(rs.next_row().await.unwrap());
+        assert_eq!(rs.get_bool_by_name("is_trial").unwrap(), Some(true));
+        assert_eq!(rs.get_f64_by_name("tax_rate").unwrap(), Some(0.0725));
+        assert_eq!(
+            rs.get_decimal_by_name("tax_amount").unwrap(),
+            Some(BigDecimal::from_str("12.345678901").unwrap())
+        );
+        // More digits than any float can hold exactly
+        assert_eq!(
+            rs.get_decimal_by_name("lifetime_value")
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "123456789012345678901234567890.12345678901234567890123456789"
+        );
+        assert_eq!(
+            rs.get_bytes_by_name("signature").unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            rs.get_date_by_name("billing_date").unwrap(),
+            Some(date!(2022 - 03 - 16))
+        );
+        let billing_time = time!(20:59:53.672304);
+        assert_eq!(
+            rs.get_time_by_name("billing_time").unwrap(),
+            Some(billing_time)
+        );
+        assert_eq!(
+            rs.get_datetime_by_name("billing_datetime").unwrap(),
+            Some(date!(2022 - 03 - 16).with_time(billing_time))
+        );
+        assert_eq!(
+            rs.get_offsetdatetime_by_name("created").unwrap(),
+            Some(date!(2022 - 03 - 16).with_time(billing_time).assume_utc())
+        );
+        assert_eq!(
+            rs.get_repeated_by_name::<String>("promotion_codes")
+                .unwrap(),
+            Some(vec!["VPN10".to_string(), "SAVE5".to_string()])
+        );
+        assert_eq!(
+            rs.get_repeated_by_name::<i64>("discounts").unwrap(),
+            Some(vec![5, 10])
+        );
+        assert_eq!(
+            rs.get_record_by_name::<Plan>("plan").unwrap(),
+            Some(Plan {
+                plan_id: "price_1J0owvKb9q6OnNsLExNhEDXm".to_string(),
+                interval_count: 6
+            })
+        );
+        assert_eq!(
+            rs.get_repeated_record_by_name::<LineItem>("line_items")
+                .unwrap(),
+            Some(vec![
+                LineItem {
+                    sku: "vpn".to_string(),
+                    amount: BigDecimal::from_str("4.99").unwrap()
+                },
+                LineItem {
+                    sku: "relay".to_string(),
+                    amount: BigDecimal::from_str("0.1").unwrap()
+                },
+            ])
+        );
+        // Reading a column as the wrong type is an error, not a panic
+        assert!(matches!(
+            rs.get_bool_by_name("tax_amount"),
+            Err(BQError::InvalidColumnType { .. })
+        ));
+        assert!(matches!(
+            rs.get_record_by_name::<Plan>("line_items"),
+            Err(BQError::InvalidColumnValue { .. })
+        ));
+
+        // Nulls and empty lists
+        assert!(rs.next_row().await.unwrap());
+        assert_eq!(rs.get_bool_by_name("is_trial").unwrap(), None);
+        assert_eq!(rs.get_decimal_by_name("tax_amount").unwrap(), None);
+        assert_eq!(rs.get_time_by_name("billing_time").unwrap(), None);
+        assert_eq!(
+            rs.get_repeated_by_name::<String>("promotion_codes")
+                .unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(rs.get_record_by_name::<Plan>("plan").unwrap(), None);
+        assert_eq!(
+            rs.get_repeated_record_by_name::<LineItem>("line_items")
+                .unwrap(),
+            Some(vec![])
+        );
+        assert!(!rs.next_row().await.unwrap());
+    }
+
+    #[test]
+    fn result_set_validates_schema_treating_type_aliases_as_equal() {
+        let response: QueryResponse = serde_json::from_value(fixture_bigquery_response()).unwrap();
+        let rs = ResultSet::new(response).unwrap();
+        rs.validate_schema(&[
+            ("start_date", FieldType::Timestamp),
+            ("plan_amount", FieldType::Int64),
+        ])
+        .expect("Schema should be valid");
+    }
+
+    #[test]
+    fn result_set_reports_every_missing_and_mistyped_column() {
+        let response: QueryResponse = serde_json::from_value(fixture_bigquery_response()).unwrap();
+        let rs = ResultSet::new(response).unwrap();
+        match rs.validate_schema(&[
+            ("plan_id", FieldType::String),
+            ("plan_amount", FieldType::String),
+            ("flow_id", FieldType::String),
+        ]) {
+            Err(BQError::Schema(message)) => assert_eq!(
+                message,
+                "column plan_amount is Integer but should be String, missing column flow_id"
+            ),
+            other => panic!("Expected a schema error, got {:?}", other),
+        }
+    }
+
+    /// Hands out token-0, token-1, ... so tests can see which token a request used.
+    struct CountingAccessToken {
+        calls: Arc<AtomicUsize>,
+        expires_in: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl GetAccessToken for CountingAccessToken {
+        async fn get(&self) -> Result<AccessToken, BQError> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(AccessToken {
+                token: Secret::new(format!("token-{}", n)),
+                expires_in: self.expires_in,
+            })
+        }
+    }
+
+    async fn bq_with_counting_token(
+        mock_google: &MockServer,
+        expires_in: Option<Duration>,
+    ) -> (BQClient, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let token = CountingAccessToken {
+            calls: calls.clone(),
+            expires_in,
+        };
+        let bq = BQClient::new(
+            &random_simple_ascii_string(),
+            token,
+            Some(&mock_google.uri()),
+        )
+        .await;
+        (bq, calls)
+    }
+
+    #[tokio::test]
+    async fn bq_client_refreshes_a_token_that_is_about_to_expire() {
+        let mock_google = MockServer::start().await;
+        Mock::given(header("Authorization", "Bearer token-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        // Expires within the refresh margin
+        let (bq, calls) = bq_with_counting_token(&mock_google, Some(Duration::from_secs(30))).await;
+
+        let rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        assert_eq!(rs.row_count(), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn bq_client_keeps_a_token_that_is_not_about_to_expire() {
+        let mock_google = MockServer::start().await;
+        Mock::given(header("Authorization", "Bearer token-0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(2)
+            .mount(&mock_google)
+            .await;
+        let (bq, calls) =
+            bq_with_counting_token(&mock_google, Some(Duration::from_secs(3600))).await;
+
+        bq.get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        bq.get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn bq_client_refreshes_token_and_retries_once_on_unauthorized() {
+        let mock_google = MockServer::start().await;
+        Mock::given(header("Authorization", "Bearer token-0"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        Mock::given(header("Authorization", "Bearer token-1"))
+            .and(body_partial_json(
+                json!({"query": "SELECT * FROM `dataset.table`;"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .expect(1)
+            .mount(&mock_google)
+            .await;
+        let (bq, calls) = bq_with_counting_token(&mock_google, None).await;
+
+        let rs = bq
+            .get_bq_results("SELECT * FROM `dataset.table`;")
+            .await
+            .unwrap();
+        assert_eq!(rs.row_count(), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn bq_client_does_not_retry_unauthorized_more_than_once() {
+        let mock_google = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(2)
+            .mount(&mock_google)
+            .await;
+        // A third attempt would succeed, so the error shows that we gave up after one retry
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture_bigquery_response()))
+            .mount(&mock_google)
+            .await;
+        let (bq, _) = bq_with_counting_token(&mock_google, None).await;
+
+        assert!(matches!(
+            bq.get_bq_results("SELECT * FROM `dataset.table`;").await,
+            Err(BQError::HttpStatus { status: 401, .. })
+        ));
+    }
 }
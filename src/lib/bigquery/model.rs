@@ -25,16 +25,13 @@ https://github.com/lquerel/gcp-bigquery-client/graphs/contributors.
 
 */
 
-use serde::{Deserialize, Serialize};
+use bigdecimal::BigDecimal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::str::FromStr;
 use thiserror::Error;
-use time::OffsetDateTime;
-
-use crate::{
-    error,
-    telemetry::{LogKey, StatsD},
-};
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
 
 #[derive(Error, Debug)]
 pub enum BQError {
@@ -56,6 +53,53 @@ pub enum BQError {
 
     #[error("BQError: Could not cast integer from i64 to i32")]
     IntegerCastUnsuccessful,
+
+    #[error("BQError: Invalid column value (col_name: {col_name}): {message}")]
+    InvalidColumnValue { col_name: String, message: String },
+
+    #[error("BQError: Request to bigquery failed: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    #[error("BQError: Bigquery responded with HTTP {status}: {body}")]
+    HttpStatus { status: u16, body: String },
+
+    #[error("BQError: Bigquery job failed: {errors:?}")]
+    JobErrors { errors: Vec<ErrorProto> },
+
+    #[error("BQError: Invalid result schema: {0}")]
+    Schema(String),
+
+    #[error("BQError: Invalid response from bigquery: {0}")]
+    InvalidResponse(String),
+
+    #[error("BQError: Timed out waiting for bigquery job to complete (job_reference: {job_reference:?})")]
+    Timeout { job_reference: Option<JobReference> },
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorResponseBody,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponseBody {
+    errors: Option<Vec<ErrorProto>>,
+}
+
+impl BQError {
+    /// The error for an unsuccessful response. Bigquery reports failed jobs, bad queries
+    /// and exceeded quotas as a list of `ErrorProto`s; anything else keeps the raw body.
+    pub fn from_response(status: u16, body: String) -> Self {
+        match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(ErrorResponse {
+                error:
+                    ErrorResponseBody {
+                        errors: Some(errors),
+                    },
+            }) if !errors.is_empty() => BQError::JobErrors { errors },
+            _ => BQError::HttpStatus { status, body },
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -142,7 +186,7 @@ pub struct TableFieldSchema {
     pub r#type: FieldType,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum FieldType {
     String,
@@ -163,6 +207,24 @@ pub enum FieldType {
     Struct, // same as RECORD
 }
 
+impl FieldType {
+    /// Maps the standard SQL aliases onto the type names BigQuery uses in result schemas.
+    fn canonical(&self) -> FieldType {
+        match self {
+            FieldType::Int64 => FieldType::Integer,
+            FieldType::Float64 => FieldType::Float,
+            FieldType::Bool => FieldType::Boolean,
+            FieldType::Struct => FieldType::Record,
+            other => other.clone(),
+        }
+    }
+
+    /// Whether the two types are the same, treating aliases such as INT64 and INTEGER as equal.
+    pub fn is_same_as(&self, other: &FieldType) -> bool {
+        self.canonical() == other.canonical()
+    }
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TableSchema {
@@ -171,6 +233,51 @@ pub struct TableSchema {
     pub fields: Option<Vec<TableFieldSchema>>,
 }
 
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryParameterType {
+    /// [Required] The top level type of this field.
+    pub r#type: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryParameterValue {
+    /// [Optional] The value of this value, if a simple scalar type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryParameter {
+    /// [Optional] If unset, this is a positional parameter. Otherwise, should be unique within a query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// [Required] The type of this parameter.
+    pub parameter_type: QueryParameterType,
+    /// [Required] The value of this parameter.
+    pub parameter_value: QueryParameterValue,
+}
+
+impl QueryParameter {
+    /// A named TIMESTAMP parameter, referenced in the query as `@name`.
+    pub fn timestamp(name: &str, value: OffsetDateTime) -> Self {
+        QueryParameter {
+            name: Some(name.to_string()),
+            parameter_type: QueryParameterType {
+                r#type: "TIMESTAMP".to_string(),
+            },
+            parameter_value: QueryParameterValue {
+                value: Some(format!(
+                    "{} UTC",
+                    value.to_offset(UtcOffset::UTC).format("%F %H:%M:%S")
+                )),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct QueryResponse {
@@ -264,33 +371,35 @@ pub struct ResultSet {
 }
 
 impl ResultSet {
-    pub fn new(query_response: QueryResponse) -> Self {
+    pub fn new(query_response: QueryResponse) -> Result<Self, BQError> {
         if query_response.job_complete.unwrap_or(false) {
             // rows and tables schema are only present for successfully completed jobs.
             let row_count = query_response.rows.as_ref().map_or(0, Vec::len) as i64;
-            let table_schema = query_response.schema.as_ref().expect("Expecting a schema");
-            let table_fields = table_schema
+            let table_fields = query_response
+                .schema
+                .as_ref()
+                .ok_or_else(|| BQError::Schema("Completed job has no schema".into()))?
                 .fields
                 .as_ref()
-                .expect("Expecting a non empty list of fields");
+                .ok_or_else(|| BQError::Schema("Schema has no fields".into()))?;
             let fields: HashMap<String, usize> = table_fields
                 .iter()
                 .enumerate()
                 .map(|(pos, field)| (field.name.clone(), pos))
                 .collect();
-            Self {
+            Ok(Self {
                 cursor: -1,
                 row_count,
                 query_response,
                 fields,
-            }
+            })
         } else {
-            Self {
+            Ok(Self {
                 cursor: -1,
                 row_count: 0,
                 query_response,
                 fields: HashMap::new(),
-            }
+            })
         }
     }
 
@@ -311,34 +420,65 @@ impl ResultSet {
         self.row_count as usize
     }
 
-    pub fn report_stats(&self, statsd: &StatsD, key: &LogKey) {
-        statsd.gauge(&key.add_suffix("n-from-bq"), self.row_count());
-        let total_rows = self
-            .query_response
-            .total_rows
-            .clone()
-            .unwrap_or_else(|| "-1".to_string());
-        match total_rows.parse::<usize>() {
-            Ok(n) => {
-                statsd.gauge(&key.add_suffix("total-n-from-bq"), n);
+    /// The table schema of the results, if the job completed.
+    pub fn schema(&self) -> Option<&TableSchema> {
+        self.query_response.schema.as_ref()
+    }
+
+    /// Checks that the results have every one of the `expected` columns with the given
+    /// type, so a job can stop before processing any rows from a table that has drifted.
+    /// Extra columns are allowed. All missing and mistyped columns are reported together.
+    pub fn validate_schema(&self, expected: &[(&str, FieldType)]) -> Result<(), BQError> {
+        let fields = self
+            .schema()
+            .and_then(|schema| schema.fields.as_deref())
+            .unwrap_or_default();
+        let mut problems = Vec::new();
+        for (name, expected_type) in expected {
+            match fields.iter().find(|field| field.name == *name) {
+                None => problems.push(format!("missing column {}", name)),
+                Some(field) if !field.r#type.is_same_as(expected_type) => problems.push(format!(
+                    "column {} is {:?} but should be {:?}",
+                    name, field.r#type, expected_type
+                )),
+                Some(_) => {}
             }
-            Err(e) => error!(LogKey::BigQuery, error = e, "Could not get total rows",),
-        };
-        let bytes_processed = self
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(BQError::Schema(problems.join(", "))),
+        }
+    }
+
+    /// Decodes the current row into `T`. See `row::from_row` for how columns are matched and typed.
+    pub fn deserialize_row<T: DeserializeOwned>(&self) -> Result<T, BQError> {
+        let (fields, columns) = self.current_row()?;
+        super::row::from_row(fields, columns)
+    }
+
+    /// The current row as a JSON object that `serde_json::from_value` decodes the same way
+    /// `deserialize_row` does. Useful for keeping a row that could not be decoded.
+    pub fn row_to_json(&self) -> Result<Value, BQError> {
+        let (fields, columns) = self.current_row()?;
+        Ok(super::row::row_to_json(fields, columns))
+    }
+
+    fn current_row(&self) -> Result<(&[TableFieldSchema], &[TableCell]), BQError> {
+        if self.cursor < 0 || self.cursor == self.row_count {
+            return Err(BQError::NoDataAvailable);
+        }
+        let fields = self
+            .schema()
+            .and_then(|schema| schema.fields.as_deref())
+            .unwrap_or_default();
+        let columns = self
             .query_response
-            .total_bytes_processed
-            .clone()
-            .unwrap_or_else(|| "-1".to_string());
-        match bytes_processed.parse::<usize>() {
-            Ok(n) => {
-                statsd.gauge(&key.add_suffix("bytes-from-bq"), n);
-            }
-            Err(e) => error!(
-                LogKey::BigQuery,
-                error = e,
-                "Could not get total bytes processed",
-            ),
-        };
+            .rows
+            .as_ref()
+            .and_then(|rows| rows.get(self.cursor as usize))
+            .and_then(|row| row.columns.as_deref())
+            .unwrap_or_default();
+        Ok((fields, columns))
     }
 
     pub fn get_i64(&self, col_index: usize) -> Result<Option<i64>, BQError> {
@@ -402,6 +542,143 @@ impl ResultSet {
         }
     }
 
+    /// Reads the column as any type that implements `FromBQValue`.
+    pub fn get<T: FromBQValue>(&self, col_index: usize) -> Result<Option<T>, BQError> {
+        match self.get_json_value(col_index)? {
+            None | Some(Value::Null) => Ok(None),
+            Some(json_value) => match T::from_bq_value(&json_value) {
+                Some(value) => Ok(Some(value)),
+                None => Err(BQError::InvalidColumnType {
+                    col_index,
+                    col_type: ResultSet::json_type(&json_value),
+                    type_requested: T::TYPE_NAME.into(),
+                }),
+            },
+        }
+    }
+
+    pub fn get_by_name<T: FromBQValue>(&self, col_name: &str) -> Result<Option<T>, BQError> {
+        self.get(self.col_index(col_name)?)
+    }
+
+    /// Reads a REPEATED column as a list of any type that implements `FromBQValue`.
+    pub fn get_repeated<T: FromBQValue>(
+        &self,
+        col_index: usize,
+    ) -> Result<Option<Vec<T>>, BQError> {
+        let invalid_column_type = |json_value: &Value| BQError::InvalidColumnType {
+            col_index,
+            col_type: ResultSet::json_type(json_value),
+            type_requested: format!("Vec<{}>", T::TYPE_NAME),
+        };
+        match self.get_json_value(col_index)? {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| {
+                    // Items are wrapped like cells, i.e. {"v": item}
+                    let item = item.get("v").unwrap_or(item);
+                    T::from_bq_value(item).ok_or_else(|| invalid_column_type(item))
+                })
+                .collect::<Result<Vec<T>, BQError>>()
+                .map(Some),
+            Some(json_value) => Err(invalid_column_type(&json_value)),
+        }
+    }
+
+    pub fn get_repeated_by_name<T: FromBQValue>(
+        &self,
+        col_name: &str,
+    ) -> Result<Option<Vec<T>>, BQError> {
+        self.get_repeated(self.col_index(col_name)?)
+    }
+
+    /// Decodes a RECORD column into `T` the same way `deserialize_row` decodes a row.
+    pub fn get_record<T: DeserializeOwned>(&self, col_index: usize) -> Result<Option<T>, BQError> {
+        self.decode_column(col_index)
+    }
+
+    pub fn get_record_by_name<T: DeserializeOwned>(
+        &self,
+        col_name: &str,
+    ) -> Result<Option<T>, BQError> {
+        self.get_record(self.col_index(col_name)?)
+    }
+
+    /// Decodes a REPEATED RECORD column into a list of `T`.
+    pub fn get_repeated_record<T: DeserializeOwned>(
+        &self,
+        col_index: usize,
+    ) -> Result<Option<Vec<T>>, BQError> {
+        self.decode_column(col_index)
+    }
+
+    pub fn get_repeated_record_by_name<T: DeserializeOwned>(
+        &self,
+        col_name: &str,
+    ) -> Result<Option<Vec<T>>, BQError> {
+        self.get_repeated_record(self.col_index(col_name)?)
+    }
+
+    pub fn get_bool_by_name(&self, col_name: &str) -> Result<Option<bool>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    pub fn get_f64_by_name(&self, col_name: &str) -> Result<Option<f64>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    /// NUMERIC and BIGNUMERIC columns, read exactly.
+    pub fn get_decimal_by_name(&self, col_name: &str) -> Result<Option<BigDecimal>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    pub fn get_bytes_by_name(&self, col_name: &str) -> Result<Option<Vec<u8>>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    pub fn get_date_by_name(&self, col_name: &str) -> Result<Option<Date>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    pub fn get_time_by_name(&self, col_name: &str) -> Result<Option<Time>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    pub fn get_datetime_by_name(
+        &self,
+        col_name: &str,
+    ) -> Result<Option<PrimitiveDateTime>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    /// TIMESTAMP columns, to the microsecond.
+    pub fn get_offsetdatetime_by_name(
+        &self,
+        col_name: &str,
+    ) -> Result<Option<OffsetDateTime>, BQError> {
+        self.get_by_name(col_name)
+    }
+
+    fn col_index(&self, col_name: &str) -> Result<usize, BQError> {
+        match self.fields.get(col_name) {
+            None => Err(BQError::InvalidColumnName {
+                col_name: col_name.into(),
+            }),
+            Some(col_index) => Ok(*col_index),
+        }
+    }
+
+    fn decode_column<T: DeserializeOwned>(&self, col_index: usize) -> Result<T, BQError> {
+        let json_value = self.get_json_value(col_index)?;
+        let field = self
+            .schema()
+            .and_then(|schema| schema.fields.as_ref())
+            .and_then(|fields| fields.get(col_index))
+            .ok_or(BQError::InvalidColumnIndex { col_index })?;
+        super::row::from_cell(field, json_value.as_ref())
+    }
+
     pub fn get_json_value(&self, col_index: usize) -> Result<Option<serde_json::Value>, BQError> {
         if self.cursor < 0 || self.cursor == self.row_count {
             return Err(BQError::NoDataAvailable);
@@ -472,3 +749,130 @@ impl ResultSet {
         }
     }
 }
+
+/// A value that can be read from a BigQuery column, as sent in the JSON API response.
+pub trait FromBQValue: Sized {
+    /// Used in `BQError::InvalidColumnType` when a value can't be read as this type.
+    const TYPE_NAME: &'static str;
+
+    fn from_bq_value(json_value: &Value) -> Option<Self>;
+}
+
+impl FromBQValue for String {
+    const TYPE_NAME: &'static str = "String";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        json_value.as_str().map(String::from)
+    }
+}
+
+impl FromBQValue for i64 {
+    const TYPE_NAME: &'static str = "I64";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        match json_value {
+            Value::Number(value) => value.as_i64(),
+            Value::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromBQValue for f64 {
+    const TYPE_NAME: &'static str = "F64";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        match json_value {
+            Value::Number(value) => value.as_f64(),
+            // Also accepts NaN, Infinity and -Infinity
+            Value::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromBQValue for bool {
+    const TYPE_NAME: &'static str = "Bool";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        match json_value {
+            Value::Bool(value) => Some(*value),
+            Value::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromBQValue for BigDecimal {
+    const TYPE_NAME: &'static str = "Decimal";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        BigDecimal::from_str(json_value.as_str()?).ok()
+    }
+}
+
+/// BYTES columns are sent base64 encoded.
+impl FromBQValue for Vec<u8> {
+    const TYPE_NAME: &'static str = "Bytes";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        base64::decode(json_value.as_str()?).ok()
+    }
+}
+
+impl FromBQValue for Date {
+    const TYPE_NAME: &'static str = "Date";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        Date::parse(json_value.as_str()?, "%F").ok()
+    }
+}
+
+impl FromBQValue for Time {
+    const TYPE_NAME: &'static str = "Time";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        parse_bq_time(json_value.as_str()?)
+    }
+}
+
+impl FromBQValue for PrimitiveDateTime {
+    const TYPE_NAME: &'static str = "DateTime";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        let (date, time) = json_value.as_str()?.split_once(['T', ' '])?;
+        Some(PrimitiveDateTime::new(
+            Date::parse(date, "%F").ok()?,
+            parse_bq_time(time)?,
+        ))
+    }
+}
+
+/// TIMESTAMP columns are sent as fractional seconds since the epoch, with microsecond precision.
+impl FromBQValue for OffsetDateTime {
+    const TYPE_NAME: &'static str = "OffsetDateTime";
+
+    fn from_bq_value(json_value: &Value) -> Option<Self> {
+        let seconds = f64::from_bq_value(json_value)?;
+        if !seconds.is_finite() {
+            return None;
+        }
+        let micros = (seconds * 1_000_000.0).round() as i128;
+        Some(OffsetDateTime::from_unix_timestamp_nanos(micros * 1000))
+    }
+}
+
+/// Parses HH:MM:SS with optional fractional seconds, e.g. 23:18:49.141794
+fn parse_bq_time(value: &str) -> Option<Time> {
+    let (hms, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let mut parts = hms.splitn(3, ':').map(|part| part.parse::<u8>().ok());
+    let (hour, minute, second) = (parts.next()??, parts.next()??, parts.next()??);
+    let nanosecond = match fraction {
+        "" => 0,
+        fraction if fraction.len() <= 9 && fraction.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<9}", fraction).parse().ok()?
+        }
+        _ => return None,
+    };
+    Time::try_from_hms_nano(hour, minute, second, nanosecond).ok()
+}
@@ -0,0 +1,481 @@
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::iter::Enumerate;
+use std::slice::Iter;
+
+use super::model::{BQError, FieldType, TableCell, TableFieldSchema};
+
+/// Decode the columns of a row into `T`, matching struct fields to columns by name.
+///
+/// Each value is interpreted according to its column's type in the table schema, so
+/// e.g. an INTEGER column decodes into any integer type and a TIMESTAMP column into
+/// unix seconds (use `#[serde(with = "time::serde::timestamp")]` for an OffsetDateTime).
+/// Columns that `T` doesn't name are ignored.
+pub(super) fn from_row<T: DeserializeOwned>(
+    fields: &[TableFieldSchema],
+    columns: &[TableCell],
+) -> Result<T, BQError> {
+    T::deserialize(RowDeserializer { fields, columns }).map_err(|e| BQError::InvalidColumnValue {
+        col_name: e.col_name.unwrap_or_default(),
+        message: e.message,
+    })
+}
+
+/// Decode a single cell into `T`, the same way `from_row` decodes each column.
+pub(super) fn from_cell<T: DeserializeOwned>(
+    field: &TableFieldSchema,
+    value: Option<&Value>,
+) -> Result<T, BQError> {
+    T::deserialize(CellDeserializer { field, value }).map_err(|e| BQError::InvalidColumnValue {
+        col_name: field.name.clone(),
+        message: e.message,
+    })
+}
+
+/// The row as a JSON object of column name to value, typed the way `from_row` types each
+/// column, so `serde_json::from_value` decodes it as `from_row` would have. A value that
+/// doesn't match its column type is kept as BigQuery sent it.
+pub(super) fn row_to_json(fields: &[TableFieldSchema], columns: &[TableCell]) -> Value {
+    let object = fields
+        .iter()
+        .zip(columns)
+        .map(|(field, cell)| {
+            let value = column_to_json(field, cell.value.as_ref())
+                .unwrap_or_else(|_| cell.value.clone().unwrap_or(Value::Null));
+            (field.name.clone(), value)
+        })
+        .collect();
+    Value::Object(object)
+}
+
+#[derive(Debug)]
+struct DecodeError {
+    col_name: Option<String>,
+    message: String,
+}
+
+impl DecodeError {
+    fn in_column(col_name: &str, message: impl fmt::Display) -> Self {
+        DecodeError {
+            col_name: Some(col_name.to_string()),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl de::Error for DecodeError {
+    fn custom<M: fmt::Display>(message: M) -> Self {
+        DecodeError {
+            col_name: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        DecodeError::in_column(field, "column is missing from the result set")
+    }
+}
+
+struct RowDeserializer<'a> {
+    fields: &'a [TableFieldSchema],
+    columns: &'a [TableCell],
+}
+
+impl<'de, 'a> Deserializer<'de> for RowDeserializer<'a> {
+    type Error = DecodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        visitor.visit_map(RowAccess {
+            fields: self.fields.iter().enumerate(),
+            columns: self.columns,
+            current: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct RowAccess<'a> {
+    fields: Enumerate<Iter<'a, TableFieldSchema>>,
+    columns: &'a [TableCell],
+    current: Option<(&'a TableFieldSchema, Option<&'a Value>)>,
+}
+
+impl<'de, 'a> MapAccess<'de> for RowAccess<'a> {
+    type Error = DecodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DecodeError> {
+        let (col_index, field) = match self.fields.next() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let value = self
+            .columns
+            .get(col_index)
+            .and_then(|col| col.value.as_ref());
+        self.current = Some((field, value));
+        seed.deserialize(field.name.as_str().into_deserializer())
+            .map(Some)
+            .map_err(|e: DecodeError| DecodeError::in_column(&field.name, e))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, DecodeError> {
+        let (field, value) = self
+            .current
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before key"))?;
+        seed.deserialize(CellDeserializer { field, value })
+            .map_err(|e| DecodeError::in_column(&field.name, e))
+    }
+}
+
+/// Converts a cell only once a field asks for it, so columns that aren't wanted can't fail.
+struct CellDeserializer<'a> {
+    field: &'a TableFieldSchema,
+    value: Option<&'a Value>,
+}
+
+impl CellDeserializer<'_> {
+    fn into_json(self) -> Result<Value, DecodeError> {
+        column_to_json(self.field, self.value).map_err(de::Error::custom)
+    }
+}
+
+macro_rules! forward_to_json {
+    ($($method:ident($($arg:ident: $ty:ty),*))*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, DecodeError> {
+                self.into_json()?
+                    .$method($($arg,)* visitor)
+                    .map_err(de::Error::custom)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for CellDeserializer<'a> {
+    type Error = DecodeError;
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_json! {
+        deserialize_any() deserialize_bool() deserialize_i8() deserialize_i16()
+        deserialize_i32() deserialize_i64() deserialize_i128() deserialize_u8()
+        deserialize_u16() deserialize_u32() deserialize_u64() deserialize_u128()
+        deserialize_f32() deserialize_f64() deserialize_char() deserialize_str()
+        deserialize_string() deserialize_bytes() deserialize_byte_buf()
+        deserialize_option() deserialize_unit() deserialize_seq() deserialize_map()
+        deserialize_identifier()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+    }
+}
+
+/// Convert a cell, as BigQuery sends it, to the JSON value its schema type describes.
+fn column_to_json(field: &TableFieldSchema, value: Option<&Value>) -> Result<Value, String> {
+    let value = match value {
+        None | Some(Value::Null) => return Ok(Value::Null),
+        Some(value) => value,
+    };
+    if field.mode.as_deref() != Some("REPEATED") {
+        return value_to_json(field, value);
+    }
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                // Items are wrapped like cells, i.e. {"v": item}
+                Value::Object(cell) if cell.contains_key("v") => value_to_json(field, &cell["v"]),
+                item => value_to_json(field, item),
+            })
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        other => Err(format!(
+            "expected an array for a REPEATED column, found {}",
+            other
+        )),
+    }
+}
+
+fn value_to_json(field: &TableFieldSchema, value: &Value) -> Result<Value, String> {
+    let parse_error = |s: &str, e: &dyn fmt::Display| {
+        format!("could not parse {:?} as {:?}: {}", s, field.r#type, e)
+    };
+    match (&field.r#type, value) {
+        (_, Value::Null) => Ok(Value::Null),
+        (FieldType::Record | FieldType::Struct, Value::Object(record)) => {
+            let sub_fields = field.fields.as_deref().unwrap_or_default();
+            let cells = record
+                .get("f")
+                .and_then(Value::as_array)
+                .ok_or_else(|| format!("expected a RECORD, found {}", value))?;
+            let mut object = Map::new();
+            for (sub_field, cell) in sub_fields.iter().zip(cells) {
+                let sub_value = column_to_json(sub_field, cell.get("v"))
+                    .map_err(|message| format!("{}: {}", sub_field.name, message))?;
+                object.insert(sub_field.name.clone(), sub_value);
+            }
+            Ok(Value::Object(object))
+        }
+        (FieldType::Integer | FieldType::Int64, Value::String(s)) => s
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|e| parse_error(s, &e)),
+        (FieldType::Float | FieldType::Float64, Value::String(s)) => {
+            let float = s.parse::<f64>().map_err(|e| parse_error(s, &e))?;
+            serde_json::Number::from_f64(float)
+                .map(Value::Number)
+                .ok_or_else(|| parse_error(s, &"not a finite number"))
+        }
+        (FieldType::Boolean | FieldType::Bool, Value::String(s)) => s
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|e| parse_error(s, &e)),
+        // Timestamps are sent as fractional seconds since the epoch.
+        (FieldType::Timestamp, Value::String(s)) => s
+            .parse::<f64>()
+            .map(|seconds| Value::from(seconds.floor() as i64))
+            .map_err(|e| parse_error(s, &e)),
+        (
+            FieldType::String
+            | FieldType::Bytes
+            | FieldType::Numeric
+            | FieldType::Bignumeric
+            | FieldType::Date
+            | FieldType::Time
+            | FieldType::Datetime,
+            Value::String(_),
+        ) => Ok(value.clone()),
+        (field_type, other) => Err(format!(
+            "unexpected {} for a {:?} column",
+            other, field_type
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+    use time::OffsetDateTime;
+
+    fn schema() -> Vec<TableFieldSchema> {
+        serde_json::from_value(json!([
+            {"name": "start_date", "type": "TIMESTAMP", "mode": "NULLABLE"},
+            {"name": "plan_id", "type": "STRING", "mode": "NULLABLE"},
+            {"name": "plan_amount", "type": "INTEGER", "mode": "NULLABLE"},
+            {"name": "is_trial", "type": "BOOLEAN", "mode": "NULLABLE"},
+            {"name": "promotion_codes", "type": "STRING", "mode": "REPEATED"},
+            {"name": "plan", "type": "RECORD", "mode": "NULLABLE", "fields": [
+                {"name": "interval", "type": "STRING", "mode": "NULLABLE"},
+                {"name": "interval_count", "type": "INTEGER", "mode": "NULLABLE"}
+            ]}
+        ]))
+        .unwrap()
+    }
+
+    fn row(values: Value) -> Vec<TableCell> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Plan {
+        interval: String,
+        interval_count: i32,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct TestRow {
+        #[serde(with = "time::serde::timestamp")]
+        start_date: OffsetDateTime,
+        plan_id: String,
+        plan_amount: i32,
+        is_trial: Option<bool>,
+        promotion_codes: Vec<String>,
+        plan: Option<Plan>,
+    }
+
+    #[test]
+    fn from_row_decodes_each_column_by_schema_type() {
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": "true"},
+            {"v": [{"v": "a"}, {"v": "b"}]},
+            {"v": {"f": [{"v": "month"}, {"v": "6"}]}}
+        ]));
+        let decoded: TestRow = from_row(&schema(), &columns).expect("Could not decode row");
+        assert_eq!(
+            decoded,
+            TestRow {
+                start_date: OffsetDateTime::from_unix_timestamp(1647020804),
+                plan_id: "price_1Iw85dJNcmPzuWtRyhMDdtM7".to_string(),
+                plan_amount: 5988,
+                is_trial: Some(true),
+                promotion_codes: vec!["a".to_string(), "b".to_string()],
+                plan: Some(Plan {
+                    interval: "month".to_string(),
+                    interval_count: 6
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn from_row_decodes_nulls_into_options() {
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": null},
+            {"v": []},
+            {"v": null}
+        ]));
+        let decoded: TestRow = from_row(&schema(), &columns).expect("Could not decode row");
+        assert_eq!(decoded.is_trial, None);
+        assert_eq!(decoded.promotion_codes, Vec::<String>::new());
+        assert_eq!(decoded.plan, None);
+    }
+
+    #[test]
+    fn from_row_ignores_columns_that_are_not_requested() {
+        #[derive(Deserialize)]
+        struct PlanIdOnly {
+            plan_id: String,
+        }
+        let columns = row(json!([
+            {"v": "not a timestamp"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": null},
+            {"v": []},
+            {"v": null}
+        ]));
+        let decoded: PlanIdOnly = from_row(&schema(), &columns).expect("Could not decode row");
+        assert_eq!(decoded.plan_id, "price_1Iw85dJNcmPzuWtRyhMDdtM7");
+    }
+
+    fn assert_invalid_column(result: Result<TestRow, BQError>, expected_col_name: &str) {
+        match result {
+            Err(BQError::InvalidColumnValue { col_name, .. }) => {
+                assert_eq!(col_name, expected_col_name)
+            }
+            other => panic!("Expected InvalidColumnValue, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_row_reports_the_column_that_failed_to_decode() {
+        // Unparseable value for the schema type
+        let columns = row(json!([
+            {"v": ""},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": null},
+            {"v": []},
+            {"v": null}
+        ]));
+        assert_invalid_column(from_row(&schema(), &columns), "start_date");
+        // Null for a required field
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": null},
+            {"v": "5988"},
+            {"v": null},
+            {"v": []},
+            {"v": null}
+        ]));
+        assert_invalid_column(from_row(&schema(), &columns), "plan_id");
+        // Value out of range for the field type
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5000000000"},
+            {"v": null},
+            {"v": []},
+            {"v": null}
+        ]));
+        assert_invalid_column(from_row(&schema(), &columns), "plan_amount");
+        // Nested record value
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": null},
+            {"v": []},
+            {"v": {"f": [{"v": "month"}, {"v": "six"}]}}
+        ]));
+        assert_invalid_column(from_row(&schema(), &columns), "plan");
+    }
+
+    #[test]
+    fn row_to_json_decodes_as_from_row_does_and_keeps_invalid_values() {
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": "price_1Iw85dJNcmPzuWtRyhMDdtM7"},
+            {"v": "5988"},
+            {"v": "true"},
+            {"v": [{"v": "a"}, {"v": "b"}]},
+            {"v": {"f": [{"v": "month"}, {"v": "6"}]}}
+        ]));
+        let json = row_to_json(&schema(), &columns);
+        let decoded: TestRow = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, from_row(&schema(), &columns).unwrap());
+
+        let columns = row(json!([
+            {"v": "1.647020804141794E9"},
+            {"v": null},
+            {"v": "not a number"},
+            {"v": "true"},
+            {"v": []},
+            {"v": null}
+        ]));
+        assert_eq!(
+            row_to_json(&schema(), &columns),
+            json!({
+                "start_date": 1647020804,
+                "plan_id": null,
+                "plan_amount": "not a number",
+                "is_trial": true,
+                "promotion_codes": [],
+                "plan": null,
+            })
+        );
+    }
+
+    #[test]
+    fn from_row_reports_missing_columns() {
+        let columns = row(json!([{"v": "1.647020804141794E9"}]));
+        assert_invalid_column(from_row(&schema()[..1], &columns), "plan_id");
+    }
+}
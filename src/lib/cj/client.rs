@@ -1,23 +1,33 @@
-use crate::{info, models::subscriptions::Subscription, settings::Settings, telemetry::LogKey};
-use rand::{thread_rng, Rng};
-use reqwest::{Client, Error, Response, Url};
+use crate::{
+    info,
+    models::subscriptions::Subscription,
+    settings::Settings,
+    telemetry::{LogKey, StatsD},
+};
+use reqwest::{Client, Error, StatusCode, Url};
 use secrecy::{ExposeSecret, Secret};
 use serde::{de, Deserialize, Deserializer};
 use serde_json::{json, Value};
+use std::borrow::Cow;
 use time::{Duration, OffsetDateTime};
 
-use super::country_codes::get_iso_code_3_from_iso_code_2;
+use super::{
+    country_codes::get_iso_code_3_from_iso_code_2,
+    event_time::EventTimeRandomizer,
+    programs::{CJProgram, CJPrograms},
+    rate_limit::TokenBucket,
+};
 
 pub struct CJClient {
     advertiser_id: String,
     client: reqwest::Client,
-    cj_cid: String,
-    cj_type: String,
-    cj_signature: String,
+    programs: CJPrograms,
+    // None when cj_s2s_requests_per_second is 0
+    s2s_rate_limit: Option<TokenBucket>,
     commission_detail_endpoint: Url,
     commission_detail_api_token: Secret<String>,
     s2s_endpoint: Url,
-    random_minutes: Duration,
+    event_times: EventTimeRandomizer,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -55,6 +65,71 @@ struct CommissionDetailQueryResponse {
     errors: Option<Value>,
 }
 
+/// What CJ made of an S2S request. CJ answers some rejected requests with a 200 and the
+/// reason in the body, so the status alone isn't enough.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum S2SOutcome {
+    Accepted,
+    Rejected(String),
+    // A 200 with a body that's neither an acceptance nor an error
+    Unknown,
+}
+
+// Rejection reasons are kept in the status history, so very long bodies are cut short
+const MAX_REASON_LENGTH: usize = 500;
+
+impl S2SOutcome {
+    pub fn parse(status: StatusCode, body: &str) -> Self {
+        if status != StatusCode::OK {
+            return S2SOutcome::Rejected(format!("CJ responded with status {}", status));
+        }
+        let body = body.trim();
+        if body.is_empty() {
+            return S2SOutcome::Accepted;
+        }
+        if let Ok(Value::Object(json)) = serde_json::from_str::<Value>(body) {
+            return match json.get("error").or_else(|| json.get("errors")) {
+                Some(Value::String(error)) => S2SOutcome::rejected(error),
+                Some(error) => S2SOutcome::rejected(&error.to_string()),
+                None => S2SOutcome::Unknown,
+            };
+        }
+        let lowercase = body.to_lowercase();
+        if ["ok", "success", "accepted"].contains(&lowercase.as_str()) {
+            return S2SOutcome::Accepted;
+        }
+        match body
+            .lines()
+            .find(|line| line.trim_start().to_lowercase().starts_with("error"))
+        {
+            Some(line) => S2SOutcome::rejected(line.trim()),
+            None => S2SOutcome::Unknown,
+        }
+    }
+
+    fn rejected(reason: &str) -> Self {
+        S2SOutcome::Rejected(reason.chars().take(MAX_REASON_LENGTH).collect())
+    }
+}
+
+/// What CJ answered an S2S request with, its body read whole so that it can be logged.
+#[derive(Debug)]
+pub struct S2SResponse {
+    pub status: StatusCode,
+    pub body: String,
+    pub outcome: S2SOutcome,
+}
+
+/// One S2S request and its outcome, as recorded in the cj_s2s_log.
+#[derive(Debug)]
+pub struct S2SExchange {
+    // The request URL with its SIGNATURE redacted
+    pub url: String,
+    pub sent: OffsetDateTime,
+    pub latency: Duration,
+    pub response: Result<S2SResponse, Error>,
+}
+
 pub fn convert_amount_to_decimal(plan_amount: i32) -> f32 {
     plan_amount as f32 / 100.0
 }
@@ -67,10 +142,17 @@ where
     s.parse::<f32>().map_err(de::Error::custom)
 }
 
-fn get_random_minutes() -> Duration {
-    let mut rng = thread_rng();
-    let minutes = rng.gen_range(15..=60);
-    Duration::minutes(minutes)
+/// The URL with the value of SIGNATURE replaced, so that it can be stored and shown.
+fn redact_signature(url: &Url) -> String {
+    let mut redacted = url.clone();
+    redacted
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(url.query_pairs().map(|(key, value)| match key.as_ref() {
+            "SIGNATURE" => (key, Cow::Borrowed("REDACTED")),
+            _ => (key, value),
+        }));
+    redacted.to_string()
 }
 
 impl CJClient {
@@ -78,7 +160,7 @@ impl CJClient {
         settings: &Settings,
         s2s_endpoint: Option<&str>,
         commission_detail_endpoint: Option<&str>,
-        random_minutes: Option<Duration>,
+        event_times: Option<EventTimeRandomizer>,
     ) -> CJClient {
         let s2s_endpoint = s2s_endpoint.unwrap_or("https://www.emjcd.com/u");
         let commission_detail_endpoint =
@@ -86,32 +168,37 @@ impl CJClient {
         CJClient {
             advertiser_id: settings.cj_sftp_user.clone(),
             client: Client::new(),
-            cj_cid: settings.cj_cid.clone(),
-            cj_type: settings.cj_type.clone(),
-            cj_signature: settings.cj_signature.clone(),
+            programs: CJPrograms::from_settings(settings),
+            s2s_rate_limit: match settings.cj_s2s_requests_per_second {
+                0 => None,
+                per_second => Some(TokenBucket::new(per_second, per_second)),
+            },
             commission_detail_endpoint: Url::parse(commission_detail_endpoint)
                 .expect("Could not parse commission_detail_endpoint"),
             commission_detail_api_token: settings.cj_api_access_token.clone(),
             s2s_endpoint: Url::parse(s2s_endpoint).expect("Could not parse s2s_endpoint"),
-            random_minutes: random_minutes.unwrap_or_else(get_random_minutes),
+            event_times: event_times
+                .unwrap_or_else(|| EventTimeRandomizer::from_settings(settings)),
         }
     }
 
     fn randomize_and_format_event_time(&self, original_event_time: OffsetDateTime) -> String {
         // Note this must be in the future or will fail CJ side
-        // We add a random number of minutes and remove seconds and microseconds to enhance privacy
-        let randomized_event_time = original_event_time + self.random_minutes;
-        randomized_event_time.format("%FT%H:%M:00.000Z")
+        // We add a random number of minutes and round to the bucket to enhance privacy
+        self.event_times
+            .randomize(original_event_time, OffsetDateTime::now_utc())
+            .format("%FT%H:%M:00.000Z")
     }
 
     fn get_url_for_sub(&self, sub: &Subscription) -> Url {
         let event_time = self.randomize_and_format_event_time(sub.subscription_created);
+        let program = self.program_for(sub);
         let mut url_for_sub = self.s2s_endpoint.clone();
         url_for_sub
             .query_pairs_mut()
-            .append_pair("CID", &self.cj_cid)
-            .append_pair("TYPE", &self.cj_type)
-            .append_pair("SIGNATURE", &self.cj_signature)
+            .append_pair("CID", &program.cid)
+            .append_pair("TYPE", &program.cj_type)
+            .append_pair("SIGNATURE", &program.signature)
             .append_pair("METHOD", "S2S")
             .append_pair(
                 "CJEVENT",
@@ -134,9 +221,38 @@ impl CJClient {
         url_for_sub
     }
 
-    pub async fn report_subscription(&self, sub: &Subscription) -> Result<Response, Error> {
+    pub fn program_for(&self, sub: &Subscription) -> &CJProgram {
+        self.programs.for_subscription(sub)
+    }
+
+    /// Waits for the S2S rate limit, then reports `sub`, recording how long CJ took to
+    /// respond. The whole exchange is returned for the cj_s2s_log.
+    pub async fn report_subscription(&self, sub: &Subscription, statsd: &StatsD) -> S2SExchange {
         let url_for_sub = self.get_url_for_sub(sub);
-        self.client.get(url_for_sub).send().await
+        let url = redact_signature(&url_for_sub);
+        if let Some(rate_limit) = &self.s2s_rate_limit {
+            rate_limit.acquire().await;
+        }
+        let sent = OffsetDateTime::now_utc();
+        let response = match self.client.get(url_for_sub).send().await {
+            Ok(r) => {
+                let status = r.status();
+                r.text().await.map(|body| S2SResponse {
+                    status,
+                    outcome: S2SOutcome::parse(status, &body),
+                    body,
+                })
+            }
+            Err(e) => Err(e),
+        };
+        let latency = OffsetDateTime::now_utc() - sent;
+        statsd.time(&LogKey::ReportSubscriptionReportToCjTimer, latency);
+        S2SExchange {
+            url,
+            sent,
+            latency,
+            response,
+        }
     }
 
     pub async fn query_commission_detail_api_between_dates(
@@ -234,6 +350,7 @@ pub mod test_telemetry {
 #[cfg(test)]
 mod tests {
 
+    use rand::rngs::mock::StepRng;
     use time::{date, time, PrimitiveDateTime};
 
     use super::*;
@@ -265,54 +382,74 @@ mod tests {
         assert!(result.is_err());
     }
 
+    fn client_with_smallest_offsets(settings: &Settings) -> CJClient {
+        // StepRng(0) always draws the smallest offset
+        let event_times = EventTimeRandomizer::with_rng(settings, StepRng::new(0, 0));
+        CJClient::new(settings, None, None, Some(event_times))
+    }
+
     #[test]
-    fn random_minutes_should_be_set_on_cjclient_if_passed() {
-        // This is used for tests settings
-        let settings = empty_settings();
-        let cj = CJClient::new(&settings, None, None, Some(Duration::minutes(88)));
-        assert_eq!(cj.random_minutes.whole_minutes(), 88);
+    fn randomize_and_format_event_time_adds_minutes_and_formats_string_correctly() {
+        let cj = client_with_smallest_offsets(&empty_settings());
+        let event_time =
+            PrimitiveDateTime::new(date!(2099 - 01 - 01), time!(11:11:11.111111)).assume_utc();
+        let result = cj.randomize_and_format_event_time(event_time);
+        assert_eq!(result, "2099-01-01T11:27:00.000Z");
     }
 
     #[test]
-    fn random_minutes_should_be_greated_than_15_and_less_than_60_if_on_cjclient_if_not_passed() {
-        let settings = empty_settings();
-        for _ in 0..10 {
-            let cj = CJClient::new(&settings, None, None, None);
-            let minutes = cj.random_minutes.whole_minutes();
-            assert!(
-                minutes >= 15,
-                "minutes was {} - should be greater than 15",
-                minutes
-            );
-            assert!(
-                minutes <= 60,
-                "minutes was {} - should be less than 60",
-                minutes
-            );
+    fn s2s_outcome_parses_acceptances_rejections_and_unknown_bodies() {
+        let ok = StatusCode::OK;
+        assert_eq!(S2SOutcome::parse(ok, ""), S2SOutcome::Accepted);
+        assert_eq!(S2SOutcome::parse(ok, " OK\n"), S2SOutcome::Accepted);
+        assert_eq!(
+            S2SOutcome::parse(ok, "ERROR: Invalid CJEVENT\n"),
+            S2SOutcome::Rejected("ERROR: Invalid CJEVENT".to_string())
+        );
+        assert_eq!(
+            S2SOutcome::parse(ok, r#"{"error": "Duplicate OID"}"#),
+            S2SOutcome::Rejected("Duplicate OID".to_string())
+        );
+        assert_eq!(
+            S2SOutcome::parse(ok, r#"{"errors": ["Bad AMT1"]}"#),
+            S2SOutcome::Rejected(r#"["Bad AMT1"]"#.to_string())
+        );
+        assert_eq!(
+            S2SOutcome::parse(StatusCode::INTERNAL_SERVER_ERROR, "ok"),
+            S2SOutcome::Rejected("CJ responded with status 500 Internal Server Error".to_string())
+        );
+        assert_eq!(S2SOutcome::parse(ok, "<html></html>"), S2SOutcome::Unknown);
+        assert_eq!(S2SOutcome::parse(ok, r#"{"id": 1}"#), S2SOutcome::Unknown);
+        let long = format!("error {}", "x".repeat(1000));
+        match S2SOutcome::parse(ok, &long) {
+            S2SOutcome::Rejected(reason) => assert_eq!(reason.len(), MAX_REASON_LENGTH),
+            outcome => panic!("Expected a rejection, got {:?}", outcome),
         }
     }
 
     #[test]
-    fn randomize_and_format_event_time_adds_minutes_and_formats_string_correctly() {
-        let settings = empty_settings();
-        let cj = CJClient::new(&settings, None, None, Some(Duration::minutes(9)));
-        let event_time =
-            PrimitiveDateTime::new(date!(2019 - 01 - 01), time!(11:11:11.111111)).assume_utc();
-        let result = cj.randomize_and_format_event_time(event_time);
-        assert_eq!(result, "2019-01-01T11:20:00.000Z");
+    fn redact_signature_replaces_only_the_signature() {
+        let mut settings = empty_settings();
+        settings.cj_signature = "secret signature".to_string();
+        let cj = CJClient::new(&settings, None, None, None);
+        let sub = make_fake_sub();
+        let url = Url::parse(&redact_signature(&cj.get_url_for_sub(&sub))).unwrap();
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert!(pairs.contains(&("SIGNATURE".to_string(), "REDACTED".to_string())));
+        assert!(pairs.contains(&("OID".to_string(), sub.id.to_string())));
+        assert!(!url.as_str().contains("secret"));
     }
 
     #[test]
     fn event_time_in_url_should_by_randomized_by_duration() {
         let mut sub = make_fake_sub();
         sub.subscription_created =
-            PrimitiveDateTime::new(date!(2021 - 12 - 31), time!(23:59:59.999999)).assume_utc();
-        let settings = empty_settings();
-        let cj = CJClient::new(&settings, None, None, Some(Duration::minutes(44)));
+            PrimitiveDateTime::new(date!(2099 - 12 - 31), time!(23:59:59.999999)).assume_utc();
+        let cj = client_with_smallest_offsets(&empty_settings());
         let url = cj.get_url_for_sub(&sub);
         for (key, value) in url.query_pairs() {
             if key == "EVENTTIME" {
-                assert_eq!(value, "2022-01-01T00:43:00.000Z");
+                assert_eq!(value, "2100-01-01T00:15:00.000Z");
             }
         }
     }
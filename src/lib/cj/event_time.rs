@@ -0,0 +1,145 @@
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use std::sync::Mutex;
+use time::{Duration, OffsetDateTime};
+
+use crate::settings::Settings;
+
+/// The range of the random offset added to each event time.
+pub const MIN_OFFSET_MINUTES: i64 = 15;
+pub const MAX_OFFSET_MINUTES: i64 = 60;
+
+/// Randomizes the event times reported to CJ, so that they can't be matched to when a
+/// subscription was created. Each subscription gets its own offset, so seeing two
+/// conversions doesn't give away the offset of either.
+pub struct EventTimeRandomizer {
+    bucket: Duration,
+    rng: Mutex<Box<dyn RngCore + Send>>,
+}
+
+impl EventTimeRandomizer {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self::with_rng(settings, StdRng::from_entropy())
+    }
+
+    /// Intentionally panics on an invalid cj_event_time_bucket_minutes.
+    pub fn with_rng(settings: &Settings, rng: impl RngCore + Send + 'static) -> Self {
+        if settings.cj_event_time_bucket_minutes == 0 {
+            panic!("Invalid cj_event_time_bucket_minutes. Must be at least 1");
+        }
+        EventTimeRandomizer {
+            bucket: Duration::minutes(settings.cj_event_time_bucket_minutes as i64),
+            rng: Mutex::new(Box::new(rng)),
+        }
+    }
+
+    fn offset(&self) -> Duration {
+        // Intentional expect. The lock is never held across an await or a panic.
+        let mut rng = self.rng.lock().expect("EventTimeRandomizer lock poisoned");
+        Duration::minutes(rng.gen_range(MIN_OFFSET_MINUTES..=MAX_OFFSET_MINUTES))
+    }
+
+    /// The event time of something that happened at `original`, offset and rounded up to
+    /// the bucket. CJ rejects event times in the past, so when the offset isn't enough it's
+    /// added to `now` instead.
+    pub fn randomize(&self, original: OffsetDateTime, now: OffsetDateTime) -> OffsetDateTime {
+        let offset = self.offset();
+        let randomized = match original + offset > now {
+            true => original + offset,
+            false => now + offset,
+        };
+        round_up(randomized, self.bucket)
+    }
+}
+
+fn round_up(t: OffsetDateTime, bucket: Duration) -> OffsetDateTime {
+    let bucket_seconds = bucket.whole_seconds();
+    let seconds = t.unix_timestamp();
+    let rounded_down = seconds - seconds.rem_euclid(bucket_seconds);
+    match rounded_down == seconds && t.nanosecond() == 0 {
+        true => t,
+        false => OffsetDateTime::from_unix_timestamp(rounded_down + bucket_seconds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::empty_settings;
+    use rand::rngs::mock::StepRng;
+    use time::{date, time, PrimitiveDateTime};
+
+    fn randomizer(bucket_minutes: u32, rng: impl RngCore + Send + 'static) -> EventTimeRandomizer {
+        let mut settings = empty_settings();
+        settings.cj_event_time_bucket_minutes = bucket_minutes;
+        EventTimeRandomizer::with_rng(&settings, rng)
+    }
+
+    #[test]
+    fn offsets_are_drawn_for_each_event_across_the_whole_range() {
+        let randomizer = randomizer(1, StdRng::seed_from_u64(25));
+        let offsets: Vec<i64> = (0..2000)
+            .map(|_| randomizer.offset().whole_minutes())
+            .collect();
+        assert!(offsets
+            .iter()
+            .all(|m| (MIN_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(m)));
+        assert_eq!(*offsets.iter().min().unwrap(), MIN_OFFSET_MINUTES);
+        assert_eq!(*offsets.iter().max().unwrap(), MAX_OFFSET_MINUTES);
+        let mean = offsets.iter().sum::<i64>() as f64 / offsets.len() as f64;
+        assert!((mean - 37.5).abs() < 2.0, "mean was {}", mean);
+        // Not one offset for everything
+        assert!(offsets.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn randomize_adds_the_offset_and_rounds_up_to_the_bucket() {
+        let now = PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(00:00)).assume_utc();
+        let original =
+            PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(11:11:11.111111)).assume_utc();
+        // StepRng(0) always draws the smallest offset
+        let by_minute = randomizer(1, StepRng::new(0, 0));
+        assert_eq!(
+            by_minute.randomize(original, now),
+            PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(11:27)).assume_utc()
+        );
+        let by_hour = randomizer(60, StepRng::new(0, 0));
+        assert_eq!(
+            by_hour.randomize(original, now),
+            PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(12:00)).assume_utc()
+        );
+        let exact = PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(11:45)).assume_utc();
+        assert_eq!(
+            by_minute.randomize(exact, now),
+            PrimitiveDateTime::new(date!(2022 - 01 - 01), time!(12:00)).assume_utc()
+        );
+    }
+
+    #[test]
+    fn randomize_never_returns_an_event_time_in_the_past() {
+        let now = OffsetDateTime::now_utc();
+        for bucket_minutes in [1, 7, 60, 1440] {
+            let randomizer = randomizer(bucket_minutes, StdRng::seed_from_u64(7));
+            for original in [
+                now - Duration::days(400),
+                now - Duration::minutes(30),
+                now - Duration::minutes(1),
+                now,
+                now + Duration::minutes(5),
+            ] {
+                let randomized = randomizer.randomize(original, now);
+                assert!(randomized >= now + Duration::minutes(MIN_OFFSET_MINUTES));
+                assert!(randomized >= original + Duration::minutes(MIN_OFFSET_MINUTES));
+                assert_eq!(
+                    randomized.unix_timestamp() % (bucket_minutes as i64 * 60),
+                    0
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cj_event_time_bucket_minutes")]
+    fn bucket_must_be_at_least_a_minute() {
+        randomizer(0, StepRng::new(0, 0));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod client;
 pub mod country_codes;
+pub mod event_time;
+pub mod programs;
+pub mod rate_limit;
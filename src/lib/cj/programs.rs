@@ -0,0 +1,199 @@
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::{models::subscriptions::Subscription, settings::Settings};
+
+/// The name of the program made from cj_cid, cj_type, cj_signature and cj_subid.
+pub const DEFAULT_PROGRAM: &str = "default";
+
+/// The CJ configuration of one affiliate program.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CJProgram {
+    pub name: String,
+    pub cid: String,
+    #[serde(rename = "type")]
+    pub cj_type: String,
+    pub signature: String,
+    // The SUBID of the program's corrections report
+    pub subid: String,
+    #[serde(default)]
+    pub plan_ids: Vec<String>,
+    #[serde(default)]
+    pub product_ids: Vec<String>,
+}
+
+/// Picks the CJ program of a subscription by its plan id, then its product id. A
+/// subscription neither matches is in the default program.
+#[derive(Debug, Clone)]
+pub struct CJPrograms {
+    default: CJProgram,
+    programs: Vec<CJProgram>,
+}
+
+impl CJPrograms {
+    /// Intentionally panics on invalid cj_programs. Reporting a subscription to the wrong
+    /// program can't be undone.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let default = CJProgram {
+            name: DEFAULT_PROGRAM.to_string(),
+            cid: settings.cj_cid.clone(),
+            cj_type: settings.cj_type.clone(),
+            signature: settings.cj_signature.clone(),
+            subid: settings.cj_subid.clone(),
+            plan_ids: vec![],
+            product_ids: vec![],
+        };
+        let configured = settings.cj_programs.expose_secret();
+        let programs: Vec<CJProgram> = match configured.trim() {
+            "" => vec![],
+            json => serde_json::from_str(json)
+                .unwrap_or_else(|e| panic!("Invalid cj_programs. Expected a JSON list. {}", e)),
+        };
+        let mut names = HashSet::new();
+        let mut plan_ids = HashSet::new();
+        let mut product_ids = HashSet::new();
+        for program in &programs {
+            let valid_name = !program.name.is_empty()
+                && program
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+            if !valid_name || program.name == DEFAULT_PROGRAM || !names.insert(&program.name) {
+                panic!(
+                    "Invalid cj_programs. Program names must be unique, lowercase, and not {}: {:?}",
+                    DEFAULT_PROGRAM, program.name
+                );
+            }
+            if program.plan_ids.is_empty() && program.product_ids.is_empty() {
+                panic!(
+                    "Invalid cj_programs. {} has no plan_ids or product_ids",
+                    program.name
+                );
+            }
+            for plan_id in &program.plan_ids {
+                if !plan_ids.insert(plan_id) {
+                    panic!(
+                        "Invalid cj_programs. Plan id {} is in two programs",
+                        plan_id
+                    );
+                }
+            }
+            for product_id in &program.product_ids {
+                if !product_ids.insert(product_id) {
+                    panic!(
+                        "Invalid cj_programs. Product id {} is in two programs",
+                        product_id
+                    );
+                }
+            }
+        }
+        CJPrograms { default, programs }
+    }
+
+    pub fn for_subscription(&self, sub: &Subscription) -> &CJProgram {
+        let by_plan = self
+            .programs
+            .iter()
+            .find(|program| program.plan_ids.contains(&sub.plan_id));
+        let by_product = || match &sub.product_id {
+            Some(product_id) => self
+                .programs
+                .iter()
+                .find(|program| program.product_ids.contains(product_id)),
+            None => None,
+        };
+        by_plan.or_else(by_product).unwrap_or(&self.default)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&CJProgram> {
+        if name == DEFAULT_PROGRAM {
+            return Some(&self.default);
+        }
+        self.programs.iter().find(|program| program.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::subscriptions::test_subscriptions::make_fake_sub, test_utils::empty_settings,
+    };
+    use secrecy::Secret;
+    use serde_json::json;
+
+    fn programs_with(configured: serde_json::Value) -> CJPrograms {
+        let mut settings = empty_settings();
+        settings.cj_cid = "default cid".to_string();
+        settings.cj_programs = Secret::new(configured.to_string());
+        CJPrograms::from_settings(&settings)
+    }
+
+    fn relay() -> serde_json::Value {
+        json!({
+            "name": "relay",
+            "cid": "relay cid",
+            "type": "relay type",
+            "signature": "relay signature",
+            "subid": "relay subid",
+            "plan_ids": ["price_relay_monthly"],
+            "product_ids": ["prod_relay"],
+        })
+    }
+
+    #[test]
+    fn empty_cj_programs_only_has_the_default_program() {
+        let programs = CJPrograms::from_settings(&empty_settings());
+        let sub = make_fake_sub();
+        assert_eq!(programs.for_subscription(&sub).name, DEFAULT_PROGRAM);
+        assert_eq!(programs.by_name(DEFAULT_PROGRAM).unwrap().cid, "_");
+        assert!(programs.by_name("relay").is_none());
+    }
+
+    #[test]
+    fn subscriptions_are_matched_by_plan_id_then_product_id() {
+        let programs = programs_with(json!([relay()]));
+        let mut sub = make_fake_sub();
+        assert_eq!(programs.for_subscription(&sub).cid, "default cid");
+        sub.plan_id = "price_relay_monthly".to_string();
+        assert_eq!(programs.for_subscription(&sub).cid, "relay cid");
+        sub.plan_id = "price_relay_yearly".to_string();
+        sub.product_id = Some("prod_relay".to_string());
+        assert_eq!(programs.for_subscription(&sub).cid, "relay cid");
+        assert_eq!(programs.by_name("relay").unwrap().subid, "relay subid");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cj_programs. Expected a JSON list")]
+    fn cj_programs_must_be_a_json_list() {
+        programs_with(json!({"name": "relay"}));
+    }
+
+    #[test]
+    #[should_panic(expected = "Program names must be unique")]
+    fn cj_programs_names_must_be_unique() {
+        let mut other = relay();
+        other["plan_ids"] = json!(["price_other"]);
+        other["product_ids"] = json!([]);
+        programs_with(json!([relay(), other]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Program names must be unique")]
+    fn cj_programs_cannot_replace_the_default_program() {
+        let mut other = relay();
+        other["name"] = json!(DEFAULT_PROGRAM);
+        programs_with(json!([other]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Plan id price_relay_monthly is in two programs")]
+    fn cj_programs_cannot_share_plan_ids() {
+        let mut other = relay();
+        other["name"] = json!("other");
+        other["product_ids"] = json!([]);
+        programs_with(json!([relay(), other]));
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Limits how often something happens to `per_second` on average, allowing bursts of up
+/// to `capacity`. Shared by everything that calls `acquire` on the same bucket.
+pub struct TokenBucket {
+    per_second: f64,
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    /// A full bucket, so the first `capacity` acquires don't wait.
+    pub fn new(per_second: u32, capacity: u32) -> Self {
+        assert!(per_second > 0, "TokenBucket needs a positive rate");
+        TokenBucket {
+            per_second: per_second as f64,
+            capacity: capacity.max(1) as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity.max(1) as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                // Intentional expect. The lock is never held across an await or a panic.
+                let mut bucket = self.bucket.lock().expect("TokenBucket lock poisoned");
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.per_second;
+                bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+                bucket.refilled = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn acquire_allows_a_burst_then_waits_for_tokens() {
+        let bucket = TokenBucket::new(20, 2);
+        let start = Instant::now();
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+        // Two more at 20 per second take at least 100ms
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(95));
+    }
+}
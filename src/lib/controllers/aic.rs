@@ -1,19 +1,26 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
+    controllers::{
+        aic_token::{AICSigner, AICTokenError},
+        errors::ApiError,
+    },
     error_and_incr, info, info_and_incr,
-    models::aic::AICModel,
+    models::{aic::AICModel, aic_clicks::ClickMetadata, idempotency_keys::IdempotencyKeyModel},
     settings::Settings,
     telemetry::{LogKey, StatsD},
 };
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AICResponse {
-    pub aic_id: Uuid,
+    /// A signed token, or a bare UUID while aic_signing_keys is empty.
+    pub aic_id: String,
     #[serde(with = "time::serde::timestamp")]
     pub expires: OffsetDateTime,
 }
@@ -23,23 +30,235 @@ pub struct AICRequest {
     pub flow_id: String,
     #[serde(default = "empty_cj_id")]
     pub cj_id: String,
+    /// Recorded with the click when the AIC is created or cj_id changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub click_metadata: Option<ClickMetadata>,
 }
 
 fn empty_cj_id() -> String {
     "empty_cj_id".to_string()
 }
 
+pub const MAX_FLOW_ID_LENGTH: usize = 128;
+pub const MAX_CJ_ID_LENGTH: usize = 128;
+pub const MAX_CLICK_METADATA_LENGTH: usize = 128;
+pub const MAX_LANDING_PAGE_LENGTH: usize = 2048;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+// How long a request whose AIC was created concurrently waits for the response to replay
+const IDEMPOTENCY_KEY_WAIT_ATTEMPTS: u32 = 10;
+const IDEMPOTENCY_KEY_WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+const FLOW_ID_CONFLICT: &str = "An AIC already exists for this flow_id";
+
+impl AICRequest {
+    /// flow_ids come from FxA and cj_ids from CJ's landing page URLs. Neither has a fixed
+    /// format, so this only checks lengths and that cj_ids are plain URL-safe tokens.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.flow_id.is_empty() || self.flow_id.len() > MAX_FLOW_ID_LENGTH {
+            return Err(ApiError::InvalidInput(format!(
+                "flow_id must be between 1 and {} characters",
+                MAX_FLOW_ID_LENGTH
+            )));
+        }
+        if !self.flow_id.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(ApiError::InvalidInput(
+                "flow_id must only contain printable ASCII characters".to_string(),
+            ));
+        }
+        validate_cj_id(&self.cj_id)?;
+        match &self.click_metadata {
+            Some(click_metadata) => validate_click_metadata(click_metadata),
+            None => Ok(()),
+        }
+    }
+
+    fn click_metadata(&self) -> ClickMetadata {
+        self.click_metadata.clone().unwrap_or_default()
+    }
+}
+
+pub fn validate_cj_id(cj_id: &str) -> Result<(), ApiError> {
+    if cj_id.is_empty() || cj_id.len() > MAX_CJ_ID_LENGTH {
+        return Err(ApiError::InvalidInput(format!(
+            "cj_id must be between 1 and {} characters",
+            MAX_CJ_ID_LENGTH
+        )));
+    }
+    if !cj_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(ApiError::InvalidInput(
+            "cj_id must only contain letters, digits, '-', '_' and '.'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Metadata values are free text from CJ's and our marketing URLs, so only their length and
+/// that they're printable is checked. landing_page must be an http(s) URL.
+pub fn validate_click_metadata(click_metadata: &ClickMetadata) -> Result<(), ApiError> {
+    for (name, value) in click_metadata.fields() {
+        let max_length = match name {
+            "landing_page" => MAX_LANDING_PAGE_LENGTH,
+            _ => MAX_CLICK_METADATA_LENGTH,
+        };
+        if value.is_empty() || value.len() > max_length {
+            return Err(ApiError::InvalidInput(format!(
+                "click_metadata.{} must be between 1 and {} characters",
+                name, max_length
+            )));
+        }
+        if value.chars().any(char::is_control) {
+            return Err(ApiError::InvalidInput(format!(
+                "click_metadata.{} must not contain control characters",
+                name
+            )));
+        }
+    }
+    if let Some(landing_page) = &click_metadata.landing_page {
+        match Url::parse(landing_page) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => {
+                return Err(ApiError::InvalidInput(
+                    "click_metadata.landing_page must be an http(s) URL".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_aic_id(aic_id: &str, signer: &AICSigner, statsd: &StatsD) -> Result<Uuid, ApiError> {
+    signer
+        .verify(aic_id, OffsetDateTime::now_utc())
+        .map_err(|e| {
+            error_and_incr!(
+                statsd,
+                LogKey::AicRecordUpdateFailedInvalidToken,
+                error = e,
+                "AIC token was rejected."
+            );
+            match e {
+                AICTokenError::Malformed => ApiError::InvalidInput(e.to_string()),
+                _ => ApiError::InvalidToken(e.to_string()),
+            }
+        })
+}
+
+fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, ApiError> {
+    let value = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(key)
+            if !key.is_empty()
+                && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH
+                && key.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(ApiError::InvalidInput(format!(
+            "{} must be between 1 and {} printable ASCII characters",
+            IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LENGTH
+        ))),
+    }
+}
+
+/// Identifies a request body, so that a repeated Idempotency-Key can be checked against it.
+/// Hashing the parsed request means formatting differences don't count as a different body.
+fn request_hash(data: &AICRequest) -> String {
+    let body = serde_json::to_vec(data).expect("AICRequest is always serializable");
+    format!("{:x}", Sha256::digest(body))
+}
+
+fn validate_request(data: &AICRequest, statsd: &StatsD) -> Result<(), ApiError> {
+    data.validate().map_err(|e| {
+        error_and_incr!(
+            statsd,
+            LogKey::RequestAicInvalid,
+            error = e,
+            "AIC request is invalid."
+        );
+        e
+    })
+}
+
+/// The response stored for `key`, if there is one and it was for the same request body.
+async fn replay_idempotency_key(
+    keys: &IdempotencyKeyModel<'_>,
+    key: &str,
+    hash: &str,
+    data: &AICRequest,
+    statsd: &StatsD,
+) -> Result<Option<HttpResponse>, ApiError> {
+    match keys.fetch_one_unexpired(key).await {
+        Ok(existing) if existing.request_hash == hash => {
+            info_and_incr!(
+                statsd,
+                LogKey::AicIdempotencyKeyReplay,
+                flow_id = &data.flow_id.as_str(),
+                "Returning the response of an earlier request with the same Idempotency-Key."
+            );
+            Ok(Some(HttpResponse::Created().json(existing.response)))
+        }
+        Ok(_) => {
+            error_and_incr!(
+                statsd,
+                LogKey::AicIdempotencyKeyReused,
+                flow_id = &data.flow_id.as_str(),
+                "Idempotency-Key was reused with a different request body."
+            );
+            Err(ApiError::IdempotencyKeyReused)
+        }
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::AicIdempotencyKeyFetchFailed,
+                error = e,
+                "Could not fetch Idempotency-Key."
+            );
+            Err(ApiError::from_db_error(&e, FLOW_ID_CONFLICT))
+        }
+    }
+}
+
 pub async fn create(
+    req: HttpRequest,
     data: web::Json<AICRequest>,
     pool: web::Data<PgPool>,
     settings: web::Data<Settings>,
+    signer: web::Data<AICSigner>,
     statsd: web::Data<StatsD>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     info!(LogKey::RequestAicCreate, flow_id = &data.flow_id.as_str(),);
+    validate_request(&data, &statsd)?;
+    let key = idempotency_key(&req)?;
+    let keys = IdempotencyKeyModel {
+        db_pool: pool.as_ref(),
+    };
+    let hash = request_hash(&data);
+    if let Some(key) = &key {
+        if let Some(replayed) = replay_idempotency_key(&keys, key, &hash, &data, &statsd).await? {
+            return Ok(replayed);
+        }
+    }
     let aic = AICModel {
         db_pool: pool.as_ref(),
     };
-    match aic.create(&data.cj_id, &data.flow_id, &settings).await {
+    match aic
+        .create(
+            &data.cj_id,
+            &data.flow_id,
+            &data.click_metadata(),
+            &settings,
+        )
+        .await
+    {
         Ok(created) => {
             info_and_incr!(
                 statsd,
@@ -50,31 +269,69 @@ pub async fn create(
                 "AIC created."
             );
             let response = AICResponse {
-                aic_id: created.id,
+                aic_id: signer.issue(created.id, created.expires),
                 expires: created.expires,
             };
-            HttpResponse::Created().json(response)
+            if let Some(key) = &key {
+                let ttl = Duration::hours(settings.idempotency_key_ttl_hours);
+                let stored = serde_json::to_value(&response).expect("AICResponse is serializable");
+                // The AIC was created, so a failure here only means a retry won't be replayed
+                if let Err(e) = keys.record(key, &hash, &stored, ttl).await {
+                    error_and_incr!(
+                        statsd,
+                        LogKey::AicIdempotencyKeyRecordFailed,
+                        error = e,
+                        aic_id = created.id.to_string().as_str(),
+                        "Could not record Idempotency-Key. Continuing..."
+                    );
+                }
+            }
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => {
+            let error = ApiError::from_db_error(&e, FLOW_ID_CONFLICT);
+            // A concurrent request with the same Idempotency-Key created the AIC after this
+            // one looked for the key. Wait for it to record its response and return that.
+            if let (Some(key), ApiError::Conflict(_)) = (&key, &error) {
+                for _ in 0..IDEMPOTENCY_KEY_WAIT_ATTEMPTS {
+                    if let Some(replayed) =
+                        replay_idempotency_key(&keys, key, &hash, &data, &statsd).await?
+                    {
+                        return Ok(replayed);
+                    }
+                    tokio::time::sleep(IDEMPOTENCY_KEY_WAIT_INTERVAL).await;
+                }
+            }
+            error_and_incr!(
+                statsd,
+                LogKey::AicRecordCreateFailed,
+                error = e,
+                flow_id = &data.flow_id.as_str(),
+                "AIC create failed."
+            );
+            Err(error)
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
 pub async fn update(
-    path: web::Path<Uuid>,
+    path: web::Path<String>,
     data: web::Json<AICRequest>,
     pool: web::Data<PgPool>,
     settings: web::Data<Settings>,
+    signer: web::Data<AICSigner>,
     statsd: web::Data<StatsD>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     let aic = AICModel {
         db_pool: pool.as_ref(),
     };
-    let aic_id = path.into_inner();
+    let aic_id = verify_aic_id(&path.into_inner(), &signer, &statsd)?;
     info!(
         LogKey::RequestAicUpdate,
         aic_id = &aic_id.to_string().as_str(),
         flow_id = &data.flow_id.as_str(),
     );
+    validate_request(&data, &statsd)?;
     let existing = aic.fetch_one_by_id(&aic_id).await;
     let updated = match existing {
         Ok(existing) => {
@@ -83,8 +340,14 @@ pub async fn update(
                 aic.update_flow_id(aic_id, &data.flow_id).await
             } else {
                 // Update both
-                aic.update_flow_id_and_cj_event_value(aic_id, &data.cj_id, &data.flow_id, &settings)
-                    .await
+                aic.update_flow_id_and_cj_event_value(
+                    aic_id,
+                    &data.cj_id,
+                    &data.flow_id,
+                    &data.click_metadata(),
+                    &settings,
+                )
+                .await
             }
         }
         Err(e) => match e {
@@ -95,7 +358,7 @@ pub async fn update(
                     aic_id = &aic_id.to_string().as_str(),
                     "AIC could not be found."
                 );
-                return HttpResponse::NotFound().finish();
+                return Err(ApiError::NotFound(format!("No AIC with id {}", aic_id)));
             }
             _ => {
                 error_and_incr!(
@@ -105,7 +368,7 @@ pub async fn update(
                     aic_id = &aic_id.to_string().as_str(),
                     "AIC update failed."
                 );
-                return HttpResponse::InternalServerError().finish();
+                return Err(ApiError::from_db_error(&e, FLOW_ID_CONFLICT));
             }
         },
     };
@@ -134,10 +397,10 @@ pub async fn update(
             }
 
             let response = AICResponse {
-                aic_id: updated.id,
+                aic_id: signer.issue(updated.id, updated.expires),
                 expires: updated.expires,
             };
-            HttpResponse::Created().json(response)
+            Ok(HttpResponse::Created().json(response))
         }
         Err(e) => {
             error_and_incr!(
@@ -147,7 +410,7 @@ pub async fn update(
                 aic_id = &aic_id.to_string().as_str(),
                 "AIC update failed."
             );
-            HttpResponse::InternalServerError().finish()
+            Err(ApiError::from_db_error(&e, FLOW_ID_CONFLICT))
         }
     }
 }
@@ -0,0 +1,249 @@
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use sha2::Sha256;
+use thiserror::Error;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::settings::Settings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AICTokenError {
+    #[error("aic_id is not a valid AIC token or UUID")]
+    Malformed,
+
+    #[error("AIC token was signed with an unknown key")]
+    UnknownKey,
+
+    #[error("AIC token signature is invalid")]
+    BadSignature,
+
+    #[error("AIC token has expired")]
+    Expired,
+
+    #[error("Unsigned aic_ids are no longer accepted")]
+    UnsignedNotAccepted,
+}
+
+#[derive(Clone)]
+struct SigningKey {
+    id: String,
+    secret: Vec<u8>,
+}
+
+/// Issues and verifies the aic_ids handed to clients.
+///
+/// A signed aic_id is `<key id>.<payload>.<signature>`, where the payload is the AIC's
+/// UUID and expiry and the signature is an HMAC-SHA256 of `<key id>.<payload>`, both
+/// base64url encoded.
+#[derive(Clone)]
+pub struct AICSigner {
+    keys: Vec<SigningKey>,
+    accept_unsigned: bool,
+}
+
+impl AICSigner {
+    /// Intentionally panics on invalid settings. The server shouldn't start without a
+    /// way to issue aic_ids.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut keys = Vec::new();
+        for pair in settings
+            .aic_signing_keys
+            .expose_secret()
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            match pair.split_once(':') {
+                Some((id, secret)) if !id.is_empty() && !id.contains('.') && !secret.is_empty() => {
+                    keys.push(SigningKey {
+                        id: id.to_string(),
+                        secret: secret.as_bytes().to_vec(),
+                    })
+                }
+                _ => {
+                    panic!("Invalid aic_signing_keys. Expected comma separated key_id:secret pairs")
+                }
+            }
+        }
+        if keys.is_empty() && !settings.aic_accept_unsigned_ids {
+            panic!("aic_signing_keys must be set when aic_accept_unsigned_ids is false");
+        }
+        AICSigner {
+            keys,
+            accept_unsigned: settings.aic_accept_unsigned_ids,
+        }
+    }
+
+    /// Signs with the newest key, or returns the bare UUID if no keys are configured.
+    pub fn issue(&self, id: Uuid, expires: OffsetDateTime) -> String {
+        let key = match self.keys.first() {
+            Some(key) => key,
+            None => return id.to_string(),
+        };
+        let mut payload = id.as_bytes().to_vec();
+        payload.extend_from_slice(&expires.unix_timestamp().to_be_bytes());
+        let signed = format!(
+            "{}.{}",
+            key.id,
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        );
+        let signature = sign(key, &signed).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            signed,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns the AIC id of a token that was signed with any configured key and hasn't
+    /// expired, or of a bare UUID while those are still accepted.
+    pub fn verify(&self, aic_id: &str, now: OffsetDateTime) -> Result<Uuid, AICTokenError> {
+        let (signed, signature) = match aic_id.rsplit_once('.') {
+            Some(parts) => parts,
+            None => {
+                let id = Uuid::parse_str(aic_id).map_err(|_| AICTokenError::Malformed)?;
+                return match self.accept_unsigned {
+                    true => Ok(id),
+                    false => Err(AICTokenError::UnsignedNotAccepted),
+                };
+            }
+        };
+        let (key_id, payload) = signed.split_once('.').ok_or(AICTokenError::Malformed)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == key_id)
+            .ok_or(AICTokenError::UnknownKey)?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| AICTokenError::Malformed)?;
+        sign(key, signed)
+            .verify_slice(&signature)
+            .map_err(|_| AICTokenError::BadSignature)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| AICTokenError::Malformed)?;
+        if payload.len() != 24 {
+            return Err(AICTokenError::Malformed);
+        }
+        let id = Uuid::from_slice(&payload[..16]).map_err(|_| AICTokenError::Malformed)?;
+        let expires = i64::from_be_bytes(payload[16..].try_into().expect("8 bytes"));
+        if expires <= now.unix_timestamp() {
+            return Err(AICTokenError::Expired);
+        }
+        Ok(id)
+    }
+}
+
+fn sign(key: &SigningKey, signed: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&key.secret).expect("HMAC accepts any key length");
+    mac.update(signed.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::empty_settings;
+    use secrecy::Secret;
+    use time::Duration;
+
+    fn signer(keys: &str, accept_unsigned: bool) -> AICSigner {
+        let mut settings = empty_settings();
+        settings.aic_signing_keys = Secret::new(keys.to_string());
+        settings.aic_accept_unsigned_ids = accept_unsigned;
+        AICSigner::from_settings(&settings)
+    }
+
+    #[test]
+    fn issued_tokens_verify_until_they_expire() {
+        let signer = signer("k1:secret", false);
+        let id = Uuid::new_v4();
+        let now = OffsetDateTime::now_utc();
+        let token = signer.issue(id, now + Duration::days(1));
+        assert!(token.starts_with("k1."));
+        assert_eq!(signer.verify(&token, now), Ok(id));
+        assert_eq!(
+            signer.verify(&token, now + Duration::days(2)),
+            Err(AICTokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn tokens_signed_with_an_older_key_verify_after_rotation() {
+        let id = Uuid::new_v4();
+        let now = OffsetDateTime::now_utc();
+        let token = signer("k1:old", false).issue(id, now + Duration::days(1));
+        let rotated = signer("k2:new, k1:old", false);
+        assert_eq!(rotated.verify(&token, now), Ok(id));
+        assert!(rotated
+            .issue(id, now + Duration::days(1))
+            .starts_with("k2."));
+        assert_eq!(
+            signer("k2:new", false).verify(&token, now),
+            Err(AICTokenError::UnknownKey)
+        );
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let now = OffsetDateTime::now_utc();
+        let token = signer("k1:secret", false).issue(Uuid::new_v4(), now + Duration::days(1));
+        let forged = signer("k1:guessed", false).issue(Uuid::new_v4(), now + Duration::days(1));
+        let signer = signer("k1:secret", false);
+        assert_eq!(
+            signer.verify(&forged, now),
+            Err(AICTokenError::BadSignature)
+        );
+        // Swap in another payload, e.g. to extend the expiry
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged_parts: Vec<&str> = forged.split('.').collect();
+        let swapped = format!("{}.{}.{}", parts[0], forged_parts[1], parts[2]);
+        assert_eq!(
+            signer.verify(&swapped, now),
+            Err(AICTokenError::BadSignature)
+        );
+        for malformed in ["k1.abc", "k1.abc.!!!", "not-a-uuid", ""] {
+            assert_eq!(
+                signer.verify(malformed, now),
+                Err(AICTokenError::Malformed),
+                "{}",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn bare_uuids_are_only_accepted_when_configured() {
+        let id = Uuid::new_v4();
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(
+            signer("k1:secret", true).verify(&id.to_string(), now),
+            Ok(id)
+        );
+        assert_eq!(
+            signer("k1:secret", false).verify(&id.to_string(), now),
+            Err(AICTokenError::UnsignedNotAccepted)
+        );
+    }
+
+    #[test]
+    fn without_keys_bare_uuids_are_issued() {
+        let id = Uuid::new_v4();
+        let signer = signer("", true);
+        assert_eq!(signer.issue(id, OffsetDateTime::now_utc()), id.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "aic_signing_keys must be set")]
+    fn requiring_signed_ids_without_keys_panics() {
+        signer("", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid aic_signing_keys")]
+    fn invalid_keys_panic() {
+        signer("k1", true);
+    }
+}
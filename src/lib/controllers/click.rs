@@ -0,0 +1,212 @@
+use actix_web::{
+    cookie::{time as cookie_time, Cookie, SameSite},
+    http::header,
+    web, HttpResponse,
+};
+use reqwest::Url;
+use serde::Deserialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    controllers::{
+        aic::{validate_cj_id, validate_click_metadata},
+        aic_token::AICSigner,
+        errors::ApiError,
+    },
+    error_and_incr, info, info_and_incr,
+    models::{
+        aic::{AICModel, AIC},
+        aic_clicks::ClickMetadata,
+    },
+    settings::Settings,
+    telemetry::{LogKey, StatsD},
+};
+
+#[derive(Deserialize)]
+pub struct ClickQuery {
+    pub cjevent: Option<String>,
+    pub target: String,
+    pub publisher_id: Option<String>,
+    pub ad_id: Option<String>,
+    pub sid: Option<String>,
+    pub utm_source: Option<String>,
+    pub utm_medium: Option<String>,
+    pub utm_campaign: Option<String>,
+    pub utm_term: Option<String>,
+    pub utm_content: Option<String>,
+}
+
+impl ClickQuery {
+    /// The landing page is the target the visitor is redirected to.
+    fn click_metadata(&self) -> ClickMetadata {
+        ClickMetadata {
+            publisher_id: self.publisher_id.clone(),
+            ad_id: self.ad_id.clone(),
+            sid: self.sid.clone(),
+            landing_page: Some(self.target.clone()),
+            utm_source: self.utm_source.clone(),
+            utm_medium: self.utm_medium.clone(),
+            utm_campaign: self.utm_campaign.clone(),
+            utm_term: self.utm_term.clone(),
+            utm_content: self.utm_content.clone(),
+        }
+    }
+}
+
+/// Whether `target` is one of the comma separated URLs in `allowlist`, or starts with an
+/// entry that ends in `/`.
+pub fn is_allowed_target(target: &Url, allowlist: &str) -> bool {
+    if !matches!(target.scheme(), "http" | "https") {
+        return false;
+    }
+    allowlist
+        .split(',')
+        .map(str::trim)
+        .filter_map(|entry| Some((entry.ends_with('/'), Url::parse(entry).ok()?)))
+        .any(|(is_prefix, entry)| {
+            // Parsing adds a trailing / to bare origins, so the configured entry decides
+            // whether it's a prefix
+            target.as_str() == entry.as_str()
+                || (is_prefix && target.as_str().starts_with(entry.as_str()))
+        })
+}
+
+fn aic_cookie(aic: &AIC, signer: &AICSigner, settings: &Settings) -> Cookie<'static> {
+    let mut cookie = Cookie::build(
+        settings.aic_cookie_name.clone(),
+        signer.issue(aic.id, aic.expires),
+    )
+    .path("/")
+    // Top level navigations from CJ's redirect must send the cookie
+    .same_site(SameSite::Lax)
+    .secure(settings.environment != "local")
+    .max_age(cookie_time::Duration::seconds(
+        (aic.expires - OffsetDateTime::now_utc()).whole_seconds(),
+    ))
+    .finish();
+    if let Ok(expires) =
+        cookie_time::OffsetDateTime::from_unix_timestamp(aic.expires.unix_timestamp())
+    {
+        cookie.set_expires(expires);
+    }
+    if let Some(domain) = &settings.aic_cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+/// Landing page for CJ's affiliate links. Creates an AIC for `cjevent`, sets it as a
+/// cookie and redirects to `target`. The visitor is redirected even if the AIC can't be
+/// created, as they shouldn't be left on an error page.
+///
+/// The AIC's flow_id is a placeholder until the site sends the real one with `PUT /aic`.
+pub async fn landing(
+    query: web::Query<ClickQuery>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    signer: web::Data<AICSigner>,
+    statsd: web::Data<StatsD>,
+) -> Result<HttpResponse, ApiError> {
+    info!(LogKey::RequestClick, target = query.target.as_str(),);
+    let target = match Url::parse(&query.target) {
+        Ok(target) if is_allowed_target(&target, &settings.click_redirect_allowlist) => target,
+        _ => {
+            error_and_incr!(
+                statsd,
+                LogKey::ClickTargetNotAllowed,
+                target = query.target.as_str(),
+                "Click target is not allowed."
+            );
+            return Err(ApiError::InvalidInput(
+                "target is not an allowed redirect URL".to_string(),
+            ));
+        }
+    };
+    let mut response = HttpResponse::Found();
+    response.insert_header((header::LOCATION, target.as_str()));
+
+    let cjevent = query.cjevent.as_deref().unwrap_or_default();
+    if let Err(e) = validate_cj_id(cjevent) {
+        error_and_incr!(
+            statsd,
+            LogKey::ClickInvalidCjevent,
+            error = e,
+            "Click has no valid cjevent, redirecting without an AIC."
+        );
+        return Ok(response.finish());
+    }
+    let aic = AICModel {
+        db_pool: pool.as_ref(),
+    };
+    let mut click_metadata = query.click_metadata();
+    if let Err(e) = validate_click_metadata(&click_metadata) {
+        error_and_incr!(
+            statsd,
+            LogKey::ClickInvalidMetadata,
+            error = e,
+            "Click metadata is invalid, creating the AIC without it."
+        );
+        click_metadata = ClickMetadata::default();
+    }
+    let flow_id = format!("click-{}", Uuid::new_v4());
+    match aic
+        .create(cjevent, &flow_id, &click_metadata, &settings)
+        .await
+    {
+        Ok(created) => {
+            info_and_incr!(
+                statsd,
+                LogKey::ClickAicCreate,
+                aic_id = created.id.to_string().as_str(),
+                expires = created.expires.to_string().as_str(),
+                "AIC created from click."
+            );
+            response.cookie(aic_cookie(&created, &signer, &settings));
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::ClickAicCreateFailed,
+                error = e,
+                "AIC could not be created from click, redirecting without it."
+            );
+        }
+    }
+    Ok(response.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowlisted_targets_are_allowed() {
+        let allowlist = "https://www.mozilla.org/products/vpn/, https://vpn.mozilla.org";
+        for allowed in [
+            "https://www.mozilla.org/products/vpn/",
+            "https://www.mozilla.org/products/vpn/pricing/?utm_source=cj",
+            "https://vpn.mozilla.org/",
+        ] {
+            let url = Url::parse(allowed).unwrap();
+            assert!(is_allowed_target(&url, allowlist), "{}", allowed);
+        }
+        for not_allowed in [
+            "https://www.mozilla.org/",
+            "https://www.mozilla.org/products/vpnx",
+            "https://vpn.mozilla.org/download/",
+            "https://vpn.mozilla.org.evil.com/",
+            "https://www.mozilla.org@evil.com/products/vpn/",
+            "http://www.mozilla.org/products/vpn/",
+            "javascript:alert(1)",
+        ] {
+            let url = Url::parse(not_allowed).unwrap();
+            assert!(!is_allowed_target(&url, allowlist), "{}", not_allowed);
+        }
+        assert!(!is_allowed_target(
+            &Url::parse("https://vpn.mozilla.org/").unwrap(),
+            ""
+        ));
+    }
+}
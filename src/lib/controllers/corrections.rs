@@ -4,6 +4,7 @@ use sqlx::PgPool;
 use time::{Date, OffsetDateTime};
 
 use crate::{
+    cj::programs::{CJProgram, CJPrograms, DEFAULT_PROGRAM},
     error_and_incr, info_and_incr,
     models::{
         refunds::{Refund, RefundModel},
@@ -13,8 +14,11 @@ use crate::{
     telemetry::{LogKey, StatsD},
 };
 
+/// Lists the refunds of `program`'s subscriptions, under the program's SUBID.
 async fn build_body_from_results(
     settings: &Settings,
+    programs: &CJPrograms,
+    program: &CJProgram,
     results: Vec<Refund>,
     db_pool: &PgPool,
     statsd: &StatsD,
@@ -22,7 +26,7 @@ async fn build_body_from_results(
     let mut body = format!(
         r#"&CID={}
 &SUBID={}"#,
-        settings.cj_sftp_user, settings.cj_subid
+        settings.cj_sftp_user, program.subid
     );
     let subscriptions = SubscriptionModel { db_pool };
     for refund in results {
@@ -52,6 +56,9 @@ async fn build_body_from_results(
                 continue;
             }
         };
+        if programs.for_subscription(&sub).name != program.name {
+            continue;
+        }
         body.push_str(&format!(
             r#"
 RETRN,,{}"#,
@@ -72,10 +79,22 @@ async fn get_results_for_day(db_pool: &PgPool, day: Date) -> Vec<Refund> {
 
 #[derive(Deserialize)]
 pub struct CorrectionsByDayPath {
+    #[serde(default = "default_program")]
+    program: String,
     #[serde(with = "date_parser")]
     day: Date,
 }
 
+#[derive(Deserialize)]
+pub struct CorrectionsTodayPath {
+    #[serde(default = "default_program")]
+    program: String,
+}
+
+fn default_program() -> String {
+    DEFAULT_PROGRAM.to_string()
+}
+
 mod date_parser {
     use serde::{self, Deserialize, Deserializer};
     use time::Date;
@@ -92,34 +111,61 @@ mod date_parser {
 pub async fn by_day(
     path: web::Path<CorrectionsByDayPath>,
     pool: web::Data<PgPool>,
+    programs: web::Data<CJPrograms>,
     settings: web::Data<Settings>,
     statsd: web::Data<StatsD>,
 ) -> HttpResponse {
+    let program = match programs.by_name(&path.program) {
+        Some(program) => program,
+        None => return HttpResponse::NotFound().finish(),
+    };
     info_and_incr!(
         statsd.as_ref(),
         LogKey::CorrectionsReportByDayAccessed,
         day = path.day.to_string().as_str(),
+        program = program.name.as_str(),
         "Corrections report accessed by day"
     );
     let results = get_results_for_day(pool.as_ref(), path.day).await;
-    let body =
-        build_body_from_results(settings.as_ref(), results, pool.as_ref(), statsd.as_ref()).await;
+    let body = build_body_from_results(
+        settings.as_ref(),
+        programs.as_ref(),
+        program,
+        results,
+        pool.as_ref(),
+        statsd.as_ref(),
+    )
+    .await;
     HttpResponse::Ok().body(body)
 }
 
 pub async fn today(
+    path: web::Path<CorrectionsTodayPath>,
     pool: web::Data<PgPool>,
+    programs: web::Data<CJPrograms>,
     settings: web::Data<Settings>,
     statsd: web::Data<StatsD>,
 ) -> HttpResponse {
+    let program = match programs.by_name(&path.program) {
+        Some(program) => program,
+        None => return HttpResponse::NotFound().finish(),
+    };
     info_and_incr!(
         statsd.as_ref(),
         LogKey::CorrectionsReportTodayAccessed,
+        program = program.name.as_str(),
         "Corrections report accessed for today"
     );
     let today = OffsetDateTime::now_utc().date();
     let results = get_results_for_day(pool.as_ref(), today).await;
-    let body =
-        build_body_from_results(settings.as_ref(), results, pool.as_ref(), statsd.as_ref()).await;
+    let body = build_body_from_results(
+        settings.as_ref(),
+        programs.as_ref(),
+        program,
+        results,
+        pool.as_ref(),
+        statsd.as_ref(),
+    )
+    .await;
     HttpResponse::Ok().body(body)
 }
@@ -0,0 +1,52 @@
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    controllers::errors::ApiError,
+    error_and_incr, info,
+    jobs::erasures::erase,
+    models::erasures::ErasureIdentifier,
+    telemetry::{LogKey, StatsD},
+};
+
+/// Exactly one of the fields identifies whose data is erased.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ErasureRequest {
+    pub fxa_uid: Option<String>,
+    pub flow_id: Option<String>,
+    pub aic_id: Option<Uuid>,
+}
+
+/// Erases a person's data and returns the audit log entry. Behind basic auth, the user
+/// name is recorded as who requested it.
+pub async fn delete(
+    credentials: BasicAuth,
+    data: web::Json<ErasureRequest>,
+    pool: web::Data<PgPool>,
+    statsd: web::Data<StatsD>,
+) -> Result<HttpResponse, ApiError> {
+    let data = data.into_inner();
+    let identifier = ErasureIdentifier::from_parts(data.fxa_uid, data.flow_id, data.aic_id)
+        .map_err(|e| {
+            error_and_incr!(
+                statsd,
+                LogKey::RequestPersonalDataDeleteInvalid,
+                error = e,
+                "Personal data delete request is invalid."
+            );
+            ApiError::InvalidInput(e.to_string())
+        })?;
+    let requested_by = format!("api:{}", credentials.user_id());
+    info!(
+        LogKey::RequestPersonalDataDelete,
+        identifier_type = identifier.identifier_type(),
+        requested_by = requested_by.as_str(),
+    );
+    match erase(&identifier, &requested_by, pool.as_ref(), &statsd).await {
+        Ok(erasure) => Ok(HttpResponse::Ok().json(erasure)),
+        Err(e) => Err(ApiError::from_db_error(&e, "Erasure conflicted, try again")),
+    }
+}
@@ -0,0 +1,163 @@
+use actix_web::{
+    error::{JsonPayloadError, ResponseError},
+    http::StatusCode,
+    web::{JsonConfig, QueryConfig},
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Largest JSON body the API accepts. AIC requests are short strings and a landing page URL.
+pub const MAX_JSON_BODY_BYTES: usize = 4096;
+
+const UNIQUE_VIOLATION: &str = "23505";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+}
+
+/// An error returned to API callers as a JSON `ErrorResponse`.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ApiError {
+    #[error("{0}")]
+    InvalidInput(String),
+
+    #[error("{0}")]
+    InvalidJson(String),
+
+    #[error("Request body is larger than {MAX_JSON_BODY_BYTES} bytes")]
+    PayloadTooLarge,
+
+    #[error("{0}")]
+    InvalidToken(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("Idempotency-Key was already used with a different request body")]
+    IdempotencyKeyReused,
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("Database is unavailable, try again later")]
+    Unavailable,
+
+    #[error("Internal server error")]
+    Internal,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidInput(_) => "invalid_input",
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::InvalidToken(_) => "invalid_token",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unavailable => "unavailable",
+            ApiError::Internal => "internal",
+        }
+    }
+
+    /// Maps a database error to what the caller can act on. A unique violation is reported
+    /// as `conflict` with the given message.
+    pub fn from_db_error(e: &sqlx::Error, conflict: &str) -> Self {
+        match e {
+            sqlx::Error::Database(db_error)
+                if db_error.code().as_deref() == Some(UNIQUE_VIOLATION) =>
+            {
+                ApiError::Conflict(conflict.to_string())
+            }
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => {
+                ApiError::Unavailable
+            }
+            _ => ApiError::Internal,
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidInput(_) | ApiError::InvalidJson(_) => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::InvalidToken(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        })
+    }
+}
+
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            ApiError::PayloadTooLarge.into()
+        }
+        e => ApiError::InvalidJson(e.to_string()).into(),
+    }
+}
+
+/// Limits JSON bodies and returns extractor failures as JSON errors.
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default()
+        .limit(MAX_JSON_BODY_BYTES)
+        .error_handler(json_error_handler)
+}
+
+/// Returns query strings that can't be parsed as JSON errors.
+pub fn query_config() -> QueryConfig {
+    QueryConfig::default().error_handler(|err, _req| ApiError::InvalidInput(err.to_string()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn db_errors_are_mapped_to_what_the_caller_can_act_on() {
+        assert_eq!(
+            ApiError::from_db_error(&sqlx::Error::PoolTimedOut, "taken"),
+            ApiError::Unavailable
+        );
+        assert_eq!(
+            ApiError::from_db_error(&sqlx::Error::PoolClosed, "taken"),
+            ApiError::Unavailable
+        );
+        assert_eq!(
+            ApiError::from_db_error(&sqlx::Error::RowNotFound, "taken"),
+            ApiError::Internal
+        );
+    }
+
+    #[test]
+    fn status_codes() {
+        for (error, status) in [
+            (ApiError::InvalidInput("x".to_string()), 400),
+            (ApiError::InvalidJson("x".to_string()), 400),
+            (ApiError::PayloadTooLarge, 413),
+            (ApiError::InvalidToken("x".to_string()), 403),
+            (ApiError::NotFound("x".to_string()), 404),
+            (ApiError::IdempotencyKeyReused, 422),
+            (ApiError::Conflict("x".to_string()), 409),
+            (ApiError::Unavailable, 503),
+            (ApiError::Internal, 500),
+        ] {
+            assert_eq!(error.status_code().as_u16(), status, "{:?}", error);
+        }
+    }
+}
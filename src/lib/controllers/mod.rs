@@ -1,3 +1,7 @@
 pub mod aic;
+pub mod aic_token;
+pub mod click;
 pub mod corrections;
 pub mod custodial;
+pub mod erasures;
+pub mod errors;
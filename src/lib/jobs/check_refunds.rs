@@ -1,10 +1,18 @@
+use serde::Deserialize;
 use sqlx::{Pool, Postgres};
+use thiserror::Error;
+use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::{
-    bigquery::client::{BQClient, BQError, ResultSet},
+    bigquery::client::{BQClient, BQError, FieldType, QueryParameter},
     error_and_incr, info_and_incr,
+    jobs::{
+        ingest_failures::record_ingest_failure,
+        watermark::{advance_watermark, fetch_since, hold_back_watermark},
+    },
     models::{
+        erasures::ErasureModel,
         refunds::{PartialRefund, Refund, RefundModel},
         status_history::{Status, UpdateStatus},
         subscriptions::SubscriptionModel,
@@ -12,38 +20,70 @@ use crate::{
     telemetry::{LogKey, StatsD},
 };
 
-fn make_refund_from_bq_row(rs: &ResultSet) -> Result<Refund, BQError> {
-    let refund = Refund::new(PartialRefund {
-        id: Uuid::new_v4(),
-        refund_id: rs.require_string_by_name("refund_id")?,
-        subscription_id: rs.require_string_by_name("subscription_id")?,
-        refund_created: rs.require_offsetdatetime_by_name("created")?,
-        refund_amount: rs.require_i32_by_name("amount")?,
-        refund_status: rs.get_string_by_name("status")?,
-        refund_reason: rs.get_string_by_name("reason")?,
-        correction_file_date: None,
-    });
-    Ok(refund)
+/// The columns `BqRefundRow` is read from, checked before any rows are processed.
+const EXPECTED_COLUMNS: &[(&str, FieldType)] = &[
+    ("refund_id", FieldType::String),
+    ("subscription_id", FieldType::String),
+    ("created", FieldType::Timestamp),
+    ("amount", FieldType::Integer),
+    ("status", FieldType::String),
+    ("reason", FieldType::String),
+];
+
+/// A row of the refunds BigQuery table (`bq_refunds_table`).
+#[derive(Debug, Deserialize)]
+pub struct BqRefundRow {
+    pub refund_id: String,
+    pub subscription_id: String,
+    #[serde(with = "time::serde::timestamp")]
+    pub created: OffsetDateTime,
+    pub amount: i32,
+    pub status: Option<String>,
+    pub reason: Option<String>,
 }
 
-pub async fn fetch_and_process_refunds(bq: &BQClient, db_pool: &Pool<Postgres>, statsd: &StatsD) {
-    let subscriptions = SubscriptionModel { db_pool };
-    let refunds = RefundModel { db_pool };
+impl From<BqRefundRow> for Refund {
+    fn from(row: BqRefundRow) -> Self {
+        Refund::new(PartialRefund {
+            id: Uuid::new_v4(),
+            refund_id: row.refund_id,
+            subscription_id: row.subscription_id,
+            refund_created: row.created,
+            refund_amount: row.amount,
+            refund_status: row.status,
+            refund_reason: row.reason,
+            correction_file_date: None,
+        })
+    }
+}
 
-    // Get all results from bigquery table that stores refunds reports
-    let query = "SELECT * FROM `cjms_bigquery.refunds_v1`;";
-    let mut rs = bq.get_bq_results(query).await;
-    rs.report_stats(statsd, &LogKey::CheckRefunds);
-    while rs.next_row() {
-        // If can't deserialize e.g. required fields are not available log and move on.
-        let r = match make_refund_from_bq_row(&rs) {
-            Ok(r) => {
+pub async fn fetch_and_process_refunds(
+    bq: &BQClient,
+    table: &str,
+    db_pool: &Pool<Postgres>,
+    statsd: &StatsD,
+) -> Result<(), BQError> {
+    // Get results from bigquery table that stores refunds reports since our last run
+    let since = fetch_since(&LogKey::CheckRefunds, db_pool, bq.watermark_overlap).await;
+    let query = format!("SELECT * FROM `{}` WHERE created >= @since;", table);
+    let mut rs = bq
+        .get_bq_results_with_params(&query, &[QueryParameter::timestamp("since", since)])
+        .await?;
+    rs.validate_schema(EXPECTED_COLUMNS)?;
+    let mut latest_refund_created = None;
+    let mut earliest_failed = None;
+    while rs.next_row().await? {
+        // If can't deserialize e.g. required fields are not available log, keep the row and move on.
+        let r = match rs.deserialize_row::<BqRefundRow>() {
+            Ok(row) => {
+                let r = Refund::from(row);
                 info_and_incr!(
                     statsd,
                     LogKey::CheckRefundsDeserializeBigQuery,
                     refund_id = r.refund_id.as_str(),
                     "Successfully deserialized refund from BigQuery row"
                 );
+                latest_refund_created = latest_refund_created.max(Some(r.refund_created));
                 r
             }
             Err(e) => {
@@ -53,134 +93,225 @@ pub async fn fetch_and_process_refunds(bq: &BQClient, db_pool: &Pool<Postgres>,
                     error = e,
                     "Failed to make refund for BigQuery result row. Continuing ...",
                 );
+                record_ingest_failure(
+                    &LogKey::CheckRefunds,
+                    &rs.row_to_json()?,
+                    &e,
+                    db_pool,
+                    statsd,
+                )
+                .await;
                 continue;
             }
         };
-        // Do we have the related subscription in the subscriptions table
-        let have_sub = subscriptions
-            .fetch_one_by_subscription_id(&r.subscription_id)
-            .await
-            .is_ok();
-        if !have_sub {
+        let t = r.refund_created;
+        match process_refund(r, db_pool, statsd).await {
+            Ok(()) => {}
+            // The subscription may never be stored, e.g. when it was purged, so rather than
+            // holding the watermark back, keep the row for `ingest_failures retry`
+            Err(e @ ProcessRefundError::SubscriptionMissing(_)) => {
+                record_ingest_failure(
+                    &LogKey::CheckRefunds,
+                    &rs.row_to_json()?,
+                    &e,
+                    db_pool,
+                    statsd,
+                )
+                .await;
+            }
+            Err(ProcessRefundError::Database(_)) => {
+                // Fetched again next run, as the watermark won't pass it
+                earliest_failed =
+                    Some(earliest_failed.map_or(t, |failed: OffsetDateTime| failed.min(t)));
+            }
+        }
+    }
+    rs.report_stats(statsd, &LogKey::CheckRefunds);
+    advance_watermark(
+        &LogKey::CheckRefunds,
+        db_pool,
+        statsd,
+        hold_back_watermark(latest_refund_created, earliest_failed),
+    )
+    .await;
+    Ok(())
+}
+
+/// Why a refund could not be processed. A database error is expected to pass on the next
+/// run. A missing subscription may never be stored, so the refund is kept as an ingest
+/// failure to be retried once it is.
+#[derive(Error, Debug)]
+pub enum ProcessRefundError {
+    #[error("Subscription {0} of the refund is missing from the database")]
+    SubscriptionMissing(String),
+
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Creates the refund, or updates it if its data has changed, provided we have its
+/// subscription. Problems are logged and returned. A refund that's already saved, or was
+/// erased, is not a problem.
+pub async fn process_refund(
+    r: Refund,
+    db_pool: &Pool<Postgres>,
+    statsd: &StatsD,
+) -> Result<(), ProcessRefundError> {
+    let subscriptions = SubscriptionModel { db_pool };
+    let refunds = RefundModel { db_pool };
+    let erasures = ErasureModel { db_pool };
+    // The subscription of an erased refund is pseudonymized, so it would never be found
+    match erasures.is_erased(&[&r.subscription_id]).await {
+        Ok(true) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckRefundsRefundErased,
+                refund_id = r.refund_id.as_str(),
+                "Refund belongs to someone whose data was erased. Skipping..."
+            );
+            return Ok(());
+        }
+        Ok(false) => {}
+        Err(e) => {
             error_and_incr!(
                 statsd,
-                LogKey::CheckRefundsSubscriptionMissingFromDatabase,
-                subscription_id = r.subscription_id.as_str(),
+                LogKey::CheckRefundsErasureLookupFailed,
+                error = e,
                 refund_id = r.refund_id.as_str(),
-                "Subscription related to refund missing from database",
+                "Could not check whether the refund was erased."
             );
-            continue;
+            return Err(e.into());
         }
-        // Do we already have it in the refunds table
-        match refunds.fetch_one_by_refund_id(&r.refund_id).await {
-            Ok(mut refund) => {
-                // Only update if data is different
-                if refund.subscription_id == r.subscription_id
-                    && refund.refund_created.unix_timestamp() == r.refund_created.unix_timestamp()
-                    && refund.refund_amount == r.refund_amount
-                    && refund.refund_status == r.refund_status
-                    && refund.refund_reason == r.refund_reason
-                {
+    }
+    // Do we have the related subscription in the subscriptions table
+    let have_sub = subscriptions
+        .fetch_one_by_subscription_id(&r.subscription_id)
+        .await
+        .is_ok();
+    if !have_sub {
+        error_and_incr!(
+            statsd,
+            LogKey::CheckRefundsSubscriptionMissingFromDatabase,
+            subscription_id = r.subscription_id.as_str(),
+            refund_id = r.refund_id.as_str(),
+            "Subscription related to refund missing from database",
+        );
+        return Err(ProcessRefundError::SubscriptionMissing(r.subscription_id));
+    }
+    // Do we already have it in the refunds table
+    match refunds.fetch_one_by_refund_id(&r.refund_id).await {
+        Ok(mut refund) => {
+            // Only update if data is different
+            if refund.subscription_id == r.subscription_id
+                && refund.refund_created.unix_timestamp() == r.refund_created.unix_timestamp()
+                && refund.refund_amount == r.refund_amount
+                && refund.refund_status == r.refund_status
+                && refund.refund_reason == r.refund_reason
+            {
+                info_and_incr!(
+                    statsd,
+                    LogKey::CheckRefundsRefundDataUnchanged,
+                    refund_id = refund.refund_id.as_str(),
+                    "Data for refund is unchanged. Continuing..."
+                );
+                return Ok(());
+            }
+
+            info_and_incr!(
+                statsd,
+                LogKey::CheckRefundsRefundDataChanged,
+                refund_id = refund.refund_id.as_str(),
+                "Data for refund is changed. Updating..."
+            );
+            refund.subscription_id = r.subscription_id;
+            refund.refund_created = r.refund_created;
+            refund.refund_amount = r.refund_amount;
+            refund.refund_status = r.refund_status;
+            refund.refund_reason = r.refund_reason;
+            refund.update_status(Status::NotReported);
+            refund.correction_file_date = None;
+            match refunds.update_refund(&refund).await {
+                Ok(_) => {
                     info_and_incr!(
                         statsd,
-                        LogKey::CheckRefundsRefundDataUnchanged,
+                        LogKey::CheckRefundsRefundUpdate,
                         refund_id = refund.refund_id.as_str(),
-                        "Data for refund is unchanged. Continuing..."
+                        "Refund updated. Continuing..."
                     );
-                    continue;
+                    Ok(())
+                }
+                Err(e) => {
+                    error_and_incr!(
+                        statsd,
+                        LogKey::CheckRefundsRefundUpdateFailed,
+                        error = e,
+                        refund_id = r.refund_id.as_str(),
+                        "Error updating refund. Continuing..."
+                    );
+                    Err(e.into())
                 }
-
-                info_and_incr!(
-                    statsd,
-                    LogKey::CheckRefundsRefundDataChanged,
-                    refund_id = refund.refund_id.as_str(),
-                    "Data for refund is changed. Updating..."
-                );
-                refund.subscription_id = r.subscription_id;
-                refund.refund_created = r.refund_created;
-                refund.refund_amount = r.refund_amount;
-                refund.refund_status = r.refund_status;
-                refund.refund_reason = r.refund_reason;
-                refund.update_status(Status::NotReported);
-                refund.correction_file_date = None;
-                match refunds.update_refund(&refund).await {
-                    Ok(_) => {
-                        info_and_incr!(
-                            statsd,
-                            LogKey::CheckRefundsRefundUpdate,
-                            refund_id = refund.refund_id.as_str(),
-                            "Refund updated. Continuing..."
-                        );
-                    }
-                    Err(e) => {
-                        error_and_incr!(
-                            statsd,
-                            LogKey::CheckRefundsRefundUpdateFailed,
-                            error = e,
-                            refund_id = r.refund_id.as_str(),
-                            "Error updating refund. Continuing..."
-                        );
-                    }
-                };
             }
-            Err(e) => {
-                match e {
-                    sqlx::Error::RowNotFound => {
-                        match refunds.create_from_refund(&r).await {
-                            Ok(r) => {
-                                info_and_incr!(
-                                    statsd,
-                                    LogKey::CheckRefundsRefundCreate,
-                                    refund_id = r.refund_id.as_str(),
-                                    "Successfully created refund"
-                                );
-                            }
-                            Err(e) => match e {
-                                sqlx::Error::Database(e) => {
-                                    // 23505 is the code for unique constraints e.g. duplicate flow id issues
-                                    if e.code() == Some(std::borrow::Cow::Borrowed("23505")) {
-                                        error_and_incr!(
-                                            statsd,
-                                            LogKey::CheckRefundsRefundCreateDuplicateKeyViolation,
-                                            error = e,
-                                            refund_id = &r.refund_id.as_str(),
-                                            "Duplicate key violation"
-                                        );
-                                    } else {
-                                        error_and_incr!(
-                                            statsd,
-                                            LogKey::CheckRefundsRefundCreateDatabaseError,
-                                            error = e,
-                                            refund_id = &r.refund_id.as_str(),
-                                            "Database error while creating refund. Continuing..."
-                                        );
-                                    }
-                                    continue;
-                                }
-                                _ => {
+        }
+        Err(e) => {
+            match e {
+                sqlx::Error::RowNotFound => {
+                    match refunds.create_from_refund(&r).await {
+                        Ok(r) => {
+                            info_and_incr!(
+                                statsd,
+                                LogKey::CheckRefundsRefundCreate,
+                                refund_id = r.refund_id.as_str(),
+                                "Successfully created refund"
+                            );
+                            Ok(())
+                        }
+                        Err(e) => match e {
+                            sqlx::Error::Database(db_error) => {
+                                // 23505 is the code for unique constraints e.g. duplicate flow id issues
+                                if db_error.code() == Some(std::borrow::Cow::Borrowed("23505")) {
+                                    error_and_incr!(
+                                        statsd,
+                                        LogKey::CheckRefundsRefundCreateDuplicateKeyViolation,
+                                        error = db_error,
+                                        refund_id = &r.refund_id.as_str(),
+                                        "Duplicate key violation"
+                                    );
+                                    Ok(())
+                                } else {
                                     error_and_incr!(
                                         statsd,
-                                        LogKey::CheckRefundsRefundCreateFailed,
-                                        error = e,
+                                        LogKey::CheckRefundsRefundCreateDatabaseError,
+                                        error = db_error,
                                         refund_id = &r.refund_id.as_str(),
-                                        "Unexpected error while creating refund. Continuing..."
+                                        "Database error while creating refund. Continuing..."
                                     );
-                                    continue;
+                                    Err(sqlx::Error::Database(db_error).into())
                                 }
-                            },
-                        };
-                    }
-                    _ => {
-                        error_and_incr!(
-                            statsd,
-                            LogKey::CheckRefundsRefundFetchFailed,
-                            error = e,
-                            refund_id = r.refund_id.as_str(),
-                            "Error while trying to retrieve refund. Continuing..."
-                        );
+                            }
+                            _ => {
+                                error_and_incr!(
+                                    statsd,
+                                    LogKey::CheckRefundsRefundCreateFailed,
+                                    error = e,
+                                    refund_id = &r.refund_id.as_str(),
+                                    "Unexpected error while creating refund. Continuing..."
+                                );
+                                Err(e.into())
+                            }
+                        },
                     }
                 }
+                _ => {
+                    error_and_incr!(
+                        statsd,
+                        LogKey::CheckRefundsRefundFetchFailed,
+                        error = e,
+                        refund_id = r.refund_id.as_str(),
+                        "Error while trying to retrieve refund. Continuing..."
+                    );
+                    Err(e.into())
+                }
             }
-        };
+        }
     }
 }
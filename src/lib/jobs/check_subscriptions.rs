@@ -1,61 +1,117 @@
+use serde::Deserialize;
 use sqlx::{Pool, Postgres};
-
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
-    bigquery::client::{BQClient, BQError, ResultSet},
+    bigquery::client::{BQClient, BQError, FieldType, QueryParameter},
     error_and_incr, info_and_incr,
+    jobs::{
+        ingest_failures::record_ingest_failure,
+        watermark::{advance_watermark, fetch_since, hold_back_watermark},
+    },
     models::{
-        aic::AICModel,
+        aic::{AICModel, AIC},
+        aic_clicks::{AICClick, AICClickModel, AttributionModel},
+        erasures::ErasureModel,
+        status_history::{Status, UpdateStatus},
         subscriptions::{PartialSubscription, Subscription, SubscriptionModel},
     },
     telemetry::{LogKey, StatsD},
 };
 
-fn make_subscription_from_bq_row(rs: &ResultSet) -> Result<Subscription, BQError> {
-    let sub = Subscription::new(PartialSubscription {
-        id: Uuid::new_v4(),
-        flow_id: rs.require_string_by_name("flow_id")?,
-        subscription_id: rs.require_string_by_name("subscription_id")?,
-        report_timestamp: rs.require_offsetdatetime_by_name("report_timestamp")?,
-        subscription_created: rs.require_offsetdatetime_by_name("subscription_created")?,
-        fxa_uid: rs.require_string_by_name("fxa_uid")?,
-        quantity: rs.require_i32_by_name("quantity")?,
-        plan_id: rs.require_string_by_name("plan_id")?,
-        plan_currency: rs.require_string_by_name("plan_currency")?,
-        plan_amount: rs.require_i32_by_name("plan_amount")?,
-        country: rs.get_string_by_name("country")?,
-        coupons: rs
-            .get_string_by_name("promotion_codes")?
-            .map(|x| x.trim().to_string()),
-        aic_id: None,
-        aic_expires: None,
-        cj_event_value: None,
-    });
-    Ok(sub)
+/// The columns `BqSubscriptionRow` is read from, checked before any rows are processed.
+const EXPECTED_COLUMNS: &[(&str, FieldType)] = &[
+    ("flow_id", FieldType::String),
+    ("subscription_id", FieldType::String),
+    ("report_timestamp", FieldType::Timestamp),
+    ("subscription_created", FieldType::Timestamp),
+    ("fxa_uid", FieldType::String),
+    ("quantity", FieldType::Integer),
+    ("plan_id", FieldType::String),
+    ("plan_currency", FieldType::String),
+    ("plan_amount", FieldType::Integer),
+    ("country", FieldType::String),
+    ("promotion_codes", FieldType::String),
+];
+
+/// A row of the subscriptions BigQuery table (`bq_subscriptions_table`).
+#[derive(Debug, Deserialize)]
+pub struct BqSubscriptionRow {
+    pub flow_id: String,
+    pub subscription_id: String,
+    #[serde(with = "time::serde::timestamp")]
+    pub report_timestamp: OffsetDateTime,
+    #[serde(with = "time::serde::timestamp")]
+    pub subscription_created: OffsetDateTime,
+    pub fxa_uid: String,
+    pub quantity: i32,
+    pub plan_id: String,
+    // Not in EXPECTED_COLUMNS, as older tables don't have it
+    pub product_id: Option<String>,
+    pub plan_currency: String,
+    pub plan_amount: i32,
+    pub country: Option<String>,
+    pub promotion_codes: Option<String>,
+}
+
+impl From<BqSubscriptionRow> for Subscription {
+    fn from(row: BqSubscriptionRow) -> Self {
+        Subscription::new(PartialSubscription {
+            id: Uuid::new_v4(),
+            flow_id: row.flow_id,
+            subscription_id: row.subscription_id,
+            report_timestamp: row.report_timestamp,
+            subscription_created: row.subscription_created,
+            fxa_uid: row.fxa_uid,
+            quantity: row.quantity,
+            plan_id: row.plan_id,
+            product_id: row.product_id,
+            plan_currency: row.plan_currency,
+            plan_amount: row.plan_amount,
+            country: row.country,
+            coupons: row.promotion_codes.map(|x| x.trim().to_string()),
+            aic_id: None,
+            aic_expires: None,
+            cj_event_value: None,
+            unattributed_reason: None,
+            click_metadata: None,
+        })
+    }
 }
 
 pub async fn fetch_and_process_new_subscriptions(
     bq: &BQClient,
+    table: &str,
+    attribution: &AttributionModel,
     db_pool: &Pool<Postgres>,
     statsd: &StatsD,
-) {
-    let subscriptions = SubscriptionModel { db_pool };
-    let aics = AICModel { db_pool };
-    // Get all results from bigquery table that stores new subscription reports
-    let query = "SELECT * FROM `cjms_bigquery.subscriptions_v1`;";
-    let mut rs = bq.get_bq_results(query).await;
-    rs.report_stats(statsd, &LogKey::CheckSubscriptions);
-    while rs.next_row() {
-        // If can't deserialize e.g. required fields are not available log and move on.
-        let mut sub = match make_subscription_from_bq_row(&rs) {
-            Ok(sub) => {
+) -> Result<(), BQError> {
+    // Get results from bigquery table that stores new subscription reports since our last run
+    let since = fetch_since(&LogKey::CheckSubscriptions, db_pool, bq.watermark_overlap).await;
+    let query = format!(
+        "SELECT * FROM `{}` WHERE report_timestamp >= @since;",
+        table
+    );
+    let mut rs = bq
+        .get_bq_results_with_params(&query, &[QueryParameter::timestamp("since", since)])
+        .await?;
+    rs.validate_schema(EXPECTED_COLUMNS)?;
+    let mut latest_report_timestamp = None;
+    let mut earliest_failed = None;
+    while rs.next_row().await? {
+        // If can't deserialize e.g. required fields are not available log, keep the row and move on.
+        let sub = match rs.deserialize_row::<BqSubscriptionRow>() {
+            Ok(row) => {
+                let sub = Subscription::from(row);
                 info_and_incr!(
                     statsd,
                     LogKey::CheckSubscriptionsDeserializeBigQuery,
                     subscription_id = sub.id.to_string().as_str(),
                     "Successfully deserialized subscription from BigQuery row",
                 );
+                latest_report_timestamp = latest_report_timestamp.max(Some(sub.report_timestamp));
                 sub
             }
             Err(e) => {
@@ -65,110 +121,403 @@ pub async fn fetch_and_process_new_subscriptions(
                     error = e,
                     "Failed to make subscription for BigQuery result row. Continuing...",
                 );
+                record_ingest_failure(
+                    &LogKey::CheckSubscriptions,
+                    &rs.row_to_json()?,
+                    &e,
+                    db_pool,
+                    statsd,
+                )
+                .await;
                 continue;
             }
         };
-        let (aic, aic_found_in_archive) = match aics.fetch_one_by_flow_id(&sub.flow_id).await {
-            Ok(aic) => {
-                info_and_incr!(
-                    statsd,
-                    LogKey::CheckSubscriptionsAicFetch,
-                    aic_id = aic.id.to_string().as_str(),
-                    "Successfully fetched aic",
-                );
-                (aic, false)
-            }
-            Err(_) => match aics.fetch_one_by_flow_id_from_archive(&sub.flow_id).await {
-                Ok(aic) => {
-                    info_and_incr!(
-                        statsd,
-                        LogKey::CheckSubscriptionsAicFetchFromArchive,
-                        aic_id = aic.id.to_string().as_str(),
-                        "AIC was fetched from archive table.",
-                    );
-                    (aic, true)
+        let t = sub.report_timestamp;
+        if process_subscription(sub, attribution, db_pool, statsd)
+            .await
+            .is_err()
+        {
+            // Fetched again next run, as the watermark won't pass it
+            earliest_failed =
+                Some(earliest_failed.map_or(t, |failed: OffsetDateTime| failed.min(t)));
+        }
+    }
+    rs.report_stats(statsd, &LogKey::CheckSubscriptions);
+    advance_watermark(
+        &LogKey::CheckSubscriptions,
+        db_pool,
+        statsd,
+        hold_back_watermark(latest_report_timestamp, earliest_failed),
+    )
+    .await;
+    Ok(())
+}
+
+/// Why a subscription is pending attribution, and later organic.
+const AIC_NOT_FOUND: &str = "No AIC found for flow_id in aic or aic_archive";
+
+/// Why a subscription whose AIC has no click chosen by the attribution model is organic.
+const NO_QUALIFYING_CLICK: &str = "No click of the AIC qualifies under the attribution model";
+
+/// Why a subscription could not be processed. These are expected to pass on a later try.
+#[derive(Error, Debug)]
+pub enum ProcessSubscriptionError {
+    #[error("Could not check whether the subscription was erased: {0}")]
+    ErasureLookup(sqlx::Error),
+
+    #[error("Could not look up the AIC of the subscription")]
+    AicLookup,
+
+    #[error("Could not attach the AIC to the subscription")]
+    Attach,
+
+    #[error("Could not create the subscription: {0}")]
+    Create(#[from] sqlx::Error),
+}
+
+/// Attaches the click of the subscription's AIC chosen by `attribution`, archiving the
+/// AIC, and saves the subscription. A subscription whose AIC can't be found is saved as
+/// pending attribution, to be resolved by `resolve_pending_attributions`. Problems are
+/// logged and returned. A subscription that's already saved, or was erased, is not a
+/// problem.
+pub async fn process_subscription(
+    mut sub: Subscription,
+    attribution: &AttributionModel,
+    db_pool: &Pool<Postgres>,
+    statsd: &StatsD,
+) -> Result<(), ProcessSubscriptionError> {
+    let subscriptions = SubscriptionModel { db_pool };
+    let aics = AICModel { db_pool };
+    let erasures = ErasureModel { db_pool };
+    // Rows of someone whose data was erased are fetched again whenever the watermark is
+    // behind them, so they're skipped rather than saved with what was erased
+    match erasures
+        .is_erased(&[&sub.fxa_uid, &sub.flow_id, &sub.subscription_id])
+        .await
+    {
+        Ok(true) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsSubscriptionErased,
+                sub_id = sub.id.to_string().as_str(),
+                "Subscription belongs to someone whose data was erased. Skipping..."
+            );
+            return Ok(());
+        }
+        Ok(false) => {}
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsErasureLookupFailed,
+                error = e,
+                sub_id = sub.id.to_string().as_str(),
+                "Could not check whether the subscription was erased."
+            );
+            return Err(ProcessSubscriptionError::ErasureLookup(e));
+        }
+    }
+    match fetch_aic(&aics, &sub.flow_id, statsd).await {
+        AicLookup::Found { aic, in_archive } => {
+            match attach_aic(&mut sub, &aic, in_archive, attribution, &aics, statsd).await {
+                Attach::Attached => {}
+                Attach::NoQualifyingClick => {
+                    sub.set_raw_status_history(None);
+                    sub.update_status(Status::Organic);
                 }
-                Err(e) => {
+                Attach::Failed => return Err(ProcessSubscriptionError::Attach),
+            }
+        }
+        AicLookup::NotFound => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAttributionPending,
+                sub_id = sub.id.to_string().as_str(),
+                "No AIC found for subscription yet. Saving as pending attribution.",
+            );
+            sub.unattributed_reason = Some(AIC_NOT_FOUND.to_string());
+            // The subscription was never ready to report, so start its history afresh
+            sub.set_raw_status_history(None);
+            sub.update_status(Status::PendingAttribution);
+        }
+        AicLookup::Failed => return Err(ProcessSubscriptionError::AicLookup),
+    };
+    // Save the new subscription entry
+    match subscriptions.create_from_sub(&sub).await {
+        Ok(sub) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsSubscriptionCreate,
+                sub_id = sub.id.to_string().as_str(),
+                "Successfully created subscription"
+            );
+            Ok(())
+        }
+        Err(e) => match e {
+            sqlx::Error::Database(db_error) => {
+                // 23505 is the code for unique constraints e.g. duplicate flow id issues
+                if db_error.code() == Some(std::borrow::Cow::Borrowed("23505")) {
                     error_and_incr!(
                         statsd,
-                        LogKey::CheckSubscriptionsAicFetchFailed,
-                        error = e,
-                        "Error getting aic for subscription. Continuing...",
+                        LogKey::CheckSubscriptionsSubscriptionCreateDuplicateKeyViolation,
+                        error = db_error,
+                        sub_from_bigquery_not_saved = serde_json::to_string(&sub)
+                            .unwrap_or(format!("{}", &sub.id))
+                            .as_str(),
+                        "Duplicate key violation"
                     );
-                    continue;
-                }
-            },
-        };
-        sub.aic_id = Some(aic.id);
-        sub.cj_event_value = Some(aic.cj_event_value.clone());
-        sub.aic_expires = Some(aic.expires);
-
-        // Archive the AIC
-        if !aic_found_in_archive {
-            match aics.archive_aic(&aic).await {
-                Ok(_) => {
-                    info_and_incr!(
-                        statsd,
-                        LogKey::CheckSubscriptionsAicArchive,
-                        aic_id = aic.id.to_string().as_str(),
-                        "Successfully archived aic",
-                    );
-                }
-                Err(e) => {
+                    Ok(())
+                } else {
                     error_and_incr!(
                         statsd,
-                        LogKey::CheckSubscriptionsAicArchiveFailed,
-                        error = e,
-                        aic_id = aic.id.to_string().as_str(),
-                        "Failed to archive aic entry. Continuing...",
+                        LogKey::CheckSubscriptionsSubscriptionCreateDatabaseError,
+                        error = db_error,
+                        "Database error while creating subscription. Continuing..."
                     );
-                    continue;
+                    Err(sqlx::Error::Database(db_error).into())
                 }
-            };
+            }
+            _ => {
+                error_and_incr!(
+                    statsd,
+                    LogKey::CheckSubscriptionsSubscriptionCreateFailed,
+                    error = e,
+                    "Unexpected error while creating subscription. Continuing...",
+                );
+                Err(e.into())
+            }
+        },
+    }
+}
+
+enum AicLookup {
+    Found { aic: AIC, in_archive: bool },
+    NotFound,
+    Failed,
+}
+
+async fn fetch_aic(aics: &AICModel<'_>, flow_id: &str, statsd: &StatsD) -> AicLookup {
+    match aics.fetch_one_by_flow_id(flow_id).await {
+        Ok(aic) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAicFetch,
+                aic_id = aic.id.to_string().as_str(),
+                "Successfully fetched aic",
+            );
+            AicLookup::Found {
+                aic,
+                in_archive: false,
+            }
         }
-        // Save the new subscription entry
-        match subscriptions.create_from_sub(&sub).await {
-            Ok(sub) => {
+        Err(_) => match aics.fetch_one_by_flow_id_from_archive(flow_id).await {
+            Ok(aic) => {
                 info_and_incr!(
                     statsd,
-                    LogKey::CheckSubscriptionsSubscriptionCreate,
-                    sub_id = sub.id.to_string().as_str(),
-                    "Successfully created subscription"
+                    LogKey::CheckSubscriptionsAicFetchFromArchive,
+                    aic_id = aic.id.to_string().as_str(),
+                    "AIC was fetched from archive table.",
                 );
+                AicLookup::Found {
+                    aic,
+                    in_archive: true,
+                }
             }
-            Err(e) => match e {
-                sqlx::Error::Database(e) => {
-                    // 23505 is the code for unique constraints e.g. duplicate flow id issues
-                    if e.code() == Some(std::borrow::Cow::Borrowed("23505")) {
-                        error_and_incr!(
-                            statsd,
-                            LogKey::CheckSubscriptionsSubscriptionCreateDuplicateKeyViolation,
-                            error = e,
-                            sub_from_bigquery_not_saved = serde_json::to_string(&sub)
-                                .unwrap_or(format!("{}", &sub.id))
-                                .as_str(),
-                            "Duplicate key violation"
-                        );
-                    } else {
-                        error_and_incr!(
-                            statsd,
-                            LogKey::CheckSubscriptionsSubscriptionCreateDatabaseError,
-                            error = e,
-                            "Database error while creating subscription. Continuing..."
-                        );
-                    }
-                    continue;
+            Err(sqlx::Error::RowNotFound) => AicLookup::NotFound,
+            Err(e) => {
+                error_and_incr!(
+                    statsd,
+                    LogKey::CheckSubscriptionsAicFetchFailed,
+                    error = e,
+                    "Error getting aic for subscription. Continuing...",
+                );
+                AicLookup::Failed
+            }
+        },
+    }
+}
+
+enum Attach {
+    Attached,
+    NoQualifyingClick,
+    Failed,
+}
+
+/// Copies the details and metadata of the AIC's click chosen by `attribution` onto the
+/// subscription, and archives the AIC. If no click qualifies, the subscription is left unattributed.
+async fn attach_aic(
+    sub: &mut Subscription,
+    aic: &AIC,
+    in_archive: bool,
+    attribution: &AttributionModel,
+    aics: &AICModel<'_>,
+    statsd: &StatsD,
+) -> Attach {
+    let clicks = AICClickModel {
+        db_pool: aics.db_pool,
+    };
+    let mut clicks = match clicks.fetch_all_by_aic_id(&aic.id).await {
+        Ok(clicks) => clicks,
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAicClicksFetchFailed,
+                error = e,
+                aic_id = aic.id.to_string().as_str(),
+                "Failed to fetch clicks of aic. Continuing...",
+            );
+            return Attach::Failed;
+        }
+    };
+    // AICs created before clicks were recorded only have their last click
+    if clicks.is_empty() {
+        clicks.push(AICClick::from(aic));
+    }
+    let attached = match attribution.choose(&clicks, sub.subscription_created) {
+        Some(click) => {
+            sub.aic_id = Some(aic.id);
+            sub.cj_event_value = Some(click.cj_event_value.clone());
+            sub.aic_expires = Some(click.expires);
+            sub.click_metadata = click.click_metadata.clone();
+            sub.unattributed_reason = None;
+            Attach::Attached
+        }
+        None => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAttributionNoQualifyingClick,
+                sub_id = sub.id.to_string().as_str(),
+                aic_id = aic.id.to_string().as_str(),
+                "No click of the aic qualifies under the attribution model.",
+            );
+            sub.aic_id = None;
+            sub.cj_event_value = None;
+            sub.aic_expires = None;
+            sub.click_metadata = None;
+            sub.unattributed_reason = Some(NO_QUALIFYING_CLICK.to_string());
+            Attach::NoQualifyingClick
+        }
+    };
+    if in_archive {
+        return attached;
+    }
+    match aics.archive_aic(aic).await {
+        Ok(_) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAicArchive,
+                aic_id = aic.id.to_string().as_str(),
+                "Successfully archived aic",
+            );
+            attached
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAicArchiveFailed,
+                error = e,
+                aic_id = aic.id.to_string().as_str(),
+                "Failed to archive aic entry. Continuing...",
+            );
+            Attach::Failed
+        }
+    }
+}
+
+/// Looks again for the AIC of each subscription pending attribution, in case it was
+/// written after the subscription was fetched. Subscriptions whose AIC is found become
+/// NotReported, to be reported to CJ. Those still without one once they have been pending
+/// for `window` are finalized as Organic. Ends by recording the attribution rate.
+pub async fn resolve_pending_attributions(
+    window: Duration,
+    attribution: &AttributionModel,
+    db_pool: &Pool<Postgres>,
+    statsd: &StatsD,
+) {
+    let subscriptions = SubscriptionModel { db_pool };
+    let aics = AICModel { db_pool };
+    // Intentional panic. Cannot continue if we can't retrieve subs.
+    let pending = subscriptions
+        .fetch_all_by_status(Status::PendingAttribution)
+        .await
+        .expect("Could not retrieve subscriptions pending attribution from DB.");
+    let now = OffsetDateTime::now_utc();
+    for mut sub in pending {
+        let next_status = match fetch_aic(&aics, &sub.flow_id, statsd).await {
+            AicLookup::Found { aic, in_archive } => {
+                match attach_aic(&mut sub, &aic, in_archive, attribution, &aics, statsd).await {
+                    Attach::Attached => Status::NotReported,
+                    Attach::NoQualifyingClick => Status::Organic,
+                    Attach::Failed => continue,
                 }
-                _ => {
-                    error_and_incr!(
-                        statsd,
-                        LogKey::CheckSubscriptionsSubscriptionCreateFailed,
-                        error = e,
-                        "Unexpected error while creating subscription. Continuing...",
-                    );
+            }
+            AicLookup::NotFound => {
+                // fetch_all_by_status only returns subscriptions with a status_t
+                let pending_since = sub.get_status_t().unwrap_or(now);
+                if now - pending_since < window {
                     continue;
                 }
-            },
+                Status::Organic
+            }
+            AicLookup::Failed => continue,
         };
+        sub.update_status(next_status.clone());
+        match subscriptions.update_attribution(&sub).await {
+            Ok(_) => {
+                let key = match next_status {
+                    Status::Organic => LogKey::CheckSubscriptionsAttributionOrganic,
+                    _ => LogKey::CheckSubscriptionsAttributionResolved,
+                };
+                info_and_incr!(
+                    statsd,
+                    key,
+                    sub_id = sub.id.to_string().as_str(),
+                    status = next_status.to_string().as_str(),
+                    "Finalized attribution for subscription"
+                );
+            }
+            Err(e) => {
+                error_and_incr!(
+                    statsd,
+                    LogKey::CheckSubscriptionsAttributionUpdateFailed,
+                    error = e,
+                    sub_id = sub.id.to_string().as_str(),
+                    "Could not update attribution for subscription. Continuing..."
+                );
+            }
+        }
+    }
+    report_attribution_rate(&subscriptions, statsd).await;
+}
+
+async fn report_attribution_rate(subscriptions: &SubscriptionModel<'_>, statsd: &StatsD) {
+    let counts = match subscriptions.get_attribution_counts().await {
+        Ok(counts) => counts,
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::CheckSubscriptionsAttributionCountsFailed,
+                error = e,
+                "Could not count subscriptions by attribution"
+            );
+            return;
+        }
+    };
+    statsd.gauge(
+        &LogKey::CheckSubscriptionsAttributionNAttributed,
+        counts.attributed as usize,
+    );
+    statsd.gauge(
+        &LogKey::CheckSubscriptionsAttributionNOrganic,
+        counts.organic as usize,
+    );
+    statsd.gauge(
+        &LogKey::CheckSubscriptionsAttributionNPending,
+        counts.pending as usize,
+    );
+    if let Some(rate) = counts.attribution_rate() {
+        statsd.gauge(
+            &LogKey::CheckSubscriptionsAttributionRate,
+            rate.round() as usize,
+        );
     }
 }
@@ -1,38 +1,108 @@
 use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
 
 use crate::{
     error_and_incr, info_and_incr,
-    models::aic::AICModel,
+    models::{aic::AICModel, idempotency_keys::IdempotencyKeyModel, watermarks::WatermarkModel},
+    settings::Settings,
     telemetry::{LogKey, StatsD},
 };
 
-pub async fn archive_expired_aics(db_pool: &PgPool, statsd: &StatsD) {
+/// Moves expired AICs into the archive `aic_archive_chunk_size` at a time.
+///
+/// The (expires, id) of the last AIC archived is kept in memory to start each chunk from,
+/// so that chunks don't rescan the rows just deleted, and its expires is stored as the
+/// job's watermark so that an interrupted run resumes from there. The watermark is
+/// removed once every AIC that had expired when the run started is archived.
+pub async fn archive_expired_aics(settings: &Settings, db_pool: &PgPool, statsd: &StatsD) {
     let aic_model = AICModel { db_pool };
-    // Intentional expect. Cannot continue without
-    let expired = aic_model
-        .fetch_expired()
-        .await
-        .expect("Could not get expired AICs");
-    for aic in expired {
-        match aic_model.archive_aic(&aic).await {
-            Ok(_) => {
-                info_and_incr!(
-                    statsd,
-                    LogKey::CleanupAicArchive,
-                    aic_id = &aic.id.to_string().as_str(),
-                    "Successfully archived aic"
-                );
-            }
+    let watermarks = WatermarkModel { db_pool };
+    let job = LogKey::CleanupAicArchive.to_string();
+    let expired_before = OffsetDateTime::now_utc();
+    let mut cursor = match watermarks.fetch_one_by_job(&job).await {
+        Ok(w) => (w.watermark, Uuid::nil()),
+        Err(sqlx::Error::RowNotFound) => (OffsetDateTime::unix_epoch(), Uuid::nil()),
+        // Intentional panic. Without a cursor we can't tell where to resume.
+        Err(e) => panic!("Could not fetch archive cursor. {:?}", e),
+    };
+    let mut n_chunks: usize = 0;
+    let mut n_archived: u64 = 0;
+    loop {
+        let chunk = match aic_model
+            .archive_expired_chunk(expired_before, cursor, settings.aic_archive_chunk_size)
+            .await
+        {
+            Ok(chunk) => chunk,
             Err(e) => {
                 error_and_incr!(
                     statsd,
                     LogKey::CleanupAicArchiveFailed,
                     error = e,
-                    aic_id = &aic.id.to_string().as_str(),
-                    "Could not archive aic. Continuing..."
+                    n_chunks = n_chunks,
+                    "Could not archive chunk of aics. Will resume from here next run..."
                 );
-                continue;
+                return;
             }
+        };
+        let last = match chunk.last {
+            Some(last) => last,
+            None => break,
+        };
+        let n_moved = chunk.n_archived + chunk.n_dropped;
+        n_chunks += 1;
+        n_archived += chunk.n_archived;
+        cursor = last;
+        info_and_incr!(
+            statsd,
+            LogKey::CleanupAicArchive,
+            chunk = n_chunks,
+            n_archived = chunk.n_archived,
+            n_dropped = chunk.n_dropped,
+            "Archived chunk of aics"
+        );
+        statsd.gauge(&LogKey::CleanupAicArchiveChunkSize, n_moved as usize);
+        if let Err(e) = watermarks.set(&job, cursor.0).await {
+            error_and_incr!(
+                statsd,
+                LogKey::CleanupAicArchiveCursorFailed,
+                error = e,
+                "Could not store archive cursor. Continuing..."
+            );
+        }
+        if n_moved < settings.aic_archive_chunk_size as u64 {
+            break;
+        }
+    }
+    statsd.gauge(&LogKey::CleanupAicArchiveNArchived, n_archived as usize);
+    if let Err(e) = watermarks.delete(&job).await {
+        error_and_incr!(
+            statsd,
+            LogKey::CleanupAicArchiveCursorFailed,
+            error = e,
+            "Could not remove archive cursor"
+        );
+    }
+}
+
+pub async fn delete_expired_idempotency_keys(db_pool: &PgPool, statsd: &StatsD) {
+    let keys = IdempotencyKeyModel { db_pool };
+    match keys.delete_expired().await {
+        Ok(n_deleted) => {
+            info_and_incr!(
+                statsd,
+                LogKey::CleanupIdempotencyKeysDelete,
+                n_deleted = n_deleted,
+                "Deleted expired idempotency keys"
+            );
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::CleanupIdempotencyKeysDeleteFailed,
+                error = e,
+                "Could not delete expired idempotency keys"
+            );
         }
     }
 }
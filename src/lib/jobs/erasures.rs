@@ -0,0 +1,123 @@
+use clap::{ArgGroup, Parser, Subcommand};
+use sqlx::PgPool;
+use std::io::Write;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    error_and_incr, info_and_incr,
+    models::erasures::{Erasure, ErasureIdentifier, ErasureIdentifierError, ErasureModel},
+    telemetry::{LogKey, StatsD},
+};
+
+#[derive(Error, Debug)]
+pub enum ErasureError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Could not write output: {0}")]
+    Output(#[from] std::io::Error),
+
+    #[error("Invalid identifier: {0}")]
+    InvalidIdentifier(#[from] ErasureIdentifierError),
+}
+
+/// Erase a person's data for a privacy request, and review past erasures.
+#[derive(Parser, Debug)]
+pub struct ErasureArgs {
+    #[command(subcommand)]
+    pub command: ErasureCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ErasureCommand {
+    /// Erase the data of whoever exactly one of --fxa-uid, --flow-id or --aic-id
+    /// identifies, and print the audit log entry as JSON.
+    #[command(group(ArgGroup::new("identifier").required(true).args(["fxa_uid", "flow_id", "aic_id"])))]
+    Erase {
+        #[arg(long)]
+        fxa_uid: Option<String>,
+        #[arg(long)]
+        flow_id: Option<String>,
+        #[arg(long)]
+        aic_id: Option<Uuid>,
+        /// Who asked for the erasure, e.g. the privacy request's ticket.
+        #[arg(long)]
+        requested_by: String,
+    },
+    /// Print the audit log as lines of JSON, oldest first.
+    List,
+}
+
+impl ErasureArgs {
+    pub async fn run(
+        &self,
+        db_pool: &PgPool,
+        statsd: &StatsD,
+        out: &mut impl Write,
+    ) -> Result<(), ErasureError> {
+        match &self.command {
+            ErasureCommand::Erase {
+                fxa_uid,
+                flow_id,
+                aic_id,
+                requested_by,
+            } => {
+                let identifier =
+                    ErasureIdentifier::from_parts(fxa_uid.clone(), flow_id.clone(), *aic_id)?;
+                let erasure = erase(&identifier, requested_by, db_pool, statsd).await?;
+                writeln!(out, "{}", erasure_to_json(&erasure))?;
+            }
+            ErasureCommand::List => {
+                let erasures = ErasureModel { db_pool };
+                for erasure in erasures.fetch_all().await? {
+                    writeln!(out, "{}", erasure_to_json(&erasure))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn erasure_to_json(erasure: &Erasure) -> String {
+    serde_json::to_string(erasure).expect("Erasure is serializable")
+}
+
+/// Erases the data of whoever `identifier` identifies and records it in the audit log.
+/// Used by both the `DELETE /personal-data` endpoint and the erasures command.
+pub async fn erase(
+    identifier: &ErasureIdentifier,
+    requested_by: &str,
+    db_pool: &PgPool,
+    statsd: &StatsD,
+) -> Result<Erasure, sqlx::Error> {
+    let erasures = ErasureModel { db_pool };
+    match erasures.erase(identifier, requested_by).await {
+        Ok(erasure) => {
+            info_and_incr!(
+                statsd,
+                LogKey::ErasuresErase,
+                id = erasure.id.to_string().as_str(),
+                identifier_type = erasure.identifier_type.as_str(),
+                requested_by = erasure.requested_by.as_str(),
+                aic_deleted = erasure.aic_deleted,
+                aic_archive_deleted = erasure.aic_archive_deleted,
+                subscriptions_pseudonymized = erasure.subscriptions_pseudonymized,
+                refunds_pseudonymized = erasure.refunds_pseudonymized,
+                "Erased personal data"
+            );
+            Ok(erasure)
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::ErasuresEraseFailed,
+                error = e,
+                identifier_type = identifier.identifier_type(),
+                requested_by = requested_by,
+                "Could not erase personal data"
+            );
+            Err(e)
+        }
+    }
+}
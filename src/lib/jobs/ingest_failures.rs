@@ -0,0 +1,262 @@
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::io::Write;
+use std::str::FromStr;
+use thiserror::Error;
+use time::Format;
+use uuid::Uuid;
+
+use crate::{
+    error_and_incr, info_and_incr,
+    jobs::{
+        check_refunds::{process_refund, BqRefundRow, ProcessRefundError},
+        check_subscriptions::{process_subscription, BqSubscriptionRow, ProcessSubscriptionError},
+    },
+    models::{
+        aic_clicks::AttributionModel,
+        ingest_failures::{IngestFailure, IngestFailureModel},
+        refunds::Refund,
+        subscriptions::Subscription,
+    },
+    telemetry::{LogKey, StatsD},
+};
+
+#[derive(Error, Debug)]
+pub enum IngestFailureError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Could not write output: {0}")]
+    Output(#[from] std::io::Error),
+
+    #[error("No ingest failure with id {0}")]
+    NotFound(Uuid),
+
+    #[error("Ingest failures can't be retried for job {0}")]
+    UnknownJob(String),
+
+    #[error("Row still can't be deserialized: {0}")]
+    Deserialize(#[from] serde_json::Error),
+
+    #[error("Subscription could not be processed: {0}")]
+    ProcessSubscription(#[from] ProcessSubscriptionError),
+
+    #[error("Refund could not be processed: {0}")]
+    ProcessRefund(#[from] ProcessRefundError),
+
+    #[error("{0} ingest failures could not be retried")]
+    RetriesFailed(usize),
+}
+
+/// Inspect and act on the BigQuery rows that check_subscriptions and check_refunds
+/// could not deserialize, or refunds whose subscription wasn't stored.
+#[derive(Parser, Debug)]
+pub struct IngestFailureArgs {
+    #[command(subcommand)]
+    pub command: IngestFailureCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IngestFailureCommand {
+    /// Print each stored row as a line of JSON, oldest first.
+    List {
+        /// Only list the rows of this job, e.g. check-subscriptions.
+        #[arg(long)]
+        job: Option<String>,
+    },
+    /// Deserialize and process stored rows again, e.g. after a fix has been deployed.
+    /// Rows that succeed are removed.
+    Retry {
+        #[arg(required_unless_present = "all")]
+        ids: Vec<Uuid>,
+        /// Retry every stored row.
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+    /// Remove stored rows without processing them.
+    Dismiss {
+        #[arg(required = true)]
+        ids: Vec<Uuid>,
+    },
+}
+
+impl IngestFailureArgs {
+    pub async fn run(
+        &self,
+        attribution: &AttributionModel,
+        db_pool: &PgPool,
+        statsd: &StatsD,
+        out: &mut impl Write,
+    ) -> Result<(), IngestFailureError> {
+        let failures = IngestFailureModel { db_pool };
+        match &self.command {
+            IngestFailureCommand::List { job } => {
+                let all = match job {
+                    Some(job) => failures.fetch_all_by_job(job).await?,
+                    None => failures.fetch_all().await?,
+                };
+                for failure in all {
+                    writeln!(out, "{}", failure_to_json(&failure))?;
+                }
+            }
+            IngestFailureCommand::Retry { ids, all } => {
+                let to_retry = match all {
+                    true => failures.fetch_all().await?,
+                    false => {
+                        let mut to_retry = Vec::new();
+                        for id in ids {
+                            to_retry.push(fetch_one(&failures, id).await?);
+                        }
+                        to_retry
+                    }
+                };
+                let mut n_failed = 0;
+                for failure in to_retry {
+                    if retry_ingest_failure(&failure, attribution, db_pool, statsd)
+                        .await
+                        .is_err()
+                    {
+                        n_failed += 1;
+                    }
+                }
+                if n_failed > 0 {
+                    return Err(IngestFailureError::RetriesFailed(n_failed));
+                }
+            }
+            IngestFailureCommand::Dismiss { ids } => {
+                for id in ids {
+                    let failure = fetch_one(&failures, id).await?;
+                    failures.delete(&failure.id).await?;
+                    info_and_incr!(
+                        statsd,
+                        LogKey::IngestFailuresDismiss,
+                        id = failure.id.to_string().as_str(),
+                        job = failure.job.as_str(),
+                        "Dismissed ingest failure"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn fetch_one(
+    failures: &IngestFailureModel<'_>,
+    id: &Uuid,
+) -> Result<IngestFailure, IngestFailureError> {
+    failures.fetch_one_by_id(id).await.map_err(|e| match e {
+        sqlx::Error::RowNotFound => IngestFailureError::NotFound(*id),
+        e => IngestFailureError::Database(e),
+    })
+}
+
+fn failure_to_json(failure: &IngestFailure) -> Value {
+    json!({
+        "id": failure.id,
+        "job": failure.job,
+        "error": failure.error,
+        "first_seen": failure.first_seen.format(Format::Rfc3339),
+        "last_seen": failure.last_seen.format(Format::Rfc3339),
+        "attempts": failure.attempts,
+        "last_attempt_at": failure.last_attempt_at.map(|t| t.format(Format::Rfc3339)),
+        "raw_row": failure.raw_row,
+    })
+}
+
+/// Keeps a row that `job` could not deserialize or process, and won't fetch again. Seeing
+/// the same row again only updates the error and when it was last seen.
+pub async fn record_ingest_failure(
+    job: &LogKey,
+    raw_row: &Value,
+    error: &impl std::fmt::Display,
+    db_pool: &PgPool,
+    statsd: &StatsD,
+) {
+    let failures = IngestFailureModel { db_pool };
+    match failures
+        .record(&job.to_string(), raw_row, &error.to_string())
+        .await
+    {
+        Ok(failure) => {
+            info_and_incr!(
+                statsd,
+                &job.add_suffix("ingest-failure-record"),
+                id = failure.id.to_string().as_str(),
+                "Recorded row that could not be ingested"
+            );
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                &job.add_suffix("ingest-failure-record-failed"),
+                error = e,
+                "Could not record row that could not be ingested. Continuing..."
+            );
+        }
+    }
+}
+
+/// Deserializes the stored row again and processes it as its job would have. Only a row
+/// that's processed is removed. If it still fails, the stored error and attempts are
+/// updated, as the watermark has moved past the row and this is the only copy left.
+pub async fn retry_ingest_failure(
+    failure: &IngestFailure,
+    attribution: &AttributionModel,
+    db_pool: &PgPool,
+    statsd: &StatsD,
+) -> Result<(), IngestFailureError> {
+    let failures = IngestFailureModel { db_pool };
+    let result = match LogKey::from_str(&failure.job) {
+        Ok(LogKey::CheckSubscriptions) => {
+            match serde_json::from_value::<BqSubscriptionRow>(failure.raw_row.clone()) {
+                Ok(row) => {
+                    process_subscription(Subscription::from(row), attribution, db_pool, statsd)
+                        .await
+                        .map_err(IngestFailureError::from)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        Ok(LogKey::CheckRefunds) => {
+            match serde_json::from_value::<BqRefundRow>(failure.raw_row.clone()) {
+                Ok(row) => process_refund(Refund::from(row), db_pool, statsd)
+                    .await
+                    .map_err(IngestFailureError::from),
+                Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(IngestFailureError::UnknownJob(failure.job.clone())),
+    };
+    match result {
+        Ok(()) => {
+            failures.delete(&failure.id).await?;
+            info_and_incr!(
+                statsd,
+                LogKey::IngestFailuresRetry,
+                id = failure.id.to_string().as_str(),
+                job = failure.job.as_str(),
+                "Retried ingest failure"
+            );
+            Ok(())
+        }
+        Err(e) => {
+            // Like a row that fails to deserialize when fetched, keep serde's error as it is
+            let error = match &e {
+                IngestFailureError::Deserialize(e) => e.to_string(),
+                e => e.to_string(),
+            };
+            failures.record_attempt(&failure.id, &error).await?;
+            error_and_incr!(
+                statsd,
+                LogKey::IngestFailuresRetryFailed,
+                error = e,
+                id = failure.id.to_string().as_str(),
+                job = failure.job.as_str(),
+                "Could not retry ingest failure"
+            );
+            Err(e)
+        }
+    }
+}
@@ -2,5 +2,9 @@ pub mod batch_refunds;
 pub mod check_refunds;
 pub mod check_subscriptions;
 pub mod cleanup;
+pub mod erasures;
+pub mod ingest_failures;
+pub mod purge;
 pub mod report_subscriptions;
 pub mod verify_reports;
+pub mod watermark;
@@ -0,0 +1,123 @@
+use sqlx::PgPool;
+use std::future::Future;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    error_and_incr, info_and_incr,
+    models::{
+        aic::AICModel, cj_s2s_log::CJS2SLogModel, refunds::RefundModel, status_history::Status,
+        subscriptions::SubscriptionModel,
+    },
+    settings::Settings,
+    telemetry::{LogKey, StatsD},
+};
+
+/// Subscriptions and refunds in these statuses won't be reported or change again.
+/// ReportFailed isn't one of them: it can still be retried when asked to, so it's kept
+/// until it's reported or given up on.
+pub const FINAL_STATUSES: [Status; 3] =
+    [Status::CJReceived, Status::WillNotReport, Status::Organic];
+
+/// Deletes archived AICs, and their clicks, `aic_archive_retention_days` after they expired.
+pub async fn purge_aic_archive(settings: &Settings, db_pool: &PgPool, statsd: &StatsD) {
+    let days = match settings.aic_archive_retention_days {
+        Some(days) => days,
+        None => return,
+    };
+    let aics = AICModel { db_pool };
+    let expired_before = OffsetDateTime::now_utc() - Duration::days(days);
+    let result = in_batches(settings.purge_batch_size, || {
+        aics.delete_archived_expired_before(expired_before, settings.purge_batch_size)
+    })
+    .await;
+    record_purge(statsd, LogKey::PurgeAicArchive, result);
+}
+
+/// Deletes logged CJ S2S requests `cj_s2s_log_retention_days` after they were sent.
+pub async fn purge_cj_s2s_log(settings: &Settings, db_pool: &PgPool, statsd: &StatsD) {
+    let days = match settings.cj_s2s_log_retention_days {
+        Some(days) => days,
+        None => return,
+    };
+    let log = CJS2SLogModel { db_pool };
+    let sent_before = OffsetDateTime::now_utc() - Duration::days(days);
+    let result = in_batches(settings.purge_batch_size, || {
+        log.delete_sent_before(sent_before, settings.purge_batch_size)
+    })
+    .await;
+    record_purge(statsd, LogKey::PurgeCjS2sLog, result);
+}
+
+/// Pseudonymizes subscriptions `subscription_retention_days` after they reached a final
+/// status. They're kept, so that counts and the corrections report don't change.
+pub async fn purge_subscriptions(settings: &Settings, db_pool: &PgPool, statsd: &StatsD) {
+    let days = match settings.subscription_retention_days {
+        Some(days) => days,
+        None => return,
+    };
+    let subscriptions = SubscriptionModel { db_pool };
+    let status_before = OffsetDateTime::now_utc() - Duration::days(days);
+    let result = in_batches(settings.purge_batch_size, || {
+        subscriptions.pseudonymize_by_status_before(
+            &FINAL_STATUSES,
+            status_before,
+            settings.purge_batch_size,
+        )
+    })
+    .await;
+    record_purge(statsd, LogKey::PurgeSubscriptions, result);
+}
+
+/// Deletes refunds `refund_retention_days` after they reached a final status.
+pub async fn purge_refunds(settings: &Settings, db_pool: &PgPool, statsd: &StatsD) {
+    let days = match settings.refund_retention_days {
+        Some(days) => days,
+        None => return,
+    };
+    let refunds = RefundModel { db_pool };
+    let status_before = OffsetDateTime::now_utc() - Duration::days(days);
+    let result = in_batches(settings.purge_batch_size, || {
+        refunds.delete_by_status_before(&FINAL_STATUSES, status_before, settings.purge_batch_size)
+    })
+    .await;
+    record_purge(statsd, LogKey::PurgeRefunds, result);
+}
+
+/// Runs `purge_batch` until it purges less than a full batch, so that each transaction
+/// stays small. Returns how many rows were purged in total.
+async fn in_batches<F, Fut>(batch_size: i64, mut purge_batch: F) -> Result<u64, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, sqlx::Error>>,
+{
+    let mut total = 0;
+    loop {
+        let n_purged = purge_batch().await?;
+        total += n_purged;
+        if n_purged == 0 || n_purged < batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+fn record_purge(statsd: &StatsD, key: LogKey, result: Result<u64, sqlx::Error>) {
+    match result {
+        Ok(n_purged) => {
+            info_and_incr!(
+                statsd,
+                key,
+                n_purged = n_purged,
+                "Purged rows past their retention period"
+            );
+            statsd.gauge(&key.add_suffix("n-purged"), n_purged as usize);
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                key.add_suffix("failed"),
+                error = e,
+                "Could not purge rows past their retention period. Continuing..."
+            );
+        }
+    }
+}
@@ -1,149 +1,431 @@
+use clap::{Parser, Subcommand};
+use futures::stream::{self, StreamExt};
+use serde_json::json;
 use sqlx::{Pool, Postgres};
+use std::io::Write;
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
 
 use crate::{
-    cj::client::CJClient,
+    cj::client::{CJClient, S2SExchange, S2SOutcome},
     error_and_incr, info_and_incr,
-    models::{status_history::Status, subscriptions::SubscriptionModel},
+    models::{
+        cj_s2s_log::{CJS2SLogEntry, CJS2SLogModel},
+        status_history::{Status, UpdateStatus},
+        subscriptions::{Subscription, SubscriptionModel},
+    },
+    settings::Settings,
     telemetry::{LogKey, StatsD},
 };
 
+#[derive(Error, Debug)]
+pub enum ReportSubscriptionsError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Could not write output: {0}")]
+    Output(#[from] std::io::Error),
+
+    #[error("Subscription {0} is not ReportFailed")]
+    NotReportFailed(Uuid),
+}
+
+/// Report subscriptions to CJ, or inspect and retry the ones that failed too many times.
+#[derive(Parser, Debug)]
+pub struct ReportSubscriptionsArgs {
+    #[command(subcommand)]
+    pub command: Option<ReportSubscriptionsCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ReportSubscriptionsCommand {
+    /// Print each ReportFailed subscription, with its attempts and last error, as a line
+    /// of JSON.
+    ListFailed,
+    /// Move ReportFailed subscriptions back to NotReported with no attempts, so that the
+    /// next run reports them, e.g. once CJ has fixed an outage.
+    Retry {
+        #[arg(required_unless_present = "all")]
+        ids: Vec<Uuid>,
+        /// Retry every ReportFailed subscription.
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+    /// Print each S2S request sent to CJ for a subscription, with what CJ answered, as a
+    /// line of JSON.
+    S2sLog { id: Uuid },
+}
+
+impl ReportSubscriptionsArgs {
+    pub async fn run(
+        &self,
+        db_pool: &Pool<Postgres>,
+        cj_client: &CJClient,
+        retry: &ReportRetryPolicy,
+        concurrency: usize,
+        statsd: &StatsD,
+        out: &mut impl Write,
+    ) -> Result<(), ReportSubscriptionsError> {
+        let subscriptions = SubscriptionModel { db_pool };
+        match &self.command {
+            None => {
+                report_subscriptions_to_cj(db_pool, cj_client, retry, concurrency, statsd).await
+            }
+            Some(ReportSubscriptionsCommand::ListFailed) => {
+                for sub in subscriptions
+                    .fetch_all_by_status(Status::ReportFailed)
+                    .await?
+                {
+                    writeln!(out, "{}", report_failed_to_json(&sub))?;
+                }
+            }
+            Some(ReportSubscriptionsCommand::S2sLog { id }) => {
+                let s2s_log = CJS2SLogModel { db_pool };
+                for entry in s2s_log.fetch_all_by_sub_id(id).await? {
+                    writeln!(out, "{}", json!(entry))?;
+                }
+            }
+            Some(ReportSubscriptionsCommand::Retry { ids, all }) => {
+                let to_retry = match all {
+                    true => {
+                        subscriptions
+                            .fetch_all_by_status(Status::ReportFailed)
+                            .await?
+                    }
+                    false => {
+                        let mut to_retry = Vec::new();
+                        for id in ids {
+                            let sub = subscriptions.fetch_one_by_id(id).await?;
+                            if sub.get_status() != Some(Status::ReportFailed) {
+                                return Err(ReportSubscriptionsError::NotReportFailed(*id));
+                            }
+                            to_retry.push(sub);
+                        }
+                        to_retry
+                    }
+                };
+                for mut sub in to_retry {
+                    sub.report_attempts = 0;
+                    sub.report_next_attempt = None;
+                    sub.update_status(Status::NotReported);
+                    subscriptions.update_report_attempt(&sub).await?;
+                    info_and_incr!(
+                        statsd,
+                        LogKey::ReportSubscriptionsRetry,
+                        sub_id = &sub.id.to_string().as_str(),
+                        "Moved ReportFailed subscription back to NotReported"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn report_failed_to_json(sub: &Subscription) -> String {
+    json!({
+        "id": sub.id,
+        "subscription_id": sub.subscription_id,
+        "plan_id": sub.plan_id,
+        "report_attempts": sub.report_attempts,
+        "report_last_error": sub.report_last_error,
+        "status_t": sub.get_status_t().map(|t| t.unix_timestamp()),
+    })
+    .to_string()
+}
+
+/// When a subscription CJ didn't accept is tried again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportRetryPolicy {
+    pub max_attempts: i32,
+    pub backoff: Duration,
+}
+
+impl ReportRetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        ReportRetryPolicy {
+            max_attempts: settings.report_max_attempts,
+            backoff: Duration::minutes(settings.report_retry_backoff_minutes),
+        }
+    }
+
+    /// When to try again after `attempts` failed attempts: the backoff, doubled for each
+    /// attempt after the first. None once there have been max_attempts.
+    pub fn next_attempt(&self, attempts: i32, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let doublings = (attempts - 1).clamp(0, 16) as u32;
+        Some(now + self.backoff * 2_i32.pow(doublings))
+    }
+}
+
+/// Reports subscriptions that are due to CJ, `concurrency` at a time. CJClient keeps the
+/// requests within cj_s2s_requests_per_second however many are in flight.
 pub async fn report_subscriptions_to_cj(
     db_pool: &Pool<Postgres>,
     cj_client: &CJClient,
+    retry: &ReportRetryPolicy,
+    concurrency: usize,
     statsd: &StatsD,
 ) {
     let subscriptions = SubscriptionModel { db_pool };
+    let s2s_log = CJS2SLogModel { db_pool };
     // Intentional panic. Cannot continue if we can't retrieve subs.
     let not_reported_subscriptions = subscriptions
-        .fetch_all_by_status(Status::NotReported)
+        .fetch_all_due_for_report(OffsetDateTime::now_utc())
         .await
         .expect("Could not retrieve subscriptions from DB.");
     statsd.gauge(
         &LogKey::ReportSubscriptionsNNotReported,
         not_reported_subscriptions.len(),
     );
+    match subscriptions.count_by_status(Status::ReportFailed).await {
+        Ok(n_report_failed) => statsd.gauge(
+            &LogKey::ReportSubscriptionsNReportFailed,
+            n_report_failed as usize,
+        ),
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::ReportSubscriptionsNReportFailedFailed,
+                error = e,
+                "Could not count ReportFailed subscriptions. Continuing..."
+            );
+        }
+    }
 
-    for sub in not_reported_subscriptions {
-        let next_status = match sub.aic_expires {
-            Some(aic_expires) => {
-                if aic_expires < sub.subscription_created {
-                    info_and_incr!(
-                        statsd,
-                        LogKey::ReportSubscriptionsAicExpiredBeforeSubscriptionCreated,
-                        sub_id = &sub.id.to_string().as_str(),
-                        "AIC expired before subscription created. Will not report."
-                    );
-                    Status::WillNotReport
-                } else {
-                    Status::Reported
-                }
+    stream::iter(not_reported_subscriptions)
+        .for_each_concurrent(concurrency.max(1), |sub| {
+            report_subscription(sub, &subscriptions, &s2s_log, cj_client, retry, statsd)
+        })
+        .await;
+}
+
+async fn report_subscription(
+    mut sub: Subscription,
+    subscriptions: &SubscriptionModel<'_>,
+    s2s_log: &CJS2SLogModel<'_>,
+    cj_client: &CJClient,
+    retry: &ReportRetryPolicy,
+    statsd: &StatsD,
+) {
+    let next_status = match sub.aic_expires {
+        Some(aic_expires) => {
+            if aic_expires < sub.subscription_created {
+                info_and_incr!(
+                    statsd,
+                    LogKey::ReportSubscriptionsAicExpiredBeforeSubscriptionCreated,
+                    sub_id = &sub.id.to_string().as_str(),
+                    "AIC expired before subscription created. Will not report."
+                );
+                Status::WillNotReport
+            } else {
+                Status::Reported
             }
-            None => {
+        }
+        None => {
+            error_and_incr!(
+                statsd,
+                LogKey::ReportSubscriptionsSubscriptionHasNoAicExpiry,
+                sub_id = &sub.id.to_string().as_str(),
+                "Subscription does not have an AIC expiry. Will not report."
+            );
+            Status::WillNotReport
+        }
+    };
+    if next_status == Status::WillNotReport {
+        match subscriptions
+            .update_sub_status(&sub.id, Status::WillNotReport)
+            .await
+        {
+            Ok(_) => {
+                info_and_incr!(
+                    statsd,
+                    LogKey::ReportSubscriptionMarkWillNotReport,
+                    sub_id = &sub.id.to_string().as_str(),
+                    "Successfully marked as WillNotReport"
+                );
+            }
+            Err(e) => {
                 error_and_incr!(
                     statsd,
-                    LogKey::ReportSubscriptionsSubscriptionHasNoAicExpiry,
+                    LogKey::ReportSubscriptionMarkWillNotReportFailed,
+                    error = e,
                     sub_id = &sub.id.to_string().as_str(),
-                    "Subscription does not have an AIC expiry. Will not report."
+                    "Could not mark subscription as WillNotReport."
                 );
-                Status::WillNotReport
             }
         };
-        if next_status == Status::WillNotReport {
-            match subscriptions
-                .update_sub_status(&sub.id, Status::WillNotReport)
-                .await
-            {
+        return;
+    }
+
+    let exchange = cj_client.report_subscription(&sub, statsd).await;
+    record_s2s_exchange(&sub, &exchange, s2s_log, statsd).await;
+    let result = match exchange.response {
+        Ok(r) => match r.outcome {
+            S2SOutcome::Accepted => Ok(()),
+            S2SOutcome::Unknown => {
+                // verify_reports finds out later whether CJ really received it
+                error_and_incr!(
+                    statsd,
+                    LogKey::ReportSubscriptionReportToCjUnknownResponse,
+                    sub_id = &sub.id.to_string().as_str(),
+                    "Reported sub to CJ; received 200 status with an unrecognized body. Treating as reported."
+                );
+                Ok(())
+            }
+            S2SOutcome::Rejected(reason) => {
+                error_and_incr!(
+                    statsd,
+                    LogKey::ReportSubscriptionReportToCjFailed,
+                    sub_id = &sub.id.to_string().as_str(),
+                    status = r.status.as_u16(),
+                    reason = reason.as_str(),
+                    "Could not report sub to CJ; CJ rejected it."
+                );
+                Err(reason)
+            }
+        },
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                LogKey::ReportSubscriptionReportToCjFailed,
+                error = e,
+                sub_id = &sub.id.to_string().as_str(),
+                "Could not report sub to CJ; unknown application failure."
+            );
+            Err(e.to_string())
+        }
+    };
+    sub.report_attempts += 1;
+    match result {
+        Ok(()) => {
+            sub.report_next_attempt = None;
+            sub.update_status(Status::Reported);
+            match subscriptions.update_report_attempt(&sub).await {
                 Ok(_) => {
                     info_and_incr!(
                         statsd,
-                        LogKey::ReportSubscriptionMarkWillNotReport,
+                        LogKey::ReportSubscriptionReportToCj,
                         sub_id = &sub.id.to_string().as_str(),
-                        "Successfully marked as WillNotReport"
+                        program = cj_client.program_for(&sub).name.as_str(),
+                        attempts = sub.report_attempts,
+                        "Successfully reported sub to CJ; received 200 status"
                     );
                 }
                 Err(e) => {
                     error_and_incr!(
                         statsd,
-                        LogKey::ReportSubscriptionMarkWillNotReportFailed,
+                        LogKey::ReportSubscriptionReportToCjButCouldNotMarkReported,
                         error = e,
                         sub_id = &sub.id.to_string().as_str(),
-                        "Could not mark subscription as WillNotReport."
+                        "Successfully reported sub to CJ; received 200 status, but could not mark the sub as reported locally."
                     );
                 }
-            };
-            continue;
-        }
-
-        let mark_not_reported = match cj_client.report_subscription(&sub).await {
-            Ok(r) => {
-                if r.status() == 200 {
-                    match subscriptions
-                        .update_sub_status(&sub.id, Status::Reported)
-                        .await
-                    {
-                        Ok(_) => {
-                            info_and_incr!(
-                                statsd,
-                                LogKey::ReportSubscriptionReportToCj,
-                                sub_id = &sub.id.to_string().as_str(),
-                                "Successfully reported sub to CJ; received 200 status"
-                            );
-                        }
-                        Err(e) => {
-                            error_and_incr!(
-                                statsd,
-                                LogKey::ReportSubscriptionReportToCjButCouldNotMarkReported,
-                                error = e,
-                                sub_id = &sub.id.to_string().as_str(),
-                                "Successfully reported sub to CJ; received 200 status, but could not mark the sub as reported locally."
-                            );
-                        }
-                    };
-                    false
-                } else {
-                    error_and_incr!(
-                        statsd,
-                        LogKey::ReportSubscriptionReportToCjFailed,
-                        sub_id = &sub.id.to_string().as_str(),
-                        "Could not report sub to CJ; received non-200 status."
-                    );
-                    true
-                }
-            }
-            Err(e) => {
-                error_and_incr!(
-                    statsd,
-                    LogKey::ReportSubscriptionReportToCjFailed,
-                    error = e,
-                    sub_id = &sub.id.to_string().as_str(),
-                    "Could not report sub to CJ; unknown application failure."
-                );
-                true
             }
-        };
-        if mark_not_reported {
-            match subscriptions
-                .update_sub_status(&sub.id, Status::NotReported)
-                .await
-            {
+        }
+        Err(report_error) => {
+            sub.report_last_error = Some(report_error.clone());
+            sub.report_next_attempt =
+                retry.next_attempt(sub.report_attempts, OffsetDateTime::now_utc());
+            let (status, key, failed_key) = match sub.report_next_attempt {
+                Some(_) => (
+                    Status::NotReported,
+                    LogKey::ReportSubscriptionMarkNotReported,
+                    LogKey::ReportSubscriptionMarkNotReportedFailed,
+                ),
+                None => (
+                    Status::ReportFailed,
+                    LogKey::ReportSubscriptionMarkReportFailed,
+                    LogKey::ReportSubscriptionMarkReportFailedFailed,
+                ),
+            };
+            sub.update_status_with_reason(status.clone(), Some(report_error));
+            match subscriptions.update_report_attempt(&sub).await {
                 Ok(_) => {
                     info_and_incr!(
                         statsd,
-                        LogKey::ReportSubscriptionMarkNotReported,
+                        key,
                         sub_id = &sub.id.to_string().as_str(),
-                        "Successfully marked as NotReported."
+                        attempts = sub.report_attempts,
+                        status = status.to_string().as_str(),
+                        "Successfully marked after a failed report."
                     );
                 }
                 Err(e) => {
                     error_and_incr!(
                         statsd,
-                        LogKey::ReportSubscriptionMarkNotReportedFailed,
+                        failed_key,
                         error = e,
                         sub_id = &sub.id.to_string().as_str(),
-                        "Could not mark subscription as NotReported."
+                        status = status.to_string().as_str(),
+                        "Could not mark subscription after a failed report."
                     );
                 }
             }
         }
     }
 }
+
+/// Failing to record the exchange doesn't stop the subscription being marked, as CJ has
+/// already answered.
+async fn record_s2s_exchange(
+    sub: &Subscription,
+    exchange: &S2SExchange,
+    s2s_log: &CJS2SLogModel<'_>,
+    statsd: &StatsD,
+) {
+    let (status, response_body, error) = match &exchange.response {
+        Ok(r) => (Some(r.status.as_u16() as i32), Some(r.body.clone()), None),
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+    let entry = CJS2SLogEntry {
+        id: Uuid::new_v4(),
+        sub_id: sub.id,
+        sent: exchange.sent,
+        url: exchange.url.clone(),
+        status,
+        response_body,
+        error,
+        latency_ms: exchange.latency.whole_milliseconds() as i64,
+    };
+    if let Err(e) = s2s_log.create(&entry).await {
+        error_and_incr!(
+            statsd,
+            LogKey::ReportSubscriptionS2sLogFailed,
+            error = e,
+            sub_id = &sub.id.to_string().as_str(),
+            "Could not record the S2S request in the cj_s2s_log. Continuing..."
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_attempt_doubles_the_backoff_until_max_attempts() {
+        let retry = ReportRetryPolicy {
+            max_attempts: 4,
+            backoff: Duration::minutes(15),
+        };
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(
+            retry.next_attempt(1, now),
+            Some(now + Duration::minutes(15))
+        );
+        assert_eq!(
+            retry.next_attempt(2, now),
+            Some(now + Duration::minutes(30))
+        );
+        assert_eq!(
+            retry.next_attempt(3, now),
+            Some(now + Duration::minutes(60))
+        );
+        assert_eq!(retry.next_attempt(4, now), None);
+        assert_eq!(retry.next_attempt(5, now), None);
+    }
+}
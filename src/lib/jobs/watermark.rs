@@ -0,0 +1,170 @@
+use clap::Parser;
+use sqlx::PgPool;
+use time::{Duration, Format, OffsetDateTime};
+
+use crate::{
+    error_and_incr, info_and_incr,
+    models::watermarks::WatermarkModel,
+    telemetry::{LogKey, StatsD},
+};
+
+/// Command line flags shared by the jobs that fetch incrementally from BigQuery.
+#[derive(Parser, Debug)]
+pub struct WatermarkArgs {
+    /// Forget the stored watermark so that the next run fetches every row.
+    #[arg(long, conflicts_with = "watermark")]
+    pub reset_watermark: bool,
+    /// Replace the stored watermark before running, e.g. 2022-03-16T00:00:00Z, to backfill from that time.
+    #[arg(long, value_parser = parse_watermark)]
+    pub watermark: Option<OffsetDateTime>,
+}
+
+fn parse_watermark(value: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(value, Format::Rfc3339).map_err(|e| e.to_string())
+}
+
+impl WatermarkArgs {
+    pub async fn apply(&self, job: &LogKey, db_pool: &PgPool, statsd: &StatsD) {
+        let watermarks = WatermarkModel { db_pool };
+        // Intentional expects. An override the operator asked for must not be silently skipped.
+        if self.reset_watermark {
+            watermarks
+                .delete(&job.to_string())
+                .await
+                .expect("Could not reset watermark.");
+            info_and_incr!(
+                statsd,
+                &job.add_suffix("watermark-reset"),
+                "Watermark reset"
+            );
+        }
+        if let Some(watermark) = self.watermark {
+            watermarks
+                .set(&job.to_string(), watermark)
+                .await
+                .expect("Could not set watermark.");
+            info_and_incr!(
+                statsd,
+                &job.add_suffix("watermark-set"),
+                watermark = watermark.to_string().as_str(),
+                "Watermark set"
+            );
+        }
+    }
+}
+
+/// The time from which a job should fetch rows: its watermark less the overlap, or the
+/// start of time if the job has never run.
+pub async fn fetch_since(job: &LogKey, db_pool: &PgPool, overlap: Duration) -> OffsetDateTime {
+    let watermarks = WatermarkModel { db_pool };
+    match watermarks.fetch_one_by_job(&job.to_string()).await {
+        Ok(w) => w.watermark - overlap,
+        Err(sqlx::Error::RowNotFound) => OffsetDateTime::unix_epoch(),
+        // Intentional panic. Without a watermark we can't bound the query.
+        Err(e) => panic!("Could not fetch watermark. {:?}", e),
+    }
+}
+
+/// The watermark to advance to after a run: the latest row seen, but never as far as the
+/// earliest row that failed to be processed, so that the row is fetched again next run.
+pub fn hold_back_watermark(
+    latest: Option<OffsetDateTime>,
+    earliest_failed: Option<OffsetDateTime>,
+) -> Option<OffsetDateTime> {
+    match earliest_failed {
+        Some(failed) => latest.map(|latest| latest.min(failed - Duration::microseconds(1))),
+        None => latest,
+    }
+}
+
+pub async fn advance_watermark(
+    job: &LogKey,
+    db_pool: &PgPool,
+    statsd: &StatsD,
+    watermark: Option<OffsetDateTime>,
+) {
+    let watermark = match watermark {
+        Some(watermark) => watermark,
+        None => return,
+    };
+    let watermarks = WatermarkModel { db_pool };
+    match watermarks.advance(&job.to_string(), watermark).await {
+        Ok(w) => {
+            info_and_incr!(
+                statsd,
+                &job.add_suffix("watermark-advance"),
+                watermark = w.watermark.to_string().as_str(),
+                "Watermark advanced"
+            );
+        }
+        Err(e) => {
+            error_and_incr!(
+                statsd,
+                &job.add_suffix("watermark-advance-failed"),
+                error = e,
+                "Could not advance watermark"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{date, time};
+
+    #[test]
+    fn hold_back_watermark_stops_before_the_earliest_failed_row() {
+        let t = |hour| {
+            date!(2022 - 03 - 16).with_time(time!(0:00)).assume_utc() + Duration::hours(hour)
+        };
+        assert_eq!(hold_back_watermark(Some(t(3)), None), Some(t(3)));
+        assert_eq!(
+            hold_back_watermark(Some(t(3)), Some(t(1))),
+            Some(t(1) - Duration::microseconds(1))
+        );
+        assert_eq!(hold_back_watermark(None, None), None);
+    }
+
+    #[test]
+    fn watermark_args_parse_reset() {
+        let args = WatermarkArgs::try_parse_from(["check_subscriptions", "--reset-watermark"])
+            .expect("Could not parse args");
+        assert!(args.reset_watermark);
+        assert!(args.watermark.is_none());
+    }
+
+    #[test]
+    fn watermark_args_parse_rfc3339_override() {
+        let args = WatermarkArgs::try_parse_from([
+            "check_subscriptions",
+            "--watermark",
+            "2022-03-16T20:59:53Z",
+        ])
+        .expect("Could not parse args");
+        assert!(!args.reset_watermark);
+        assert_eq!(
+            args.watermark,
+            Some(
+                date!(2022 - 03 - 16)
+                    .with_time(time!(20:59:53))
+                    .assume_utc()
+            )
+        );
+    }
+
+    #[test]
+    fn watermark_args_reject_bad_timestamp_and_conflicting_flags() {
+        assert!(
+            WatermarkArgs::try_parse_from(["check_subscriptions", "--watermark", "yesterday"])
+                .is_err()
+        );
+        assert!(WatermarkArgs::try_parse_from([
+            "check_subscriptions",
+            "--reset-watermark",
+            "--watermark",
+            "2022-03-16T20:59:53Z",
+        ])
+        .is_err());
+    }
+}
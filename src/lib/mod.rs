@@ -40,24 +40,54 @@ pub mod test_utils {
 
     pub fn empty_settings() -> Settings {
         Settings {
+            aic_accept_unsigned_ids: true,
+            aic_archive_chunk_size: 1000,
+            aic_archive_retention_days: None,
+            aic_cookie_domain: None,
+            aic_cookie_name: "aic".to_string(),
             aic_expiration_days: 2,
+            aic_signing_keys: Secret::new(String::new()),
+            attribution_model: "last-click".to_string(),
+            attribution_window_days: 30,
             authentication: "_".to_string(),
+            bq_max_retries: 3,
+            bq_page_size: 10000,
+            bq_query_timeout_seconds: 300,
+            bq_refunds_table: "cjms_bigquery.refunds_v1".to_string(),
+            bq_retry_backoff_ms: 1000,
+            bq_service_account_key_file: None,
+            bq_subscriptions_table: "cjms_bigquery.subscriptions_v1".to_string(),
+            bq_token_endpoint: None,
+            bq_watermark_overlap_minutes: 1440,
             cj_api_access_token: Secret::new("_".to_string()),
             cj_cid: "_".to_string(),
+            cj_event_time_bucket_minutes: 1,
+            cj_programs: Secret::new(String::new()),
+            cj_s2s_log_retention_days: None,
+            cj_s2s_requests_per_second: 10,
             cj_sftp_user: "_".to_string(),
             cj_signature: "_".to_string(),
             cj_subid: "_".to_string(),
             cj_type: "_".to_string(),
+            click_redirect_allowlist: String::new(),
             database_url: Secret::new("_".to_string()),
             environment: "_".to_string(),
             gcp_project: "_".to_string(),
             host: "_".to_string(),
+            idempotency_key_ttl_hours: 24,
             log_level: "_".to_string(),
+            pending_attribution_window_hours: 48,
             port: 1111,
+            purge_batch_size: 1000,
+            refund_retention_days: None,
+            report_concurrency: 4,
+            report_max_attempts: 8,
+            report_retry_backoff_minutes: 15,
             sentry_dsn: Secret::new("_".to_string()),
             sentry_environment: "_".to_string(),
             statsd_host: "_".to_string(),
             statsd_port: 2222,
+            subscription_retention_days: None,
         }
     }
 }
@@ -1,8 +1,9 @@
+use serde_json::Value as JsonValue;
 use sqlx::{query, query_as, Error, PgPool};
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
-use crate::{error, settings::Settings, telemetry::LogKey};
+use crate::{error, models::aic_clicks::ClickMetadata, settings::Settings, telemetry::LogKey};
 
 #[derive(Debug)]
 pub struct AIC {
@@ -11,12 +12,15 @@ pub struct AIC {
     pub flow_id: String,
     pub created: OffsetDateTime,
     pub expires: OffsetDateTime,
+    // The metadata of the last click, see ClickMetadata
+    pub click_metadata: Option<JsonValue>,
 }
 impl PartialEq for AIC {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id &&
         self.cj_event_value == other.cj_event_value &&
         self.flow_id == other.flow_id &&
+        self.click_metadata == other.click_metadata &&
         // When timestamps go in and out of database they lose precision to milliseconds
         self.created.unix_timestamp() == other.created.unix_timestamp() &&
         self.expires.unix_timestamp() == other.expires.unix_timestamp()
@@ -32,14 +36,15 @@ impl AICModel<'_> {
     pub async fn create_from_aic(&self, aic: &AIC) -> Result<AIC, Error> {
         query_as!(
             AIC,
-            "INSERT INTO aic (id, cj_event_value, flow_id, created, expires)
-			VALUES ($1, $2, $3, $4, $5)
+            "INSERT INTO aic (id, cj_event_value, flow_id, created, expires, click_metadata)
+			VALUES ($1, $2, $3, $4, $5, $6)
 			RETURNING *",
             aic.id,
             aic.cj_event_value,
             aic.flow_id,
             aic.created,
-            aic.expires
+            aic.expires,
+            aic.click_metadata
         )
         .fetch_one(self.db_pool)
         .await
@@ -50,25 +55,19 @@ impl AICModel<'_> {
         &self,
         cj_event_value: &str,
         flow_id: &str,
+        click_metadata: &ClickMetadata,
         settings: &Settings,
     ) -> Result<AIC, Error> {
-        let id = Uuid::new_v4();
         let created = OffsetDateTime::now_utc();
-        let expires = created + Duration::days(settings.aic_expiration_days as i64);
-        query_as!(
-            AIC,
-            "INSERT INTO aic (id, cj_event_value, flow_id, created, expires)
-			VALUES ($1, $2, $3, $4, $5)
-			RETURNING *",
-            id,
-            cj_event_value,
-            flow_id,
+        let aic = AIC {
+            id: Uuid::new_v4(),
+            cj_event_value: cj_event_value.to_string(),
+            flow_id: flow_id.to_string(),
             created,
-            expires
-        )
-        .fetch_one(self.db_pool)
-        .await
-        .map_err(|e| {
+            expires: created + Duration::days(settings.aic_expiration_days as i64),
+            click_metadata: click_metadata.to_json(),
+        };
+        self.create_with_click(&aic).await.map_err(|e| {
             error!(
                 LogKey::AicRecordCreateFailed,
                 error = e,
@@ -78,6 +77,27 @@ impl AICModel<'_> {
         })
     }
 
+    async fn create_with_click(&self, aic: &AIC) -> Result<AIC, Error> {
+        let mut transaction = self.db_pool.begin().await?;
+        let created = query_as!(
+            AIC,
+            "INSERT INTO aic (id, cj_event_value, flow_id, created, expires, click_metadata)
+			VALUES ($1, $2, $3, $4, $5, $6)
+			RETURNING *",
+            aic.id,
+            aic.cj_event_value,
+            aic.flow_id,
+            aic.created,
+            aic.expires,
+            aic.click_metadata
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+        record_click(&mut transaction, &created).await?;
+        transaction.commit().await?;
+        Ok(created)
+    }
+
     pub async fn update_flow_id(&self, id: Uuid, flow_id: &str) -> Result<AIC, Error> {
         // A new flow_id alone, does not reset the clock on the cookie
         query_as!(
@@ -98,29 +118,36 @@ impl AICModel<'_> {
         id: Uuid,
         cj_event_value: &str,
         flow_id: &str,
+        click_metadata: &ClickMetadata,
         settings: &Settings,
     ) -> Result<AIC, Error> {
-        // A new cj_event_value resets the clock on the cookie
+        // A new cj_event_value resets the clock on the cookie, and is another click
         let created = OffsetDateTime::now_utc();
         let expires = created + Duration::days(settings.aic_expiration_days as i64);
-        query_as!(
+        let mut transaction = self.db_pool.begin().await?;
+        let aic = query_as!(
             AIC,
             "UPDATE aic
             SET
                 cj_event_value = $1,
                 flow_id = $2,
                 created = $3,
-                expires = $4
-            WHERE id = $5
+                expires = $4,
+                click_metadata = $5
+            WHERE id = $6
 			RETURNING *",
             cj_event_value,
             flow_id,
             created,
             expires,
+            click_metadata.to_json(),
             id,
         )
-        .fetch_one(self.db_pool)
-        .await
+        .fetch_one(&mut transaction)
+        .await?;
+        record_click(&mut transaction, &aic).await?;
+        transaction.commit().await?;
+        Ok(aic)
     }
 
     pub async fn fetch_expired(&self) -> Result<Vec<AIC>, Error> {
@@ -162,19 +189,47 @@ impl AICModel<'_> {
     pub async fn create_archive_from_aic(&self, aic: &AIC) -> Result<AIC, Error> {
         query_as!(
             AIC,
-            "INSERT INTO aic_archive (id, cj_event_value, flow_id, created, expires)
-			VALUES ($1, $2, $3, $4, $5)
+            "INSERT INTO aic_archive (id, cj_event_value, flow_id, created, expires, click_metadata)
+			VALUES ($1, $2, $3, $4, $5, $6)
 			RETURNING *",
             aic.id,
             aic.cj_event_value,
             aic.flow_id,
             aic.created,
-            aic.expires
+            aic.expires,
+            aic.click_metadata
         )
         .fetch_one(self.db_pool)
         .await
     }
 
+    /// Deletes up to `limit` archived AICs that expired before `expired_before`, and their
+    /// clicks. Returns how many AICs were deleted.
+    pub async fn delete_archived_expired_before(
+        &self,
+        expired_before: OffsetDateTime,
+        limit: i64,
+    ) -> Result<u64, Error> {
+        let mut transaction = self.db_pool.begin().await?;
+        let ids: Vec<Uuid> = query!(
+            "DELETE FROM aic_archive
+            WHERE id IN (SELECT id FROM aic_archive WHERE expires < $1 LIMIT $2)
+            RETURNING id",
+            expired_before,
+            limit,
+        )
+        .fetch_all(&mut transaction)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        query!("DELETE FROM aic_clicks WHERE aic_id = ANY($1)", &ids)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(ids.len() as u64)
+    }
+
     async fn create_archive_delete_aic(
         &self,
         transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
@@ -184,14 +239,15 @@ impl AICModel<'_> {
             .execute(&mut *transaction)
             .await?;
         query!(
-            "INSERT INTO aic_archive (id, cj_event_value, flow_id, created, expires)
-			VALUES ($1, $2, $3, $4, $5)
+            "INSERT INTO aic_archive (id, cj_event_value, flow_id, created, expires, click_metadata)
+			VALUES ($1, $2, $3, $4, $5, $6)
 			RETURNING *",
             aic.id,
             aic.cj_event_value,
             aic.flow_id,
             aic.created,
-            aic.expires
+            aic.expires,
+            aic.click_metadata
         )
         .fetch_one(&mut *transaction)
         .await?;
@@ -206,4 +262,80 @@ impl AICModel<'_> {
         transaction.commit().await?;
         Ok(())
     }
+
+    /// Moves up to `limit` AICs that expired before `expired_before` into the archive in one
+    /// statement, taking them in (expires, id) order after `after`. Returns the (expires, id)
+    /// of the last AIC moved, to continue from, or None when there were none left.
+    ///
+    /// An AIC whose id is already archived is dropped from aic, keeping the archived row.
+    pub async fn archive_expired_chunk(
+        &self,
+        expired_before: OffsetDateTime,
+        after: (OffsetDateTime, Uuid),
+        limit: i64,
+    ) -> Result<ArchivedChunk, Error> {
+        let moved = query!(
+            r#"WITH moved AS (
+                DELETE FROM aic
+                WHERE id IN (
+                    SELECT id FROM aic
+                    WHERE expires < $1
+                    AND (expires, id) > ($2::TIMESTAMPTZ, $3::UUID)
+                    ORDER BY expires, id
+                    LIMIT $4
+                )
+                RETURNING *
+            ), archived AS (
+                INSERT INTO aic_archive (id, cj_event_value, flow_id, created, expires, click_metadata)
+                SELECT id, cj_event_value, flow_id, created, expires, click_metadata FROM moved
+                ON CONFLICT (id) DO NOTHING
+                RETURNING id
+            )
+            SELECT
+                moved.id AS "id!",
+                moved.expires AS "expires!",
+                archived.id IS NOT NULL AS "archived!"
+            FROM moved LEFT JOIN archived ON moved.id = archived.id
+            ORDER BY moved.expires DESC, moved.id DESC"#,
+            expired_before,
+            after.0,
+            after.1,
+            limit,
+        )
+        .fetch_all(self.db_pool)
+        .await?;
+        Ok(ArchivedChunk {
+            n_archived: moved.iter().filter(|row| row.archived).count() as u64,
+            n_dropped: moved.iter().filter(|row| !row.archived).count() as u64,
+            last: moved.first().map(|row| (row.expires, row.id)),
+        })
+    }
+}
+
+/// The result of `archive_expired_chunk`
+#[derive(Debug, PartialEq, Eq)]
+pub struct ArchivedChunk {
+    pub n_archived: u64,
+    /// AICs that were already archived
+    pub n_dropped: u64,
+    pub last: Option<(OffsetDateTime, Uuid)>,
+}
+
+async fn record_click(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    aic: &AIC,
+) -> Result<(), Error> {
+    query!(
+        "INSERT INTO aic_clicks (id, aic_id, cj_event_value, clicked, expires, click_metadata)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        Uuid::new_v4(),
+        aic.id,
+        aic.cj_event_value,
+        aic.created,
+        aic.expires,
+        aic.click_metadata
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
 }
@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sqlx::{query_as, Error, PgPool};
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{models::aic::AIC, settings::Settings};
+
+/// What CJ and the landing page tell us about a click, so that conversions can be broken
+/// down by publisher or campaign. Stored as JSON with the click, its AIC and the
+/// subscription attributed to it.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClickMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ad_id: Option<String>,
+    // The publisher's own id for the click, CJ's SID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landing_page: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_medium: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_campaign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_term: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_content: Option<String>,
+}
+
+impl ClickMetadata {
+    /// The fields that are set, by name.
+    pub fn fields(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("publisher_id", &self.publisher_id),
+            ("ad_id", &self.ad_id),
+            ("sid", &self.sid),
+            ("landing_page", &self.landing_page),
+            ("utm_source", &self.utm_source),
+            ("utm_medium", &self.utm_medium),
+            ("utm_campaign", &self.utm_campaign),
+            ("utm_term", &self.utm_term),
+            ("utm_content", &self.utm_content),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+        .collect()
+    }
+
+    /// None if no field is set, so clicks without metadata store NULL.
+    pub fn to_json(&self) -> Option<JsonValue> {
+        match self.fields().is_empty() {
+            true => None,
+            false => Some(serde_json::to_value(self).expect("ClickMetadata is serializable")),
+        }
+    }
+
+    /// Reads metadata stored by `to_json`. Anything unreadable is treated as no metadata.
+    pub fn from_json(value: Option<&JsonValue>) -> Self {
+        value
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+// Every CJ click that created or updated an AIC. The AIC itself only keeps the last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AICClick {
+    pub id: Uuid,
+    pub aic_id: Uuid,
+    pub cj_event_value: String,
+    pub clicked: OffsetDateTime,
+    pub expires: OffsetDateTime,
+    pub click_metadata: Option<JsonValue>,
+}
+
+impl From<&AIC> for AICClick {
+    fn from(aic: &AIC) -> Self {
+        AICClick {
+            id: aic.id,
+            aic_id: aic.id,
+            cj_event_value: aic.cj_event_value.clone(),
+            clicked: aic.created,
+            expires: aic.expires,
+            click_metadata: aic.click_metadata.clone(),
+        }
+    }
+}
+
+pub struct AICClickModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl AICClickModel<'_> {
+    pub async fn create_from_click(&self, click: &AICClick) -> Result<AICClick, Error> {
+        query_as!(
+            AICClick,
+            "INSERT INTO aic_clicks (id, aic_id, cj_event_value, clicked, expires, click_metadata)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *",
+            click.id,
+            click.aic_id,
+            click.cj_event_value,
+            click.clicked,
+            click.expires,
+            click.click_metadata,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn fetch_all_by_aic_id(&self, aic_id: &Uuid) -> Result<Vec<AICClick>, Error> {
+        query_as!(
+            AICClick,
+            "SELECT * FROM aic_clicks WHERE aic_id = $1 ORDER BY clicked",
+            aic_id
+        )
+        .fetch_all(self.db_pool)
+        .await
+    }
+}
+
+/// How the click a subscription is attributed to is chosen from its AIC's clicks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributionModel {
+    LastClick,
+    FirstClick,
+    /// The last click at most this long before the subscription was created.
+    LastClickWithin(Duration),
+}
+
+impl AttributionModel {
+    /// Intentionally panics on an unknown attribution_model, jobs can't attribute without it.
+    pub fn from_settings(settings: &Settings) -> Self {
+        match settings.attribution_model.as_str() {
+            "last-click" => AttributionModel::LastClick,
+            "first-click" => AttributionModel::FirstClick,
+            "last-click-within" => {
+                AttributionModel::LastClickWithin(Duration::days(settings.attribution_window_days))
+            }
+            other => panic!("Invalid attribution_model: {}", other),
+        }
+    }
+
+    /// Chooses from `clicks`, which must be ordered by when they were clicked. None means
+    /// no click qualifies.
+    pub fn choose<'a>(
+        &self,
+        clicks: &'a [AICClick],
+        subscription_created: OffsetDateTime,
+    ) -> Option<&'a AICClick> {
+        match self {
+            AttributionModel::LastClick => clicks.last(),
+            AttributionModel::FirstClick => clicks.first(),
+            AttributionModel::LastClickWithin(window) => clicks.iter().rev().find(|click| {
+                click.clicked <= subscription_created
+                    && subscription_created - click.clicked <= *window
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::empty_settings;
+
+    fn click_days_ago(now: OffsetDateTime, days: i64) -> AICClick {
+        AICClick {
+            id: Uuid::new_v4(),
+            aic_id: Uuid::nil(),
+            cj_event_value: format!("{} days ago", days),
+            clicked: now - Duration::days(days),
+            expires: now - Duration::days(days) + Duration::days(30),
+            click_metadata: None,
+        }
+    }
+
+    #[test]
+    fn attribution_models_choose_a_click() {
+        let now = OffsetDateTime::now_utc();
+        let clicks = [
+            click_days_ago(now, 20),
+            click_days_ago(now, 10),
+            click_days_ago(now, 5),
+        ];
+        assert_eq!(
+            AttributionModel::LastClick.choose(&clicks, now),
+            Some(&clicks[2])
+        );
+        assert_eq!(
+            AttributionModel::FirstClick.choose(&clicks, now),
+            Some(&clicks[0])
+        );
+        assert_eq!(
+            AttributionModel::LastClickWithin(Duration::days(7)).choose(&clicks, now),
+            Some(&clicks[2])
+        );
+        // Clicks after the subscription was created don't count
+        assert_eq!(
+            AttributionModel::LastClickWithin(Duration::days(30))
+                .choose(&clicks, now - Duration::days(6)),
+            Some(&clicks[1])
+        );
+        assert_eq!(
+            AttributionModel::LastClickWithin(Duration::days(3)).choose(&clicks, now),
+            None
+        );
+        assert_eq!(AttributionModel::LastClick.choose(&[], now), None);
+    }
+
+    #[test]
+    fn click_metadata_is_stored_as_json_without_unset_fields() {
+        assert_eq!(ClickMetadata::default().to_json(), None);
+        let metadata = ClickMetadata {
+            publisher_id: Some("1234".to_string()),
+            utm_source: Some("cj".to_string()),
+            ..Default::default()
+        };
+        let json = metadata.to_json();
+        assert_eq!(
+            json,
+            Some(serde_json::json!({"publisher_id": "1234", "utm_source": "cj"}))
+        );
+        assert_eq!(ClickMetadata::from_json(json.as_ref()), metadata);
+        assert_eq!(ClickMetadata::from_json(None), ClickMetadata::default());
+        assert_eq!(
+            ClickMetadata::from_json(Some(&serde_json::json!("not an object"))),
+            ClickMetadata::default()
+        );
+    }
+
+    #[test]
+    fn attribution_model_from_settings() {
+        let mut settings = empty_settings();
+        settings.attribution_window_days = 7;
+        for (name, expected) in [
+            ("last-click", AttributionModel::LastClick),
+            ("first-click", AttributionModel::FirstClick),
+            (
+                "last-click-within",
+                AttributionModel::LastClickWithin(Duration::days(7)),
+            ),
+        ] {
+            settings.attribution_model = name.to_string();
+            assert_eq!(AttributionModel::from_settings(&settings), expected);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid attribution_model: most-clicks")]
+    fn unknown_attribution_model_panics() {
+        let mut settings = empty_settings();
+        settings.attribution_model = "most-clicks".to_string();
+        AttributionModel::from_settings(&settings);
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+use sqlx::{query, query_as, Error, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// One S2S request sent to CJ and what CJ answered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CJS2SLogEntry {
+    pub id: Uuid,
+    pub sub_id: Uuid,
+    #[serde(with = "time::serde::timestamp")]
+    pub sent: OffsetDateTime,
+    // The request URL with its SIGNATURE redacted
+    pub url: String,
+    pub status: Option<i32>,
+    pub response_body: Option<String>,
+    // Why there was no response
+    pub error: Option<String>,
+    pub latency_ms: i64,
+}
+
+pub struct CJS2SLogModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl CJS2SLogModel<'_> {
+    pub async fn create(&self, entry: &CJS2SLogEntry) -> Result<CJS2SLogEntry, Error> {
+        query_as!(
+            CJS2SLogEntry,
+            "INSERT INTO cj_s2s_log (id, sub_id, sent, url, status, response_body, error, latency_ms)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *",
+            entry.id,
+            entry.sub_id,
+            entry.sent,
+            entry.url,
+            entry.status,
+            entry.response_body,
+            entry.error,
+            entry.latency_ms,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn fetch_all_by_sub_id(&self, sub_id: &Uuid) -> Result<Vec<CJS2SLogEntry>, Error> {
+        query_as!(
+            CJS2SLogEntry,
+            "SELECT * FROM cj_s2s_log WHERE sub_id = $1 ORDER BY sent",
+            sub_id
+        )
+        .fetch_all(self.db_pool)
+        .await
+    }
+
+    pub async fn delete_sent_before(
+        &self,
+        sent_before: OffsetDateTime,
+        limit: i64,
+    ) -> Result<u64, Error> {
+        let result = query!(
+            "DELETE FROM cj_s2s_log
+            WHERE id IN (SELECT id FROM cj_s2s_log WHERE sent < $1 LIMIT $2)",
+            sent_before,
+            limit,
+        )
+        .execute(self.db_pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,296 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, Error, PgPool};
+use thiserror::Error;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::subscriptions::pseudonymize_subscriptions;
+
+/// Identifiers in subscriptions and refunds are replaced with this followed by the row's id.
+pub const ERASED_PREFIX: &str = "erased-";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ErasureIdentifierError {
+    #[error("Exactly one of fxa_uid, flow_id or aic_id must be given")]
+    NotExactlyOne,
+
+    #[error("{0} must not be empty or an already erased value")]
+    Invalid(&'static str),
+}
+
+/// Identifies whose data is erased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErasureIdentifier {
+    FxaUid(String),
+    FlowId(String),
+    AicId(Uuid),
+}
+
+impl ErasureIdentifier {
+    pub fn from_parts(
+        fxa_uid: Option<String>,
+        flow_id: Option<String>,
+        aic_id: Option<Uuid>,
+    ) -> Result<Self, ErasureIdentifierError> {
+        let identifier = match (fxa_uid, flow_id, aic_id) {
+            (Some(fxa_uid), None, None) => ErasureIdentifier::FxaUid(fxa_uid),
+            (None, Some(flow_id), None) => ErasureIdentifier::FlowId(flow_id),
+            (None, None, Some(aic_id)) => ErasureIdentifier::AicId(aic_id),
+            _ => return Err(ErasureIdentifierError::NotExactlyOne),
+        };
+        // An erased value would match every row erased before
+        let value = identifier.value();
+        if value.is_empty() || value.starts_with(ERASED_PREFIX) {
+            return Err(ErasureIdentifierError::Invalid(
+                identifier.identifier_type(),
+            ));
+        }
+        Ok(identifier)
+    }
+
+    pub fn identifier_type(&self) -> &'static str {
+        match self {
+            ErasureIdentifier::FxaUid(_) => "fxa_uid",
+            ErasureIdentifier::FlowId(_) => "flow_id",
+            ErasureIdentifier::AicId(_) => "aic_id",
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            ErasureIdentifier::FxaUid(fxa_uid) => fxa_uid.clone(),
+            ErasureIdentifier::FlowId(flow_id) => flow_id.clone(),
+            ErasureIdentifier::AicId(aic_id) => aic_id.to_string(),
+        }
+    }
+
+    pub fn hash(&self) -> String {
+        hash_identifier(&self.value())
+    }
+}
+
+/// A sha256 of an identifier, as it's kept in erasures and erased_identifiers.
+pub fn hash_identifier(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+// The audit log entry of an erasure
+#[derive(Debug, Serialize)]
+pub struct Erasure {
+    pub id: Uuid,
+    pub identifier_type: String,
+    pub identifier_hash: String,
+    pub requested_by: String,
+    #[serde(with = "time::serde::timestamp")]
+    pub erased: OffsetDateTime,
+    pub aic_deleted: i64,
+    pub aic_archive_deleted: i64,
+    pub aic_clicks_deleted: i64,
+    pub ingest_failures_deleted: i64,
+    pub subscriptions_pseudonymized: i64,
+    pub refunds_pseudonymized: i64,
+}
+
+pub struct ErasureModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl ErasureModel<'_> {
+    /// Deletes the AICs, clicks and ingest failures of whoever `identifier` matches, and
+    /// replaces the identifiers in their subscriptions and refunds, all in one transaction
+    /// that also records the erasure.
+    ///
+    /// Subscriptions and refunds are kept with their ids, amounts and statuses, so that
+    /// attribution counts, reports to CJ and the corrections report don't change.
+    ///
+    /// A hash of every identifier found is kept in erased_identifiers, so that fetching
+    /// the same rows from BigQuery again doesn't bring them back, see `is_erased`.
+    pub async fn erase(
+        &self,
+        identifier: &ErasureIdentifier,
+        requested_by: &str,
+    ) -> Result<Erasure, Error> {
+        let (fxa_uid, flow_id, aic_id) = match identifier {
+            ErasureIdentifier::FxaUid(fxa_uid) => (Some(fxa_uid.as_str()), None, None),
+            ErasureIdentifier::FlowId(flow_id) => (None, Some(flow_id.as_str()), None),
+            ErasureIdentifier::AicId(aic_id) => (None, None, Some(*aic_id)),
+        };
+        let mut transaction = self.db_pool.begin().await?;
+        let subs = query!(
+            "SELECT id, subscription_id, flow_id, fxa_uid, aic_id
+            FROM subscriptions
+            WHERE fxa_uid = $1
+            OR flow_id = $2
+            OR aic_id = $3
+            OR flow_id IN (
+                SELECT flow_id FROM aic WHERE id = $3
+                UNION ALL
+                SELECT flow_id FROM aic_archive WHERE id = $3
+            )",
+            fxa_uid,
+            flow_id,
+            aic_id,
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let sub_ids: Vec<Uuid> = subs.iter().map(|sub| sub.id).collect();
+        let subscription_ids: Vec<String> =
+            subs.iter().map(|sub| sub.subscription_id.clone()).collect();
+        let mut fxa_uids: Vec<String> = subs.iter().map(|sub| sub.fxa_uid.clone()).collect();
+        fxa_uids.extend(fxa_uid.map(String::from));
+        let mut flow_ids: Vec<String> = subs.iter().map(|sub| sub.flow_id.clone()).collect();
+        flow_ids.extend(flow_id.map(String::from));
+        let mut aic_ids: Vec<Uuid> = subs.iter().filter_map(|sub| sub.aic_id).collect();
+        aic_ids.extend(aic_id);
+
+        let aics = query!(
+            "DELETE FROM aic WHERE id = ANY($1) OR flow_id = ANY($2) RETURNING id, flow_id",
+            &aic_ids,
+            &flow_ids,
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let archived = query!(
+            "DELETE FROM aic_archive WHERE id = ANY($1) OR flow_id = ANY($2) RETURNING id, flow_id",
+            &aic_ids,
+            &flow_ids,
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+        let (aic_deleted, aic_archive_deleted) = (aics.len() as i64, archived.len() as i64);
+        for aic in aics {
+            aic_ids.push(aic.id);
+            flow_ids.push(aic.flow_id);
+        }
+        for aic in archived {
+            aic_ids.push(aic.id);
+            flow_ids.push(aic.flow_id);
+        }
+        let aic_clicks_deleted = query!("DELETE FROM aic_clicks WHERE aic_id = ANY($1)", &aic_ids)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        // Rows of either job that could not be deserialized
+        let ingest_failures_deleted = query!(
+            "DELETE FROM ingest_failures
+            WHERE raw_row->>'fxa_uid' = ANY($1)
+            OR raw_row->>'flow_id' = ANY($2)
+            OR raw_row->>'subscription_id' = ANY($3)",
+            &fxa_uids,
+            &flow_ids,
+            &subscription_ids,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+        let (subscriptions_pseudonymized, refunds_pseudonymized) =
+            pseudonymize_subscriptions(&mut transaction, &sub_ids).await?;
+        let erasure = query_as!(
+            Erasure,
+            "INSERT INTO erasures (
+                id,
+                identifier_type,
+                identifier_hash,
+                requested_by,
+                erased,
+                aic_deleted,
+                aic_archive_deleted,
+                aic_clicks_deleted,
+                ingest_failures_deleted,
+                subscriptions_pseudonymized,
+                refunds_pseudonymized
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *",
+            Uuid::new_v4(),
+            identifier.identifier_type(),
+            identifier.hash(),
+            requested_by,
+            OffsetDateTime::now_utc(),
+            aic_deleted,
+            aic_archive_deleted,
+            aic_clicks_deleted as i64,
+            ingest_failures_deleted as i64,
+            subscriptions_pseudonymized,
+            refunds_pseudonymized,
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+        let hashes: Vec<String> = fxa_uids
+            .iter()
+            .chain(&flow_ids)
+            .chain(&subscription_ids)
+            .map(|value| hash_identifier(value))
+            .chain(std::iter::once(identifier.hash()))
+            .collect();
+        query!(
+            "INSERT INTO erased_identifiers (identifier_hash, erasure_id)
+            SELECT UNNEST($1::text[]), $2
+            ON CONFLICT DO NOTHING",
+            &hashes,
+            erasure.id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(erasure)
+    }
+
+    /// Whether any of `identifiers` belonged to someone whose data was erased.
+    pub async fn is_erased(&self, identifiers: &[&str]) -> Result<bool, Error> {
+        let hashes: Vec<String> = identifiers
+            .iter()
+            .map(|value| hash_identifier(value))
+            .collect();
+        let result = query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM erased_identifiers WHERE identifier_hash = ANY($1)
+            ) AS "erased!""#,
+            &hashes,
+        )
+        .fetch_one(self.db_pool)
+        .await?;
+        Ok(result.erased)
+    }
+
+    pub async fn fetch_all(&self) -> Result<Vec<Erasure>, Error> {
+        query_as!(Erasure, "SELECT * FROM erasures ORDER BY erased")
+            .fetch_all(self.db_pool)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifier_must_be_exactly_one_value_that_is_not_erased() {
+        let aic_id = Uuid::new_v4();
+        assert_eq!(
+            ErasureIdentifier::from_parts(Some("a-uid".to_string()), None, None),
+            Ok(ErasureIdentifier::FxaUid("a-uid".to_string()))
+        );
+        assert_eq!(
+            ErasureIdentifier::from_parts(None, None, Some(aic_id)),
+            Ok(ErasureIdentifier::AicId(aic_id))
+        );
+        assert_eq!(
+            ErasureIdentifier::from_parts(None, None, None),
+            Err(ErasureIdentifierError::NotExactlyOne)
+        );
+        assert_eq!(
+            ErasureIdentifier::from_parts(Some("a-uid".to_string()), None, Some(aic_id)),
+            Err(ErasureIdentifierError::NotExactlyOne)
+        );
+        assert_eq!(
+            ErasureIdentifier::from_parts(None, Some("".to_string()), None),
+            Err(ErasureIdentifierError::Invalid("flow_id"))
+        );
+        assert_eq!(
+            ErasureIdentifier::from_parts(Some(format!("{}{}", ERASED_PREFIX, aic_id)), None, None),
+            Err(ErasureIdentifierError::Invalid("fxa_uid"))
+        );
+    }
+}
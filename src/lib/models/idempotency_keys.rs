@@ -0,0 +1,72 @@
+use serde_json::Value as JsonValue;
+use sqlx::{query, query_as, Error, PgPool};
+use time::{Duration, OffsetDateTime};
+
+// The response to a request that was sent with an Idempotency-Key header, returned again
+// for repeats of that request until it expires
+#[derive(Debug)]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub request_hash: String,
+    pub response: JsonValue,
+    pub created: OffsetDateTime,
+    pub expires: OffsetDateTime,
+}
+
+pub struct IdempotencyKeyModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl IdempotencyKeyModel<'_> {
+    pub async fn record(
+        &self,
+        key: &str,
+        request_hash: &str,
+        response: &JsonValue,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        // An expired key that hasn't been cleaned up yet can be reused. A live one is kept,
+        // it was recorded by a concurrent request.
+        let created = OffsetDateTime::now_utc();
+        query!(
+            "INSERT INTO idempotency_keys (key, request_hash, response, created, expires)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (key) DO UPDATE
+            SET
+                request_hash = EXCLUDED.request_hash,
+                response = EXCLUDED.response,
+                created = EXCLUDED.created,
+                expires = EXCLUDED.expires
+            WHERE idempotency_keys.expires <= EXCLUDED.created",
+            key,
+            request_hash,
+            response,
+            created,
+            created + ttl,
+        )
+        .execute(self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_one_unexpired(&self, key: &str) -> Result<IdempotencyKey, Error> {
+        query_as!(
+            IdempotencyKey,
+            "SELECT * FROM idempotency_keys WHERE key = $1 AND expires > $2",
+            key,
+            OffsetDateTime::now_utc(),
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn delete_expired(&self) -> Result<u64, Error> {
+        let result = query!(
+            "DELETE FROM idempotency_keys WHERE expires <= $1",
+            OffsetDateTime::now_utc(),
+        )
+        .execute(self.db_pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
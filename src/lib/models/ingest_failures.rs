@@ -0,0 +1,108 @@
+use serde_json::Value as JsonValue;
+use sqlx::{query, query_as, Error, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// A BigQuery row that a job could not deserialize or process, kept so it can be retried or
+// explained
+#[derive(Debug)]
+pub struct IngestFailure {
+    pub id: Uuid,
+    pub job: String,
+    pub raw_row: JsonValue,
+    pub error: String,
+    pub first_seen: OffsetDateTime,
+    pub last_seen: OffsetDateTime,
+    // Retries that failed, see retry_ingest_failure
+    pub attempts: i32,
+    pub last_attempt_at: Option<OffsetDateTime>,
+}
+
+pub struct IngestFailureModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl IngestFailureModel<'_> {
+    pub async fn record(
+        &self,
+        job: &str,
+        raw_row: &JsonValue,
+        error: &str,
+    ) -> Result<IngestFailure, Error> {
+        // Seeing the same row again keeps first_seen and refreshes the error and last_seen
+        let now = OffsetDateTime::now_utc();
+        query_as!(
+            IngestFailure,
+            "INSERT INTO ingest_failures (id, job, raw_row, error, first_seen, last_seen)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (job, md5(raw_row::text)) DO UPDATE
+            SET
+                error = EXCLUDED.error,
+                last_seen = EXCLUDED.last_seen
+            RETURNING *",
+            Uuid::new_v4(),
+            job,
+            raw_row,
+            error,
+            now,
+            now,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    /// Records a retry that failed, with why.
+    pub async fn record_attempt(&self, id: &Uuid, error: &str) -> Result<IngestFailure, Error> {
+        query_as!(
+            IngestFailure,
+            "UPDATE ingest_failures
+            SET
+                error = $1,
+                attempts = attempts + 1,
+                last_attempt_at = $2
+            WHERE id = $3
+            RETURNING *",
+            error,
+            OffsetDateTime::now_utc(),
+            id,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn fetch_one_by_id(&self, id: &Uuid) -> Result<IngestFailure, Error> {
+        query_as!(
+            IngestFailure,
+            "SELECT * FROM ingest_failures WHERE id = $1",
+            id
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn fetch_all(&self) -> Result<Vec<IngestFailure>, Error> {
+        query_as!(
+            IngestFailure,
+            "SELECT * FROM ingest_failures ORDER BY first_seen"
+        )
+        .fetch_all(self.db_pool)
+        .await
+    }
+
+    pub async fn fetch_all_by_job(&self, job: &str) -> Result<Vec<IngestFailure>, Error> {
+        query_as!(
+            IngestFailure,
+            "SELECT * FROM ingest_failures WHERE job = $1 ORDER BY first_seen",
+            job
+        )
+        .fetch_all(self.db_pool)
+        .await
+    }
+
+    pub async fn delete(&self, id: &Uuid) -> Result<(), Error> {
+        query!("DELETE FROM ingest_failures WHERE id = $1", id)
+            .execute(self.db_pool)
+            .await?;
+        Ok(())
+    }
+}
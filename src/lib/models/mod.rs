@@ -1,4 +1,10 @@
 pub mod aic;
+pub mod aic_clicks;
+pub mod cj_s2s_log;
+pub mod erasures;
+pub mod idempotency_keys;
+pub mod ingest_failures;
 pub mod refunds;
 pub mod status_history;
 pub mod subscriptions;
+pub mod watermarks;
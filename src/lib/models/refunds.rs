@@ -228,6 +228,28 @@ impl RefundModel<'_> {
         .await
     }
 
+    /// Deletes up to `limit` refunds that reached one of `statuses` before `status_before`.
+    pub async fn delete_by_status_before(
+        &self,
+        statuses: &[Status],
+        status_before: OffsetDateTime,
+        limit: i64,
+    ) -> Result<u64, Error> {
+        let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+        let result = query!(
+            "DELETE FROM refunds
+            WHERE id IN (
+                SELECT id FROM refunds WHERE status = ANY($1) AND status_t < $2 LIMIT $3
+            )",
+            &statuses,
+            status_before,
+            limit,
+        )
+        .execute(self.db_pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
     pub async fn get_reported_date_range(&self) -> Result<DateRange, Error> {
         let result = query!(
             "SELECT MIN(status_t), MAX(status_t) FROM refunds WHERE status = 'Reported' AND status_t IS NOT NULL",
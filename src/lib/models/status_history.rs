@@ -19,16 +19,27 @@ pub enum Status {
     WillNotReport,
     CJReceived,
     CJNotReceived,
+    // The subscription's AIC has not been found yet. It may still arrive.
+    PendingAttribution,
+    // No AIC was found for the subscription within the attribution window.
+    Organic,
+    // Reporting to CJ failed report_max_attempts times. Only retried when asked to.
+    ReportFailed,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusHistoryEntry {
     pub t: OffsetDateTime,
     pub status: Status,
+    // Why the status changed, when the status alone doesn't say, e.g. why CJ rejected a report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 impl PartialEq for StatusHistoryEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.status == other.status && self.t.unix_timestamp() == other.t.unix_timestamp()
+        self.status == other.status
+            && self.reason == other.reason
+            && self.t.unix_timestamp() == other.t.unix_timestamp()
     }
 }
 impl Eq for StatusHistoryEntry {}
@@ -78,6 +89,10 @@ pub trait UpdateStatus {
     }
 
     fn update_status(&mut self, new_status: Status) {
+        self.update_status_with_reason(new_status, None);
+    }
+
+    fn update_status_with_reason(&mut self, new_status: Status, reason: Option<String>) {
         let t = OffsetDateTime::now_utc();
         self.set_status_t(Some(t));
         self.set_raw_status(Some(new_status.to_string()));
@@ -88,6 +103,7 @@ pub trait UpdateStatus {
         status_history.entries.push(StatusHistoryEntry {
             status: new_status,
             t,
+            reason,
         });
         self.set_raw_status_history(Some(json!(status_history)));
     }
@@ -4,7 +4,10 @@ use sqlx::{query, query_as, Error, PgPool};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::models::status_history::{Status, UpdateStatus};
+use crate::models::{
+    erasures::ERASED_PREFIX,
+    status_history::{Status, UpdateStatus},
+};
 
 use super::status_history::DateRange;
 
@@ -19,6 +22,8 @@ pub struct PartialSubscription {
     pub fxa_uid: String,
     pub quantity: i32,
     pub plan_id: String,
+    // The product the plan is for, when the BigQuery table has it
+    pub product_id: Option<String>,
     pub plan_currency: String,
     pub plan_amount: i32,
     pub country: Option<String>,
@@ -26,6 +31,10 @@ pub struct PartialSubscription {
     pub aic_id: Option<Uuid>,
     pub aic_expires: Option<OffsetDateTime>,
     pub cj_event_value: Option<String>,
+    // Why a subscription has no AIC, while it is pending attribution or once it is organic
+    pub unattributed_reason: Option<String>,
+    // The ClickMetadata of the attributed click
+    pub click_metadata: Option<JsonValue>,
 }
 
 #[derive(Debug, Serialize)]
@@ -39,6 +48,7 @@ pub struct Subscription {
     pub fxa_uid: String,
     pub quantity: i32,
     pub plan_id: String,
+    pub product_id: Option<String>,
     pub plan_currency: String,
     pub plan_amount: i32,
     pub country: Option<String>,
@@ -46,6 +56,13 @@ pub struct Subscription {
     pub aic_id: Option<Uuid>,
     pub aic_expires: Option<OffsetDateTime>,
     pub cj_event_value: Option<String>,
+    pub unattributed_reason: Option<String>,
+    pub click_metadata: Option<JsonValue>,
+    // How many times reporting to CJ was tried, why the last try failed, and when it may
+    // be tried again
+    pub report_attempts: i32,
+    pub report_last_error: Option<String>,
+    pub report_next_attempt: Option<OffsetDateTime>,
     // Note we use strings and json, not enums, in the database for simplicity
     status: Option<String>,
     status_t: Option<OffsetDateTime>,
@@ -62,12 +79,17 @@ impl PartialEq for Subscription {
         self.fxa_uid == other.fxa_uid &&
         self.quantity == other.quantity &&
         self.plan_id == other.plan_id &&
+        self.product_id == other.product_id &&
         self.plan_currency == other.plan_currency &&
         self.plan_amount == other.plan_amount &&
         self.country == other.country &&
         self.coupons == other.coupons &&
         self.aic_id == other.aic_id &&
         self.cj_event_value == other.cj_event_value &&
+        self.unattributed_reason == other.unattributed_reason &&
+        self.click_metadata == other.click_metadata &&
+        self.report_attempts == other.report_attempts &&
+        self.report_last_error == other.report_last_error &&
         self.status == other.status
         // Compare manually if needed
         // self.status_history == other.status_history
@@ -128,6 +150,7 @@ impl Subscription {
             fxa_uid: partial_sub.fxa_uid,
             quantity: partial_sub.quantity,
             plan_id: partial_sub.plan_id,
+            product_id: partial_sub.product_id,
             plan_currency: partial_sub.plan_currency,
             plan_amount: partial_sub.plan_amount,
             country: partial_sub.country,
@@ -135,6 +158,11 @@ impl Subscription {
             aic_id: partial_sub.aic_id,
             aic_expires: partial_sub.aic_expires,
             cj_event_value: partial_sub.cj_event_value,
+            unattributed_reason: partial_sub.unattributed_reason,
+            click_metadata: partial_sub.click_metadata,
+            report_attempts: 0,
+            report_last_error: None,
+            report_next_attempt: None,
             status: None,
             status_t: None,
             status_history: None,
@@ -144,6 +172,23 @@ impl Subscription {
     }
 }
 
+pub struct AttributionCounts {
+    pub attributed: i64,
+    pub organic: i64,
+    pub pending: i64,
+}
+
+impl AttributionCounts {
+    /// The percentage of subscriptions with a final attribution that came from an affiliate.
+    pub fn attribution_rate(&self) -> Option<f64> {
+        let finalized = self.attributed + self.organic;
+        match finalized {
+            0 => None,
+            _ => Some(100.0 * self.attributed as f64 / finalized as f64),
+        }
+    }
+}
+
 pub struct SubscriptionModel<'a> {
     pub db_pool: &'a PgPool,
 }
@@ -168,11 +213,17 @@ impl SubscriptionModel<'_> {
                 aic_id,
                 aic_expires,
                 cj_event_value,
+                unattributed_reason,
+                click_metadata,
                 status,
                 status_t,
-                status_history
+                status_history,
+                product_id,
+                report_attempts,
+                report_last_error,
+                report_next_attempt
              )
-			VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24)
 			RETURNING *",
             sub.id,
             sub.flow_id,
@@ -189,9 +240,15 @@ impl SubscriptionModel<'_> {
             sub.aic_id,
             sub.aic_expires,
             sub.cj_event_value,
+            sub.unattributed_reason,
+            sub.click_metadata,
             sub.status,
             sub.status_t,
             sub.status_history,
+            sub.product_id,
+            sub.report_attempts,
+            sub.report_last_error,
+            sub.report_next_attempt,
         )
         .fetch_one(self.db_pool)
         .await
@@ -251,6 +308,62 @@ impl SubscriptionModel<'_> {
         .await
     }
 
+    /// The NotReported subscriptions whose next report attempt isn't deferred past `now`.
+    pub async fn fetch_all_due_for_report(
+        &self,
+        now: OffsetDateTime,
+    ) -> Result<Vec<Subscription>, Error> {
+        query_as!(
+            Subscription,
+            r#"
+            SELECT *
+            FROM subscriptions
+            WHERE status = $1
+            AND status_t IS NOT NULL
+            AND (report_next_attempt IS NULL OR report_next_attempt <= $2)"#,
+            Status::NotReported.to_string(),
+            now,
+        )
+        .fetch_all(self.db_pool)
+        .await
+    }
+
+    pub async fn count_by_status(&self, status: Status) -> Result<i64, Error> {
+        let result = query!(
+            r#"SELECT COUNT(*) AS "count!" FROM subscriptions WHERE status = $1"#,
+            status.to_string()
+        )
+        .fetch_one(self.db_pool)
+        .await?;
+        Ok(result.count)
+    }
+
+    /// Saves the status and report_ columns after an attempt to report `sub` to CJ.
+    pub async fn update_report_attempt(&self, sub: &Subscription) -> Result<Subscription, Error> {
+        query_as!(
+            Subscription,
+            r#"UPDATE subscriptions
+            SET
+                report_attempts = $1,
+                report_last_error = $2,
+                report_next_attempt = $3,
+                status = $4,
+                status_t = $5,
+                status_history = $6
+            WHERE id = $7
+			RETURNING *"#,
+            sub.report_attempts,
+            sub.report_last_error,
+            sub.report_next_attempt,
+            sub.status,
+            sub.status_t,
+            sub.status_history,
+            sub.id,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
     pub async fn update_sub_status(
         &self,
         id: &Uuid,
@@ -276,6 +389,83 @@ impl SubscriptionModel<'_> {
         .await
     }
 
+    pub async fn update_attribution(&self, sub: &Subscription) -> Result<Subscription, Error> {
+        query_as!(
+            Subscription,
+            r#"UPDATE subscriptions
+            SET
+                aic_id = $1,
+                aic_expires = $2,
+                cj_event_value = $3,
+                unattributed_reason = $4,
+                click_metadata = $5,
+                status = $6,
+                status_t = $7,
+                status_history = $8
+            WHERE id = $9
+			RETURNING *"#,
+            sub.aic_id,
+            sub.aic_expires,
+            sub.cj_event_value,
+            sub.unattributed_reason,
+            sub.click_metadata,
+            sub.status,
+            sub.status_t,
+            sub.status_history,
+            sub.id,
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    /// Pseudonymizes up to `limit` subscriptions that reached one of `statuses` before
+    /// `status_before` and haven't been pseudonymized yet. Returns how many were.
+    pub async fn pseudonymize_by_status_before(
+        &self,
+        statuses: &[Status],
+        status_before: OffsetDateTime,
+        limit: i64,
+    ) -> Result<u64, Error> {
+        let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+        let mut transaction = self.db_pool.begin().await?;
+        let ids: Vec<Uuid> = query!(
+            "SELECT id FROM subscriptions
+            WHERE status = ANY($1)
+            AND status_t < $2
+            AND fxa_uid NOT LIKE $3 || '%'
+            LIMIT $4",
+            &statuses,
+            status_before,
+            ERASED_PREFIX,
+            limit,
+        )
+        .fetch_all(&mut transaction)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        let (n_pseudonymized, _) = pseudonymize_subscriptions(&mut transaction, &ids).await?;
+        transaction.commit().await?;
+        Ok(n_pseudonymized as u64)
+    }
+
+    pub async fn get_attribution_counts(&self) -> Result<AttributionCounts, Error> {
+        let result = query!(
+            r#"SELECT
+                COUNT(*) FILTER (WHERE aic_id IS NOT NULL) AS "attributed!",
+                COUNT(*) FILTER (WHERE status = 'Organic') AS "organic!",
+                COUNT(*) FILTER (WHERE status = 'PendingAttribution') AS "pending!"
+            FROM subscriptions"#,
+        )
+        .fetch_one(self.db_pool)
+        .await?;
+        Ok(AttributionCounts {
+            attributed: result.attributed,
+            organic: result.organic,
+            pending: result.pending,
+        })
+    }
+
     pub async fn get_reported_date_range(&self) -> Result<DateRange, Error> {
         let result = query!(
             "SELECT MIN(status_t), MAX(status_t) FROM subscriptions WHERE status = 'Reported' AND status_t IS NOT NULL",
@@ -289,6 +479,44 @@ impl SubscriptionModel<'_> {
     }
 }
 
+/// Replaces what identifies the person in the subscriptions with `ids`, and the ids of
+/// their refunds. Refunds are matched to their subscription by subscription_id, so it's
+/// replaced with the same value in both. Returns how many subscriptions and refunds changed.
+pub async fn pseudonymize_subscriptions(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ids: &[Uuid],
+) -> Result<(i64, i64), Error> {
+    let refunds = query!(
+        "UPDATE refunds
+        SET
+            refund_id = $1 || refunds.id::text,
+            subscription_id = $1 || subscriptions.id::text
+        FROM subscriptions
+        WHERE subscriptions.id = ANY($2)
+        AND refunds.subscription_id = subscriptions.subscription_id",
+        ERASED_PREFIX,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    let subscriptions = query!(
+        "UPDATE subscriptions
+        SET
+            flow_id = $1 || id::text,
+            subscription_id = $1 || id::text,
+            fxa_uid = $1 || id::text,
+            click_metadata = NULL
+        WHERE id = ANY($2)",
+        ERASED_PREFIX,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    Ok((subscriptions as i64, refunds as i64))
+}
+
 #[cfg(test)]
 pub mod test_subscriptions {
 
@@ -308,6 +536,7 @@ pub mod test_subscriptions {
             fxa_uid: random_ascii_string(),
             quantity: 1,
             plan_id: random_simple_ascii_string(),
+            product_id: None,
             plan_currency: random_currency_or_country(),
             plan_amount: random_price(),
             country: Some(random_currency_or_country()),
@@ -315,6 +544,8 @@ pub mod test_subscriptions {
             aic_id: Some(Uuid::new_v4()),
             aic_expires: Some(OffsetDateTime::now_utc()),
             cj_event_value: Some(random_ascii_string()),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     }
 
@@ -329,6 +560,7 @@ pub mod test_subscriptions {
             fxa_uid: random_simple_ascii_string(),
             quantity: 1,
             plan_id: random_simple_ascii_string(),
+            product_id: None,
             plan_currency: random_simple_ascii_string(),
             plan_amount: 1,
             country: None,
@@ -336,6 +568,8 @@ pub mod test_subscriptions {
             aic_id: None,
             aic_expires: None,
             cj_event_value: None,
+            unattributed_reason: None,
+            click_metadata: None,
         });
         let now = OffsetDateTime::now_utc();
         assert_eq!(new.get_status().unwrap(), Status::NotReported);
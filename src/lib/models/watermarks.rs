@@ -0,0 +1,66 @@
+use sqlx::{query, query_as, Error, PgPool};
+use time::OffsetDateTime;
+
+// The latest row timestamp a job has processed from BigQuery
+#[derive(Debug)]
+pub struct Watermark {
+    pub job: String,
+    pub watermark: OffsetDateTime,
+    pub updated: OffsetDateTime,
+}
+
+pub struct WatermarkModel<'a> {
+    pub db_pool: &'a PgPool,
+}
+
+impl WatermarkModel<'_> {
+    pub async fn fetch_one_by_job(&self, job: &str) -> Result<Watermark, Error> {
+        query_as!(Watermark, "SELECT * FROM watermarks WHERE job = $1", job)
+            .fetch_one(self.db_pool)
+            .await
+    }
+
+    pub async fn set(&self, job: &str, watermark: OffsetDateTime) -> Result<Watermark, Error> {
+        query_as!(
+            Watermark,
+            "INSERT INTO watermarks (job, watermark, updated)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (job) DO UPDATE
+            SET
+                watermark = EXCLUDED.watermark,
+                updated = EXCLUDED.updated
+            RETURNING *",
+            job,
+            watermark,
+            OffsetDateTime::now_utc(),
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn advance(&self, job: &str, watermark: OffsetDateTime) -> Result<Watermark, Error> {
+        // Never moves an existing watermark backwards
+        query_as!(
+            Watermark,
+            "INSERT INTO watermarks (job, watermark, updated)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (job) DO UPDATE
+            SET
+                watermark = GREATEST(watermarks.watermark, EXCLUDED.watermark),
+                updated = EXCLUDED.updated
+            RETURNING *",
+            job,
+            watermark,
+            OffsetDateTime::now_utc(),
+        )
+        .fetch_one(self.db_pool)
+        .await
+    }
+
+    pub async fn delete(&self, job: &str) -> Result<(), Error> {
+        query!("DELETE FROM watermarks WHERE job = $1", job)
+            .execute(self.db_pool)
+            .await?;
+        Ok(())
+    }
+}
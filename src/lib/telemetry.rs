@@ -22,10 +22,15 @@ use crate::version::{read_version, VERSION_FILE};
 #[derive(Debug, EnumToString, EnumString, PartialEq, Eq, Clone, Copy)]
 #[strum(serialize_all = "kebab_case")]
 pub enum LogKey {
+    AicIdempotencyKeyFetchFailed,
+    AicIdempotencyKeyRecordFailed,
+    AicIdempotencyKeyReplay,
+    AicIdempotencyKeyReused,
     AicRecordCreate,
     AicRecordCreateFailed,
     AicRecordUpdate,
     AicRecordUpdateFailed,
+    AicRecordUpdateFailedInvalidToken,
     AicRecordUpdateFailedNotFound,
     BatchRefunds,
     BatchRefundsEnding,
@@ -40,6 +45,10 @@ pub enum LogKey {
     CheckRefundsDeserializeBigQuery,
     CheckRefundsDeserializeBigQueryFailed,
     CheckRefundsEnding,
+    CheckRefundsErasureLookupFailed,
+    CheckRefundsFailed,
+    CheckRefundsIngestFailureRecord,
+    CheckRefundsIngestFailureRecordFailed,
     CheckRefundsNFromBq,
     CheckRefundsRefundCreate,
     CheckRefundsRefundCreateDatabaseError,
@@ -47,6 +56,7 @@ pub enum LogKey {
     CheckRefundsRefundCreateFailed,
     CheckRefundsRefundDataChanged,
     CheckRefundsRefundDataUnchanged,
+    CheckRefundsRefundErased,
     CheckRefundsRefundFetchFailed,
     CheckRefundsRefundUpdate,
     CheckRefundsRefundUpdateFailed,
@@ -54,28 +64,62 @@ pub enum LogKey {
     CheckRefundsSubscriptionMissingFromDatabase,
     CheckRefundsTimer,
     CheckRefundsTotalNFromBq,
+    CheckRefundsWatermarkAdvance,
+    CheckRefundsWatermarkAdvanceFailed,
+    CheckRefundsWatermarkReset,
+    CheckRefundsWatermarkSet,
     CheckSubscriptions,
     CheckSubscriptionsAicArchive,
     CheckSubscriptionsAicArchiveFailed,
+    CheckSubscriptionsAicClicksFetchFailed,
     CheckSubscriptionsAicFetch,
     CheckSubscriptionsAicFetchFailed,
     CheckSubscriptionsAicFetchFromArchive,
+    CheckSubscriptionsAttributionCountsFailed,
+    CheckSubscriptionsAttributionNAttributed,
+    CheckSubscriptionsAttributionNoQualifyingClick,
+    CheckSubscriptionsAttributionNOrganic,
+    CheckSubscriptionsAttributionNPending,
+    CheckSubscriptionsAttributionOrganic,
+    CheckSubscriptionsAttributionPending,
+    CheckSubscriptionsAttributionRate,
+    CheckSubscriptionsAttributionResolved,
+    CheckSubscriptionsAttributionUpdateFailed,
     CheckSubscriptionsBytesFromBq,
     CheckSubscriptionsDeserializeBigQuery,
     CheckSubscriptionsDeserializeBigQueryFailed,
     CheckSubscriptionsEnding,
+    CheckSubscriptionsErasureLookupFailed,
+    CheckSubscriptionsFailed,
+    CheckSubscriptionsIngestFailureRecord,
+    CheckSubscriptionsIngestFailureRecordFailed,
     CheckSubscriptionsNFromBq,
     CheckSubscriptionsStarting,
     CheckSubscriptionsSubscriptionCreate,
     CheckSubscriptionsSubscriptionCreateDatabaseError,
     CheckSubscriptionsSubscriptionCreateDuplicateKeyViolation,
     CheckSubscriptionsSubscriptionCreateFailed,
+    CheckSubscriptionsSubscriptionErased,
     CheckSubscriptionsTimer,
     CheckSubscriptionsTotalNFromBq,
+    CheckSubscriptionsWatermarkAdvance,
+    CheckSubscriptionsWatermarkAdvanceFailed,
+    CheckSubscriptionsWatermarkReset,
+    CheckSubscriptionsWatermarkSet,
     Cleanup,
+    ClickAicCreate,
+    ClickAicCreateFailed,
+    ClickInvalidCjevent,
+    ClickInvalidMetadata,
+    ClickTargetNotAllowed,
     CleanupAicArchive,
+    CleanupAicArchiveChunkSize,
+    CleanupAicArchiveCursorFailed,
     CleanupAicArchiveFailed,
+    CleanupAicArchiveNArchived,
     CleanupEnding,
+    CleanupIdempotencyKeysDelete,
+    CleanupIdempotencyKeysDeleteFailed,
     CleanupStarting,
     CleanupTimer,
     CorrectionsReport,
@@ -83,19 +127,63 @@ pub enum LogKey {
     CorrectionsReportTodayAccessed,
     CorrectionsSubscriptionFetch,
     CorrectionsSubscriptionFetchFailed,
+    Erasures,
+    ErasuresEnding,
+    ErasuresErase,
+    ErasuresEraseFailed,
+    ErasuresFailed,
+    ErasuresStarting,
+    ErasuresTimer,
+    IngestFailures,
+    IngestFailuresDismiss,
+    IngestFailuresEnding,
+    IngestFailuresFailed,
+    IngestFailuresRetry,
+    IngestFailuresRetryFailed,
+    IngestFailuresStarting,
+    IngestFailuresTimer,
+    Purge,
+    PurgeAicArchive,
+    PurgeAicArchiveFailed,
+    PurgeAicArchiveNPurged,
+    PurgeCjS2sLog,
+    PurgeCjS2sLogFailed,
+    PurgeCjS2sLogNPurged,
+    PurgeEnding,
+    PurgeRefunds,
+    PurgeRefundsFailed,
+    PurgeRefundsNPurged,
+    PurgeStarting,
+    PurgeSubscriptions,
+    PurgeSubscriptionsFailed,
+    PurgeSubscriptionsNPurged,
+    PurgeTimer,
     RequestAicCreate,
+    RequestAicInvalid,
     RequestAicUpdate,
+    RequestClick,
+    RequestPersonalDataDelete,
+    RequestPersonalDataDeleteInvalid,
     ReportSubscriptionMarkNotReported,
     ReportSubscriptionMarkNotReportedFailed,
+    ReportSubscriptionMarkReportFailed,
+    ReportSubscriptionMarkReportFailedFailed,
     ReportSubscriptionMarkWillNotReport,
     ReportSubscriptionMarkWillNotReportFailed,
     ReportSubscriptionReportToCj,
     ReportSubscriptionReportToCjButCouldNotMarkReported,
     ReportSubscriptionReportToCjFailed,
+    ReportSubscriptionReportToCjTimer,
+    ReportSubscriptionReportToCjUnknownResponse,
+    ReportSubscriptionS2sLogFailed,
     ReportSubscriptions,
     ReportSubscriptionsAicExpiredBeforeSubscriptionCreated,
     ReportSubscriptionsEnding,
+    ReportSubscriptionsFailed,
     ReportSubscriptionsNNotReported,
+    ReportSubscriptionsNReportFailed,
+    ReportSubscriptionsNReportFailedFailed,
+    ReportSubscriptionsRetry,
     ReportSubscriptionsStarting,
     ReportSubscriptionsSubscriptionHasNoAicExpiry,
     ReportSubscriptionsTimer,
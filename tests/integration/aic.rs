@@ -1,14 +1,20 @@
+use futures::future::join_all;
 use lib::{
-    controllers::aic::AICResponse,
-    models::aic::{AICModel, AIC},
+    controllers::{aic::AICResponse, errors::ErrorResponse},
+    models::{
+        aic::{AICModel, AIC},
+        aic_clicks::ClickMetadata,
+    },
     settings::Settings,
 };
+use secrecy::Secret;
 use serde_json::json;
 use time::OffsetDateTime;
 use uuid::{Uuid, Version};
 
 use crate::utils::{
-    random_ascii_string, send_get_request, send_post_request, send_put_request, spawn_app, TestApp,
+    random_ascii_string, random_simple_ascii_string, send_get_request, send_post_request,
+    send_put_request, spawn_app, spawn_app_with_settings, TestApp,
 };
 
 #[tokio::test]
@@ -37,11 +43,89 @@ async fn aic_create_with_bad_data() {
     for data in test_cases {
         let r = send_post_request(&app, "/aic", data).await;
         assert_eq!(r.status(), 400);
-        let response = r.text().await.expect("Failed to get response text.");
-        assert!(response.contains("Json deserialize error"));
+        let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+        assert_eq!(response.code, "invalid_json");
+        assert!(response.message.contains("Json deserialize error"));
     }
 }
 
+#[tokio::test]
+async fn aic_create_with_invalid_values() {
+    let app = spawn_app().await;
+    let test_cases = [
+        json!({
+            "flow_id": "",
+            "cj_id": random_simple_ascii_string(),
+        }),
+        json!({
+            "flow_id": "a".repeat(129),
+            "cj_id": random_simple_ascii_string(),
+        }),
+        json!({
+            "flow_id": "has a space",
+            "cj_id": random_simple_ascii_string(),
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": "",
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": "a".repeat(129),
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": "<script>",
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": random_simple_ascii_string(),
+            "click_metadata": {"publisher_id": ""},
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": random_simple_ascii_string(),
+            "click_metadata": {"sid": "a".repeat(129)},
+        }),
+        json!({
+            "flow_id": random_ascii_string(),
+            "cj_id": random_simple_ascii_string(),
+            "click_metadata": {"landing_page": "javascript:alert(1)"},
+        }),
+    ];
+    for data in test_cases {
+        let r = send_post_request(&app, "/aic", data.clone()).await;
+        assert_eq!(r.status(), 400, "Failed on: {}", data);
+        let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+        assert_eq!(response.code, "invalid_input");
+    }
+}
+
+#[tokio::test]
+async fn aic_create_with_oversized_body() {
+    let app = spawn_app().await;
+    let data = json!({
+        "flow_id": random_ascii_string(),
+        "cj_id": random_simple_ascii_string(),
+        "padding": "a".repeat(5000),
+    });
+    let r = send_post_request(&app, "/aic", data).await;
+    assert_eq!(r.status(), 413);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "payload_too_large");
+}
+
+#[tokio::test]
+async fn aic_create_with_duplicate_flow_id() {
+    let setup = setup_aic_test().await;
+    let r = send_post_request(&setup.app, "/aic", setup.data.clone()).await;
+    assert_eq!(r.status(), 201);
+    let r = send_post_request(&setup.app, "/aic", setup.data).await;
+    assert_eq!(r.status(), 409);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "conflict");
+}
+
 #[tokio::test]
 async fn aic_create_success() {
     /* Caller sends flowId and CJEvent value and not an AIC value
@@ -57,7 +141,7 @@ async fn aic_create_success() {
     assert_eq!(r.status(), 201);
     let response: AICResponse = r.json().await.expect("Failed to get JSON response.");
     // Should be UUID v4 aka Version::Random
-    assert_eq!(Some(Version::Random), response.aic_id.get_version());
+    assert_eq!(Some(Version::Random), unsigned_id(&response).get_version());
     /*
     Expires date is X days from today (per settings value)
     (because we created the expires a few nano seconds ago, this is a minute under 30 days)
@@ -69,7 +153,7 @@ async fn aic_create_success() {
     let saved = assert_saved(
         model,
         &setup.app.settings,
-        response.aic_id,
+        unsigned_id(&response),
         response.expires.unix_timestamp(),
         setup.cj_event_value,
         setup.flow_id,
@@ -81,6 +165,42 @@ async fn aic_create_success() {
     );
 }
 
+#[tokio::test]
+async fn aic_create_with_click_metadata() {
+    let setup = setup_aic_test().await;
+    let model = AICModel {
+        db_pool: &setup.app.db_connection(),
+    };
+    let click_metadata = json!({
+        "publisher_id": "1234",
+        "ad_id": "5678",
+        "sid": "a publisher's sid",
+        "landing_page": "https://www.mozilla.org/products/vpn/?utm_source=cj",
+        "utm_source": "cj",
+        "utm_campaign": "spring",
+    });
+    let data = json!({
+        "flow_id": setup.flow_id,
+        "cj_id": setup.cj_event_value,
+        "click_metadata": click_metadata,
+    });
+    let r = send_post_request(&setup.app, "/aic", data).await;
+    assert_eq!(r.status(), 201);
+    let saved = model.fetch_one().await.expect("Failed to get DB response.");
+    assert_eq!(saved.click_metadata, Some(click_metadata));
+
+    // Unknown fields are rejected, in case of typos
+    let data = json!({
+        "flow_id": random_ascii_string(),
+        "cj_id": random_simple_ascii_string(),
+        "click_metadata": {"publisher": "1234"},
+    });
+    let r = send_post_request(&setup.app, "/aic", data).await;
+    assert_eq!(r.status(), 400);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "invalid_json");
+}
+
 #[tokio::test]
 async fn aic_update_with_existing_aic_and_new_flow_and_cjid() {
     /* Caller sends AIC id, flowId, new CJEvent value
@@ -95,7 +215,12 @@ async fn aic_update_with_existing_aic_and_new_flow_and_cjid() {
     let cj_event_value_orig = setup.cj_event_value;
     let flow_id_orig = setup.flow_id;
     let aic_orig = model
-        .create(&cj_event_value_orig, &flow_id_orig, &setup.app.settings)
+        .create(
+            &cj_event_value_orig,
+            &flow_id_orig,
+            &ClickMetadata::default(),
+            &setup.app.settings,
+        )
         .await
         .expect("Failed to create test object.");
     // Make sure time has passed so timestamps are different
@@ -111,13 +236,13 @@ async fn aic_update_with_existing_aic_and_new_flow_and_cjid() {
     let r = send_put_request(&setup.app, &path, update_data).await;
     assert_eq!(r.status(), 201);
     let response: AICResponse = r.json().await.expect("Failed to get JSON response.");
-    assert_eq!(aic_orig.id, response.aic_id);
+    assert_eq!(aic_orig.id, unsigned_id(&response));
     // New expires time should be later than the original
     assert!(response.expires > aic_orig.expires);
     assert_saved(
         model,
         &setup.app.settings,
-        response.aic_id,
+        unsigned_id(&response),
         response.expires.unix_timestamp(),
         cj_event_value_new,
         flow_id_new,
@@ -139,7 +264,12 @@ async fn aic_update_when_aic_and_cjevent_exists() {
     let cj_event_value_orig = setup.cj_event_value;
     let flow_id_orig = setup.flow_id;
     let aic_orig = model
-        .create(&cj_event_value_orig, &flow_id_orig, &setup.app.settings)
+        .create(
+            &cj_event_value_orig,
+            &flow_id_orig,
+            &ClickMetadata::default(),
+            &setup.app.settings,
+        )
         .await
         .expect("Failed to create test object.");
     std::thread::sleep(std::time::Duration::from_secs(1));
@@ -153,7 +283,7 @@ async fn aic_update_when_aic_and_cjevent_exists() {
     let r = send_put_request(&setup.app, &path, update_data).await;
     assert_eq!(r.status(), 201);
     let response: AICResponse = r.json().await.expect("Failed to get JSON response.");
-    assert_eq!(aic_orig.id, response.aic_id);
+    assert_eq!(aic_orig.id, unsigned_id(&response));
     assert_eq!(
         response.expires.unix_timestamp(),
         aic_orig.expires.unix_timestamp()
@@ -161,7 +291,7 @@ async fn aic_update_when_aic_and_cjevent_exists() {
     assert_saved(
         model,
         &setup.app.settings,
-        response.aic_id,
+        unsigned_id(&response),
         response.expires.unix_timestamp(),
         cj_event_value_orig,
         flow_id_new,
@@ -183,7 +313,12 @@ async fn aic_update_with_new_flow_id() {
     let cj_event_value_orig = setup.cj_event_value;
     let flow_id_orig = setup.flow_id;
     let aic_orig = model
-        .create(&cj_event_value_orig, &flow_id_orig, &setup.app.settings)
+        .create(
+            &cj_event_value_orig,
+            &flow_id_orig,
+            &ClickMetadata::default(),
+            &setup.app.settings,
+        )
         .await
         .expect("Failed to create test object.");
     std::thread::sleep(std::time::Duration::from_secs(1));
@@ -196,7 +331,7 @@ async fn aic_update_with_new_flow_id() {
     let r = send_put_request(&setup.app, &path, update_data).await;
     assert_eq!(r.status(), 201);
     let response: AICResponse = r.json().await.expect("Failed to get JSON response.");
-    assert_eq!(aic_orig.id, response.aic_id);
+    assert_eq!(aic_orig.id, unsigned_id(&response));
     assert_eq!(
         response.expires.unix_timestamp(),
         aic_orig.expires.unix_timestamp()
@@ -204,7 +339,7 @@ async fn aic_update_with_new_flow_id() {
     assert_saved(
         model,
         &setup.app.settings,
-        response.aic_id,
+        unsigned_id(&response),
         response.expires.unix_timestamp(),
         cj_event_value_orig,
         flow_id_new,
@@ -221,10 +356,209 @@ async fn aic_update_when_no_aic_exists() {
     let path = format!("/aic/{}", Uuid::new_v4());
     let r = send_put_request(&setup.app, &path, setup.data).await;
     assert_eq!(r.status(), 404);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "not_found");
+}
+
+#[tokio::test]
+async fn aic_update_with_invalid_aic_id_or_values() {
+    let setup = setup_aic_test().await;
+    let r = send_put_request(&setup.app, "/aic/123", setup.data).await;
+    assert_eq!(r.status(), 400);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "invalid_input");
+
+    let path = format!("/aic/{}", Uuid::new_v4());
+    let data = json!({
+        "flow_id": setup.flow_id,
+        "cj_id": "not valid!",
+    });
+    let r = send_put_request(&setup.app, &path, data).await;
+    assert_eq!(r.status(), 400);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "invalid_input");
+}
+
+#[tokio::test]
+async fn aic_update_to_a_flow_id_that_is_already_taken() {
+    let setup = setup_aic_test().await;
+    let model = AICModel {
+        db_pool: &setup.app.db_connection(),
+    };
+    let taken = model
+        .create(
+            &setup.cj_event_value,
+            &setup.flow_id,
+            &ClickMetadata::default(),
+            &setup.app.settings,
+        )
+        .await
+        .expect("Failed to create test object.");
+    let other = model
+        .create(
+            &setup.cj_event_value,
+            &random_ascii_string(),
+            &ClickMetadata::default(),
+            &setup.app.settings,
+        )
+        .await
+        .expect("Failed to create test object.");
+    let path = format!("/aic/{}", other.id);
+    let data = json!({
+        "flow_id": taken.flow_id,
+    });
+    let r = send_put_request(&setup.app, &path, data).await;
+    assert_eq!(r.status(), 409);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "conflict");
+}
+
+#[tokio::test]
+async fn aic_create_and_update_with_signed_tokens() {
+    let app = spawn_app_with_settings(|settings| {
+        settings.aic_signing_keys = Secret::new("k2:new-secret,k1:old-secret".to_string());
+        settings.aic_accept_unsigned_ids = false;
+    })
+    .await;
+    let model = AICModel {
+        db_pool: &app.db_connection(),
+    };
+    let data = json!({
+        "flow_id": random_ascii_string(),
+        "cj_id": random_simple_ascii_string(),
+    });
+    let r = send_post_request(&app, "/aic", data).await;
+    assert_eq!(r.status(), 201);
+    let created: AICResponse = r.json().await.expect("Failed to get JSON response.");
+    assert!(created.aic_id.starts_with("k2."));
+    let saved = model.fetch_one().await.expect("Failed to get DB response.");
+
+    let update_data = json!({
+        "flow_id": random_ascii_string(),
+    });
+    let r = send_put_request(&app, &format!("/aic/{}", created.aic_id), update_data).await;
+    assert_eq!(r.status(), 201);
+    let updated: AICResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(updated.aic_id, created.aic_id);
+
+    // A bare UUID is rejected once unsigned ids are turned off
+    let update_data = json!({
+        "flow_id": random_ascii_string(),
+    });
+    let r = send_put_request(&app, &format!("/aic/{}", saved.id), update_data).await;
+    assert_eq!(r.status(), 403);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "invalid_token");
+
+    // So is a token with a tampered signature
+    let (signed, signature) = created.aic_id.rsplit_once('.').unwrap();
+    let replacement = if signature.starts_with('A') { "B" } else { "A" };
+    let tampered = format!("{}.{}{}", signed, replacement, &signature[1..]);
+    let update_data = json!({
+        "flow_id": random_ascii_string(),
+    });
+    let r = send_put_request(&app, &format!("/aic/{}", tampered), update_data).await;
+    assert_eq!(r.status(), 403);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "invalid_token");
+}
+
+#[tokio::test]
+async fn aic_create_with_idempotency_key() {
+    let setup = setup_aic_test().await;
+    let model = AICModel {
+        db_pool: &setup.app.db_connection(),
+    };
+    let r = send_post_request_with_idempotency_key(&setup.app, "a-key", setup.data.clone()).await;
+    assert_eq!(r.status(), 201);
+    let first: AICResponse = r.json().await.expect("Failed to get JSON response.");
+
+    // A retry returns the original response instead of a conflict
+    let r = send_post_request_with_idempotency_key(&setup.app, "a-key", setup.data.clone()).await;
+    assert_eq!(r.status(), 201);
+    let retried: AICResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(retried.aic_id, first.aic_id);
+    assert_eq!(
+        retried.expires.unix_timestamp(),
+        first.expires.unix_timestamp()
+    );
+    model.fetch_one().await.expect("Expected a single AIC.");
+
+    // Reusing the key for another request is rejected
+    let other_data = json!({
+        "flow_id": random_ascii_string(),
+        "cj_id": setup.cj_event_value,
+    });
+    let r = send_post_request_with_idempotency_key(&setup.app, "a-key", other_data.clone()).await;
+    assert_eq!(r.status(), 422);
+    let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response.code, "idempotency_key_reused");
+
+    // Another key is a new request
+    let r = send_post_request_with_idempotency_key(&setup.app, "b-key", other_data).await;
+    assert_eq!(r.status(), 201);
+    let other: AICResponse = r.json().await.expect("Failed to get JSON response.");
+    assert_ne!(other.aic_id, first.aic_id);
+
+    // So is a request without a key
+    let r = send_post_request(&setup.app, "/aic", setup.data).await;
+    assert_eq!(r.status(), 409);
+}
+
+#[tokio::test]
+async fn aic_create_with_idempotency_key_concurrently() {
+    let setup = setup_aic_test().await;
+    let model = AICModel {
+        db_pool: &setup.app.db_connection(),
+    };
+    // They all miss the key, one creates the AIC and the others replay its response
+    let responses =
+        join_all((0..5).map(|_| {
+            send_post_request_with_idempotency_key(&setup.app, "a-key", setup.data.clone())
+        }))
+        .await;
+    let mut aic_ids = Vec::new();
+    for r in responses {
+        assert_eq!(r.status(), 201);
+        let response: AICResponse = r.json().await.expect("Failed to get JSON response.");
+        aic_ids.push(response.aic_id);
+    }
+    aic_ids.dedup();
+    assert_eq!(aic_ids.len(), 1);
+    model.fetch_one().await.expect("Expected a single AIC.");
+}
+
+#[tokio::test]
+async fn aic_create_with_invalid_idempotency_key() {
+    let setup = setup_aic_test().await;
+    for key in ["", &"a".repeat(256), "has space"] {
+        let r = send_post_request_with_idempotency_key(&setup.app, key, setup.data.clone()).await;
+        assert_eq!(r.status(), 400, "Failed on: {}", key);
+        let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+        assert_eq!(response.code, "invalid_input");
+    }
 }
 
 ///// HELPERS
 
+async fn send_post_request_with_idempotency_key(
+    app: &TestApp,
+    key: &str,
+    data: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(app.build_url("/aic"))
+        .header("Idempotency-Key", key)
+        .json(&data)
+        .send()
+        .await
+        .expect("Failed to POST")
+}
+
+fn unsigned_id(response: &AICResponse) -> Uuid {
+    Uuid::parse_str(&response.aic_id).expect("Expected a bare UUID aic_id.")
+}
+
 struct TestData {
     app: TestApp,
     cj_event_value: String,
@@ -234,7 +568,7 @@ struct TestData {
 
 async fn setup_aic_test() -> TestData {
     let app = spawn_app().await;
-    let cj_event_value = random_ascii_string();
+    let cj_event_value = random_simple_ascii_string();
     let flow_id = random_ascii_string();
     let data = json!({
         "flow_id": flow_id,
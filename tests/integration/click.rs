@@ -0,0 +1,119 @@
+use actix_web::cookie::{Cookie, SameSite};
+use lib::{controllers::errors::ErrorResponse, models::aic::AICModel};
+use reqwest::{header, redirect::Policy, Response};
+use secrecy::Secret;
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::utils::{random_simple_ascii_string, spawn_app_with_settings, TestApp};
+
+const TARGET: &str = "https://www.mozilla.org/products/vpn/";
+
+async fn spawn_click_app() -> TestApp {
+    spawn_app_with_settings(|settings| {
+        settings.aic_cookie_domain = Some(".mozilla.org".to_string());
+        settings.aic_signing_keys = Secret::new("k1:secret".to_string());
+        settings.click_redirect_allowlist = TARGET.to_string();
+    })
+    .await
+}
+
+async fn send_click(app: &TestApp, query: &[(&str, &str)]) -> Response {
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .expect("Failed to build client");
+    client
+        .get(app.build_url("/click"))
+        .query(query)
+        .send()
+        .await
+        .expect("Failed to GET")
+}
+
+#[tokio::test]
+async fn click_creates_an_aic_sets_the_cookie_and_redirects() {
+    let app = spawn_click_app().await;
+    let model = AICModel {
+        db_pool: &app.db_connection(),
+    };
+    let cjevent = random_simple_ascii_string();
+    let target = format!("{}?utm_source=cj", TARGET);
+    let r = send_click(
+        &app,
+        &[
+            ("cjevent", &cjevent),
+            ("target", &target),
+            ("publisher_id", "1234"),
+            ("utm_source", "cj"),
+        ],
+    )
+    .await;
+    assert_eq!(r.status(), 302);
+    assert_eq!(r.headers()[header::LOCATION], target.as_str());
+
+    let saved = model.fetch_one().await.expect("Failed to get DB response.");
+    assert_eq!(saved.cj_event_value, cjevent);
+    assert_eq!(
+        saved.click_metadata,
+        Some(json!({
+            "publisher_id": "1234",
+            "landing_page": target,
+            "utm_source": "cj",
+        }))
+    );
+    assert!(saved.flow_id.starts_with("click-"));
+    assert_eq!(
+        (saved.expires - OffsetDateTime::now_utc()).whole_days() + 1,
+        app.settings.aic_expiration_days as i64
+    );
+
+    let cookie = Cookie::parse(
+        r.headers()[header::SET_COOKIE]
+            .to_str()
+            .expect("Expected the AIC cookie to be set"),
+    )
+    .expect("Failed to parse cookie");
+    assert_eq!(cookie.name(), "aic");
+    assert!(cookie.value().starts_with("k1."));
+    assert_eq!(cookie.domain(), Some("mozilla.org"));
+    assert_eq!(cookie.path(), Some("/"));
+    assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+    assert_eq!(
+        cookie
+            .expires_datetime()
+            .expect("Expected cookie to expire")
+            .unix_timestamp(),
+        saved.expires.unix_timestamp()
+    );
+}
+
+#[tokio::test]
+async fn click_without_a_valid_cjevent_redirects_without_a_cookie() {
+    let app = spawn_click_app().await;
+    for query in [
+        vec![("target", TARGET)],
+        vec![("cjevent", "<script>"), ("target", TARGET)],
+    ] {
+        let r = send_click(&app, &query).await;
+        assert_eq!(r.status(), 302);
+        assert_eq!(r.headers()[header::LOCATION], TARGET);
+        assert!(r.headers().get(header::SET_COOKIE).is_none());
+    }
+}
+
+#[tokio::test]
+async fn click_with_a_target_that_is_not_allowed() {
+    let app = spawn_click_app().await;
+    for query in [
+        vec![("cjevent", "abc"), ("target", "https://evil.example.com/")],
+        vec![("cjevent", "abc"), ("target", "not a url")],
+        vec![("cjevent", "abc")],
+    ] {
+        let r = send_click(&app, &query).await;
+        assert_eq!(r.status(), 400);
+        assert!(r.headers().get(header::SET_COOKIE).is_none());
+        let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+        assert_eq!(response.code, "invalid_input");
+    }
+}
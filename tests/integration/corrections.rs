@@ -1,5 +1,7 @@
 use lib::models::{refunds::RefundModel, subscriptions::SubscriptionModel};
 use reqwest::Response;
+use secrecy::Secret;
+use serde_json::json;
 use time::{date, Date, OffsetDateTime};
 
 use crate::{
@@ -7,7 +9,7 @@ use crate::{
         refunds::{make_fake_refund, save_refund},
         subscriptions::{make_fake_sub, save_sub},
     },
-    utils::spawn_app,
+    utils::{spawn_app, spawn_app_with_settings},
 };
 
 const ANOTHER_DAY: Date = date!(2021 - 11 - 07);
@@ -165,3 +167,78 @@ RETRN,,{}"#,
     );
     assert_eq!(actual_body, expected_body);
 }
+
+#[tokio::test]
+async fn test_corrections_by_program() {
+    let app = spawn_app_with_settings(|settings| {
+        settings.cj_programs = Secret::new(
+            json!([{
+                "name": "relay",
+                "cid": "relay cid",
+                "type": "relay type",
+                "signature": "relay signature",
+                "subid": "relay subid",
+                "plan_ids": ["price_relay"],
+            }])
+            .to_string(),
+        )
+    })
+    .await;
+    let db_pool = app.db_connection();
+    let refunds = RefundModel { db_pool: &db_pool };
+    let subs = SubscriptionModel { db_pool: &db_pool };
+    let mut vpn_refund = make_fake_refund();
+    vpn_refund.correction_file_date = Some(ANOTHER_DAY);
+    let mut vpn_sub = make_fake_sub();
+    vpn_sub.subscription_id = vpn_refund.subscription_id.clone();
+    let mut relay_refund = make_fake_refund();
+    relay_refund.correction_file_date = Some(ANOTHER_DAY);
+    let mut relay_sub = make_fake_sub();
+    relay_sub.subscription_id = relay_refund.subscription_id.clone();
+    relay_sub.plan_id = "price_relay".to_string();
+    for r in [&vpn_refund, &relay_refund] {
+        save_refund(&refunds, r).await;
+    }
+    for s in [&vpn_sub, &relay_sub] {
+        save_sub(&subs, s).await;
+    }
+
+    let path = app.build_url("/corrections/relay/2021-11-07.csv");
+    let r = get_authed_path(&path, &app.settings.authentication).await;
+    assert_eq!(r.status(), 200);
+    let expected_body = format!(
+        r#"&CID={}
+&SUBID=relay subid
+RETRN,,{}"#,
+        app.settings.cj_sftp_user, relay_sub.id
+    );
+    assert_eq!(r.text().await.unwrap(), expected_body);
+
+    // The default program leaves out the other programs' subscriptions
+    for path in [
+        "/corrections/2021-11-07.csv",
+        "/corrections/default/2021-11-07.csv",
+    ] {
+        let r = get_authed_path(&app.build_url(path), &app.settings.authentication).await;
+        assert_eq!(r.status(), 200);
+        let expected_body = format!(
+            r#"&CID={}
+&SUBID={}
+RETRN,,{}"#,
+            app.settings.cj_sftp_user, app.settings.cj_subid, vpn_sub.id
+        );
+        assert_eq!(r.text().await.unwrap(), expected_body);
+    }
+
+    let r = reqwest::get(app.build_url("/corrections/relay/today.csv"))
+        .await
+        .expect("Failed to GET");
+    assert_eq!(r.status(), 200);
+    assert!(r.text().await.unwrap().contains("&SUBID=relay subid"));
+    let r = get_authed_path(
+        &app.build_url("/corrections/unknown/2021-11-07.csv"),
+        &app.settings.authentication,
+    )
+    .await;
+    assert_eq!(r.status(), 404);
+}
@@ -0,0 +1,75 @@
+use lib::{
+    controllers::errors::ErrorResponse,
+    models::{aic::AICModel, erasures::ErasureModel},
+};
+use pretty_assertions::assert_eq;
+use reqwest::Response;
+use serde_json::{json, Value};
+
+use crate::models::aic::make_fake_aic;
+use crate::utils::{spawn_app, TestApp};
+
+async fn send_delete_request(app: &TestApp, password: Option<&str>, data: Value) -> Response {
+    let mut request = reqwest::Client::new()
+        .delete(app.build_url("/personal-data"))
+        .json(&data);
+    if let Some(password) = password {
+        request = request.basic_auth("privacy", Some(password));
+    }
+    request.send().await.expect("Failed to DELETE")
+}
+
+#[tokio::test]
+async fn personal_data_delete_requires_auth() {
+    let app = spawn_app().await;
+    let data = json!({"flow_id": "a-flow-id"});
+    let r = send_delete_request(&app, None, data.clone()).await;
+    assert_eq!(r.status(), 401);
+    let r = send_delete_request(&app, Some("not the password"), data).await;
+    assert_eq!(r.status(), 401);
+}
+
+#[tokio::test]
+async fn personal_data_delete_requires_exactly_one_identifier() {
+    let app = spawn_app().await;
+    for data in [
+        json!({}),
+        json!({"fxa_uid": "a-uid", "flow_id": "a-flow-id"}),
+        json!({"flow_id": ""}),
+    ] {
+        let r = send_delete_request(&app, Some(&app.settings.authentication), data.clone()).await;
+        assert_eq!(r.status(), 400, "{}", data);
+        let response: ErrorResponse = r.json().await.expect("Failed to get JSON response.");
+        assert_eq!(response.code, "invalid_input");
+    }
+}
+
+#[tokio::test]
+async fn personal_data_delete_erases_and_returns_the_audit_log_entry() {
+    let app = spawn_app().await;
+    let db_pool = app.db_connection();
+    let aics = AICModel { db_pool: &db_pool };
+    let aic = make_fake_aic();
+    aics.create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+
+    let r = send_delete_request(
+        &app,
+        Some(&app.settings.authentication),
+        json!({ "aic_id": aic.id }),
+    )
+    .await;
+    assert_eq!(r.status(), 200);
+    let response: Value = r.json().await.expect("Failed to get JSON response.");
+    assert_eq!(response["identifier_type"], "aic_id");
+    assert_eq!(response["requested_by"], "api:privacy");
+    assert_eq!(response["aic_deleted"], 1);
+    assert!(aics.fetch_one_by_id(&aic.id).await.is_err());
+    let logged = ErasureModel { db_pool: &db_pool }
+        .fetch_all()
+        .await
+        .expect("Could not fetch audit log");
+    assert_eq!(logged.len(), 1);
+    assert_eq!(response["id"], logged[0].id.to_string());
+}
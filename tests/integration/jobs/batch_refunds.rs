@@ -76,7 +76,8 @@ async fn batch_refunds_by_day_makes_unreported_subscriptions_reported_and_gives_
             refund_updated_history.entries[1],
             StatusHistoryEntry {
                 status: Status::Reported,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -89,7 +90,8 @@ async fn batch_refunds_by_day_makes_unreported_subscriptions_reported_and_gives_
             refund_updated_history.entries[1],
             StatusHistoryEntry {
                 status: Status::WillNotReport,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -3,12 +3,18 @@ use std::fs::File;
 use std::io::Read;
 
 use lib::bigquery::client::{AccessTokenFromEnv, BQClient};
-use lib::jobs::check_refunds::fetch_and_process_refunds;
+use lib::jobs::check_refunds::{fetch_and_process_refunds, process_refund};
+use lib::jobs::ingest_failures::retry_ingest_failure;
+use lib::jobs::watermark::fetch_since;
+use lib::models::aic_clicks::AttributionModel;
+use lib::models::erasures::{ErasureIdentifier, ErasureModel, ERASED_PREFIX};
+use lib::models::ingest_failures::{IngestFailure, IngestFailureModel};
 use lib::models::refunds::{PartialRefund, Refund, RefundModel};
 use lib::models::status_history::{Status, UpdateStatus};
 use lib::models::subscriptions::SubscriptionModel;
+use lib::models::watermarks::WatermarkModel;
 use lib::settings::get_settings;
-use lib::telemetry::StatsD;
+use lib::telemetry::{LogKey, StatsD};
 use pretty_assertions::assert_eq;
 use serde_json::Value;
 use serial_test::serial;
@@ -121,7 +127,9 @@ async fn check_refunds() {
         .await;
 
     // GO
-    fetch_and_process_refunds(&bq, &db_pool, &mock_statsd).await;
+    fetch_and_process_refunds(&bq, &settings.bq_refunds_table, &db_pool, &mock_statsd)
+        .await
+        .expect("Failed to process refunds");
 
     // Expect missing refunds
     for refund_id in [refund_3_refund_id, refund_5_refund_id] {
@@ -237,6 +245,144 @@ async fn check_refunds() {
         refund_7_status_t.unix_timestamp()
     );
 
+    // The watermark is advanced to the latest refund seen, past refund 3, which is kept as
+    // an ingest failure for want of its subscription
+    let watermark = WatermarkModel { db_pool: &db_pool }
+        .fetch_one_by_job(&LogKey::CheckRefunds.to_string())
+        .await
+        .expect("Could not fetch watermark");
+    assert_eq!(
+        watermark.watermark,
+        OffsetDateTime::from_unix_timestamp(1647900890)
+    );
+
+    // CLEAN UP
+    env::remove_var("BQ_ACCESS_TOKEN");
+}
+
+#[tokio::test]
+#[serial]
+async fn check_refunds_keeps_refunds_without_a_subscription_and_advances_the_watermark() {
+    env::set_var("BQ_ACCESS_TOKEN", "a token");
+
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let refund_model = RefundModel { db_pool: &db_pool };
+    let failures = IngestFailureModel { db_pool: &db_pool };
+    let refund_id = "re_3KftCmKb9q6OnNsL0oIyzN1U_1";
+    let refund_created = OffsetDateTime::from_unix_timestamp(1647900890);
+
+    let mock_bq = MockServer::start().await;
+    let bq = BQClient::new("a project", AccessTokenFromEnv {}, Some(&mock_bq.uri())).await;
+    let response = ResponseTemplate::new(200).set_body_json(fixture_bigquery_response());
+    Mock::given(any())
+        .respond_with(response)
+        .expect(2)
+        .mount(&mock_bq)
+        .await;
+
+    // GO
+    // The refund's subscription is never stored, e.g. it was purged
+    for _ in 0..2 {
+        fetch_and_process_refunds(&bq, &settings.bq_refunds_table, &db_pool, &mock_statsd)
+            .await
+            .expect("Failed to process refunds");
+    }
+
+    // ASSERT
+    assert!(refund_model
+        .fetch_one_by_refund_id(refund_id)
+        .await
+        .is_err());
+    let since = fetch_since(&LogKey::CheckRefunds, &db_pool, Duration::zero()).await;
+    assert_eq!(since, refund_created);
+    // Kept once, however many times it's fetched
+    let kept: Vec<IngestFailure> = failures
+        .fetch_all_by_job(&LogKey::CheckRefunds.to_string())
+        .await
+        .expect("Could not fetch ingest failures")
+        .into_iter()
+        .filter(|failure| failure.raw_row["refund_id"] == refund_id)
+        .collect();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(
+        kept[0].error,
+        "Subscription NOT IN THE SUBSCRIPTION TABLE of the refund is missing from the database"
+    );
+
+    // Retried once the subscription is stored
+    let mut sub = make_fake_sub();
+    sub.subscription_id = "NOT IN THE SUBSCRIPTION TABLE".to_string();
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+    retry_ingest_failure(
+        &kept[0],
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await
+    .expect("Could not retry refund");
+    let refund = refund_model
+        .fetch_one_by_refund_id(refund_id)
+        .await
+        .expect("Refund was not created on retry");
+    assert_eq!(refund.subscription_id, sub.subscription_id);
+
     // CLEAN UP
     env::remove_var("BQ_ACCESS_TOKEN");
 }
+
+#[tokio::test]
+async fn process_refund_skips_refunds_of_erased_subscriptions() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let refund_model = RefundModel { db_pool: &db_pool };
+    let erasures = ErasureModel { db_pool: &db_pool };
+    let sub = make_fake_sub();
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+    let mut refund = make_fake_refund();
+    refund.subscription_id = sub.subscription_id.clone();
+    refund_model
+        .create_from_refund(&refund)
+        .await
+        .expect("Failed to create refund.");
+    erasures
+        .erase(&ErasureIdentifier::FxaUid(sub.fxa_uid.clone()), "a-ticket")
+        .await
+        .expect("Could not erase");
+
+    // GO
+    // The same refund, fetched from BigQuery again
+    let mut fetched_again = make_fake_refund();
+    fetched_again.refund_id = refund.refund_id.clone();
+    fetched_again.subscription_id = sub.subscription_id.clone();
+    process_refund(fetched_again, &db_pool, &mock_statsd)
+        .await
+        .expect("Expected the refund to be skipped");
+
+    // ASSERT
+    assert!(refund_model
+        .fetch_one_by_refund_id(&refund.refund_id)
+        .await
+        .is_err());
+    let erased = refund_model
+        .fetch_all()
+        .await
+        .expect("Failed to get refunds")
+        .into_iter()
+        .find(|r| r.id == refund.id)
+        .expect("Expected the erased refund to be kept");
+    assert_eq!(erased.refund_id, format!("{}{}", ERASED_PREFIX, refund.id));
+}
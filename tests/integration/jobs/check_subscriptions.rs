@@ -2,20 +2,29 @@ use std::env;
 use std::fs::File;
 use std::io::Read;
 
-use lib::bigquery::client::{AccessTokenFromEnv, BQClient};
-use lib::jobs::check_subscriptions::fetch_and_process_new_subscriptions;
+use lib::bigquery::client::{AccessTokenFromEnv, BQClient, BQError};
+use lib::jobs::check_subscriptions::{
+    fetch_and_process_new_subscriptions, process_subscription, resolve_pending_attributions,
+};
 use lib::models::aic::AICModel;
+use lib::models::aic_clicks::{AICClick, AICClickModel, AttributionModel, ClickMetadata};
+use lib::models::erasures::{ErasureIdentifier, ErasureModel, ERASED_PREFIX};
+use lib::models::ingest_failures::IngestFailureModel;
 use lib::models::status_history::{Status, UpdateStatus};
 use lib::models::subscriptions::{PartialSubscription, Subscription, SubscriptionModel};
+use lib::models::watermarks::WatermarkModel;
 use lib::settings::get_settings;
-use lib::telemetry::StatsD;
+use lib::telemetry::{LogKey, StatsD};
 use pretty_assertions::assert_eq;
 
-use serde_json::Value;
+use serde_json::{json, Value};
 use serial_test::serial;
-use time::{date, time};
+use time::{date, time, Duration};
 use uuid::Version;
-use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+use wiremock::{
+    matchers::{any, body_partial_json},
+    Mock, MockServer, ResponseTemplate,
+};
 
 use crate::models::aic::make_fake_aic;
 use crate::models::subscriptions::make_fake_sub;
@@ -45,8 +54,8 @@ async fn check_subscriptions() {
     let sub_empty_string_flow_id = "empty strings";
     // Archived aic (shold still make a sub)
     let sub_archived_flow_id = "1a33a74efc6b850f51b832ef5b6290e5f4d28a1dbcffef79b485e188a867c362";
-    // flow id isn't in aic table (no sub)
-    let sub_no_aic_entry_flow_id = "not-in-the-aic_tables";
+    // flow id isn't in aic table (sub pending attribution)
+    let sub_no_aic_entry_flow_id = "not-in-the-aic-tables";
     // Happy path 2 at the end of all the other tests to ensure we're continuing correctly
     let sub_happy_2_flow_id = "6d8c011f70525c1d04aaa9813f93a3cdfc7316b95cdc172c48b1d6b7a522d338";
     // Happy path with coupons
@@ -91,7 +100,15 @@ async fn check_subscriptions() {
         .await;
 
     // GO
-    fetch_and_process_new_subscriptions(&bq, &db_pool, &mock_statsd).await;
+    fetch_and_process_new_subscriptions(
+        &bq,
+        &settings.bq_subscriptions_table,
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await
+    .expect("Failed to process subscriptions");
 
     // ASSERT
     let sub_1 = sub_model
@@ -145,6 +162,7 @@ async fn check_subscriptions() {
             fxa_uid: "37794607f1f1a8f9ad310d32d84e606cd8884c0d965d1036316d8ab64892b1f7".to_string(),
             quantity: 1,
             plan_id: "price_1J0owvKb9q6OnNsLExNhEDXm".to_string(),
+            product_id: None,
             plan_currency: "usd".to_string(),
             plan_amount: 100,
             country: Some("us - THIS IS SUB 1".to_string()),
@@ -152,6 +170,8 @@ async fn check_subscriptions() {
             aic_id: Some(aic_1.id),
             aic_expires: Some(aic_1.expires),
             cj_event_value: Some(aic_1.cj_event_value.to_string()),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     );
     let sub_1_status_history = sub_1.get_status_history().unwrap();
@@ -171,6 +191,7 @@ async fn check_subscriptions() {
             fxa_uid: "37794607f1f1a8f9ad310d32d84e606cd8884c0d965d1036316d8ab64892b1f7".to_string(),
             quantity: 1,
             plan_id: "price_1J0owvKb9q6OnNsLExNhEDXm".to_string(),
+            product_id: None,
             plan_currency: "usd".to_string(),
             plan_amount: 100,
             country: Some(
@@ -181,6 +202,8 @@ async fn check_subscriptions() {
             aic_id: Some(pre_archived.id),
             aic_expires: Some(pre_archived.expires),
             cj_event_value: Some(pre_archived.cj_event_value),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     );
     assert_eq!(
@@ -200,6 +223,7 @@ async fn check_subscriptions() {
             fxa_uid: "bc5bdcb1c00baf74c85d19413c3889d4653c9a79f5715a45389241ef6fc51ecb".to_string(),
             quantity: 1,
             plan_id: "price_1J0Y12Kb9q6OnNsL4SB2hhmp".to_string(),
+            product_id: None,
             plan_currency: "usd".to_string(),
             plan_amount: 4794,
             country: None,
@@ -207,6 +231,8 @@ async fn check_subscriptions() {
             aic_id: Some(aic_4.id),
             aic_expires: Some(aic_4.expires),
             cj_event_value: Some(aic_4.cj_event_value),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     );
     assert_eq!(
@@ -225,6 +251,7 @@ async fn check_subscriptions() {
             fxa_uid: "f71b0f8ef98e6d1f6a6b7ce533d58298b2e96b7db53bed198a5ff7ba6f4cda9d".to_string(),
             quantity: 1,
             plan_id: "price_1J0Y1iKb9q6OnNsLXwdOFgDr".to_string(),
+            product_id: None,
             plan_currency: "usd".to_string(),
             plan_amount: 5389,
             country: Some("us".to_string()),
@@ -232,6 +259,8 @@ async fn check_subscriptions() {
             aic_id: Some(aic_5.id),
             aic_expires: Some(aic_5.expires),
             cj_event_value: Some(aic_5.cj_event_value),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     );
     assert_eq!(
@@ -250,6 +279,7 @@ async fn check_subscriptions() {
             fxa_uid: "id_5".to_string(),
             quantity: 1,
             plan_id: "price_1J0Y1iKb9q6OnNsLXwdOFgDr".to_string(),
+            product_id: None,
             plan_currency: "usd".to_string(),
             plan_amount: 999,
             country: Some("us".to_string()),
@@ -257,14 +287,12 @@ async fn check_subscriptions() {
             aic_id: Some(aic_6.id),
             aic_expires: Some(aic_6.expires),
             cj_event_value: Some(aic_6.cj_event_value),
+            unattributed_reason: None,
+            click_metadata: None,
         })
     );
     // Expect to NOT have certain entries from the test fixtures
-    for bad_flow_id in [
-        sub_null_flow_id,
-        sub_empty_string_flow_id,
-        sub_no_aic_entry_flow_id,
-    ] {
+    for bad_flow_id in [sub_null_flow_id, sub_empty_string_flow_id] {
         match sub_model.fetch_one_by_flow_id(bad_flow_id).await {
             Err(sqlx::Error::RowNotFound) => {}
             _ => {
@@ -275,6 +303,32 @@ async fn check_subscriptions() {
             }
         }
     }
+    // Expect a subscription without an AIC to be pending attribution
+    let sub_pending = sub_model
+        .fetch_one_by_flow_id(sub_no_aic_entry_flow_id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(sub_pending.aic_id, None);
+    assert_eq!(sub_pending.cj_event_value, None);
+    assert!(sub_pending.unattributed_reason.is_some());
+    assert_eq!(
+        sub_pending.get_status().unwrap(),
+        Status::PendingAttribution
+    );
+    assert_eq!(sub_pending.get_status_history().unwrap().entries.len(), 1);
+    // Rows that could not be deserialized are kept
+    let ingest_failures = IngestFailureModel { db_pool: &db_pool }
+        .fetch_all_by_job("check-subscriptions")
+        .await
+        .expect("Could not fetch ingest failures");
+    let failed_flow_ids: Vec<&Value> = ingest_failures
+        .iter()
+        .map(|failure| &failure.raw_row["flow_id"])
+        .collect();
+    assert_eq!(
+        failed_flow_ids,
+        vec![&json!(sub_null_flow_id), &json!(sub_empty_string_flow_id)]
+    );
 
     // CLEAN UP
     env::remove_var("BQ_ACCESS_TOKEN");
@@ -318,7 +372,15 @@ async fn check_subscriptions_when_it_already_exists() {
         .await;
 
     // GO
-    fetch_and_process_new_subscriptions(&bq, &db_pool, &mock_statsd).await;
+    fetch_and_process_new_subscriptions(
+        &bq,
+        &settings.bq_subscriptions_table,
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await
+    .expect("Failed to process subscriptions");
 
     // ASSERT
     let sub_updated = sub_model
@@ -334,3 +396,423 @@ async fn check_subscriptions_when_it_already_exists() {
     // CLEAN UP
     env::remove_var("BQ_ACCESS_TOKEN");
 }
+
+#[tokio::test]
+#[serial]
+async fn check_subscriptions_fetches_from_watermark_and_never_moves_it_backwards() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let watermark_model = WatermarkModel { db_pool: &db_pool };
+    let job = LogKey::CheckSubscriptions.to_string();
+    let watermark = date!(2022 - 03 - 20).with_time(time!(00:00)).assume_utc();
+    watermark_model
+        .set(&job, watermark)
+        .await
+        .expect("Could not set watermark");
+
+    // Setup fake bigquery that only responds to a query bounded by the watermark less one day
+    env::set_var("BQ_ACCESS_TOKEN", "a token");
+    let mock_bq = MockServer::start().await;
+    let bq = BQClient::new("a project", AccessTokenFromEnv {}, Some(&mock_bq.uri())).await;
+    let response = ResponseTemplate::new(200).set_body_json(fixture_bigquery_response());
+    Mock::given(body_partial_json(json!({
+        "parameterMode": "NAMED",
+        "queryParameters": [{
+            "name": "since",
+            "parameterValue": {"value": "2022-03-19 00:00:00 UTC"}
+        }]
+    })))
+    .respond_with(response)
+    .expect(1)
+    .mount(&mock_bq)
+    .await;
+
+    // GO
+    fetch_and_process_new_subscriptions(
+        &bq,
+        &settings.bq_subscriptions_table,
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await
+    .expect("Failed to process subscriptions");
+
+    // ASSERT
+    // Rows in the overlap are older than the watermark, which must be left where it was
+    let updated = watermark_model
+        .fetch_one_by_job(&job)
+        .await
+        .expect("Could not fetch watermark");
+    assert_eq!(updated.watermark, watermark);
+
+    // CLEAN UP
+    env::remove_var("BQ_ACCESS_TOKEN");
+}
+
+#[tokio::test]
+#[serial]
+async fn check_subscriptions_does_not_fetch_erased_subscriptions_again() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let erasures = ErasureModel { db_pool: &db_pool };
+    let sub_happy_2_flow_id = "6d8c011f70525c1d04aaa9813f93a3cdfc7316b95cdc172c48b1d6b7a522d338";
+
+    env::set_var("BQ_ACCESS_TOKEN", "a token");
+    let mock_bq = MockServer::start().await;
+    let bq = BQClient::new("a project", AccessTokenFromEnv {}, Some(&mock_bq.uri())).await;
+    let response = ResponseTemplate::new(200).set_body_json(fixture_bigquery_response());
+    Mock::given(any())
+        .respond_with(response)
+        .mount(&mock_bq)
+        .await;
+    let run = || {
+        fetch_and_process_new_subscriptions(
+            &bq,
+            &settings.bq_subscriptions_table,
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+        )
+    };
+    run().await.expect("Failed to process subscriptions");
+    let sub = sub_model
+        .fetch_one_by_flow_id(sub_happy_2_flow_id)
+        .await
+        .expect("Failed to get sub");
+    erasures
+        .erase(&ErasureIdentifier::FxaUid(sub.fxa_uid.clone()), "a-ticket")
+        .await
+        .expect("Could not erase");
+
+    // GO
+    // The same rows, as when the watermark overlaps them or is reset
+    run().await.expect("Failed to process subscriptions");
+
+    // ASSERT
+    assert!(sub_model
+        .fetch_one_by_flow_id(sub_happy_2_flow_id)
+        .await
+        .is_err());
+    let all = sub_model.fetch_all().await.expect("Failed to get subs");
+    assert!(all.iter().all(|s| s.fxa_uid != sub.fxa_uid));
+    assert!(all.iter().all(|s| s.subscription_id != sub.subscription_id));
+    let erased = sub_model
+        .fetch_one_by_id(&sub.id)
+        .await
+        .expect("Failed to get erased sub");
+    assert_eq!(erased.fxa_uid, format!("{}{}", ERASED_PREFIX, sub.id));
+
+    // CLEAN UP
+    env::remove_var("BQ_ACCESS_TOKEN");
+}
+
+#[tokio::test]
+#[serial]
+async fn check_subscriptions_stops_before_processing_rows_when_the_schema_has_changed() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let aic_model = AICModel { db_pool: &db_pool };
+    let mut aic = make_fake_aic();
+    aic.flow_id = "531c7ddd31d17cbb608dcf9c8f40be89fe957c951cb5a2acd7052e6765efafcb".to_string();
+    aic_model
+        .create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+
+    // Rows are still readable, but plan_amount has become a string and country was dropped
+    let mut body = fixture_bigquery_response();
+    let fields = body["schema"]["fields"].as_array_mut().unwrap();
+    for field in fields.iter_mut() {
+        if field["name"] == "plan_amount" {
+            field["type"] = json!("STRING");
+        }
+    }
+    fields.retain(|field| field["name"] != "country");
+
+    // Setup fake bigquery that only responds to a query of the configured table
+    env::set_var("BQ_ACCESS_TOKEN", "a token");
+    let mock_bq = MockServer::start().await;
+    let bq = BQClient::new("a project", AccessTokenFromEnv {}, Some(&mock_bq.uri())).await;
+    Mock::given(body_partial_json(json!({
+        "query": "SELECT * FROM `cjms_bigquery.subscriptions_v2` WHERE report_timestamp >= @since;"
+    })))
+    .respond_with(ResponseTemplate::new(200).set_body_json(body))
+    .expect(1)
+    .mount(&mock_bq)
+    .await;
+
+    // GO
+    let result = fetch_and_process_new_subscriptions(
+        &bq,
+        "cjms_bigquery.subscriptions_v2",
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await;
+
+    // ASSERT
+    match result {
+        Err(BQError::Schema(message)) => assert_eq!(
+            message,
+            "column plan_amount is String but should be Integer, missing column country"
+        ),
+        other => panic!("Expected a schema error, got {:?}", other),
+    }
+    assert!(sub_model.fetch_all().await.unwrap().is_empty());
+    let watermark_model = WatermarkModel { db_pool: &db_pool };
+    assert!(watermark_model
+        .fetch_one_by_job(&LogKey::CheckSubscriptions.to_string())
+        .await
+        .is_err());
+
+    // CLEAN UP
+    env::remove_var("BQ_ACCESS_TOKEN");
+}
+
+async fn save_pending_sub(db_pool: &sqlx::PgPool, statsd: &StatsD) -> Subscription {
+    let mut sub = make_fake_sub();
+    sub.aic_id = None;
+    sub.aic_expires = None;
+    sub.cj_event_value = None;
+    let flow_id = sub.flow_id.clone();
+    process_subscription(sub, &AttributionModel::LastClick, db_pool, statsd)
+        .await
+        .expect("Could not process subscription");
+    let sub_model = SubscriptionModel { db_pool };
+    let sub = sub_model
+        .fetch_one_by_flow_id(&flow_id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(sub.get_status().unwrap(), Status::PendingAttribution);
+    sub
+}
+
+#[tokio::test]
+async fn resolve_pending_attributions_attaches_an_aic_that_arrived_late() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let aic_model = AICModel { db_pool: &db_pool };
+    let pending = save_pending_sub(&db_pool, &mock_statsd).await;
+    let mut aic = make_fake_aic();
+    aic.flow_id = pending.flow_id.clone();
+    aic_model
+        .create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+
+    // GO
+    resolve_pending_attributions(
+        Duration::hours(48),
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await;
+
+    // ASSERT
+    let resolved = sub_model
+        .fetch_one_by_id(&pending.id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(resolved.aic_id, Some(aic.id));
+    assert_eq!(resolved.cj_event_value, Some(aic.cj_event_value.clone()));
+    assert_eq!(resolved.unattributed_reason, None);
+    assert_eq!(resolved.get_status().unwrap(), Status::NotReported);
+    let history: Vec<Status> = resolved
+        .get_status_history()
+        .unwrap()
+        .entries
+        .into_iter()
+        .map(|entry| entry.status)
+        .collect();
+    assert_eq!(
+        history,
+        vec![Status::PendingAttribution, Status::NotReported]
+    );
+    assert!(aic_model
+        .fetch_one_by_flow_id_from_archive(&pending.flow_id)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn resolve_pending_attributions_waits_for_the_window_then_finalizes_as_organic() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let pending = save_pending_sub(&db_pool, &mock_statsd).await;
+
+    // GO - within the window
+    resolve_pending_attributions(
+        Duration::hours(48),
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await;
+
+    // ASSERT
+    let sub = sub_model
+        .fetch_one_by_id(&pending.id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(sub.get_status().unwrap(), Status::PendingAttribution);
+
+    // GO - after the window
+    resolve_pending_attributions(
+        Duration::zero(),
+        &AttributionModel::LastClick,
+        &db_pool,
+        &mock_statsd,
+    )
+    .await;
+
+    // ASSERT
+    let sub = sub_model
+        .fetch_one_by_id(&pending.id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(sub.get_status().unwrap(), Status::Organic);
+    assert_eq!(sub.aic_id, None);
+    assert_eq!(sub.unattributed_reason, pending.unattributed_reason);
+    let counts = sub_model.get_attribution_counts().await.unwrap();
+    assert_eq!(counts.organic, 1);
+    assert_eq!(counts.pending, 0);
+}
+
+/// Saves an AIC for `sub` with clicks 10 and 2 days before it was created.
+async fn save_aic_with_two_clicks(db_pool: &sqlx::PgPool, sub: &Subscription) -> Vec<AICClick> {
+    let aic_model = AICModel { db_pool };
+    let click_model = AICClickModel { db_pool };
+    let mut aic = make_fake_aic();
+    aic.flow_id = sub.flow_id.clone();
+    aic_model
+        .create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+    let mut clicks = Vec::new();
+    for days in [10, 2] {
+        let clicked = sub.subscription_created - Duration::days(days);
+        let click = AICClick {
+            id: uuid::Uuid::new_v4(),
+            aic_id: aic.id,
+            cj_event_value: format!("click-{}-days-before", days),
+            clicked,
+            expires: clicked + Duration::days(30),
+            click_metadata: ClickMetadata {
+                publisher_id: Some(format!("publisher-{}", days)),
+                ..Default::default()
+            }
+            .to_json(),
+        };
+        clicks.push(
+            click_model
+                .create_from_click(&click)
+                .await
+                .expect("Could not create click"),
+        );
+    }
+    clicks
+}
+
+#[tokio::test]
+async fn process_subscription_attributes_the_click_chosen_by_the_attribution_model() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    for (attribution, expected_click) in [
+        (AttributionModel::FirstClick, 0),
+        (AttributionModel::LastClick, 1),
+        (AttributionModel::LastClickWithin(Duration::days(3)), 1),
+    ] {
+        let sub = make_fake_sub();
+        let flow_id = sub.flow_id.clone();
+        let clicks = save_aic_with_two_clicks(&db_pool, &sub).await;
+
+        // GO
+        process_subscription(sub, &attribution, &db_pool, &mock_statsd)
+            .await
+            .expect("Could not process subscription");
+
+        // ASSERT
+        let saved = sub_model
+            .fetch_one_by_flow_id(&flow_id)
+            .await
+            .expect("Could not get sub");
+        let click = &clicks[expected_click];
+        assert_eq!(saved.aic_id, Some(click.aic_id), "{:?}", attribution);
+        assert_eq!(
+            saved.cj_event_value,
+            Some(click.cj_event_value.clone()),
+            "{:?}",
+            attribution
+        );
+        assert_eq!(
+            saved.aic_expires.map(|e| e.unix_timestamp()),
+            Some(click.expires.unix_timestamp())
+        );
+        // The metadata of the chosen click is carried onto the subscription
+        assert_eq!(
+            saved.click_metadata, click.click_metadata,
+            "{:?}",
+            attribution
+        );
+        assert_eq!(saved.get_status().unwrap(), Status::NotReported);
+    }
+}
+
+#[tokio::test]
+async fn process_subscription_without_a_qualifying_click_is_organic() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let aic_model = AICModel { db_pool: &db_pool };
+    let sub = make_fake_sub();
+    let flow_id = sub.flow_id.clone();
+    let clicks = save_aic_with_two_clicks(&db_pool, &sub).await;
+
+    // GO
+    process_subscription(
+        sub,
+        &AttributionModel::LastClickWithin(Duration::days(1)),
+        &db_pool,
+        &mock_statsd,
+    )
+    .await
+    .expect("Could not process subscription");
+
+    // ASSERT
+    let saved = sub_model
+        .fetch_one_by_flow_id(&flow_id)
+        .await
+        .expect("Could not get sub");
+    assert_eq!(saved.aic_id, None);
+    assert_eq!(saved.cj_event_value, None);
+    assert_eq!(saved.click_metadata, None);
+    assert!(saved.unattributed_reason.is_some());
+    assert_eq!(saved.get_status().unwrap(), Status::Organic);
+    // The AIC was still used up
+    assert!(aic_model
+        .fetch_one_by_id_from_archive(&clicks[0].aic_id)
+        .await
+        .is_ok());
+}
@@ -1,5 +1,9 @@
-use lib::models::aic::AICModel;
-use lib::{jobs::cleanup::archive_expired_aics, settings::get_settings, telemetry::StatsD};
+use lib::models::{aic::AICModel, watermarks::WatermarkModel};
+use lib::{
+    jobs::cleanup::archive_expired_aics,
+    settings::get_settings,
+    telemetry::{LogKey, StatsD},
+};
 use time::{Duration, OffsetDateTime};
 
 use crate::{models::aic::make_fake_aic, utils::get_test_db_pool};
@@ -16,7 +20,7 @@ async fn test_archive_expired_aics() {
     // Should be archived
     let mut aic_1 = make_fake_aic();
     aic_1.expires = now - Duration::seconds(5);
-    // Add one that's already archived to check we can handle errors
+    // Add one that's already archived to check it doesn't stop the others
     let mut aic_bad = make_fake_aic();
     aic_bad.expires = now - Duration::seconds(5);
     // Should not be archived
@@ -36,7 +40,7 @@ async fn test_archive_expired_aics() {
         .await
         .expect("Could not create pre-archived AIC.");
 
-    archive_expired_aics(&db_pool, &statsd).await;
+    archive_expired_aics(&settings, &db_pool, &statsd).await;
 
     assert!(aic_model
         .fetch_one_by_id_from_archive(&aic_1.id)
@@ -54,6 +58,89 @@ async fn test_archive_expired_aics() {
     assert!(aic_model.fetch_one_by_id(&aic_1.id).await.is_err());
     assert!(aic_model.fetch_one_by_id(&aic_2.id).await.is_ok());
     assert!(aic_model.fetch_one_by_id(&aic_3.id).await.is_err());
+    assert!(aic_model.fetch_one_by_id(&aic_bad.id).await.is_err());
+    assert!(aic_model
+        .fetch_one_by_id_from_archive(&aic_bad.id)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn archive_expired_aics_in_chunks_and_removes_cursor_when_done() {
+    let mut settings = get_settings();
+    settings.aic_archive_chunk_size = 2;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aic_model = AICModel { db_pool: &db_pool };
+
+    let now = OffsetDateTime::now_utc();
+    let mut expired = vec![];
+    for i in 1..=5 {
+        let mut aic = make_fake_aic();
+        aic.expires = now - Duration::minutes(i);
+        aic_model
+            .create_from_aic(&aic)
+            .await
+            .expect("Could not create AIC");
+        expired.push(aic);
+    }
+
+    archive_expired_aics(&settings, &db_pool, &statsd).await;
+
+    for aic in &expired {
+        assert!(aic_model.fetch_one_by_id(&aic.id).await.is_err());
+        assert!(aic_model
+            .fetch_one_by_id_from_archive(&aic.id)
+            .await
+            .is_ok());
+    }
+    let watermarks = WatermarkModel { db_pool: &db_pool };
+    assert!(watermarks
+        .fetch_one_by_job(&LogKey::CleanupAicArchive.to_string())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn archive_expired_aics_resumes_from_stored_cursor() {
+    let settings = get_settings();
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aic_model = AICModel { db_pool: &db_pool };
+    let watermarks = WatermarkModel { db_pool: &db_pool };
+
+    let now = OffsetDateTime::now_utc();
+    // Before the cursor an interrupted run stored, so not looked at again this run
+    let mut before = make_fake_aic();
+    before.expires = now - Duration::hours(2);
+    let mut after = make_fake_aic();
+    after.expires = now - Duration::minutes(5);
+    for aic in [&before, &after] {
+        aic_model
+            .create_from_aic(aic)
+            .await
+            .expect("Could not create AIC");
+    }
+    watermarks
+        .set(
+            &LogKey::CleanupAicArchive.to_string(),
+            now - Duration::hours(1),
+        )
+        .await
+        .expect("Could not set cursor");
+
+    archive_expired_aics(&settings, &db_pool, &statsd).await;
+
+    assert!(aic_model.fetch_one_by_id(&before.id).await.is_ok());
+    assert!(aic_model.fetch_one_by_id(&after.id).await.is_err());
+    assert!(aic_model
+        .fetch_one_by_id_from_archive(&after.id)
+        .await
+        .is_ok());
+
+    // The next run starts from the beginning again
+    archive_expired_aics(&settings, &db_pool, &statsd).await;
+    assert!(aic_model.fetch_one_by_id(&before.id).await.is_err());
 }
 
 #[tokio::test]
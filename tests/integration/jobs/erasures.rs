@@ -0,0 +1,96 @@
+use clap::Parser;
+use lib::jobs::erasures::{ErasureArgs, ErasureError};
+use lib::models::aic::AICModel;
+use lib::settings::get_settings;
+use lib::telemetry::StatsD;
+use pretty_assertions::assert_eq;
+use serde_json::Value;
+
+use crate::models::aic::make_fake_aic;
+use crate::utils::get_test_db_pool;
+
+fn args(args: &[&str]) -> ErasureArgs {
+    ErasureArgs::try_parse_from([&["erasures"], args].concat()).expect("Could not parse args")
+}
+
+#[test]
+fn erase_requires_exactly_one_identifier() {
+    for invalid in [
+        vec!["erase", "--requested-by", "someone"],
+        vec![
+            "erase",
+            "--fxa-uid",
+            "a-uid",
+            "--flow-id",
+            "a-flow-id",
+            "--requested-by",
+            "someone",
+        ],
+        vec!["erase", "--flow-id", "a-flow-id"],
+    ] {
+        assert!(
+            ErasureArgs::try_parse_from([&["erasures"], &invalid[..]].concat()).is_err(),
+            "{:?}",
+            invalid
+        );
+    }
+}
+
+#[tokio::test]
+async fn erase_prints_the_audit_log_entry_and_list_prints_them_all() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+    let aic = make_fake_aic();
+    aics.create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+
+    let mut out = Vec::new();
+    args(&[
+        "erase",
+        "--flow-id",
+        &aic.flow_id,
+        "--requested-by",
+        "privacy-request-1",
+    ])
+    .run(&db_pool, &mock_statsd, &mut out)
+    .await
+    .expect("Could not erase");
+    let printed: Value = serde_json::from_slice(&out).expect("Expected JSON");
+    assert_eq!(printed["identifier_type"], "flow_id");
+    assert_eq!(printed["requested_by"], "privacy-request-1");
+    assert_eq!(printed["aic_deleted"], 1);
+    assert!(aics.fetch_one_by_id(&aic.id).await.is_err());
+
+    let mut out = Vec::new();
+    args(&["list"])
+        .run(&db_pool, &mock_statsd, &mut out)
+        .await
+        .expect("Could not list");
+    let lines: Vec<Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["id"], printed["id"]);
+}
+
+#[tokio::test]
+async fn erase_rejects_erased_values() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let result = args(&[
+        "erase",
+        "--fxa-uid",
+        "erased-4f0b7e2c-6c3e-4ea4-9c1a-0d8a3c0e5b1f",
+        "--requested-by",
+        "someone",
+    ])
+    .run(&db_pool, &mock_statsd, &mut Vec::new())
+    .await;
+    assert!(matches!(result, Err(ErasureError::InvalidIdentifier(_))));
+}
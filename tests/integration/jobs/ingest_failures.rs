@@ -0,0 +1,255 @@
+use clap::Parser;
+use lib::jobs::ingest_failures::{IngestFailureArgs, IngestFailureError};
+use lib::models::aic::AICModel;
+use lib::models::aic_clicks::AttributionModel;
+use lib::models::ingest_failures::IngestFailureModel;
+use lib::models::refunds::RefundModel;
+use lib::models::subscriptions::SubscriptionModel;
+use lib::settings::get_settings;
+use lib::telemetry::{LogKey, StatsD};
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+
+use crate::models::aic::make_fake_aic;
+use crate::utils::get_test_db_pool;
+
+fn args(args: &[&str]) -> IngestFailureArgs {
+    IngestFailureArgs::try_parse_from([&["ingest_failures"], args].concat())
+        .expect("Could not parse args")
+}
+
+// A subscriptions_v1 row as the job stores it
+fn subscription_row(flow_id: &str, plan_amount: Value) -> Value {
+    json!({
+        "report_timestamp": 1647464393,
+        "subscription_created": 1647450897,
+        "subscription_id": "sub_1Ke0R3Kb9q6OnNsLD1OIZsxm",
+        "fxa_uid": "37794607f1f1a8f9ad310d32d84e606cd8884c0d965d1036316d8ab64892b1f7",
+        "quantity": 1,
+        "plan_id": "price_1J0owvKb9q6OnNsLExNhEDXm",
+        "plan_currency": "usd",
+        "plan_amount": plan_amount,
+        "country": "us",
+        "flow_id": flow_id,
+        "promotion_codes": null,
+    })
+}
+
+#[tokio::test]
+async fn retry_processes_rows_that_now_deserialize_and_removes_them() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aic_model = AICModel { db_pool: &db_pool };
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let failures = IngestFailureModel { db_pool: &db_pool };
+    let aic = make_fake_aic();
+    aic_model
+        .create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+    let failure = failures
+        .record(
+            &LogKey::CheckSubscriptions.to_string(),
+            &subscription_row(&aic.flow_id, json!(100)),
+            "an error that has since been fixed",
+        )
+        .await
+        .expect("Could not record failure");
+
+    // GO
+    args(&["retry", &failure.id.to_string()])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await
+        .expect("Retry failed");
+
+    // ASSERT
+    let sub = sub_model
+        .fetch_one_by_flow_id(&aic.flow_id)
+        .await
+        .expect("Subscription was not created");
+    assert_eq!(sub.plan_amount, 100);
+    assert_eq!(sub.aic_id, Some(aic.id));
+    assert!(matches!(
+        failures.fetch_one_by_id(&failure.id).await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+}
+
+#[tokio::test]
+async fn retry_keeps_rows_that_still_fail_to_deserialize() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let failures = IngestFailureModel { db_pool: &db_pool };
+    let failure = failures
+        .record(
+            &LogKey::CheckSubscriptions.to_string(),
+            &subscription_row("a-flow-id", json!("not a number")),
+            "an old error",
+        )
+        .await
+        .expect("Could not record failure");
+
+    // GO
+    let result = args(&["retry", "--all"])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await;
+
+    // ASSERT
+    assert!(matches!(result, Err(IngestFailureError::RetriesFailed(1))));
+    let updated = failures
+        .fetch_one_by_id(&failure.id)
+        .await
+        .expect("Failure should still be stored");
+    assert!(
+        updated.error.starts_with("invalid type"),
+        "{}",
+        updated.error
+    );
+    assert_ne!(updated.error, "an old error");
+    assert_eq!(updated.attempts, 1);
+    assert!(updated.last_attempt_at.is_some());
+    assert_eq!(updated.first_seen, failure.first_seen);
+}
+
+#[tokio::test]
+async fn retry_keeps_rows_that_deserialize_but_fail_to_process() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let failures = IngestFailureModel { db_pool: &db_pool };
+    let refunds = RefundModel { db_pool: &db_pool };
+    // A refund whose subscription hasn't been stored yet
+    let failure = failures
+        .record(
+            &LogKey::CheckRefunds.to_string(),
+            &json!({
+                "refund_id": "re_subscription_not_stored_yet",
+                "subscription_id": "sub_not_stored_yet",
+                "created": 1647900890,
+                "amount": 999,
+                "status": "succeeded",
+                "reason": null,
+            }),
+            "an error that has since been fixed",
+        )
+        .await
+        .expect("Could not record failure");
+
+    // GO
+    let result = args(&["retry", "--all"])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await;
+
+    // ASSERT
+    assert!(matches!(result, Err(IngestFailureError::RetriesFailed(1))));
+    let updated = failures
+        .fetch_one_by_id(&failure.id)
+        .await
+        .expect("Failure should still be stored");
+    assert_eq!(
+        updated.error,
+        "Refund could not be processed: Subscription sub_not_stored_yet of the refund is missing from the database"
+    );
+    assert_eq!(updated.attempts, 1);
+    assert!(updated.last_attempt_at.is_some());
+    assert!(refunds
+        .fetch_one_by_refund_id("re_subscription_not_stored_yet")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn list_and_dismiss_ingest_failures() {
+    // SETUP
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let failures = IngestFailureModel { db_pool: &db_pool };
+    let subscription_failure = failures
+        .record(
+            &LogKey::CheckSubscriptions.to_string(),
+            &subscription_row("nulls", Value::Null),
+            "a subscription error",
+        )
+        .await
+        .expect("Could not record failure");
+    failures
+        .record(
+            &LogKey::CheckRefunds.to_string(),
+            &json!({"refund_id": null}),
+            "a refund error",
+        )
+        .await
+        .expect("Could not record failure");
+
+    // GO - list
+    let mut out = Vec::new();
+    args(&["list", "--job", "check-subscriptions"])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut out,
+        )
+        .await
+        .expect("List failed");
+
+    // ASSERT
+    let lines: Vec<Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Line is not JSON"))
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["id"], json!(subscription_failure.id));
+    assert_eq!(lines[0]["job"], "check-subscriptions");
+    assert_eq!(lines[0]["error"], "a subscription error");
+    assert_eq!(lines[0]["raw_row"]["flow_id"], "nulls");
+
+    // GO - dismiss
+    args(&["dismiss", &subscription_failure.id.to_string()])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await
+        .expect("Dismiss failed");
+
+    // ASSERT
+    let remaining = failures.fetch_all().await.unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].job, "check-refunds");
+    let result = args(&["dismiss", &subscription_failure.id.to_string()])
+        .run(
+            &AttributionModel::LastClick,
+            &db_pool,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await;
+    assert!(
+        matches!(result, Err(IngestFailureError::NotFound(id)) if id == subscription_failure.id)
+    );
+}
@@ -2,5 +2,8 @@ mod batch_refunds;
 mod check_refunds;
 mod check_subscriptions;
 mod cleanup;
+mod erasures;
+mod ingest_failures;
+mod purge;
 mod report_subscriptions;
 mod verify_reports;
@@ -0,0 +1,258 @@
+use lib::{
+    jobs::purge::{purge_aic_archive, purge_cj_s2s_log, purge_refunds, purge_subscriptions},
+    models::{
+        aic::AICModel,
+        cj_s2s_log::{CJS2SLogEntry, CJS2SLogModel},
+        erasures::ERASED_PREFIX,
+        refunds::RefundModel,
+        status_history::{Status, UpdateStatus},
+        subscriptions::SubscriptionModel,
+    },
+    settings::get_settings,
+    telemetry::StatsD,
+};
+use pretty_assertions::assert_eq;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        aic::make_fake_aic,
+        refunds::{make_fake_refund, save_refund},
+        subscriptions::{make_fake_sub, save_sub},
+    },
+    utils::get_test_db_pool,
+};
+
+#[tokio::test]
+async fn purge_aic_archive_deletes_aics_past_retention() {
+    let mut settings = get_settings();
+    settings.aic_archive_retention_days = Some(30);
+    settings.purge_batch_size = 1;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+
+    let now = OffsetDateTime::now_utc();
+    // Should be deleted
+    let mut old_1 = make_fake_aic();
+    old_1.expires = now - Duration::days(31);
+    let mut old_2 = make_fake_aic();
+    old_2.expires = now - Duration::days(40);
+    // Should be kept
+    let mut recent = make_fake_aic();
+    recent.expires = now - Duration::days(29);
+    for aic in [&old_1, &old_2, &recent] {
+        aics.create_archive_from_aic(aic)
+            .await
+            .expect("Could not create archived AIC");
+    }
+    // Not archived yet, so should be kept whatever its expiry
+    let mut live = make_fake_aic();
+    live.expires = now - Duration::days(31);
+    aics.create_from_aic(&live)
+        .await
+        .expect("Could not create AIC");
+
+    purge_aic_archive(&settings, &db_pool, &statsd).await;
+
+    assert!(aics.fetch_one_by_id_from_archive(&old_1.id).await.is_err());
+    assert!(aics.fetch_one_by_id_from_archive(&old_2.id).await.is_err());
+    assert!(aics.fetch_one_by_id_from_archive(&recent.id).await.is_ok());
+    assert!(aics.fetch_one_by_id(&live.id).await.is_ok());
+}
+
+#[tokio::test]
+async fn purge_cj_s2s_log_deletes_entries_past_retention() {
+    let mut settings = get_settings();
+    settings.cj_s2s_log_retention_days = Some(30);
+    settings.purge_batch_size = 1;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let log = CJS2SLogModel { db_pool: &db_pool };
+
+    let now = OffsetDateTime::now_utc();
+    let sub_id = Uuid::new_v4();
+    let make_entry = |sent| CJS2SLogEntry {
+        id: Uuid::new_v4(),
+        sub_id,
+        sent,
+        url: "https://www.emjcd.com/u?SIGNATURE=REDACTED".to_string(),
+        status: Some(200),
+        response_body: Some("OK".to_string()),
+        error: None,
+        latency_ms: 10,
+    };
+    // Should be deleted
+    let old_1 = make_entry(now - Duration::days(31));
+    let old_2 = make_entry(now - Duration::days(40));
+    // Should be kept
+    let recent = make_entry(now - Duration::days(29));
+    for entry in [&old_1, &old_2, &recent] {
+        log.create(entry).await.expect("Could not create log entry");
+    }
+
+    purge_cj_s2s_log(&settings, &db_pool, &statsd).await;
+
+    let kept = log.fetch_all_by_sub_id(&sub_id).await.unwrap();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].id, recent.id);
+}
+
+#[tokio::test]
+async fn purge_subscriptions_pseudonymizes_final_subscriptions_past_retention() {
+    let mut settings = get_settings();
+    settings.subscription_retention_days = Some(30);
+    settings.purge_batch_size = 1;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let subs = SubscriptionModel { db_pool: &db_pool };
+
+    let old = OffsetDateTime::now_utc() - Duration::days(31);
+    // Should be pseudonymized
+    let mut received = make_fake_sub();
+    received.update_status(Status::CJReceived);
+    received.set_status_t(Some(old));
+    let mut will_not_report = make_fake_sub();
+    will_not_report.update_status(Status::WillNotReport);
+    will_not_report.set_status_t(Some(old));
+    // Should be kept, as they're recent or could still change
+    let mut recent = make_fake_sub();
+    recent.update_status(Status::CJReceived);
+    let mut reported = make_fake_sub();
+    reported.update_status(Status::Reported);
+    reported.set_status_t(Some(old));
+    for sub in [&received, &will_not_report, &recent, &reported] {
+        save_sub(&subs, sub).await;
+    }
+
+    purge_subscriptions(&settings, &db_pool, &statsd).await;
+
+    for sub in [&received, &will_not_report] {
+        let purged = subs.fetch_one_by_id(&sub.id).await.unwrap();
+        assert_eq!(purged.fxa_uid, format!("{}{}", ERASED_PREFIX, sub.id));
+        assert_eq!(purged.get_status(), sub.get_status());
+        assert_eq!(purged.plan_amount, sub.plan_amount);
+    }
+    for sub in [&recent, &reported] {
+        assert_eq!(subs.fetch_one_by_id(&sub.id).await.unwrap(), *sub);
+    }
+
+    // Already pseudonymized subscriptions aren't purged again
+    purge_subscriptions(&settings, &db_pool, &statsd).await;
+    let purged = subs.fetch_one_by_id(&received.id).await.unwrap();
+    assert_eq!(purged.fxa_uid, format!("{}{}", ERASED_PREFIX, received.id));
+}
+
+#[tokio::test]
+async fn purge_subscriptions_pseudonymizes_organic_but_keeps_report_failed() {
+    let mut settings = get_settings();
+    settings.subscription_retention_days = Some(30);
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let subs = SubscriptionModel { db_pool: &db_pool };
+
+    let old = OffsetDateTime::now_utc() - Duration::days(31);
+    let mut organic = make_fake_sub();
+    organic.update_status(Status::Organic);
+    organic.set_status_t(Some(old));
+    // Can still be retried, so isn't final
+    let mut report_failed = make_fake_sub();
+    report_failed.update_status(Status::ReportFailed);
+    report_failed.set_status_t(Some(old));
+    for sub in [&organic, &report_failed] {
+        save_sub(&subs, sub).await;
+    }
+
+    purge_subscriptions(&settings, &db_pool, &statsd).await;
+
+    let purged = subs.fetch_one_by_id(&organic.id).await.unwrap();
+    assert_eq!(purged.fxa_uid, format!("{}{}", ERASED_PREFIX, organic.id));
+    assert_eq!(purged.get_status(), Some(Status::Organic));
+    assert_eq!(
+        subs.fetch_one_by_id(&report_failed.id).await.unwrap(),
+        report_failed
+    );
+}
+
+#[tokio::test]
+async fn purge_refunds_deletes_final_refunds_past_retention() {
+    let mut settings = get_settings();
+    settings.refund_retention_days = Some(30);
+    settings.purge_batch_size = 1;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let refunds = RefundModel { db_pool: &db_pool };
+
+    let old = OffsetDateTime::now_utc() - Duration::days(31);
+    // Should be deleted
+    let mut received = make_fake_refund();
+    received.update_status(Status::CJReceived);
+    received.set_status_t(Some(old));
+    // Should be kept
+    let mut recent = make_fake_refund();
+    recent.update_status(Status::CJReceived);
+    let mut reported = make_fake_refund();
+    reported.update_status(Status::Reported);
+    reported.set_status_t(Some(old));
+    for refund in [&received, &recent, &reported] {
+        save_refund(&refunds, refund).await;
+    }
+
+    purge_refunds(&settings, &db_pool, &statsd).await;
+
+    assert!(refunds
+        .fetch_one_by_refund_id(&received.refund_id)
+        .await
+        .is_err());
+    assert!(refunds
+        .fetch_one_by_refund_id(&recent.refund_id)
+        .await
+        .is_ok());
+    assert!(refunds
+        .fetch_one_by_refund_id(&reported.refund_id)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn purge_keeps_everything_without_retention_settings() {
+    let mut settings = get_settings();
+    settings.aic_archive_retention_days = None;
+    settings.subscription_retention_days = None;
+    settings.refund_retention_days = None;
+    let statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+    let subs = SubscriptionModel { db_pool: &db_pool };
+    let refunds = RefundModel { db_pool: &db_pool };
+
+    let old = OffsetDateTime::now_utc() - Duration::days(3650);
+    let mut aic = make_fake_aic();
+    aic.expires = old;
+    aics.create_archive_from_aic(&aic)
+        .await
+        .expect("Could not create archived AIC");
+    let mut sub = make_fake_sub();
+    sub.update_status(Status::CJReceived);
+    sub.set_status_t(Some(old));
+    save_sub(&subs, &sub).await;
+    let mut refund = make_fake_refund();
+    refund.update_status(Status::CJReceived);
+    refund.set_status_t(Some(old));
+    save_refund(&refunds, &refund).await;
+
+    purge_aic_archive(&settings, &db_pool, &statsd).await;
+    purge_subscriptions(&settings, &db_pool, &statsd).await;
+    purge_refunds(&settings, &db_pool, &statsd).await;
+
+    assert!(aics.fetch_one_by_id_from_archive(&aic.id).await.is_ok());
+    assert_eq!(subs.fetch_one_by_id(&sub.id).await.unwrap(), sub);
+    assert_eq!(
+        refunds
+            .fetch_one_by_refund_id(&refund.refund_id)
+            .await
+            .unwrap(),
+        refund
+    );
+}
@@ -1,6 +1,14 @@
+use clap::Parser;
 use lib::{
-    cj::{client::CJClient, country_codes::get_iso_code_3_from_iso_code_2},
-    jobs::report_subscriptions::report_subscriptions_to_cj,
+    cj::{
+        client::CJClient,
+        country_codes::get_iso_code_3_from_iso_code_2,
+        event_time::{EventTimeRandomizer, MIN_OFFSET_MINUTES},
+    },
+    jobs::report_subscriptions::{
+        report_subscriptions_to_cj, ReportRetryPolicy, ReportSubscriptionsArgs,
+        ReportSubscriptionsError,
+    },
     models::{
         status_history::{Status, StatusHistoryEntry, UpdateStatus},
         subscriptions::SubscriptionModel,
@@ -8,6 +16,9 @@ use lib::{
     settings::{get_settings, Settings},
     telemetry::StatsD,
 };
+use rand::rngs::mock::StepRng;
+use secrecy::Secret;
+use serde_json::{json, Value};
 
 use time::{Duration, OffsetDateTime};
 use wiremock::{
@@ -35,13 +46,15 @@ async fn report_subscriptions() {
     let db_pool = get_test_db_pool().await;
     let sub_model = SubscriptionModel { db_pool: &db_pool };
 
+    // Subs that are reported are created in the future, so that their EVENTTIME is the
+    // created time plus the offset, rather than now plus the offset
+    let future = OffsetDateTime::parse("2099-12-25 14:22:33 +0000", "%F %T %z").unwrap();
     // Sub 1 - should be reported
     let mut sub_1 = make_fake_sub();
     sub_1.flow_id = "1".to_string();
     sub_1.plan_amount = 5988;
-    sub_1.subscription_created =
-        OffsetDateTime::parse("2021-12-25 14:22:33 +0000", "%F %T %z").unwrap();
-    sub_1.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_1.subscription_created = future;
+    sub_1.aic_expires = Some(future + Duration::days(10));
     // Sub 2 - should be "will_not_report" (because aic_expires before subscription created)
     let mut sub_2 = make_fake_sub();
     sub_2.flow_id = "2".to_string();
@@ -50,13 +63,13 @@ async fn report_subscriptions() {
     // Sub 3 - should be reported but will fail because mock cj fails
     let mut sub_3 = make_fake_sub();
     sub_3.flow_id = "3".to_string();
-    sub_3.subscription_created = OffsetDateTime::now_utc() - Duration::days(5);
-    sub_3.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_3.subscription_created = future - Duration::days(5);
+    sub_3.aic_expires = Some(future + Duration::days(10));
     // Sub 4 - should be reported (no country)
     let mut sub_4 = make_fake_sub();
     sub_4.flow_id = "4".to_string();
-    sub_4.subscription_created = OffsetDateTime::now_utc() - Duration::days(5);
-    sub_4.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_4.subscription_created = future - Duration::days(5);
+    sub_4.aic_expires = Some(future + Duration::days(10));
     sub_4.country = None;
     // Sub 5 - no aic
     let mut sub_5 = make_fake_sub();
@@ -66,9 +79,8 @@ async fn report_subscriptions() {
     let mut sub_6 = make_fake_sub();
     sub_6.flow_id = "6".to_string();
     sub_6.plan_amount = 5388;
-    sub_6.subscription_created =
-        OffsetDateTime::parse("2021-12-25 14:22:33 +0000", "%F %T %z").unwrap();
-    sub_6.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_6.subscription_created = future;
+    sub_6.aic_expires = Some(future + Duration::days(10));
 
     for sub in [&sub_1, &sub_2, &sub_3, &sub_4, &sub_5, &sub_6] {
         sub_model
@@ -78,17 +90,14 @@ async fn report_subscriptions() {
     }
 
     let mock_cj = MockServer::start().await;
-    let random_minutes = Duration::minutes(19);
+    // StepRng(0) always draws the smallest offset, and event times are rounded up to the
+    // minute
+    let event_times = EventTimeRandomizer::with_rng(&settings, StepRng::new(0, 0));
+    let offset = Duration::minutes(MIN_OFFSET_MINUTES + 1);
     let format_str = "%FT%H:%M:00.000Z";
     when_sending_to_cj(&settings)
         .and(query_param("CJEVENT", sub_1.cj_event_value.unwrap()))
-        .and(query_param(
-            "EVENTTIME",
-            format!(
-                "2021-12-25T14:{}:00.000Z",
-                22 + random_minutes.whole_minutes()
-            ),
-        ))
+        .and(query_param("EVENTTIME", "2099-12-25T14:38:00.000Z"))
         .and(query_param("OID", sub_1.id.to_string()))
         .and(query_param("CURRENCY", sub_1.plan_currency))
         .and(query_param("ITEM1", sub_1.plan_id))
@@ -107,7 +116,7 @@ async fn report_subscriptions() {
         .and(query_param("CJEVENT", sub_3.cj_event_value.unwrap()))
         .and(query_param(
             "EVENTTIME",
-            (sub_3.subscription_created + random_minutes).format(format_str),
+            (sub_3.subscription_created + offset).format(format_str),
         ))
         .and(query_param("OID", sub_3.id.to_string()))
         .and(query_param("CURRENCY", sub_3.plan_currency))
@@ -130,7 +139,7 @@ async fn report_subscriptions() {
         .and(query_param("CJEVENT", sub_4.cj_event_value.unwrap()))
         .and(query_param(
             "EVENTTIME",
-            (sub_4.subscription_created + random_minutes).format(format_str),
+            (sub_4.subscription_created + offset).format(format_str),
         ))
         .and(query_param("OID", sub_4.id.to_string()))
         .and(query_param("CURRENCY", sub_4.plan_currency))
@@ -148,13 +157,7 @@ async fn report_subscriptions() {
         .await;
     when_sending_to_cj(&settings)
         .and(query_param("CJEVENT", sub_6.cj_event_value.unwrap()))
-        .and(query_param(
-            "EVENTTIME",
-            format!(
-                "2021-12-25T14:{}:00.000Z",
-                22 + random_minutes.whole_minutes()
-            ),
-        ))
+        .and(query_param("EVENTTIME", "2099-12-25T14:38:00.000Z"))
         .and(query_param("OID", sub_6.id.to_string()))
         .and(query_param("CURRENCY", sub_6.plan_currency))
         .and(query_param("ITEM1", sub_6.plan_id))
@@ -170,12 +173,19 @@ async fn report_subscriptions() {
         .expect(1)
         .mount(&mock_cj)
         .await;
-    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, Some(random_minutes));
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, Some(event_times));
 
     // GO
     std::thread::sleep(std::time::Duration::from_secs(2));
     let now = OffsetDateTime::now_utc();
-    report_subscriptions_to_cj(&db_pool, &mock_cj_client, &mock_statsd).await;
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &ReportRetryPolicy::from_settings(&settings),
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
 
     // ASSERT
 
@@ -213,7 +223,8 @@ async fn report_subscriptions() {
             updated_history.entries[1],
             StatusHistoryEntry {
                 status: Status::Reported,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -225,7 +236,8 @@ async fn report_subscriptions() {
         sub_3_updated_history.entries[1],
         StatusHistoryEntry {
             status: Status::NotReported,
-            t: now
+            t: now,
+            reason: Some("CJ responded with status 500 Internal Server Error".to_string()),
         }
     );
 
@@ -241,8 +253,433 @@ async fn report_subscriptions() {
             updated_history.entries[1],
             StatusHistoryEntry {
                 status: Status::WillNotReport,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
 }
+
+#[tokio::test]
+async fn report_subscriptions_uses_the_program_of_each_subscription() {
+    let mut settings = get_settings();
+    settings.cj_programs = Secret::new(
+        json!([{
+            "name": "relay",
+            "cid": "relay cid",
+            "type": "relay type",
+            "signature": "relay signature",
+            "subid": "relay subid",
+            "product_ids": ["prod_relay"],
+        }])
+        .to_string(),
+    );
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+
+    let mut vpn_sub = make_fake_sub();
+    vpn_sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    let mut relay_sub = make_fake_sub();
+    relay_sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    relay_sub.product_id = Some("prod_relay".to_string());
+    for sub in [&vpn_sub, &relay_sub] {
+        sub_model
+            .create_from_sub(sub)
+            .await
+            .expect("Failed to create sub.");
+    }
+
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .and(query_param("OID", vpn_sub.id.to_string()))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_cj)
+        .await;
+    Mock::given(path("/"))
+        .and(method("GET"))
+        .and(query_param("CID", "relay cid"))
+        .and(query_param("TYPE", "relay type"))
+        .and(query_param("SIGNATURE", "relay signature"))
+        .and(query_param("OID", relay_sub.id.to_string()))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &ReportRetryPolicy::from_settings(&settings),
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
+
+    for sub in [&vpn_sub, &relay_sub] {
+        let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+        assert_eq!(updated.get_status().unwrap(), Status::Reported);
+    }
+}
+
+#[tokio::test]
+async fn report_subscriptions_backs_off_then_marks_report_failed() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let retry = ReportRetryPolicy {
+        max_attempts: 2,
+        backoff: Duration::minutes(15),
+    };
+
+    let mut sub = make_fake_sub();
+    sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .and(query_param("OID", sub.id.to_string()))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    // The first attempt fails and defers the next one
+    let before = OffsetDateTime::now_utc();
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &retry,
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
+    let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::NotReported);
+    assert_eq!(updated.report_attempts, 1);
+    assert_eq!(
+        updated.report_last_error.as_deref(),
+        Some("CJ responded with status 500 Internal Server Error")
+    );
+    let next_attempt = updated.report_next_attempt.unwrap();
+    assert!(next_attempt >= before + Duration::minutes(15));
+
+    // It isn't tried again before then
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &retry,
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
+    assert_eq!(
+        sub_model
+            .fetch_one_by_id(&sub.id)
+            .await
+            .unwrap()
+            .report_attempts,
+        1
+    );
+
+    // The last attempt fails and the subscription is ReportFailed
+    sqlx::query("UPDATE subscriptions SET report_next_attempt = $1 WHERE id = $2")
+        .bind(OffsetDateTime::now_utc() - Duration::minutes(1))
+        .bind(sub.id)
+        .execute(&db_pool)
+        .await
+        .unwrap();
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &retry,
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
+    let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::ReportFailed);
+    assert_eq!(updated.report_attempts, 2);
+    assert!(updated.report_next_attempt.is_none());
+
+    // It's listed, and can be retried
+    let mut out = Vec::new();
+    ReportSubscriptionsArgs::try_parse_from(["report_subscriptions", "list-failed"])
+        .unwrap()
+        .run(
+            &db_pool,
+            &mock_cj_client,
+            &retry,
+            settings.report_concurrency,
+            &mock_statsd,
+            &mut out,
+        )
+        .await
+        .expect("Could not list");
+    let listed: Value = serde_json::from_slice(&out).expect("Expected JSON");
+    assert_eq!(listed["id"], sub.id.to_string());
+    assert_eq!(listed["report_attempts"], 2);
+    ReportSubscriptionsArgs::try_parse_from(["report_subscriptions", "retry", "--all"])
+        .unwrap()
+        .run(
+            &db_pool,
+            &mock_cj_client,
+            &retry,
+            settings.report_concurrency,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await
+        .expect("Could not retry");
+    let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::NotReported);
+    assert_eq!(updated.report_attempts, 0);
+}
+
+#[tokio::test]
+async fn report_subscriptions_retry_rejects_subscriptions_that_did_not_fail() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let sub = make_fake_sub();
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+    let cj_client = CJClient::new(&settings, None, None, None);
+    let id = sub.id.to_string();
+    let result = ReportSubscriptionsArgs::try_parse_from(["report_subscriptions", "retry", &id])
+        .unwrap()
+        .run(
+            &db_pool,
+            &cj_client,
+            &ReportRetryPolicy::from_settings(&settings),
+            settings.report_concurrency,
+            &mock_statsd,
+            &mut Vec::new(),
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(ReportSubscriptionsError::NotReportFailed(_))
+    ));
+}
+
+#[tokio::test]
+async fn report_subscriptions_concurrently_within_the_rate_limit() {
+    let mut settings = get_settings();
+    settings.report_concurrency = 3;
+    settings.cj_s2s_requests_per_second = 4;
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+
+    let mut subs = vec![];
+    for _ in 0..6 {
+        let mut sub = make_fake_sub();
+        sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+        sub_model
+            .create_from_sub(&sub)
+            .await
+            .expect("Failed to create sub.");
+        subs.push(sub);
+    }
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(100)))
+        .expect(6)
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    let start = std::time::Instant::now();
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &ReportRetryPolicy::from_settings(&settings),
+        settings.report_concurrency,
+        &mock_statsd,
+    )
+    .await;
+
+    // A burst of 4, then 2 more at 4 per second
+    assert!(start.elapsed() >= std::time::Duration::from_millis(450));
+    for sub in &subs {
+        let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+        assert_eq!(updated.get_status().unwrap(), Status::Reported);
+    }
+}
+
+#[tokio::test]
+async fn report_subscriptions_records_each_s2s_request_in_the_log() {
+    let mut settings = get_settings();
+    settings.cj_signature = "secret-signature".to_string();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let retry = ReportRetryPolicy::from_settings(&settings);
+
+    let mut sub = make_fake_sub();
+    sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .respond_with(ResponseTemplate::new(500).set_body_string("Try again later"))
+        .up_to_n_times(1)
+        .mount(&mock_cj)
+        .await;
+    when_sending_to_cj(&settings)
+        .respond_with(ResponseTemplate::new(200).set_body_string("OK"))
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    report_subscriptions_to_cj(&db_pool, &mock_cj_client, &retry, 1, &mock_statsd).await;
+    sqlx::query("UPDATE subscriptions SET report_next_attempt = $1 WHERE id = $2")
+        .bind(OffsetDateTime::now_utc() - Duration::minutes(1))
+        .bind(sub.id)
+        .execute(&db_pool)
+        .await
+        .unwrap();
+    report_subscriptions_to_cj(&db_pool, &mock_cj_client, &retry, 1, &mock_statsd).await;
+    let updated = sub_model.fetch_one_by_id(&sub.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::Reported);
+
+    let id = sub.id.to_string();
+    let mut out = Vec::new();
+    ReportSubscriptionsArgs::try_parse_from(["report_subscriptions", "s2s-log", &id])
+        .unwrap()
+        .run(&db_pool, &mock_cj_client, &retry, 1, &mock_statsd, &mut out)
+        .await
+        .expect("Could not list the S2S log");
+    let entries: Vec<Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Expected JSON"))
+        .collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["status"], 500);
+    assert_eq!(entries[0]["response_body"], "Try again later");
+    assert_eq!(entries[1]["status"], 200);
+    assert_eq!(entries[1]["response_body"], "OK");
+    for entry in entries {
+        assert_eq!(entry["sub_id"], id);
+        assert_eq!(entry["error"], Value::Null);
+        let url = entry["url"].as_str().unwrap();
+        assert!(url.contains("SIGNATURE=REDACTED"));
+        assert!(url.contains(&format!("OID={}", id)));
+        assert!(!url.contains("secret-signature"));
+    }
+}
+
+#[tokio::test]
+async fn report_subscriptions_treats_a_rejection_in_a_200_body_as_a_failure() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+    let retry = ReportRetryPolicy {
+        max_attempts: 1,
+        backoff: Duration::minutes(15),
+    };
+
+    let mut rejected = make_fake_sub();
+    rejected.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    let mut unknown = make_fake_sub();
+    unknown.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    for sub in [&rejected, &unknown] {
+        sub_model
+            .create_from_sub(sub)
+            .await
+            .expect("Failed to create sub.");
+    }
+
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .and(query_param("OID", rejected.id.to_string()))
+        .respond_with(ResponseTemplate::new(200).set_body_string("ERROR: Invalid CJEVENT"))
+        .expect(1)
+        .mount(&mock_cj)
+        .await;
+    when_sending_to_cj(&settings)
+        .and(query_param("OID", unknown.id.to_string()))
+        .respond_with(ResponseTemplate::new(200).set_body_string("<html></html>"))
+        .expect(1)
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    report_subscriptions_to_cj(&db_pool, &mock_cj_client, &retry, 1, &mock_statsd).await;
+
+    // The rejection goes through the failure path, with CJ's reason
+    let updated = sub_model.fetch_one_by_id(&rejected.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::ReportFailed);
+    assert_eq!(
+        updated.report_last_error.as_deref(),
+        Some("ERROR: Invalid CJEVENT")
+    );
+    let history = updated.get_status_history().unwrap();
+    let last = history.entries.last().unwrap();
+    assert_eq!(last.status, Status::ReportFailed);
+    assert_eq!(last.reason.as_deref(), Some("ERROR: Invalid CJEVENT"));
+
+    // A body that isn't recognized is left for verify_reports to check
+    let updated = sub_model.fetch_one_by_id(&unknown.id).await.unwrap();
+    assert_eq!(updated.get_status().unwrap(), Status::Reported);
+}
+
+#[tokio::test]
+async fn report_subscriptions_never_sends_an_event_time_in_the_past() {
+    let settings = get_settings();
+    let mock_statsd = StatsD::new(&settings);
+    let db_pool = get_test_db_pool().await;
+    let sub_model = SubscriptionModel { db_pool: &db_pool };
+
+    let mut sub = make_fake_sub();
+    sub.subscription_created = OffsetDateTime::now_utc() - Duration::days(3);
+    sub.aic_expires = Some(OffsetDateTime::now_utc() + Duration::days(10));
+    sub_model
+        .create_from_sub(&sub)
+        .await
+        .expect("Failed to create sub.");
+    let mock_cj = MockServer::start().await;
+    when_sending_to_cj(&settings)
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_cj)
+        .await;
+    let mock_cj_client = CJClient::new(&settings, Some(&mock_cj.uri()), None, None);
+
+    let before = OffsetDateTime::now_utc();
+    report_subscriptions_to_cj(
+        &db_pool,
+        &mock_cj_client,
+        &ReportRetryPolicy::from_settings(&settings),
+        1,
+        &mock_statsd,
+    )
+    .await;
+
+    let requests = mock_cj.received_requests().await.unwrap();
+    let event_time = requests[0]
+        .url
+        .query_pairs()
+        .find(|(key, _)| key == "EVENTTIME")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    let event_time = OffsetDateTime::parse(event_time.replace(".000Z", " +0000"), "%FT%T %z")
+        .expect("Could not parse EVENTTIME");
+    assert!(event_time >= before + Duration::minutes(MIN_OFFSET_MINUTES));
+}
@@ -547,7 +547,8 @@ async fn test_correct_and_incorrectly_received_subscriptions_are_handled_correct
             updated_history.entries[2],
             StatusHistoryEntry {
                 status: Status::CJReceived,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -565,7 +566,8 @@ async fn test_correct_and_incorrectly_received_subscriptions_are_handled_correct
             updated_history.entries[2],
             StatusHistoryEntry {
                 status: Status::CJNotReceived,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -650,7 +652,8 @@ async fn test_correct_and_incorrectly_received_refunds_are_handled_correctly() {
             updated_history.entries[2],
             StatusHistoryEntry {
                 status: Status::CJReceived,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -671,7 +674,8 @@ async fn test_correct_and_incorrectly_received_refunds_are_handled_correctly() {
             updated_history.entries[2],
             StatusHistoryEntry {
                 status: Status::CJNotReceived,
-                t: now
+                t: now,
+                reason: None,
             }
         );
     }
@@ -1,7 +1,9 @@
 mod aic;
 mod appconfig;
+mod click;
 mod corrections;
 mod custodial;
+mod erasures;
 mod jobs;
 mod models;
 mod utils;
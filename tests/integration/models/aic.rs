@@ -1,5 +1,8 @@
 use crate::utils::{get_test_db_pool, random_ascii_string, spawn_app};
-use lib::models::aic::{AICModel, AIC};
+use lib::models::{
+    aic::{AICModel, AIC},
+    aic_clicks::ClickMetadata,
+};
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
@@ -10,6 +13,7 @@ pub fn make_fake_aic() -> AIC {
         cj_event_value: random_ascii_string(),
         created: OffsetDateTime::now_utc(),
         expires: OffsetDateTime::now_utc() + Duration::days(10),
+        click_metadata: None,
     }
 }
 
@@ -23,6 +27,7 @@ async fn test_aic_model_fetch_one_by_ids() {
         .create(
             &random_ascii_string(),
             &random_ascii_string(),
+            &ClickMetadata::default(),
             &test.settings,
         )
         .await
@@ -51,6 +56,7 @@ async fn test_aic_model_fetch_one_by_uuid_if_not_available() {
         .create(
             &random_ascii_string(),
             &random_ascii_string(),
+            &ClickMetadata::default(),
             &test.settings,
         )
         .await
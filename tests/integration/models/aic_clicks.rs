@@ -0,0 +1,90 @@
+use crate::utils::get_test_db_pool;
+use lib::{
+    models::{
+        aic::AICModel,
+        aic_clicks::{AICClickModel, ClickMetadata},
+    },
+    settings::get_settings,
+};
+use pretty_assertions::assert_eq;
+
+#[tokio::test]
+async fn test_aic_model_records_every_click() {
+    let settings = get_settings();
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+    let clicks = AICClickModel { db_pool: &db_pool };
+
+    let click_metadata = ClickMetadata {
+        publisher_id: Some("1234".to_string()),
+        sid: Some("a-sid".to_string()),
+        ..Default::default()
+    };
+    let aic = aics
+        .create("first-click", "a-flow-id", &click_metadata, &settings)
+        .await
+        .expect("Could not create AIC.");
+    // A new flow_id alone isn't a click
+    aics.update_flow_id(aic.id, "another-flow-id")
+        .await
+        .expect("Could not update AIC.");
+    let updated = aics
+        .update_flow_id_and_cj_event_value(
+            aic.id,
+            "second-click",
+            "another-flow-id",
+            &ClickMetadata::default(),
+            &settings,
+        )
+        .await
+        .expect("Could not update AIC.");
+
+    let recorded = clicks
+        .fetch_all_by_aic_id(&aic.id)
+        .await
+        .expect("Could not fetch clicks.");
+    assert_eq!(recorded.len(), 2);
+    assert_eq!(recorded[0].cj_event_value, "first-click");
+    assert_eq!(recorded[0].click_metadata, click_metadata.to_json());
+    assert_eq!(
+        recorded[0].expires.unix_timestamp(),
+        aic.expires.unix_timestamp()
+    );
+    assert_eq!(recorded[1].cj_event_value, "second-click");
+    assert_eq!(
+        recorded[1].clicked.unix_timestamp(),
+        updated.created.unix_timestamp()
+    );
+    // The AIC only keeps the metadata of its last click
+    assert_eq!(recorded[1].click_metadata, None);
+    assert_eq!(updated.click_metadata, None);
+    assert!(recorded.iter().all(|click| click.aic_id == aic.id));
+}
+
+#[tokio::test]
+async fn test_aic_model_does_not_record_a_click_for_an_aic_that_is_not_created() {
+    let settings = get_settings();
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+    let clicks = AICClickModel { db_pool: &db_pool };
+    let aic = aics
+        .create("a-click", "a-flow-id", &ClickMetadata::default(), &settings)
+        .await
+        .expect("Could not create AIC.");
+    assert!(aics
+        .create(
+            "another-click",
+            "a-flow-id",
+            &ClickMetadata::default(),
+            &settings,
+        )
+        .await
+        .is_err());
+    assert_eq!(clicks.fetch_all_by_aic_id(&aic.id).await.unwrap().len(), 1);
+    // Only the one click is in the table
+    let n_clicks: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM aic_clicks")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_clicks, 1);
+}
@@ -0,0 +1,165 @@
+use crate::models::{
+    aic::make_fake_aic,
+    refunds::{make_fake_refund, save_refund},
+    subscriptions::{make_fake_sub, save_sub},
+};
+use crate::utils::get_test_db_pool;
+use lib::models::{
+    aic::{AICModel, AIC},
+    aic_clicks::{AICClick, AICClickModel, ClickMetadata},
+    erasures::{ErasureIdentifier, ErasureModel},
+    ingest_failures::IngestFailureModel,
+    refunds::{Refund, RefundModel},
+    status_history::UpdateStatus,
+    subscriptions::{Subscription, SubscriptionModel},
+};
+use lib::settings::get_settings;
+use pretty_assertions::assert_eq;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+/// Saves an archived AIC with a click, its subscription with a refund, and a row of the
+/// subscription that failed to ingest.
+async fn save_person(db_pool: &PgPool) -> (AIC, Subscription, Refund) {
+    let aics = AICModel { db_pool };
+    let aic = make_fake_aic();
+    aics.create_from_aic(&aic)
+        .await
+        .expect("Could not create AIC");
+    AICClickModel { db_pool }
+        .create_from_click(&AICClick::from(&aic))
+        .await
+        .expect("Could not create click");
+    aics.archive_aic(&aic).await.expect("Could not archive AIC");
+    let mut sub = make_fake_sub();
+    sub.flow_id = aic.flow_id.clone();
+    sub.aic_id = Some(aic.id);
+    sub.click_metadata = ClickMetadata {
+        sid: Some("a-sid".to_string()),
+        ..Default::default()
+    }
+    .to_json();
+    save_sub(&SubscriptionModel { db_pool }, &sub).await;
+    let mut refund = make_fake_refund();
+    refund.subscription_id = sub.subscription_id.clone();
+    save_refund(&RefundModel { db_pool }, &refund).await;
+    IngestFailureModel { db_pool }
+        .record(
+            "check-subscriptions",
+            &json!({"fxa_uid": sub.fxa_uid, "flow_id": sub.flow_id}),
+            "an error",
+        )
+        .await
+        .expect("Could not record ingest failure");
+    (aic, sub, refund)
+}
+
+#[tokio::test]
+async fn test_erasure_model_erase_deletes_aics_and_pseudonymizes_subscriptions_and_refunds() {
+    let db_pool = get_test_db_pool().await;
+    let aics = AICModel { db_pool: &db_pool };
+    let subs = SubscriptionModel { db_pool: &db_pool };
+    let refunds = RefundModel { db_pool: &db_pool };
+    let erasures = ErasureModel { db_pool: &db_pool };
+    let (aic, sub, refund) = save_person(&db_pool).await;
+    let (other_aic, other_sub, _) = save_person(&db_pool).await;
+    let counts_before = subs.get_attribution_counts().await.unwrap();
+
+    let erasure = erasures
+        .erase(
+            &ErasureIdentifier::FxaUid(sub.fxa_uid.clone()),
+            "privacy-request-1",
+        )
+        .await
+        .expect("Could not erase");
+
+    assert_eq!(erasure.identifier_type, "fxa_uid");
+    assert_eq!(
+        erasure.identifier_hash,
+        format!("{:x}", Sha256::digest(sub.fxa_uid.as_bytes()))
+    );
+    assert_eq!(erasure.requested_by, "privacy-request-1");
+    assert_eq!(
+        (
+            erasure.aic_deleted,
+            erasure.aic_archive_deleted,
+            erasure.aic_clicks_deleted,
+            erasure.ingest_failures_deleted,
+            erasure.subscriptions_pseudonymized,
+            erasure.refunds_pseudonymized,
+        ),
+        (0, 1, 1, 1, 1, 1)
+    );
+    assert!(aics.fetch_one_by_id_from_archive(&aic.id).await.is_err());
+    assert!(AICClickModel { db_pool: &db_pool }
+        .fetch_all_by_aic_id(&aic.id)
+        .await
+        .unwrap()
+        .is_empty());
+    // The subscription is kept for reports and counts, without what identifies the person
+    let erased = subs.fetch_one_by_id(&sub.id).await.unwrap();
+    for value in [&erased.flow_id, &erased.subscription_id, &erased.fxa_uid] {
+        assert_eq!(value, &format!("erased-{}", sub.id));
+    }
+    assert_eq!(erased.click_metadata, None);
+    assert_eq!(erased.aic_id, sub.aic_id);
+    assert_eq!(erased.plan_amount, sub.plan_amount);
+    assert_eq!(erased.get_status().unwrap(), sub.get_status().unwrap());
+    // And its refund still belongs to it
+    let erased_refund = refunds
+        .fetch_all()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|r| r.id == refund.id)
+        .unwrap();
+    assert_eq!(erased_refund.refund_id, format!("erased-{}", refund.id));
+    assert_eq!(erased_refund.subscription_id, erased.subscription_id);
+    assert_eq!(erased_refund.refund_amount, refund.refund_amount);
+    let counts_after = subs.get_attribution_counts().await.unwrap();
+    assert_eq!(counts_after.attributed, counts_before.attributed);
+    // Nobody else's data is touched
+    assert!(aics
+        .fetch_one_by_id_from_archive(&other_aic.id)
+        .await
+        .is_ok());
+    assert_eq!(
+        subs.fetch_one_by_id(&other_sub.id).await.unwrap(),
+        other_sub
+    );
+    let remaining_failures = IngestFailureModel { db_pool: &db_pool }
+        .fetch_all()
+        .await
+        .unwrap();
+    assert_eq!(remaining_failures.len(), 1);
+}
+
+#[tokio::test]
+async fn test_erasure_model_erase_by_aic_id_and_audit_log() {
+    let db_pool = get_test_db_pool().await;
+    let settings = get_settings();
+    let aics = AICModel { db_pool: &db_pool };
+    let erasures = ErasureModel { db_pool: &db_pool };
+    let aic = aics
+        .create("a-click", "a-flow-id", &ClickMetadata::default(), &settings)
+        .await
+        .expect("Could not create AIC");
+    let identifier = ErasureIdentifier::AicId(aic.id);
+
+    let first = erasures.erase(&identifier, "someone").await.unwrap();
+    assert_eq!(first.aic_deleted, 1);
+    assert_eq!(first.aic_clicks_deleted, 1);
+    assert!(aics.fetch_one_by_id(&aic.id).await.is_err());
+    // Erasing again finds nothing, but is still recorded
+    let second = erasures.erase(&identifier, "someone").await.unwrap();
+    assert_eq!(second.aic_deleted, 0);
+    assert_eq!(second.aic_clicks_deleted, 0);
+
+    let logged = erasures.fetch_all().await.unwrap();
+    assert_eq!(
+        logged.iter().map(|e| e.id).collect::<Vec<_>>(),
+        vec![first.id, second.id]
+    );
+    assert!(logged.iter().all(|e| e.identifier_type == "aic_id"));
+}
@@ -0,0 +1,71 @@
+use crate::utils::get_test_db_pool;
+use lib::models::idempotency_keys::IdempotencyKeyModel;
+use pretty_assertions::assert_eq;
+use serde_json::json;
+use time::Duration;
+
+#[tokio::test]
+async fn test_idempotency_key_model_record_and_fetch() {
+    let db_pool = get_test_db_pool().await;
+    let model = IdempotencyKeyModel { db_pool: &db_pool };
+    let response = json!({"aic_id": "an-aic-id", "expires": 1234});
+    model
+        .record("a-key", "a-hash", &response, Duration::hours(1))
+        .await
+        .expect("Could not record.");
+    let result = model
+        .fetch_one_unexpired("a-key")
+        .await
+        .expect("Could not fetch from DB.");
+    assert_eq!(result.request_hash, "a-hash");
+    assert_eq!(result.response, response);
+    assert_eq!((result.expires - result.created).whole_hours(), 1);
+
+    // A live key is kept
+    model
+        .record("a-key", "another-hash", &json!({}), Duration::hours(1))
+        .await
+        .expect("Could not record.");
+    let result = model
+        .fetch_one_unexpired("a-key")
+        .await
+        .expect("Could not fetch from DB.");
+    assert_eq!(result.request_hash, "a-hash");
+}
+
+#[tokio::test]
+async fn test_idempotency_key_model_expired_keys() {
+    let db_pool = get_test_db_pool().await;
+    let model = IdempotencyKeyModel { db_pool: &db_pool };
+    model
+        .record("expired", "a-hash", &json!({}), Duration::seconds(-1))
+        .await
+        .expect("Could not record.");
+    model
+        .record("live", "a-hash", &json!({}), Duration::hours(1))
+        .await
+        .expect("Could not record.");
+    assert!(matches!(
+        model.fetch_one_unexpired("expired").await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+
+    // An expired key can be reused before it has been deleted
+    model
+        .record("expired", "another-hash", &json!({}), Duration::hours(1))
+        .await
+        .expect("Could not record.");
+    let result = model
+        .fetch_one_unexpired("expired")
+        .await
+        .expect("Could not fetch from DB.");
+    assert_eq!(result.request_hash, "another-hash");
+
+    model
+        .record("expired-2", "a-hash", &json!({}), Duration::seconds(-1))
+        .await
+        .expect("Could not record.");
+    assert_eq!(model.delete_expired().await.unwrap(), 1);
+    assert!(model.fetch_one_unexpired("live").await.is_ok());
+    assert!(model.fetch_one_unexpired("expired").await.is_ok());
+}
@@ -0,0 +1,72 @@
+use crate::utils::get_test_db_pool;
+use lib::models::ingest_failures::IngestFailureModel;
+use pretty_assertions::assert_eq;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_ingest_failure_model_record_and_fetch() {
+    let db_pool = get_test_db_pool().await;
+    let model = IngestFailureModel { db_pool: &db_pool };
+    let raw_row = json!({"flow_id": "a-flow-id", "plan_amount": "not a number"});
+    let recorded = model
+        .record("a-job", &raw_row, "an error")
+        .await
+        .expect("Could not record.");
+    assert_eq!(recorded.first_seen, recorded.last_seen);
+    let result = model
+        .fetch_one_by_id(&recorded.id)
+        .await
+        .expect("Could not fetch from DB.");
+    assert_eq!(result.job, "a-job");
+    assert_eq!(result.raw_row, raw_row);
+    assert_eq!(result.error, "an error");
+}
+
+#[tokio::test]
+async fn test_ingest_failure_model_records_a_row_once_per_job() {
+    let db_pool = get_test_db_pool().await;
+    let model = IngestFailureModel { db_pool: &db_pool };
+    let raw_row = json!({"flow_id": "a-flow-id", "plan_amount": "not a number"});
+    let first = model
+        .record("a-job", &raw_row, "an error")
+        .await
+        .expect("Could not record.");
+    let second = model
+        .record("a-job", &raw_row, "another error")
+        .await
+        .expect("Could not record.");
+    assert_eq!(second.id, first.id);
+    assert_eq!(second.first_seen, first.first_seen);
+    assert!(second.last_seen > first.last_seen);
+    assert_eq!(second.error, "another error");
+    // The same row for another job, or another row, is a separate failure
+    model
+        .record("another-job", &raw_row, "an error")
+        .await
+        .expect("Could not record.");
+    model
+        .record("a-job", &json!({"flow_id": "another-flow-id"}), "an error")
+        .await
+        .expect("Could not record.");
+    assert_eq!(model.fetch_all().await.unwrap().len(), 3);
+    assert_eq!(model.fetch_all_by_job("a-job").await.unwrap().len(), 2);
+    assert_eq!(
+        model.fetch_all_by_job("another-job").await.unwrap().len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_ingest_failure_model_delete() {
+    let db_pool = get_test_db_pool().await;
+    let model = IngestFailureModel { db_pool: &db_pool };
+    let recorded = model
+        .record("a-job", &json!({"flow_id": "a-flow-id"}), "an error")
+        .await
+        .expect("Could not record.");
+    model.delete(&recorded.id).await.expect("Could not delete.");
+    assert!(matches!(
+        model.fetch_one_by_id(&recorded.id).await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+}
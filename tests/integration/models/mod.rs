@@ -1,3 +1,8 @@
 pub mod aic;
+pub mod aic_clicks;
+pub mod erasures;
+pub mod idempotency_keys;
+pub mod ingest_failures;
 pub mod refunds;
 pub mod subscriptions;
+pub mod watermarks;
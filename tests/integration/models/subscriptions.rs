@@ -20,6 +20,7 @@ pub fn make_fake_sub() -> Subscription {
         fxa_uid: random_ascii_string(),
         quantity: 1,
         plan_id: random_simple_ascii_string(),
+        product_id: None,
         plan_currency: random_currency_or_country(),
         plan_amount: random_price(),
         country: Some(random_currency_or_country()),
@@ -27,6 +28,8 @@ pub fn make_fake_sub() -> Subscription {
         aic_id: Some(Uuid::new_v4()),
         aic_expires: Some(OffsetDateTime::now_utc()),
         cj_event_value: Some(random_ascii_string()),
+        unattributed_reason: None,
+        click_metadata: None,
     })
 }
 
@@ -230,3 +233,30 @@ async fn test_subscription_update_sub_status() {
         OffsetDateTime::now_utc().unix_timestamp()
     );
 }
+
+#[tokio::test]
+async fn test_subscription_model_get_attribution_counts() {
+    let db_pool = get_test_db_pool().await;
+    let model = SubscriptionModel { db_pool: &db_pool };
+    let counts = model.get_attribution_counts().await.unwrap();
+    assert_eq!(counts.attribution_rate(), None);
+    for status in [
+        Status::NotReported,
+        Status::Reported,
+        Status::Reported,
+        Status::Organic,
+        Status::PendingAttribution,
+    ] {
+        let mut sub = make_fake_sub();
+        if status == Status::Organic || status == Status::PendingAttribution {
+            sub.aic_id = None;
+        }
+        sub.update_status(status);
+        save_sub(&model, &sub).await;
+    }
+    let counts = model.get_attribution_counts().await.unwrap();
+    assert_eq!(counts.attributed, 3);
+    assert_eq!(counts.organic, 1);
+    assert_eq!(counts.pending, 1);
+    assert_eq!(counts.attribution_rate(), Some(75.0));
+}
@@ -0,0 +1,71 @@
+use crate::utils::get_test_db_pool;
+use lib::models::watermarks::WatermarkModel;
+use pretty_assertions::assert_eq;
+use time::{date, time};
+
+#[tokio::test]
+async fn test_watermark_model_set_and_fetch_by_job() {
+    let db_pool = get_test_db_pool().await;
+    let model = WatermarkModel { db_pool: &db_pool };
+    assert!(matches!(
+        model.fetch_one_by_job("a-job").await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+    let t1 = date!(2022 - 03 - 16)
+        .with_time(time!(20:59:53))
+        .assume_utc();
+    let t0 = date!(2022 - 01 - 01).with_time(time!(00:00)).assume_utc();
+    model.set("a-job", t1).await.expect("Could not set.");
+    // Set overwrites even when moving backwards
+    model.set("a-job", t0).await.expect("Could not set.");
+    let result = model
+        .fetch_one_by_job("a-job")
+        .await
+        .expect("Could not fetch from DB.");
+    assert_eq!(result.job, "a-job");
+    assert_eq!(result.watermark, t0);
+}
+
+#[tokio::test]
+async fn test_watermark_model_advance_only_moves_forwards() {
+    let db_pool = get_test_db_pool().await;
+    let model = WatermarkModel { db_pool: &db_pool };
+    let t0 = date!(2022 - 01 - 01).with_time(time!(00:00)).assume_utc();
+    let t1 = date!(2022 - 03 - 16)
+        .with_time(time!(20:59:53))
+        .assume_utc();
+    // Advancing a job with no watermark creates one
+    let result = model
+        .advance("a-job", t1)
+        .await
+        .expect("Could not advance.");
+    assert_eq!(result.watermark, t1);
+    let result = model
+        .advance("a-job", t0)
+        .await
+        .expect("Could not advance.");
+    assert_eq!(result.watermark, t1);
+    // Other jobs are unaffected
+    let result = model
+        .advance("another-job", t0)
+        .await
+        .expect("Could not advance.");
+    assert_eq!(result.watermark, t0);
+}
+
+#[tokio::test]
+async fn test_watermark_model_delete() {
+    let db_pool = get_test_db_pool().await;
+    let model = WatermarkModel { db_pool: &db_pool };
+    let t1 = date!(2022 - 03 - 16)
+        .with_time(time!(20:59:53))
+        .assume_utc();
+    model.set("a-job", t1).await.expect("Could not set.");
+    model.delete("a-job").await.expect("Could not delete.");
+    assert!(matches!(
+        model.fetch_one_by_job("a-job").await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+    // Deleting a missing watermark is not an error
+    model.delete("a-job").await.expect("Could not delete.");
+}
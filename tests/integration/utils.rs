@@ -47,6 +47,10 @@ pub async fn get_test_db_pool() -> Pool<Postgres> {
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with_settings(|_| {}).await
+}
+
+pub async fn spawn_app_with_settings(customize: impl FnOnce(&mut Settings)) -> TestApp {
     let mut settings = get_settings();
     let test_subid = random_simple_ascii_string();
     let test_aic_expiration_days = random_integer();
@@ -62,6 +66,7 @@ pub async fn spawn_app() -> TestApp {
     settings.cj_subid = test_subid;
     settings.database_url = Secret::new(test_database_url);
     settings.port = port;
+    customize(&mut settings);
     let statsd = StatsD::new(&settings);
     let db_pool = connect_to_database_and_migrate(settings.database_url.expose_secret()).await;
     let server =